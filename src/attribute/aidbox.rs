@@ -1,4 +1,4 @@
-use std::io::Read;
+use std::{collections::BTreeMap, io::Read, path::PathBuf};
 
 use miette::Diagnostic;
 use serde::Deserialize;
@@ -55,6 +55,9 @@ pub struct Attribute {
     /// List of allowed values
     pub r#enum: Option<Vec<String>>,
 
+    /// Maximum length of a string value
+    pub max_length: Option<u32>,
+
     /// I don't know
     pub order: Option<i64>,
 
@@ -70,18 +73,27 @@ pub struct Attribute {
     /// If this is a reference, which targets are allowed
     pub refers: Option<Vec<String>>,
 
+    /// Translation of legacy codes to FHIR codes, emitted as a ConceptMap
+    pub concept_map: Option<ConceptMapping>,
+
     pub resource_type: Option<String>,
 
     #[serde(rename = "_source")]
     pub source: Option<String>,
+
+    /// File the attribute was read from (not a part of the resource)
+    #[serde(skip)]
+    pub filename: Option<PathBuf>,
 }
 
 #[derive(Debug, Error, Diagnostic)]
 pub enum Error {
     #[error("Could not parse Attribute resource as JSON (malformed JSON or invalid resource)")]
+    #[diagnostic(code(attribute::aidbox::invalid_json))]
     InvalidJson(#[from] serde_json::Error),
 
     #[error("Could not parse Attribute resource as YAML (malformed YAML or invalid resource)")]
+    #[diagnostic(code(attribute::aidbox::invalid_yaml))]
     InvalidYaml(#[from] serde_yaml::Error),
 }
 
@@ -92,6 +104,26 @@ pub struct Reference {
     pub resource_type: String,
 }
 
+/// Translation of the legacy codes of a coded attribute to FHIR codes, for example:
+///
+/// ```json
+/// "conceptMap": {
+///   "source": "http://example.org/CodeSystem/legacy-gender",
+///   "target": "http://hl7.org/fhir/administrative-gender",
+///   "element": {"M": "male", "F": "female"}
+/// }
+/// ```
+///
+/// `element` maps every legacy code to the FHIR code it translates to. `source` is the code
+/// system of the legacy codes, by default the code system of the attribute enumeration.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ConceptMapping {
+    pub source: Option<String>,
+    pub target: String,
+    pub element: BTreeMap<String, String>,
+}
+
 impl Attribute {
     pub fn from_json(reader: impl Read) -> Result<Self, Error> {
         serde_json::from_reader(reader).map_err(|e| e.into())
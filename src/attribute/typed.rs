@@ -1,7 +1,9 @@
+use std::path::{Path, PathBuf};
+
 use miette::Diagnostic;
 use thiserror::Error;
 
-use crate::attribute::aidbox;
+use crate::{attribute::aidbox, report::Subject, resource_map};
 
 #[derive(Debug, Clone)]
 pub struct Attribute {
@@ -11,10 +13,18 @@ pub struct Attribute {
     pub kind: AttributeKind,
     pub array: bool,
     pub required: bool,
+    /// Marked with isModifier, see [`InvalidAttributeError::ModifierPresent`]
+    pub modifier: bool,
+    /// Marked with isSummary, see [`InvalidAttributeError::SummaryPresent`]
+    pub summary: bool,
     pub fce: Option<String>,
+    /// File the attribute was read from, reported with the errors of the tries built from it
+    pub filename: Option<PathBuf>,
+    /// Aidbox module the attribute comes from
+    pub module: Option<String>,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum AttributeKind {
     /// `value[x]`
     Poly(AttributeKindPoly),
@@ -24,167 +34,312 @@ pub enum AttributeKind {
     Complex(AttributeKindComplex),
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct AttributeKindPoly {
     pub targets: Vec<String>,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct AttributeKindConcrete {
     pub target: String,
     pub value_set: Option<String>,
     pub refers: Option<Vec<String>>,
     pub enumeration: Option<Vec<String>>,
+    pub max_length: Option<u32>,
+    pub description: Option<String>,
+    /// Attribute referenced instead of a type, for recursive structures. Holds the id of the
+    /// referenced attribute when read, [`crate::convert`] resolves it to the `#Type.path` of its
+    /// element.
+    pub content_reference: Option<String>,
+    pub concept_map: Option<aidbox::ConceptMapping>,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct AttributeKindComplex {
     pub open: bool,
+    pub description: Option<String>,
 }
 
 // FIXME: something better than <missing id>
-#[derive(Debug, Error, Diagnostic)]
+#[derive(Debug, Error)]
 #[error("Attribute {} is invalid", id.clone().unwrap_or(String::from("<missing id>")))]
 pub struct Error {
     pub id: Option<String>,
+    pub filename: Option<PathBuf>,
     #[source]
-    #[diagnostic_source]
-    #[diagnostic(transparent)]
     pub source: InvalidAttributeError,
 }
 
+/// Shows the details of the source, so that warnings are reported as warnings
+impl Diagnostic for Error {
+    fn code<'a>(&'a self) -> Option<Box<dyn std::fmt::Display + 'a>> {
+        self.source.code()
+    }
+
+    fn severity(&self) -> Option<miette::Severity> {
+        self.source.severity()
+    }
+
+    fn diagnostic_source(&self) -> Option<&dyn Diagnostic> {
+        Some(&self.source)
+    }
+}
+
+impl Subject for Error {
+    fn attribute_id(&self) -> Option<&str> {
+        self.id.as_deref()
+    }
+
+    fn filename(&self) -> Option<&Path> {
+        self.filename.as_deref()
+    }
+}
+
 #[derive(Debug, Error, Diagnostic)]
 pub enum InvalidAttributeError {
     #[error("Missing id property")]
-    #[diagnostic(help(
-        "The id property is important for automatic conversion. Populate the id properties or extract Attributes from live Aidbox."
-    ))]
+    #[diagnostic(
+        code(attribute::typed::missing_id),
+        help(
+            "The id property is important for automatic conversion. Populate the id properties or extract Attributes from live Aidbox."
+        )
+    )]
     MissingId,
 
     #[error("Both union and type cannot be present")]
-    #[diagnostic(help(
-        "In Aidbox union takes the effect. To avoid ambiguity during conversion, leave only one."
-    ))]
+    #[diagnostic(
+        code(attribute::typed::invalid_kind),
+        help(
+            "In Aidbox union takes the effect. To avoid ambiguity during conversion, leave only one."
+        )
+    )]
     InvalidKind,
 
     #[error("schema field is present. JSON Schema is not supported")]
-    #[diagnostic(help(
-        "{} {}\n{}",
-        "schema field is a JSON Schema for validating the property.",
-        "This converter does not JSON Schema.",
-        "Consider writing corresponding StructureDefinition manually."
-    ))]
+    #[diagnostic(
+        code(attribute::typed::schema_present),
+        help(
+            "{} {}\n{}",
+            "schema field is a JSON Schema for validating the property.",
+            "This converter does not JSON Schema.",
+            "Consider writing corresponding StructureDefinition manually."
+        )
+    )]
     SchemaPresent,
 
     #[error("Unsupported property: isSummary")]
-    #[diagnostic(help(
-        "{}\n{}",
-        "isSummary makes element appear in _summary. Only FHIR itself can mark elements as summary.",
-        "Consider removing it to conform with the FHIR spec."
-    ))]
+    #[diagnostic(
+        code(attribute::typed::summary_present),
+        help(
+            "{}\n{}",
+            "isSummary makes element appear in _summary. Only FHIR itself can mark elements as summary.",
+            "Consider removing it to conform with the FHIR spec."
+        )
+    )]
     SummaryPresent,
 
     #[error("Unsupported property: isModifier")]
-    #[diagnostic(help(
-        "{} {}\n{}",
-        "isModifier marks modifier element or modifier extension.",
-        "There are some additional restrictions from FHIR, so the converter does not support them.",
-        "Consider removing isModifier from Attributes and adding to generated StructureDefintion resources manually."
-    ))]
+    #[diagnostic(
+        code(attribute::typed::modifier_present),
+        help(
+            "{} {}\n{}",
+            "isModifier marks modifier element or modifier extension.",
+            "There are some additional restrictions from FHIR, so the converter does not support them.",
+            "Consider removing isModifier from Attributes and adding to generated StructureDefintion resources manually."
+        )
+    )]
     ModifierPresent,
 
     #[error("Unsupported property: isUnique")]
-    #[diagnostic(help(
-        "{} {}\n{}",
-        "isUnique provides automatic validation of some kind of uniqueness across all resources in database.",
-        "This validation is not supported in FHIR Schema mode.",
-        "Construct equivalent unique index in database and remove the isUnique on the Attribute."
-    ))]
+    #[diagnostic(
+        code(attribute::typed::unique_present),
+        help(
+            "{} {}\n{}",
+            "isUnique provides automatic validation of some kind of uniqueness across all resources in database.",
+            "This validation is not supported in FHIR Schema mode.",
+            "Construct equivalent unique index in database and remove the isUnique on the Attribute."
+        )
+    )]
     UniquePresent,
 
     #[error("Unsupported property: order")]
-    #[diagnostic(help(
-        "{} {}\n{}",
-        "The order property in Aidbox Attribute reflects the ElementDefinition position in the differential.",
-        "This converter does not support order or ordered slices, and ignoring it is probably safe.",
-        "But you should consider removing it."
-    ))]
+    #[diagnostic(
+        code(attribute::typed::order_present),
+        help(
+            "{} {}\n{}",
+            "The order property in Aidbox Attribute reflects the ElementDefinition position in the differential.",
+            "This converter does not support order or ordered slices, and ignoring it is probably safe.",
+            "But you should consider removing it."
+        )
+    )]
     OrderPresent,
 
     #[error("Invalid type reference resourceType: expected Entity, found {}", .0.resource_type)]
-    #[diagnostic(help(
-        "{} {}",
-        "In valid Aidbox Attribute type is either reference to Entity, or to Attribute.",
-        "Reference to Attribute is used to describe recursive structure, which is not supported by this converter",
-    ))]
+    #[diagnostic(
+        code(attribute::typed::invalid_entity_reference),
+        help(
+            "{} {}",
+            "In valid Aidbox Attribute type is either reference to Entity, or to Attribute.",
+            "Reference to Attribute is used to describe recursive structure.",
+        )
+    )]
     InvalidEntityReference(aidbox::Reference),
 
+    #[error("Recursive reference to attribute {}", .0.id)]
+    #[diagnostic(
+        code(attribute::typed::recursive_reference),
+        help(
+            "{} {}",
+            "Reference to Attribute describes a recursive structure, which is converted only with --allow-recursive.",
+            "The generated element gets a contentReference to the element of the referenced attribute."
+        )
+    )]
+    RecursiveReference(aidbox::Reference),
+
+    #[error("Recursive reference to attribute {} on extension", .0.id)]
+    #[diagnostic(
+        code(attribute::typed::recursive_extension),
+        help(
+            "Extension values cannot refer to other elements. Model the recursive structure without extensionUrl."
+        )
+    )]
+    RecursiveExtension(aidbox::Reference),
+
     #[error("Invalid ValueSet reference resourceType: expected ValueSet, found {}", .0.resource_type)]
-    #[diagnostic(help("Check ValueSet reference."))]
+    #[diagnostic(
+        code(attribute::typed::invalid_valueset_reference),
+        help("Check ValueSet reference.")
+    )]
     InvalidValuesetReference(aidbox::Reference),
 
+    #[error("Type {0} is not a FHIR type")]
+    #[diagnostic(
+        code(attribute::typed::custom_target_type),
+        severity(Warning),
+        help(
+            "The type becomes the type code of the generated element, which FHIR validators do not know. Consider modeling it as a complex attribute with children, or as a Reference to a resource."
+        )
+    )]
+    CustomTargetType(String),
+
+    #[error("Empty extensionUrl")]
+    #[diagnostic(
+        code(attribute::typed::empty_extension_url),
+        help(
+            "extensionUrl becomes the url of the generated extension. Set it to the extension url or remove it."
+        )
+    )]
+    EmptyExtensionUrl,
+
     #[error("Invalid concrete attribute.")]
+    #[diagnostic(code(attribute::typed::invalid_concrete))]
     InvalidConcrete(#[from] InvalidConcrete),
 
     #[error("Invalid polymorphic attribute.")]
+    #[diagnostic(code(attribute::typed::invalid_polymorphic))]
     InvalidPolymorphic(#[from] InvalidPolymorphic),
 
     #[error("Invalid complex attribute.")]
+    #[diagnostic(code(attribute::typed::invalid_complex))]
     InvalidComplex(#[from] InvalidComplex),
 }
 
 #[derive(Debug, Error, Diagnostic)]
 pub enum InvalidPolymorphic {
     #[error("ValueSet binding on polymorphic root is not allowed")]
-    #[diagnostic(help(
-        "{}\n{}",
-        "It is allowed by FHIR spec, but Aidbox Attribute validator doesn't support it, so the converter rejects such cases.",
-        "Consider removing binding or moving it to polymorphic targets."
-    ))]
+    #[diagnostic(
+        code(attribute::typed::value_set_present),
+        help(
+            "{}\n{}",
+            "It is allowed by FHIR spec, but Aidbox Attribute validator doesn't support it, so the converter rejects such cases.",
+            "Consider removing binding or moving it to polymorphic targets."
+        )
+    )]
     ValueSetPresent,
 
     #[error("isOpen on polymorhic is not allowed")]
-    #[diagnostic(help(
-        "It is not clear how to map isOpen to correct FHIR extensions. Contact us to come up with solution."
-    ))]
+    #[diagnostic(
+        code(attribute::typed::open_schema),
+        help(
+            "It is not clear how to map isOpen to correct FHIR extensions. Contact us to come up with solution."
+        )
+    )]
     OpenSchema,
 
     #[error("enum on polymorphic is not allowed")]
-    #[diagnostic(help(
-        "{} {}",
-        "Aidbox attribute validator doesn't interpret enum on polymorphic root attribute.",
-        "To avoid ambiguites the converter considers it an error."
-    ))]
+    #[diagnostic(
+        code(attribute::typed::enum_present),
+        help(
+            "{} {}",
+            "Aidbox attribute validator doesn't interpret enum on polymorphic root attribute.",
+            "To avoid ambiguites the converter considers it an error."
+        )
+    )]
     EnumPresent,
 
     #[error("Reference target binding on polymorhpic is not allowed")]
-    #[diagnostic(help(
-        "Reference target should be placed on concrete polymorphic choice attribute."
-    ))]
+    #[diagnostic(
+        code(attribute::typed::refers_present),
+        help("Reference target should be placed on concrete polymorphic choice attribute.")
+    )]
     RefersPresent,
 
+    #[error("conceptMap on polymorphic is not allowed")]
+    #[diagnostic(
+        code(attribute::typed::concept_map_present),
+        help("conceptMap should be placed on concrete polymorphic choice attribute.")
+    )]
+    ConceptMapPresent,
+
+    #[error("maxLength on polymorphic is not allowed")]
+    #[diagnostic(
+        code(attribute::typed::max_length_present),
+        help("maxLength should be placed on concrete polymorphic choice attribute.")
+    )]
+    MaxLengthPresent,
+
     #[error("Empty list of targets")]
-    #[diagnostic(help(
-        "Polymorphic element without any targets could not be present in a resource."
-    ))]
+    #[diagnostic(
+        code(attribute::typed::no_targets),
+        help("Polymorphic element without any targets could not be present in a resource.")
+    )]
     NoTargets,
 }
 
 #[derive(Debug, Error, Diagnostic)]
 pub enum InvalidConcrete {
     #[error("ValueSet binding declared on type not supporting bindings: {0}")]
-    #[diagnostic(help(
-        "ValueSet binding can be only on coded types. Refer to the FHIR specification to get a list of all coded data types."
-    ))]
+    #[diagnostic(
+        code(attribute::typed::value_set_on_wrong_type),
+        help(
+            "ValueSet binding can be only on coded types. Refer to the FHIR specification to get a list of all coded data types."
+        )
+    )]
     ValueSetOnWrongType(String),
 
+    #[error("conceptMap declared on type not supporting codes: {0}")]
+    #[diagnostic(
+        code(attribute::typed::concept_map_on_wrong_type),
+        help(
+            "conceptMap translates codes, so it is only allowed on coded types, same as ValueSet binding."
+        )
+    )]
+    ConceptMapOnWrongType(String),
+
     #[error("Reference target binding on non-reference type: {0}")]
+    #[diagnostic(code(attribute::typed::refers_on_non_reference_type))]
     RefersOnNonReferenceType(String),
 
     #[error("enum specified on non-string-type: {0}")]
+    #[diagnostic(code(attribute::typed::enum_on_non_stirng_type))]
     EnumOnNonStirngType(String),
 
+    #[error("maxLength specified on non-string type: {0}")]
+    #[diagnostic(code(attribute::typed::max_length_on_non_string_type))]
+    MaxLengthOnNonStringType(String),
+
     #[error("isOpen is not allowed on concrete Attribute resources")]
+    #[diagnostic(code(attribute::typed::open_schema))]
     OpenSchema,
 }
 
@@ -198,6 +353,12 @@ pub enum InvalidComplex {
 
     #[error("refers is not allowed on complex attributes")]
     RefersPresent,
+
+    #[error("maxLength is not allowed on complex attributes")]
+    MaxLengthPresent,
+
+    #[error("conceptMap is not allowed on complex attributes")]
+    ConceptMapPresent,
 }
 
 const CODED_TYPES: &[&str] = &[
@@ -231,7 +392,23 @@ const STRING_TYPES: &[&str] = &[
     "xhtml",
 ];
 
+/// Target of attributes referring to another attribute, see
+/// [`AttributeKindConcrete::content_reference`]
+pub const RECURSIVE_TARGET: &str = "BackboneElement";
+
 impl Attribute {
+    /// Both attributes define the same element, only their ids and origins may differ
+    pub fn same_definition(&self, other: &Attribute) -> bool {
+        self.resource_type == other.resource_type
+            && self.path == other.path
+            && self.kind == other.kind
+            && self.array == other.array
+            && self.required == other.required
+            && self.modifier == other.modifier
+            && self.summary == other.summary
+            && self.fce == other.fce
+    }
+
     fn check_unsupported_properties(attr: &aidbox::Attribute) -> Vec<InvalidAttributeError> {
         let mut errors: Vec<InvalidAttributeError> = Vec::new();
 
@@ -336,10 +513,28 @@ impl Attribute {
             None
         };
 
-        let (target, error) = Self::parse_type(attr_type);
-        if let Some(error) = error {
-            errors.push(error);
-        }
+        let (target, content_reference) = if attr_type.resource_type == "Attribute" {
+            if attr.extension_url.is_some() {
+                errors.push(InvalidAttributeError::RecursiveExtension(
+                    attr_type.to_owned(),
+                ));
+                return (None, errors);
+            }
+            errors.push(InvalidAttributeError::RecursiveReference(
+                attr_type.to_owned(),
+            ));
+            // The referenced element is not expanded, so it has no type of its own
+            (
+                Some(RECURSIVE_TARGET.to_owned()),
+                Some(attr_type.id.to_owned()),
+            )
+        } else {
+            let (target, error) = Self::parse_type(attr_type);
+            if let Some(error) = error {
+                errors.push(error);
+            }
+            (target, None)
+        };
         if let Some(target) = target {
             if value_set.is_some() && !CODED_TYPES.contains(&target.as_str()) {
                 errors.push(InvalidConcrete::ValueSetOnWrongType(target.clone()).into());
@@ -349,10 +544,22 @@ impl Attribute {
                 errors.push(InvalidConcrete::EnumOnNonStirngType(target.clone()).into());
             }
 
+            if attr.max_length.is_some() && !STRING_TYPES.contains(&target.as_str()) {
+                errors.push(InvalidConcrete::MaxLengthOnNonStringType(target.clone()).into());
+            }
+
+            if attr.concept_map.is_some() && !CODED_TYPES.contains(&target.as_str()) {
+                errors.push(InvalidConcrete::ConceptMapOnWrongType(target.clone()).into());
+            }
+
             if attr.refers.is_some() && target != "Reference" {
                 errors.push(InvalidConcrete::RefersOnNonReferenceType(target.clone()).into());
             }
 
+            if !resource_map::is_fhir_type(&target) {
+                errors.push(InvalidAttributeError::CustomTargetType(target.clone()));
+            }
+
             let Some(id) = attr.id else {
                 errors.push(InvalidAttributeError::MissingId);
                 return (None, errors);
@@ -367,6 +574,10 @@ impl Attribute {
                 value_set,
                 refers: attr.refers.to_owned(),
                 enumeration: attr.r#enum,
+                max_length: attr.max_length,
+                description: attr.description,
+                content_reference,
+                concept_map: attr.concept_map,
             });
 
             let attr = Some(Attribute {
@@ -376,7 +587,11 @@ impl Attribute {
                 kind,
                 array: attr.is_collection.is_some_and(|x| x),
                 required: attr.is_required.is_some_and(|x| x),
+                modifier: attr.is_modifier.is_some_and(|x| x),
+                summary: attr.is_summary.is_some_and(|x| x),
                 fce: attr.extension_url.to_owned(),
+                filename: attr.filename.clone(),
+                module: attr.module.clone(),
             });
 
             (attr, errors)
@@ -417,6 +632,14 @@ impl Attribute {
             errors.push(InvalidPolymorphic::RefersPresent.into());
         }
 
+        if attr.max_length.is_some() {
+            errors.push(InvalidPolymorphic::MaxLengthPresent.into());
+        }
+
+        if attr.concept_map.is_some() {
+            errors.push(InvalidPolymorphic::ConceptMapPresent.into());
+        }
+
         if attr_types.is_empty() {
             errors.push(InvalidPolymorphic::NoTargets.into());
         }
@@ -454,7 +677,11 @@ impl Attribute {
             kind,
             array: attr.is_collection.is_some_and(|x| x),
             required: attr.is_required.is_some_and(|x| x),
+            modifier: attr.is_modifier.is_some_and(|x| x),
+            summary: attr.is_summary.is_some_and(|x| x),
             fce: attr.extension_url,
+            filename: attr.filename.clone(),
+            module: attr.module.clone(),
         });
 
         (attr, errors)
@@ -485,6 +712,14 @@ impl Attribute {
             errors.push(InvalidComplex::RefersPresent.into());
         }
 
+        if attr.max_length.is_some() {
+            errors.push(InvalidComplex::MaxLengthPresent.into());
+        }
+
+        if attr.concept_map.is_some() {
+            errors.push(InvalidComplex::ConceptMapPresent.into());
+        }
+
         let Some(id) = attr.id else {
             errors.push(InvalidAttributeError::MissingId);
             return (None, errors);
@@ -496,6 +731,7 @@ impl Attribute {
 
         let kind = AttributeKind::Complex(AttributeKindComplex {
             open: attr.is_open.is_some_and(|x| x),
+            description: attr.description,
         });
         let attr = Some(Attribute {
             id,
@@ -504,7 +740,11 @@ impl Attribute {
             kind,
             array: attr.is_collection.is_some_and(|x| x),
             required: attr.is_required.is_some_and(|x| x),
+            modifier: attr.is_modifier.is_some_and(|x| x),
+            summary: attr.is_summary.is_some_and(|x| x),
             fce: attr.extension_url,
+            filename: attr.filename.clone(),
+            module: attr.module.clone(),
         });
         (attr, errors)
     }
@@ -513,8 +753,16 @@ impl Attribute {
         let mut errors: Vec<InvalidAttributeError> = Self::check_unsupported_properties(&attr);
 
         let id = attr.id.clone();
+        let filename = attr.filename.clone();
 
         let (typed_attr, mut read_errors) = match (&attr.r#type, &attr.union) {
+            _ if attr
+                .extension_url
+                .as_ref()
+                .is_some_and(|url| url.trim().is_empty()) =>
+            {
+                (None, vec![InvalidAttributeError::EmptyExtensionUrl])
+            }
             (Some(_), None) => Self::read_target_attribute(attr),
             (None, Some(_)) => Self::read_poly_attribute(attr),
             (None, None) => Self::read_complex_attribute(attr),
@@ -527,6 +775,7 @@ impl Attribute {
             .into_iter()
             .map(|error| Error {
                 id: id.clone(),
+                filename: filename.clone(),
                 source: error,
             })
             .collect();
@@ -534,3 +783,53 @@ impl Attribute {
         (typed_attr, errors)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use miette::Diagnostic;
+    use serde_json::json;
+
+    use crate::attribute::{aidbox, typed::Attribute, typed::InvalidAttributeError};
+
+    #[test]
+    fn test_empty_extension_url() {
+        for url in ["", "  "] {
+            let attr: aidbox::Attribute = serde_json::from_value(json!({
+                "id": "Patient.nickname",
+                "path": ["nickname"],
+                "resource": {"id": "Patient", "resourceType": "Entity"},
+                "type": {"id": "string", "resourceType": "Entity"},
+                "extensionUrl": url,
+            }))
+            .unwrap();
+
+            let (typed, errors) = Attribute::build_from(attr);
+            assert!(typed.is_none());
+            assert_eq!(errors.len(), 1);
+            assert!(matches!(
+                errors[0].source,
+                InvalidAttributeError::EmptyExtensionUrl
+            ));
+        }
+    }
+
+    #[test]
+    fn test_custom_target_type() {
+        let attr: aidbox::Attribute = serde_json::from_value(json!({
+            "id": "Patient.address",
+            "path": ["address"],
+            "resource": {"id": "Patient", "resourceType": "Entity"},
+            "type": {"id": "MyAddress", "resourceType": "Entity"},
+        }))
+        .unwrap();
+
+        let (typed, errors) = Attribute::build_from(attr);
+        assert!(typed.is_some());
+        assert_eq!(errors.len(), 1);
+        assert!(matches!(
+            &errors[0].source,
+            InvalidAttributeError::CustomTargetType(target) if target == "MyAddress"
+        ));
+        assert_eq!(errors[0].severity(), Some(miette::Severity::Warning));
+    }
+}
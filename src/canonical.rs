@@ -0,0 +1,196 @@
+//! Check that canonical urls referenced by generated StructureDefinitions resolve.
+//!
+//! Core urls (`http://hl7.org/fhir/StructureDefinition/<Type>`) must name a type of the builtin
+//! resources for the FHIR version. Other urls must be defined by the generated resources
+//! themselves: extensions, profiles, value sets, code systems or concept maps. The code systems
+//! included by generated value sets are checked the same way. Bindings to value sets given by
+//! id rather than by url, and other hl7.org urls, are not checked.
+
+use std::collections::BTreeSet;
+
+use miette::Diagnostic;
+use thiserror::Error;
+
+use crate::{ConversionResult, report::Subject, resource_map, trie::fhir::StructureDefinition};
+
+const CORE_STRUCTURE_DEFINITION_URL: &str = "http://hl7.org/fhir/StructureDefinition/";
+
+#[derive(Debug, Error, Diagnostic)]
+pub enum Error {
+    #[error("{referenced_by} refers to {url}, which is not a type of this FHIR version")]
+    #[diagnostic(
+        code(canonical::unknown_core_type),
+        help(
+            "Core StructureDefinitions are only resolvable for types of the target FHIR version. Check the --fhir-version and the type names."
+        )
+    )]
+    UnknownCoreType { url: String, referenced_by: String },
+
+    #[error("{referenced_by} refers to {url}, which is not defined in the generated package")]
+    #[diagnostic(
+        code(canonical::undefined),
+        help(
+            "The package must define every non-core canonical it refers to. Check the extension urls and the --extension-base values."
+        )
+    )]
+    Undefined { url: String, referenced_by: String },
+}
+
+impl Subject for Error {}
+
+/// Urls of the canonical references of the StructureDefinition
+fn references(sd: &StructureDefinition) -> Vec<&str> {
+    let mut urls = vec![sd.base_definition.as_str()];
+    for element in &sd.differential.element {
+        for r#type in element.r#type.iter().flatten() {
+            for url in r#type
+                .profile
+                .iter()
+                .chain(&r#type.target_profile)
+                .flatten()
+            {
+                urls.push(url);
+            }
+        }
+        if let Some(binding) = &element.binding
+            && binding.value_set.contains("://")
+        {
+            urls.push(&binding.value_set);
+        }
+    }
+    urls
+}
+
+/// Report dangling canonical references of the generated StructureDefinitions.
+///
+/// `builtin_types` are the types with builtin attributes for the target FHIR version. Urls of
+/// Aidbox custom types are defined by Aidbox itself and always resolve.
+pub fn check_references(result: &ConversionResult, builtin_types: &BTreeSet<&str>) -> Vec<Error> {
+    let defined: BTreeSet<&str> = result
+        .extensions
+        .iter()
+        .chain(&result.profiles)
+        .map(|sd| sd.url.as_str())
+        .chain(result.value_sets.iter().map(|vs| vs.url.as_str()))
+        .chain(result.code_systems.iter().map(|cs| cs.url.as_str()))
+        .chain(result.concept_maps.iter().map(|cm| cm.url.as_str()))
+        .collect();
+
+    let mut errors = Vec::new();
+    for sd in result.extensions.iter().chain(&result.profiles) {
+        // Each url is reported once per referencing resource
+        let urls: BTreeSet<&str> = references(sd).into_iter().collect();
+        for url in urls {
+            if defined.contains(url) {
+                continue;
+            }
+            if let Some(name) = url.strip_prefix(CORE_STRUCTURE_DEFINITION_URL) {
+                if !builtin_types.contains(name) {
+                    errors.push(Error::UnknownCoreType {
+                        url: url.to_owned(),
+                        referenced_by: sd.url.clone(),
+                    });
+                }
+            } else if !url.starts_with("http://hl7.org/") && !resource_map::is_aidbox_type_url(url)
+            {
+                errors.push(Error::Undefined {
+                    url: url.to_owned(),
+                    referenced_by: sd.url.clone(),
+                });
+            }
+        }
+    }
+
+    for vs in &result.value_sets {
+        let systems: BTreeSet<&str> = vs
+            .compose
+            .include
+            .iter()
+            .map(|include| include.system.as_str())
+            .collect();
+        for system in systems {
+            if !defined.contains(system) && !system.starts_with("http://hl7.org/") {
+                errors.push(Error::Undefined {
+                    url: system.to_owned(),
+                    referenced_by: vs.url.clone(),
+                });
+            }
+        }
+    }
+    errors
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+
+    use serde_json::json;
+
+    use crate::{
+        FhirVersion,
+        canonical::{self, Error},
+        convert_attributes,
+    };
+
+    #[test]
+    fn test_check_references() {
+        let attribute = |id: &str, url: &str| {
+            serde_json::from_value(json!({
+                "id": id,
+                "path": [id],
+                "resource": {"id": "Patient", "resourceType": "Entity"},
+                "type": {"id": "string", "resourceType": "Entity"},
+                "extensionUrl": url,
+            }))
+            .unwrap()
+        };
+        let mut result = convert_attributes(
+            vec![attribute("nickname", "http://example.org/nickname")],
+            Vec::new(),
+            FhirVersion::V4_0_1,
+        );
+
+        let builtin_types = BTreeSet::from(["Patient", "Extension"]);
+        assert!(canonical::check_references(&result, &builtin_types).is_empty());
+
+        // The profile slice refers to the extension, which is no longer in the package
+        result.extensions.clear();
+        let errors = canonical::check_references(&result, &BTreeSet::from(["Extension"]));
+        let urls: Vec<&str> = errors
+            .iter()
+            .map(|error| match error {
+                Error::UnknownCoreType { url, .. } | Error::Undefined { url, .. } => url.as_str(),
+            })
+            .collect();
+        assert_eq!(
+            urls,
+            [
+                "http://example.org/nickname",
+                "http://hl7.org/fhir/StructureDefinition/Patient"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_check_value_set_systems() {
+        let attribute = serde_json::from_value(json!({
+            "id": "Patient.status",
+            "path": ["status"],
+            "resource": {"id": "Patient", "resourceType": "Entity"},
+            "type": {"id": "code", "resourceType": "Entity"},
+            "enum": ["active", "gone"],
+        }))
+        .unwrap();
+        let mut result = convert_attributes(vec![attribute], Vec::new(), FhirVersion::V4_0_1);
+
+        let builtin_types = BTreeSet::from(["Patient"]);
+        assert!(canonical::check_references(&result, &builtin_types).is_empty());
+
+        result.code_systems.clear();
+        let errors = canonical::check_references(&result, &builtin_types);
+        assert!(matches!(
+            &errors[..],
+            [Error::Undefined { url, .. }] if url == "http://legacy.aidbox.app/fhir/CodeSystem/Patient.status"
+        ));
+    }
+}
@@ -0,0 +1,32 @@
+use serde::Deserialize;
+
+pub mod fhir;
+
+/// Compartment definition as stored in Aidbox
+#[derive(Debug, Clone, Deserialize)]
+pub struct CompartmentDefinition {
+    /// Compartment definition ID
+    pub id: Option<String>,
+
+    /// Canonical url, generated if missing
+    pub url: Option<String>,
+
+    /// Name of the compartment definition, the code is used if missing
+    pub name: Option<String>,
+
+    /// Compartment type: Patient, Encounter, RelatedPerson, Practitioner or Device
+    pub code: String,
+
+    /// Resource types which may be a part of the compartment
+    #[serde(default)]
+    pub resource: Vec<CompartmentResource>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct CompartmentResource {
+    /// Resource type
+    pub code: String,
+
+    /// Search parameters linking the resource to the compartment
+    pub param: Option<Vec<String>>,
+}
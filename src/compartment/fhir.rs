@@ -0,0 +1,196 @@
+use miette::Diagnostic;
+use serde::Serialize;
+use thiserror::Error;
+
+use crate::{
+    compartment::{self as aidbox},
+    report::Subject,
+    search_param::{self, SearchParameterType},
+    trie::fhir::UsageContext,
+};
+
+#[derive(Debug, Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct CompartmentDefinition {
+    pub resource_type: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub language: Option<String>,
+    pub url: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub version: Option<String>,
+    pub name: String,
+    pub status: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub date: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub publisher: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub use_context: Option<Vec<UsageContext>>,
+    pub code: String,
+    pub search: bool,
+    pub resource: Vec<CompartmentDefinitionResource>,
+}
+
+#[derive(Debug, Serialize, Clone)]
+pub struct CompartmentDefinitionResource {
+    pub code: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub param: Option<Vec<String>>,
+}
+
+#[derive(Debug, Error, Diagnostic)]
+pub enum Error {
+    #[error("Compartment {compartment} refers to unknown search parameter {resource_type}.{param}")]
+    #[diagnostic(
+        code(compartment::unknown_search_parameter),
+        help("Compartment parameters must be defined either in the input or by FHIR itself.")
+    )]
+    UnknownSearchParameter {
+        compartment: String,
+        resource_type: String,
+        param: String,
+    },
+
+    #[error(
+        "Compartment {compartment} refers to search parameter {resource_type}.{param}, which is not a reference"
+    )]
+    #[diagnostic(
+        code(compartment::not_reference_search_parameter),
+        help("Only reference search parameters can link resources to a compartment.")
+    )]
+    NotReferenceSearchParameter {
+        compartment: String,
+        resource_type: String,
+        param: String,
+    },
+}
+
+impl Subject for Error {}
+
+/// Convert Aidbox compartment definition, resolving its parameters against `search_params`.
+///
+/// `search_params` should contain both parameters from the input and the builtin ones.
+pub fn convert(
+    search_params: &[search_param::SearchParameter],
+    aidbox_cd: &aidbox::CompartmentDefinition,
+) -> Result<CompartmentDefinition, Vec<Error>> {
+    let mut errors: Vec<Error> = Vec::new();
+
+    for resource in &aidbox_cd.resource {
+        for param in resource.param.iter().flatten() {
+            let error = match search_param::find(search_params, &resource.code, param) {
+                Some(sp) if matches!(sp.r#type, SearchParameterType::Reference) => continue,
+                Some(_) => Error::NotReferenceSearchParameter {
+                    compartment: aidbox_cd.code.to_owned(),
+                    resource_type: resource.code.to_owned(),
+                    param: param.to_owned(),
+                },
+                None => Error::UnknownSearchParameter {
+                    compartment: aidbox_cd.code.to_owned(),
+                    resource_type: resource.code.to_owned(),
+                    param: param.to_owned(),
+                },
+            };
+            errors.push(error);
+        }
+    }
+
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+
+    let url_component = match &aidbox_cd.id {
+        Some(id) => format!("id-{}", id),
+        None => format!("gen-{}", aidbox_cd.code),
+    };
+
+    Ok(CompartmentDefinition {
+        resource_type: "CompartmentDefinition".to_owned(),
+        language: None,
+        version: None,
+        url: aidbox_cd.url.to_owned().unwrap_or(format!(
+            "http://fhir.example.org/fhir/CompartmentDefinition/{}",
+            url_component
+        )),
+        name: aidbox_cd
+            .name
+            .to_owned()
+            .unwrap_or(aidbox_cd.code.to_owned()),
+        status: "active".to_owned(),
+        date: None,
+        publisher: None,
+        use_context: None,
+        code: aidbox_cd.code.to_owned(),
+        search: true,
+        resource: aidbox_cd
+            .resource
+            .iter()
+            .map(|resource| CompartmentDefinitionResource {
+                code: resource.code.to_owned(),
+                param: resource.param.to_owned(),
+            })
+            .collect(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        FhirVersion, builtin,
+        compartment::{
+            CompartmentDefinition, CompartmentResource,
+            fhir::{Error, convert},
+        },
+    };
+
+    fn patient_compartment(resource_type: &str, params: &[&str]) -> CompartmentDefinition {
+        CompartmentDefinition {
+            id: Some("patient".to_owned()),
+            url: None,
+            name: None,
+            code: "Patient".to_owned(),
+            resource: vec![CompartmentResource {
+                code: resource_type.to_owned(),
+                param: Some(params.iter().map(|p| p.to_string()).collect()),
+            }],
+        }
+    }
+
+    #[test]
+    fn test_convert_compartment() {
+        let search_params = builtin::get_builtin_resources(FhirVersion::V4_0_1).search_parameter;
+        let cd = convert(
+            &search_params,
+            &patient_compartment("Observation", &["subject", "performer"]),
+        )
+        .unwrap();
+
+        assert_eq!(
+            cd.url,
+            "http://fhir.example.org/fhir/CompartmentDefinition/id-patient"
+        );
+        assert_eq!(cd.code, "Patient");
+        assert_eq!(cd.resource[0].code, "Observation");
+        assert_eq!(
+            cd.resource[0].param.as_deref(),
+            Some(&["subject".to_owned(), "performer".to_owned()][..])
+        );
+    }
+
+    #[test]
+    fn test_convert_compartment_unknown_param() {
+        let search_params = builtin::get_builtin_resources(FhirVersion::V4_0_1).search_parameter;
+        let errors = convert(
+            &search_params,
+            &patient_compartment("Observation", &["subject", "no-such-param", "code"]),
+        )
+        .unwrap_err();
+
+        assert_eq!(errors.len(), 2);
+        assert!(matches!(errors[0], Error::UnknownSearchParameter { .. }));
+        assert!(matches!(
+            errors[1],
+            Error::NotReferenceSearchParameter { .. }
+        ));
+    }
+}
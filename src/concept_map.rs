@@ -0,0 +1,170 @@
+//! ConceptMap resources for Attributes translating legacy codes, see
+//! [`aidbox::ConceptMapping`] for the source shape.
+//!
+//! Every such attribute gets its own ConceptMap with a single group, mapping each legacy code to
+//! an equivalent FHIR code.
+
+use serde::Serialize;
+
+use crate::{
+    FhirVersion,
+    attribute::{
+        aidbox,
+        typed::{self, AttributeKind},
+    },
+    trie::fhir::{UsageContext, make_id, slugify},
+    value_set,
+};
+
+const CONCEPT_MAP_BASE_URL: &str = "http://legacy.aidbox.app/fhir/ConceptMap";
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ConceptMap {
+    pub resource_type: String,
+    pub id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub language: Option<String>,
+    pub url: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub version: Option<String>,
+    pub name: String,
+    pub status: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub date: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub publisher: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub use_context: Option<Vec<UsageContext>>,
+    pub group: Vec<ConceptMapGroup>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ConceptMapGroup {
+    pub source: String,
+    pub target: String,
+    pub element: Vec<ConceptMapElement>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ConceptMapElement {
+    pub code: String,
+    pub target: Vec<ConceptMapTarget>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ConceptMapTarget {
+    pub code: String,
+    /// Before FHIR R5
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub equivalence: Option<String>,
+    /// Since FHIR R5, replaces `equivalence`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub relationship: Option<String>,
+}
+
+/// Canonical url of the ConceptMap generated for the attribute
+pub fn concept_map_url(attribute_id: &str) -> String {
+    format!("{CONCEPT_MAP_BASE_URL}/{}", slugify(attribute_id))
+}
+
+pub fn make_concept_map(
+    attribute_id: &str,
+    mapping: &aidbox::ConceptMapping,
+    version: FhirVersion,
+) -> ConceptMap {
+    let (equivalence, relationship) = match version {
+        FhirVersion::V5_0_0 => (None, Some("equivalent".to_owned())),
+        _ => (Some("equivalent".to_owned()), None),
+    };
+
+    ConceptMap {
+        resource_type: "ConceptMap".to_owned(),
+        id: make_id(attribute_id),
+        language: None,
+        version: None,
+        url: concept_map_url(attribute_id),
+        name: value_set::resource_name(attribute_id),
+        status: "active".to_owned(),
+        date: None,
+        publisher: None,
+        use_context: None,
+        group: vec![ConceptMapGroup {
+            source: mapping
+                .source
+                .clone()
+                .unwrap_or_else(|| value_set::code_system_url(attribute_id)),
+            target: mapping.target.clone(),
+            element: mapping
+                .element
+                .iter()
+                .map(|(code, target)| ConceptMapElement {
+                    code: code.to_owned(),
+                    target: vec![ConceptMapTarget {
+                        code: target.to_owned(),
+                        equivalence: equivalence.clone(),
+                        relationship: relationship.clone(),
+                    }],
+                })
+                .collect(),
+        }],
+    }
+}
+
+/// ConceptMaps for all attributes with `conceptMap`
+pub fn make_concept_maps(attributes: &[typed::Attribute], version: FhirVersion) -> Vec<ConceptMap> {
+    attributes
+        .iter()
+        .filter_map(|attribute| match &attribute.kind {
+            AttributeKind::Concrete(kind) => kind
+                .concept_map
+                .as_ref()
+                .map(|mapping| make_concept_map(&attribute.id, mapping, version)),
+            _ => None,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use crate::{FhirVersion, attribute::aidbox::ConceptMapping, concept_map};
+
+    #[test]
+    fn test_make_concept_map() {
+        let mapping = ConceptMapping {
+            source: None,
+            target: "http://hl7.org/fhir/administrative-gender".to_owned(),
+            element: BTreeMap::from([
+                ("F".to_owned(), "female".to_owned()),
+                ("M".to_owned(), "male".to_owned()),
+            ]),
+        };
+
+        let concept_map =
+            concept_map::make_concept_map("Patient.sex", &mapping, FhirVersion::V4_0_1);
+        let value = serde_json::to_value(&concept_map).unwrap();
+        assert_eq!(
+            value["url"],
+            "http://legacy.aidbox.app/fhir/ConceptMap/Patient.sex"
+        );
+        assert_eq!(value["name"], "PatientSex");
+        assert_eq!(
+            value["group"][0]["source"],
+            "http://legacy.aidbox.app/fhir/CodeSystem/Patient.sex"
+        );
+        assert_eq!(
+            value["group"][0]["element"][1],
+            serde_json::json!({"code": "M", "target": [{"code": "male", "equivalence": "equivalent"}]})
+        );
+
+        let concept_map =
+            concept_map::make_concept_map("Patient.sex", &mapping, FhirVersion::V5_0_0);
+        let value = serde_json::to_value(&concept_map).unwrap();
+        assert_eq!(
+            value["group"][0]["element"][0]["target"][0],
+            serde_json::json!({"code": "female", "relationship": "equivalent"})
+        );
+    }
+}
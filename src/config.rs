@@ -0,0 +1,224 @@
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+};
+
+use miette::Diagnostic;
+use serde::Deserialize;
+use thiserror::Error;
+
+use fhir_schema_migration_tool::{
+    FhirVersion,
+    trie::fhir::{BindingStrength, ElementSlicingDiscriminator, ExtensionOrder, PublicationStatus},
+};
+
+use crate::{OutputFormat, UnusedBuiltinsReport};
+
+/// Options read from a configuration file (e.g. `fhir-migration.toml`).
+///
+/// Every field mirrors a command line argument with the same name. Arguments given on the
+/// command line take precedence over the values from the file. Relative paths are resolved
+/// against the directory containing the configuration file.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// A single path or a list of paths
+    pub path: Option<OneOrMany<PathBuf>>,
+    pub ignore_errors: Option<bool>,
+    pub ignore_flags: Option<bool>,
+    pub emit_modifier: Option<bool>,
+    pub emit_summary: Option<bool>,
+    pub must_support: Option<Vec<String>>,
+    /// A single version or a list of versions
+    pub fhir_version: Option<OneOrMany<FhirVersion>>,
+    pub assume_version_from_package: Option<bool>,
+    pub output: Option<PathBuf>,
+    pub package_name: Option<String>,
+    pub package_version: Option<String>,
+    /// Package name to version
+    pub dependency: Option<BTreeMap<String, String>>,
+    pub tar_prefix: Option<String>,
+    pub output_dir: Option<PathBuf>,
+    pub ndjson_out: Option<PathBuf>,
+    pub error_report: Option<PathBuf>,
+    pub output_format: Option<OutputFormat>,
+    /// FHIR server base url, the token is only taken from the command line
+    pub push: Option<String>,
+    pub include: Option<Vec<String>>,
+    pub exclude: Option<Vec<String>>,
+    pub strict: Option<bool>,
+    pub no_fce_marker: Option<bool>,
+    pub no_root_element: Option<bool>,
+    pub per_context_extensions: Option<bool>,
+    pub merge_duplicates: Option<bool>,
+    pub prefer_local_profiles: Option<bool>,
+    pub snapshot: Option<bool>,
+    pub check_urls_resolvable: Option<bool>,
+    pub typed_value_element: Option<bool>,
+    pub min_constraints: Option<usize>,
+    pub max_extension_elements: Option<usize>,
+    pub preserve_source_order: Option<bool>,
+    pub respect_order: Option<bool>,
+    pub sort_extensions: Option<ExtensionOrder>,
+    pub binding_strength: Option<BindingStrength>,
+    pub default_description: Option<String>,
+    pub keep_going: Option<bool>,
+    pub quiet: Option<bool>,
+    pub summary_json: Option<bool>,
+    pub allow_recursive: Option<bool>,
+    pub trim_empty_inferred: Option<bool>,
+    pub jobs: Option<usize>,
+    pub language: Option<String>,
+    pub resource_version: Option<String>,
+    pub status: Option<PublicationStatus>,
+    pub publisher: Option<String>,
+    pub date: Option<String>,
+    /// Usage context type code (e.g. program) to its value, `code` or `system|code`
+    pub use_context: Option<BTreeMap<String, String>>,
+    /// Extension url to the discriminator of its nested extension slicing
+    pub slicing_discriminator: Option<BTreeMap<String, ElementSlicingDiscriminator>>,
+    pub report_unused_builtins: Option<UnusedBuiltinsReport>,
+    /// Extension url to the url of the extension it is based on
+    pub extension_base: Option<BTreeMap<String, String>>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+pub enum OneOrMany<T> {
+    One(T),
+    Many(Vec<T>),
+}
+
+impl<T> From<OneOrMany<T>> for Vec<T> {
+    fn from(value: OneOrMany<T>) -> Self {
+        match value {
+            OneOrMany::One(value) => vec![value],
+            OneOrMany::Many(values) => values,
+        }
+    }
+}
+
+#[derive(Debug, Error, Diagnostic)]
+pub enum Error {
+    #[error("Could not read config file {filename}")]
+    #[diagnostic(code(config::read))]
+    Read {
+        filename: PathBuf,
+        #[source]
+        source: std::io::Error,
+    },
+
+    #[error("Could not parse config file {filename}")]
+    #[diagnostic(
+        code(config::parse),
+        help("Config file keys are the same as command line arguments, in snake_case")
+    )]
+    Parse {
+        filename: PathBuf,
+        #[source]
+        source: toml::de::Error,
+    },
+}
+
+fn resolve_path(base: &Path, path: PathBuf) -> PathBuf {
+    if path.is_relative() {
+        base.join(path)
+    } else {
+        path
+    }
+}
+
+fn resolve(base: &Path, path: Option<PathBuf>) -> Option<PathBuf> {
+    path.map(|path| resolve_path(base, path))
+}
+
+impl Config {
+    pub fn read(filename: &Path) -> Result<Self, Error> {
+        let contents = std::fs::read_to_string(filename).map_err(|error| Error::Read {
+            filename: filename.to_owned(),
+            source: error,
+        })?;
+
+        let config: Config = toml::from_str(&contents).map_err(|error| Error::Parse {
+            filename: filename.to_owned(),
+            source: error,
+        })?;
+
+        let base = filename.parent().unwrap_or(Path::new(""));
+
+        Ok(Config {
+            path: config.path.map(|paths| {
+                OneOrMany::Many(
+                    Vec::from(paths)
+                        .into_iter()
+                        .map(|path| resolve_path(base, path))
+                        .collect(),
+                )
+            }),
+            output: resolve(base, config.output),
+            output_dir: resolve(base, config.output_dir),
+            ndjson_out: resolve(base, config.ndjson_out),
+            error_report: resolve(base, config.error_report),
+            ..config
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::{Path, PathBuf};
+
+    use fhir_schema_migration_tool::FhirVersion;
+
+    use crate::config::{Config, Error};
+
+    fn read(name: &str, contents: &str) -> (Result<Config, Error>, PathBuf) {
+        let dir = std::env::temp_dir().join(format!("config-{name}-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let filename = dir.join("fhir-migration.toml");
+        std::fs::write(&filename, contents).unwrap();
+        let config = Config::read(&filename);
+        _ = std::fs::remove_dir_all(&dir);
+        (config, dir)
+    }
+
+    #[test]
+    fn test_read_config() {
+        let (config, dir) = read(
+            "paths",
+            r#"
+            path = ["attributes", "/abs/attributes"]
+            fhir_version = "4.0.1"
+            output = "out/package.tgz"
+            error_report = "/tmp/report.json"
+            strict = true
+            "#,
+        );
+        let config = config.unwrap();
+
+        // Relative paths are resolved against the directory of the file
+        let paths: Vec<PathBuf> = config.path.map(Vec::from).unwrap();
+        assert_eq!(
+            paths,
+            [dir.join("attributes"), PathBuf::from("/abs/attributes")]
+        );
+        assert_eq!(config.output, Some(dir.join("out/package.tgz")));
+        assert_eq!(
+            config.error_report.as_deref(),
+            Some(Path::new("/tmp/report.json"))
+        );
+        // A single value is a list of one
+        let versions: Vec<FhirVersion> = config.fhir_version.map(Vec::from).unwrap();
+        assert_eq!(versions, [FhirVersion::V4_0_1]);
+        assert_eq!(config.strict, Some(true));
+    }
+
+    #[test]
+    fn test_read_config_errors() {
+        let (config, _) = read("unknown", "no_such_option = true\n");
+        assert!(matches!(config, Err(Error::Parse { .. })));
+
+        let missing = std::env::temp_dir().join("no-such-dir/fhir-migration.toml");
+        assert!(matches!(Config::read(&missing), Err(Error::Read { .. })));
+    }
+}
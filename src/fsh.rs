@@ -0,0 +1,313 @@
+//! Render generated StructureDefinitions as FHIR Shorthand (FSH).
+//!
+//! Profiles become `Profile:` blocks and extensions become `Extension:` blocks, with one rule per
+//! constraint of their differential elements. Only the constructs this tool generates are
+//! supported, so that SUSHI builds equivalent StructureDefinitions from the output. Invariant
+//! names are prefixed with the id of the StructureDefinition, FSH invariants are global while
+//! the generated constraint keys are only unique within one definition.
+
+use std::fmt::Write;
+
+use crate::trie::fhir::{ElementDefinition, ElementType, StructureDefinition};
+
+const CORE_STRUCTURE_DEFINITION_URL: &str = "http://hl7.org/fhir/StructureDefinition/";
+
+fn quote(value: &str) -> String {
+    let mut result = String::from("\"");
+    for c in value.chars() {
+        match c {
+            '"' | '\\' => {
+                result.push('\\');
+                result.push(c);
+            }
+            '\n' => result.push_str("\\n"),
+            _ => result.push(c),
+        }
+    }
+    result.push('"');
+    result
+}
+
+/// Name of a core definition, or the url itself for other definitions
+fn definition_name(url: &str) -> &str {
+    url.strip_prefix(CORE_STRUCTURE_DEFINITION_URL)
+        .unwrap_or(url)
+}
+
+/// FSH path of an element, e.g. `extension[nickname].value[x]` for
+/// `Extension.extension:nickname.value[x]`. Type slices `value[x]:valueString` are addressed by
+/// their slice name, as FSH does.
+fn element_path(root: &str, id: &str) -> String {
+    let Some(relative) = id.strip_prefix(root).and_then(|id| id.strip_prefix('.')) else {
+        return ".".to_owned();
+    };
+    relative
+        .split('.')
+        .map(|component| match component.split_once(':') {
+            Some((name, slice)) if name.ends_with("[x]") => slice.to_owned(),
+            Some((name, slice)) => format!("{name}[{slice}]"),
+            None => component.to_owned(),
+        })
+        .collect::<Vec<_>>()
+        .join(".")
+}
+
+fn cardinality(element: &ElementDefinition) -> Option<String> {
+    match (element.min, &element.max) {
+        (None, None) => None,
+        (min, max) => Some(format!(
+            "{}..{}",
+            min.map(|min| min.to_string()).unwrap_or_default(),
+            max.as_deref().unwrap_or_default()
+        )),
+    }
+}
+
+fn flags(element: &ElementDefinition) -> String {
+    let mut flags = String::new();
+    if element.must_support == Some(true) {
+        flags.push_str(" MS");
+    }
+    if element.is_summary == Some(true) {
+        flags.push_str(" SU");
+    }
+    if element.is_modifier == Some(true) {
+        flags.push_str(" ?!");
+    }
+    flags
+}
+
+fn render_type(r#type: &ElementType) -> String {
+    match (&r#type.target_profile, &r#type.profile) {
+        (Some(targets), _) => {
+            let targets: Vec<&str> = targets.iter().map(|url| definition_name(url)).collect();
+            format!("{}({})", r#type.code, targets.join(" or "))
+        }
+        (None, Some(profiles)) => profiles
+            .iter()
+            .map(|url| definition_name(url))
+            .collect::<Vec<_>>()
+            .join(" or "),
+        (None, None) => r#type.code.clone(),
+    }
+}
+
+fn is_extension_slice(element: &ElementDefinition) -> bool {
+    element.slice_name.is_some()
+        && (element.path.ends_with(".extension") || element.path.ends_with(".modifierExtension"))
+}
+
+/// Rules of one differential element, invariants it obeys are appended to `invariants`
+fn render_element(
+    out: &mut String,
+    sd: &StructureDefinition,
+    element: &ElementDefinition,
+    invariants: &mut Vec<String>,
+) {
+    let root = sd.r#type.as_str();
+    let path = element_path(root, &element.id);
+    let flags = flags(element);
+
+    if is_extension_slice(element) {
+        // The slice is declared together with its cardinality, its type is the extension itself
+        let slice_name = element.slice_name.as_deref().unwrap_or_default();
+        let parent = element_path(root, element.id.rsplit_once(':').map_or("", |(id, _)| id));
+        let target = match element
+            .r#type
+            .iter()
+            .flatten()
+            .find_map(|t| t.profile.as_ref())
+        {
+            Some(profiles) => format!("{} named {slice_name}", profiles.join(" or ")),
+            None => slice_name.to_owned(),
+        };
+        _ = writeln!(
+            out,
+            "* {parent} contains {target} {}..{}{flags}",
+            element.min.unwrap_or(0),
+            element.max.as_deref().unwrap_or("*")
+        );
+    } else {
+        match cardinality(element) {
+            Some(cardinality) => _ = writeln!(out, "* {path} {cardinality}{flags}"),
+            None if !flags.is_empty() => _ = writeln!(out, "* {path}{flags}"),
+            None => (),
+        }
+        if let Some(types) = &element.r#type {
+            let types: Vec<String> = types.iter().map(render_type).collect();
+            _ = writeln!(out, "* {path} only {}", types.join(" or "));
+        }
+    }
+
+    if let Some(reason) = &element.is_modifier_reason {
+        _ = writeln!(out, "* {path} ^isModifierReason = {}", quote(reason));
+    }
+    if let Some(reference) = &element.content_reference {
+        _ = writeln!(out, "* {path} contentReference {reference}");
+    }
+    if let Some(url) = &element.fixed_url {
+        _ = writeln!(out, "* {path} = {} (exactly)", quote(url));
+    }
+    if let Some(max_length) = element.max_length {
+        _ = writeln!(out, "* {path} ^maxLength = {max_length}");
+    }
+    if let Some(binding) = &element.binding {
+        _ = writeln!(
+            out,
+            "* {path} from {} ({})",
+            binding.value_set, binding.strength
+        );
+        if let Some(description) = &binding.description {
+            _ = writeln!(
+                out,
+                "* {path} ^binding.description = {}",
+                quote(description)
+            );
+        }
+    }
+    if let Some(slicing) = &element.slicing {
+        for (index, discriminator) in slicing.discriminator.iter().enumerate() {
+            _ = writeln!(
+                out,
+                "* {path} ^slicing.discriminator[{index}].type = #{}",
+                discriminator.r#type
+            );
+            _ = writeln!(
+                out,
+                "* {path} ^slicing.discriminator[{index}].path = {}",
+                quote(&discriminator.path)
+            );
+        }
+        _ = writeln!(out, "* {path} ^slicing.rules = #{}", slicing.rules);
+    }
+    for (index, extension) in element.extension.iter().flatten().enumerate() {
+        _ = writeln!(
+            out,
+            "* {path} ^extension[{index}].url = {}",
+            quote(&extension.url)
+        );
+        _ = writeln!(
+            out,
+            "* {path} ^extension[{index}].valueString = {}",
+            quote(&extension.value_string)
+        );
+    }
+    for constraint in element.constraint.iter().flatten() {
+        let name = format!(
+            "{}-{}",
+            sd.id.as_deref().unwrap_or(&sd.name),
+            constraint.key
+        );
+        _ = writeln!(out, "* {path} obeys {name}");
+        invariants.push(format!(
+            "Invariant: {name}\nDescription: {}\nExpression: {}\nSeverity: #{}\n",
+            quote(&constraint.human),
+            quote(&constraint.expression),
+            constraint.severity
+        ));
+    }
+}
+
+/// Render a profile or an extension as a FSH definition, followed by the invariants it obeys
+pub fn render(sd: &StructureDefinition) -> String {
+    let mut out = String::new();
+    if sd.r#type == "Extension" {
+        _ = writeln!(out, "Extension: {}", sd.name);
+        if sd.base_definition != format!("{CORE_STRUCTURE_DEFINITION_URL}Extension") {
+            _ = writeln!(out, "Parent: {}", definition_name(&sd.base_definition));
+        }
+    } else {
+        _ = writeln!(out, "Profile: {}", sd.name);
+        _ = writeln!(out, "Parent: {}", definition_name(&sd.base_definition));
+    }
+    if let Some(id) = &sd.id {
+        _ = writeln!(out, "Id: {id}");
+    }
+    if let Some(title) = &sd.title {
+        _ = writeln!(out, "Title: {}", quote(title));
+    }
+    if let Some(description) = &sd.description {
+        _ = writeln!(out, "Description: {}", quote(description));
+    }
+    for context in sd.context.iter().flatten() {
+        _ = writeln!(out, "Context: {}", context.expression);
+    }
+    _ = writeln!(out, "* ^url = {}", quote(&sd.url));
+    if let Some(version) = &sd.version {
+        _ = writeln!(out, "* ^version = {}", quote(version));
+    }
+    _ = writeln!(out, "* ^status = #{}", sd.status);
+    if let Some(date) = &sd.date {
+        _ = writeln!(out, "* ^date = \"{date}\"");
+    }
+    if let Some(publisher) = &sd.publisher {
+        _ = writeln!(out, "* ^publisher = {}", quote(publisher));
+    }
+    if let Some(language) = &sd.language {
+        _ = writeln!(out, "* ^language = #{language}");
+    }
+
+    let mut invariants: Vec<String> = Vec::new();
+    for element in &sd.differential.element {
+        render_element(&mut out, sd, element, &mut invariants);
+    }
+    for invariant in invariants {
+        out.push('\n');
+        out.push_str(&invariant);
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use crate::{FhirVersion, convert_attributes, fsh};
+
+    #[test]
+    fn test_render() {
+        let attribute = serde_json::from_value(json!({
+            "id": "Patient.nickname",
+            "path": ["nickname"],
+            "resource": {"id": "Patient", "resourceType": "Entity"},
+            "type": {"id": "string", "resourceType": "Entity"},
+            "extensionUrl": "http://example.org/nickname",
+            "isRequired": true,
+        }))
+        .unwrap();
+        let result = convert_attributes(vec![attribute], Vec::new(), FhirVersion::V4_0_1);
+
+        assert_eq!(
+            fsh::render(&result.extensions[0]),
+            r#"Extension: nickname
+Id: nickname
+Title: "nickname"
+Description: "Auto-converted from Aidbox first-class extension nickname"
+Context: Patient
+* ^url = "http://example.org/nickname"
+* ^status = #active
+* . 1..1
+* . ^extension[0].url = "http://fhir.aidbox.app/fhir/StructureDefinition/legacy-fce"
+* . ^extension[0].valueString = "nickname"
+* url 1..1
+* url = "http://example.org/nickname" (exactly)
+* value[x] 1..1
+* value[x] only string
+"#
+        );
+        assert_eq!(
+            fsh::render(&result.profiles[0]),
+            r#"Profile: Patient_fce
+Parent: Patient
+Id: Patient-fce
+Title: "Patient_fce"
+* ^url = "http://legacy.aidbox.app/fhir/StructureDefinition/Patient-fce"
+* ^status = #active
+* extension ^slicing.discriminator[0].type = #value
+* extension ^slicing.discriminator[0].path = "url"
+* extension ^slicing.rules = #open
+* extension contains http://example.org/nickname named nickname 1..1
+"#
+        );
+    }
+}
@@ -0,0 +1,86 @@
+//! Minimal glob matching for type names: `*` matches any sequence of characters and `?` matches
+//! any single character. Other characters match themselves, so plain names match exactly.
+
+#[derive(Debug, Clone, Default)]
+pub struct GlobSet {
+    patterns: Vec<Vec<char>>,
+}
+
+impl GlobSet {
+    pub fn new(patterns: &[String]) -> Self {
+        Self {
+            patterns: patterns
+                .iter()
+                .map(|pattern| pattern.chars().collect())
+                .collect(),
+        }
+    }
+
+    /// Whether any of the patterns matches the whole name
+    pub fn is_match(&self, name: &str) -> bool {
+        let name: Vec<char> = name.chars().collect();
+        self.patterns.iter().any(|pattern| matches(pattern, &name))
+    }
+}
+
+fn matches(pattern: &[char], name: &[char]) -> bool {
+    let (mut p, mut n) = (0, 0);
+    // Position after the last `*` and the name position it is currently matched up to
+    let mut backtrack: Option<(usize, usize)> = None;
+
+    while n < name.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                p += 1;
+                backtrack = Some((p, n));
+            }
+            Some('?') => {
+                p += 1;
+                n += 1;
+            }
+            Some(c) if *c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => match backtrack {
+                Some((star_p, star_n)) => {
+                    p = star_p;
+                    n = star_n + 1;
+                    backtrack = Some((star_p, star_n + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::glob::GlobSet;
+
+    #[test]
+    fn test_glob_set() {
+        let set = GlobSet::new(&[
+            "Custom*".to_owned(),
+            "My?Resource".to_owned(),
+            "Exact".to_owned(),
+        ]);
+
+        assert!(set.is_match("Custom"));
+        assert!(set.is_match("CustomPatient"));
+        assert!(set.is_match("MyXResource"));
+        assert!(set.is_match("Exact"));
+
+        assert!(!set.is_match("NotCustom"));
+        assert!(!set.is_match("MyResource"));
+        assert!(!set.is_match("Exactly"));
+        assert!(!set.is_match("exact"));
+
+        let set = GlobSet::new(&["*a*b".to_owned()]);
+        assert!(set.is_match("xaxxb"));
+        assert!(set.is_match("abab"));
+        assert!(!set.is_match("abba"));
+    }
+}
@@ -0,0 +1,1645 @@
+//! Conversion of Aidbox Attribute, SearchParameter and CompartmentDefinition resources into
+//! FHIR StructureDefinition, SearchParameter, CompartmentDefinition, ValueSet, CodeSystem and
+//! ConceptMap resources.
+//!
+//! [`convert`] runs the whole pipeline and returns the generated resources together with the
+//! accumulated diagnostics instead of printing them, so the converter can be embedded into
+//! other tools. The binary is a thin wrapper reading the input files and writing the output.
+
+pub mod attribute;
+pub mod builtin;
+pub mod canonical;
+pub mod compartment;
+pub mod concept_map;
+pub mod fsh;
+mod glob;
+pub mod package;
+pub mod paths;
+pub mod push;
+pub mod report;
+pub mod resource_map;
+pub mod search_param;
+pub mod snapshot;
+pub mod trie;
+pub mod value_set;
+
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    path::{Path, PathBuf},
+};
+
+use clap::ValueEnum;
+use miette::Diagnostic;
+use serde::Deserialize;
+use thiserror::Error;
+
+pub use crate::search_param::SearchParameter;
+use crate::{
+    report::Subject,
+    trie::fhir::{EmitOptions, PublicationStatus, StructureDefinition, UsageContext},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Deserialize)]
+pub enum FhirVersion {
+    #[value(name = "4.0.0")]
+    #[serde(rename = "4.0.0")]
+    V4_0_0,
+    #[value(name = "4.0.1")]
+    #[serde(rename = "4.0.1")]
+    V4_0_1,
+    #[value(name = "4.3.0")]
+    #[serde(rename = "4.3.0")]
+    V4_3_0,
+    #[value(name = "5.0.0")]
+    #[serde(rename = "5.0.0")]
+    V5_0_0,
+}
+
+impl FhirVersion {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            FhirVersion::V4_0_0 => "4.0.0",
+            FhirVersion::V4_0_1 => "4.0.1",
+            FhirVersion::V4_3_0 => "4.3.0",
+            FhirVersion::V5_0_0 => "5.0.0",
+        }
+    }
+}
+
+#[derive(Debug, Error, Diagnostic)]
+pub enum Error {
+    #[error("Not allowed target resource type {resource_type}")]
+    #[diagnostic(code(convert::not_allowed_target_resource))]
+    NotAllowedTargetResource { resource_type: String },
+
+    #[error("Attribute id {id} is used by several attributes: {}", format_occurrences(.occurrences))]
+    #[diagnostic(
+        code(convert::duplicate_attribute_id),
+        severity(Warning),
+        help("Attribute ids must be unique. This usually indicates a bug in the export.")
+    )]
+    DuplicateAttributeId {
+        id: String,
+        occurrences: Vec<(Option<PathBuf>, String)>,
+    },
+
+    #[error("Conversion of attribute {} at {} panicked: {message}", .id.as_deref().unwrap_or("<no id>"), .path.join("."))]
+    #[diagnostic(
+        code(convert::conversion_panicked),
+        help("This is a bug in the migration tool. Please report it together with the attribute.")
+    )]
+    ConversionPanicked {
+        id: Option<String>,
+        path: Vec<String>,
+        message: String,
+    },
+
+    #[error("All {count} attributes were skipped by --include and --exclude")]
+    #[diagnostic(
+        code(convert::all_attributes_skipped),
+        severity(Warning),
+        help(
+            "--include keeps only attributes of the listed types (all types if it is empty), then --exclude skips the listed types from the rest. Check the type names, they are case-sensitive."
+        )
+    )]
+    AllAttributesSkipped { count: usize },
+
+    #[error("Attribute {id} refers to unknown attribute {reference}")]
+    #[diagnostic(
+        code(convert::unknown_content_reference),
+        help(
+            "The referenced attribute defines the content of the recursive element. Include it in the input, or refer to a builtin attribute."
+        )
+    )]
+    UnknownContentReference { id: String, reference: String },
+
+    #[error("--must-support {pattern} does not match any profile element")]
+    #[diagnostic(
+        code(convert::unmatched_must_support),
+        severity(Warning),
+        help(
+            "Patterns are FHIR paths (e.g. Patient.name) or element ids (e.g. Patient.extension:nickname) of the generated profile elements. Only elements constrained by some attribute are in the differential."
+        )
+    )]
+    UnmatchedMustSupport { pattern: String },
+
+    #[error("StructureDefinition url {url} is used by several definitions: {}", .names.join(", "))]
+    #[diagnostic(
+        code(convert::duplicate_canonical_url),
+        help(
+            "FHIR servers reject packages with duplicate canonical urls. Give the extension attributes distinct extensionUrl values."
+        )
+    )]
+    DuplicateCanonicalUrl { url: String, names: Vec<String> },
+
+    #[error("StructureDefinition id {id} is used by several definitions: {}", .urls.join(", "))]
+    #[diagnostic(
+        code(convert::duplicate_resource_id),
+        severity(Warning),
+        help(
+            "Extension ids are made from the last segment of their url. The colliding definitions get ids ending with a hash of their url instead, so that they are not written to the same file or pushed to the same resource."
+        )
+    )]
+    DuplicateResourceId { id: String, urls: Vec<String> },
+
+    #[error("Extension {url} is not referenced by any profile")]
+    #[diagnostic(
+        code(convert::orphan_extension),
+        severity(Warning),
+        help(
+            "Every generated extension is expected to be sliced into the profile of its resource type. The profile may have been dropped by --min-constraints or --exclude."
+        )
+    )]
+    OrphanExtension { url: String },
+
+    #[error("Profile {profile} references extension {url}, which is not generated")]
+    #[diagnostic(
+        code(convert::dangling_extension_reference),
+        severity(Warning),
+        help(
+            "Extension slices of generated profiles are expected to refer to generated extensions. This indicates an internal error in the emission."
+        )
+    )]
+    DanglingExtensionReference { url: String, profile: String },
+
+    #[error("Attribute {id} refers to {} type {reference}", if *.excluded { "excluded" } else { "unknown" })]
+    #[diagnostic(
+        code(convert::dangling_type_reference),
+        severity(Warning),
+        help(
+            "The generated profile points to a type which is not part of the output. Keep the type with --include and --exclude, or add its attributes to the input."
+        )
+    )]
+    DanglingTypeReference {
+        id: String,
+        reference: String,
+        /// Skipped by `--include` or `--exclude`, otherwise not defined at all
+        excluded: bool,
+        filename: Option<PathBuf>,
+    },
+}
+
+impl Subject for Error {
+    fn attribute_id(&self) -> Option<&str> {
+        match self {
+            Error::DuplicateAttributeId { id, .. }
+            | Error::UnknownContentReference { id, .. }
+            | Error::DanglingTypeReference { id, .. } => Some(id),
+            Error::ConversionPanicked { id, .. } => id.as_deref(),
+            _ => None,
+        }
+    }
+
+    /// The first file of duplicated attribute ids
+    fn filename(&self) -> Option<&Path> {
+        match self {
+            Error::DuplicateAttributeId { occurrences, .. } => occurrences
+                .iter()
+                .find_map(|(filename, _)| filename.as_deref()),
+            Error::DanglingTypeReference { filename, .. } => filename.as_deref(),
+            _ => None,
+        }
+    }
+}
+
+/// Whether the options make an attribute error moot, e.g. a summary flag when summaries are
+/// emitted.
+fn is_suppressed(
+    error: &attribute::typed::InvalidAttributeError,
+    options: &ConvertOptions,
+    emits_modifier: bool,
+) -> bool {
+    use attribute::typed::InvalidAttributeError::*;
+
+    match error {
+        RecursiveReference(_) => options.allow_recursive,
+        OrderPresent => options.respect_order || options.ignore_flags,
+        SummaryPresent => options.emit_summary || options.ignore_flags,
+        ModifierPresent => emits_modifier || options.ignore_flags,
+        _ => false,
+    }
+}
+
+fn panic_message(payload: &(dyn std::any::Any + Send)) -> String {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "unknown panic payload".to_string()
+    }
+}
+
+fn format_occurrences(occurrences: &[(Option<PathBuf>, String)]) -> String {
+    occurrences
+        .iter()
+        .map(|(filename, path)| match filename {
+            Some(filename) => format!("{} ({})", filename.display(), path),
+            None => path.to_owned(),
+        })
+        .collect::<Vec<String>>()
+        .join(", ")
+}
+
+/// Attribute ids used several times. With `merge_duplicates`, ids of attributes which all have
+/// the same path are left to the raw trie, which merges identical attributes and reports the
+/// conflicting ones.
+fn check_duplicate_ids(
+    attrs: &[attribute::aidbox::Attribute],
+    merge_duplicates: bool,
+) -> Vec<Error> {
+    let mut by_id: BTreeMap<&str, Vec<&attribute::aidbox::Attribute>> = BTreeMap::new();
+    for attr in attrs {
+        if let Some(id) = &attr.id {
+            by_id.entry(id).or_default().push(attr);
+        }
+    }
+
+    by_id
+        .into_iter()
+        .filter(|(_, attrs)| attrs.len() > 1)
+        .filter(|(_, attrs)| {
+            !merge_duplicates
+                || attrs.iter().any(|attr| {
+                    attr.resource.id != attrs[0].resource.id || attr.path != attrs[0].path
+                })
+        })
+        .map(|(id, attrs)| Error::DuplicateAttributeId {
+            id: id.to_owned(),
+            occurrences: attrs
+                .into_iter()
+                .map(|attr| {
+                    let path = attr
+                        .path
+                        .iter()
+                        .fold(attr.resource.id.clone(), |acc, c| format!("{acc}.{c}"));
+                    (attr.filename.clone(), path)
+                })
+                .collect(),
+        })
+        .collect()
+}
+
+fn check_duplicate_urls<'a>(sds: impl Iterator<Item = &'a StructureDefinition>) -> Vec<Error> {
+    let mut by_url: BTreeMap<&str, Vec<&str>> = BTreeMap::new();
+    for sd in sds {
+        by_url.entry(&sd.url).or_default().push(&sd.name);
+    }
+
+    by_url
+        .into_iter()
+        .filter(|(_, names)| names.len() > 1)
+        .map(|(url, names)| Error::DuplicateCanonicalUrl {
+            url: url.to_owned(),
+            names: names.into_iter().map(str::to_owned).collect(),
+        })
+        .collect()
+}
+
+/// Give the StructureDefinitions sharing an id distinct ids, see
+/// [`trie::fhir::disambiguated_id`]
+fn disambiguate_ids<'a>(sds: impl Iterator<Item = &'a mut StructureDefinition>) -> Vec<Error> {
+    let mut by_id: BTreeMap<String, Vec<&mut StructureDefinition>> = BTreeMap::new();
+    for sd in sds {
+        if let Some(id) = &sd.id {
+            by_id.entry(id.clone()).or_default().push(sd);
+        }
+    }
+
+    by_id
+        .into_iter()
+        .filter(|(_, sds)| sds.len() > 1)
+        .map(|(id, sds)| {
+            let urls = sds.iter().map(|sd| sd.url.clone()).collect();
+            for sd in sds {
+                sd.id = Some(trie::fhir::disambiguated_id(&id, &sd.url));
+            }
+            Error::DuplicateResourceId { id, urls }
+        })
+        .collect()
+}
+
+/// Cross-check the extension slices of the profiles against the generated extensions.
+///
+/// An extension is referenced by a profile slice, or by another extension based on it.
+fn check_extension_references(
+    exts: &[StructureDefinition],
+    profiles: &[StructureDefinition],
+) -> Vec<Error> {
+    let emitted: BTreeSet<&str> = exts.iter().map(|sd| sd.url.as_str()).collect();
+    let mut referenced: BTreeSet<&str> =
+        exts.iter().map(|sd| sd.base_definition.as_str()).collect();
+
+    let mut errors = Vec::new();
+    for profile in profiles {
+        let slices = profile.differential.element.iter().filter(|element| {
+            element.slice_name.is_some()
+                && (element.path.ends_with(".extension")
+                    || element.path.ends_with(".modifierExtension"))
+        });
+        let urls: BTreeSet<&str> = slices
+            .flat_map(|element| element.r#type.iter().flatten())
+            .flat_map(|r#type| r#type.profile.iter().flatten())
+            .map(String::as_str)
+            .collect();
+        for url in urls {
+            if !emitted.contains(url) {
+                errors.push(Error::DanglingExtensionReference {
+                    url: url.to_owned(),
+                    profile: profile.url.clone(),
+                });
+            }
+            referenced.insert(url);
+        }
+    }
+
+    for url in emitted.difference(&referenced) {
+        errors.push(Error::OrphanExtension {
+            url: (*url).to_owned(),
+        });
+    }
+    errors
+}
+
+/// Types and referenced resource types of the kept attributes which are not generated.
+///
+/// `skipped` are the custom types whose attributes were all dropped by `--include` and
+/// `--exclude`. Custom types used as the type of an attribute are already reported when the
+/// attribute is read, see [`attribute::typed::InvalidAttributeError::CustomTargetType`], so only
+/// the skipped ones are reported again.
+fn check_type_references(
+    attrs: &[attribute::typed::Attribute],
+    skipped: &BTreeSet<String>,
+) -> Vec<Error> {
+    let kept: BTreeSet<&str> = attrs
+        .iter()
+        .map(|attr| attr.resource_type.as_str())
+        .collect();
+
+    let mut errors = Vec::new();
+    for attr in attrs {
+        let attribute::typed::AttributeKind::Concrete(kind) = &attr.kind else {
+            continue;
+        };
+        let mut report = |reference: &str, excluded: bool| {
+            errors.push(Error::DanglingTypeReference {
+                id: attr.id.clone(),
+                reference: reference.to_owned(),
+                excluded,
+                filename: attr.filename.clone(),
+            })
+        };
+        if skipped.contains(&kind.target) {
+            report(&kind.target, true);
+        }
+        for reference in kind.refers.iter().flatten() {
+            if skipped.contains(reference) {
+                report(reference, true);
+            } else if !resource_map::is_known_type(reference) && !kept.contains(reference.as_str())
+            {
+                report(reference, false);
+            }
+        }
+    }
+    errors
+}
+
+/// Options controlling the conversion
+#[derive(Debug, Clone, Default)]
+pub struct ConvertOptions {
+    /// Only convert attributes of these types, unless empty. Applied before [`Self::exclude`].
+    pub include: Vec<String>,
+    /// Types to skip (e.g. custom resources), either names or glob patterns like `Custom*`
+    pub exclude: Vec<String>,
+    /// Ignore errors related to isSummary, isModifier, order flags
+    pub ignore_flags: bool,
+    /// Emit extensions of isModifier attributes as modifier extensions, instead of reporting
+    /// isModifier. Sets [`EmitOptions::modifiers`].
+    pub emit_modifier: bool,
+    /// Mark profile elements of isSummary attributes with isSummary, instead of reporting it.
+    /// Sets [`EmitOptions::summaries`].
+    pub emit_summary: bool,
+    /// Order differential elements by the `order` of their attributes, instead of reporting it.
+    /// Overrides [`EmitOptions::source_order`], see [`trie::fhir::declared_order`].
+    pub respect_order: bool,
+    /// Treat warnings as errors
+    pub strict: bool,
+    /// Keep one of several attributes defining the same element at the same path, instead of
+    /// reporting them, see [`attribute::typed::Attribute::same_definition`]
+    pub merge_duplicates: bool,
+    /// Report attributes whose conversion panics as errors and convert the remaining ones
+    pub keep_going: bool,
+    /// Convert attributes referring to other attributes to elements with a contentReference
+    pub allow_recursive: bool,
+    /// Remove inferred nodes producing no elements before emitting, see
+    /// [`trie::fhir::trim_empty_inferred`]
+    pub trim_empty_inferred: bool,
+    /// Point reference targets at the generated profiles of the referenced types, see
+    /// [`trie::fhir::prefer_local_profiles`]
+    pub prefer_local_profiles: bool,
+    /// Also emit snapshots, overlaying differentials onto the base FHIR definitions
+    pub snapshot: bool,
+    /// Report canonical references of the generated resources which do not resolve, see
+    /// [`canonical::check_references`]
+    pub check_urls: bool,
+    /// Language of the generated resources (e.g. `de`)
+    pub language: Option<String>,
+    /// Business version of the generated resources (e.g. `1.2.0`)
+    pub resource_version: Option<String>,
+    /// Usage contexts of the generated resources
+    pub use_context: Vec<UsageContext>,
+    /// Status of all generated resources
+    pub status: PublicationStatus,
+    /// Publisher of all generated resources
+    pub publisher: Option<String>,
+    /// Date of all generated resources, in FHIR date format
+    pub date: Option<String>,
+    /// Options controlling the shape of emitted StructureDefinition resources
+    pub emit: EmitOptions,
+}
+
+/// Generated resources carrying the metadata given in [`ConvertOptions`]
+trait Metadata {
+    /// Set the language, business version, usage contexts, status, date and publisher of the
+    /// resource
+    fn stamp(&mut self, options: &ConvertOptions);
+}
+
+fn stamp_all(resources: &mut [impl Metadata], options: &ConvertOptions) {
+    for resource in resources {
+        resource.stamp(options);
+    }
+}
+
+/// Usage contexts of the options, omitted from the resources when there are none
+fn use_context(options: &ConvertOptions) -> Option<Vec<UsageContext>> {
+    (!options.use_context.is_empty()).then(|| options.use_context.clone())
+}
+
+impl Metadata for StructureDefinition {
+    fn stamp(&mut self, options: &ConvertOptions) {
+        self.language = options.language.clone();
+        self.version = options.resource_version.clone();
+        self.use_context = use_context(options);
+        self.status = options.status.as_str().to_owned();
+        self.date = options.date.clone();
+        self.publisher = options.publisher.clone();
+    }
+}
+
+impl Metadata for search_param::fhir::SearchParameter {
+    fn stamp(&mut self, options: &ConvertOptions) {
+        self.language = options.language.clone();
+        self.version = options.resource_version.clone();
+        self.use_context = use_context(options);
+        self.status = options.status.into();
+        self.date = options.date.clone();
+        self.publisher = options.publisher.clone();
+    }
+}
+
+impl Metadata for compartment::fhir::CompartmentDefinition {
+    fn stamp(&mut self, options: &ConvertOptions) {
+        self.language = options.language.clone();
+        self.version = options.resource_version.clone();
+        self.use_context = use_context(options);
+        self.status = options.status.as_str().to_owned();
+        self.date = options.date.clone();
+        self.publisher = options.publisher.clone();
+    }
+}
+
+impl Metadata for value_set::ValueSet {
+    fn stamp(&mut self, options: &ConvertOptions) {
+        self.language = options.language.clone();
+        self.version = options.resource_version.clone();
+        self.use_context = use_context(options);
+        self.status = options.status.as_str().to_owned();
+        self.date = options.date.clone();
+        self.publisher = options.publisher.clone();
+    }
+}
+
+impl Metadata for value_set::CodeSystem {
+    fn stamp(&mut self, options: &ConvertOptions) {
+        self.language = options.language.clone();
+        self.version = options.resource_version.clone();
+        self.use_context = use_context(options);
+        self.status = options.status.as_str().to_owned();
+        self.date = options.date.clone();
+        self.publisher = options.publisher.clone();
+    }
+}
+
+impl Metadata for concept_map::ConceptMap {
+    fn stamp(&mut self, options: &ConvertOptions) {
+        self.language = options.language.clone();
+        self.version = options.resource_version.clone();
+        self.use_context = use_context(options);
+        self.status = options.status.as_str().to_owned();
+        self.date = options.date.clone();
+        self.publisher = options.publisher.clone();
+    }
+}
+
+/// Aidbox resources to convert
+#[derive(Debug, Clone, Default)]
+pub struct ConversionInput {
+    pub attributes: Vec<attribute::aidbox::Attribute>,
+    pub search_params: Vec<SearchParameter>,
+    pub compartments: Vec<compartment::CompartmentDefinition>,
+}
+
+/// Generated FHIR resources and the diagnostics found while generating them
+#[derive(Debug, Default)]
+pub struct ConversionResult {
+    pub profiles: Vec<StructureDefinition>,
+    pub extensions: Vec<StructureDefinition>,
+    pub search_params: Vec<search_param::fhir::SearchParameter>,
+    pub compartments: Vec<compartment::fhir::CompartmentDefinition>,
+    /// ValueSets of enumerated attributes, bound by the generated elements
+    pub value_sets: Vec<value_set::ValueSet>,
+    /// CodeSystems defining the codes of the ValueSets
+    pub code_systems: Vec<value_set::CodeSystem>,
+    /// ConceptMaps of attributes translating legacy codes
+    pub concept_maps: Vec<concept_map::ConceptMap>,
+    /// Errors and warnings in the order they were found
+    pub errors: Vec<miette::Report>,
+    /// The same errors and warnings, for the machine-readable report
+    pub report: Vec<report::Entry>,
+    /// Builtin attributes (as `Type.path`) not referenced by any search parameter
+    pub unused_builtin_attributes: Vec<String>,
+    /// There were errors, or warnings in strict mode
+    pub had_errors: bool,
+    /// There were errors before emitting StructureDefinitions, i.e. in the input itself
+    pub had_structural_errors: bool,
+}
+
+impl ConversionResult {
+    fn error(&mut self, error: impl Diagnostic + Subject + Send + Sync + 'static) {
+        self.had_errors = true;
+        self.report.push(report::Entry::new(&error));
+        self.errors.push(miette::Report::new(error));
+    }
+
+    fn warning(
+        &mut self,
+        strict: bool,
+        warning: impl Diagnostic + Subject + Send + Sync + 'static,
+    ) {
+        if strict {
+            self.had_errors = true;
+        }
+        self.report.push(report::Entry::new(&warning));
+        self.errors.push(miette::Report::new(warning));
+    }
+}
+
+/// Convert attributes and search parameters with the default options
+pub fn convert_attributes(
+    attrs: Vec<attribute::aidbox::Attribute>,
+    sps: Vec<SearchParameter>,
+    version: FhirVersion,
+) -> ConversionResult {
+    let input = ConversionInput {
+        attributes: attrs,
+        search_params: sps,
+        compartments: Vec::new(),
+    };
+    convert(input, version, &ConvertOptions::default())
+}
+
+pub fn convert(
+    input: ConversionInput,
+    version: FhirVersion,
+    options: &ConvertOptions,
+) -> ConversionResult {
+    convert_with(
+        input,
+        version,
+        options,
+        attribute::typed::Attribute::build_from,
+    )
+}
+
+type BuildAttribute = fn(
+    attribute::aidbox::Attribute,
+) -> (
+    Option<attribute::typed::Attribute>,
+    Vec<attribute::typed::Error>,
+);
+
+/// [`convert`] with the step building typed attributes replaced, so tests can make it panic
+fn convert_with(
+    input: ConversionInput,
+    version: FhirVersion,
+    options: &ConvertOptions,
+    build_attribute: BuildAttribute,
+) -> ConversionResult {
+    let mut result = ConversionResult::default();
+
+    let ConversionInput {
+        attributes: aidbox_attributes,
+        search_params: aidbox_search_params,
+        compartments: aidbox_compartments,
+    } = input;
+
+    for warning in check_duplicate_ids(&aidbox_attributes, options.merge_duplicates) {
+        result.warning(options.strict, warning);
+    }
+
+    let builtin_resources = builtin::get_builtin_resources(version);
+    let builtin_attributes = builtin_resources.attribute;
+    let all_attributes = search_param::fhir::AttributeIndex::new(
+        aidbox_attributes.iter().chain(&builtin_attributes),
+    );
+
+    let mut referenced: BTreeSet<(&str, Vec<String>)> = BTreeSet::new();
+    for aidbox_sp in &aidbox_search_params {
+        let (sp, errors) = search_param::fhir::convert(&all_attributes, aidbox_sp);
+        if let Some(sp) = sp {
+            result.search_params.push(sp);
+        }
+        for error in errors {
+            result.error(error);
+        }
+
+        referenced.extend(
+            search_param::fhir::referenced_paths(&all_attributes, aidbox_sp)
+                .into_iter()
+                .map(|path| (aidbox_sp.resource.id.as_str(), path)),
+        );
+    }
+
+    result.unused_builtin_attributes = builtin_attributes
+        .iter()
+        .filter(|attr| !referenced.contains(&(attr.resource.id.as_str(), attr.path.clone())))
+        .map(|attr| format!("{}.{}", attr.resource.id, attr.path.join(".")))
+        .collect();
+
+    let mut all_search_params = aidbox_search_params;
+    all_search_params.extend(builtin_resources.search_parameter);
+
+    for aidbox_cd in &aidbox_compartments {
+        match compartment::fhir::convert(&all_search_params, aidbox_cd) {
+            Ok(cd) => result.compartments.push(cd),
+            Err(errors) => {
+                for error in errors {
+                    result.error(error);
+                }
+            }
+        }
+    }
+
+    // Recursive attributes are never expanded, their elements only point to the referenced
+    // element. So cycles of references need no detection, they cannot recurse.
+    let element_paths: HashMap<String, String> = if options.allow_recursive {
+        aidbox_attributes
+            .iter()
+            .chain(&builtin_attributes)
+            .filter_map(|attr| {
+                let id = attr.id.clone()?;
+                Some((id, format!("{}.{}", attr.resource.id, attr.path.join("."))))
+            })
+            .collect()
+    } else {
+        HashMap::new()
+    };
+
+    let declared_order = options
+        .respect_order
+        .then(|| trie::fhir::declared_order(&aidbox_attributes));
+
+    let mut typed_attributes: Vec<attribute::typed::Attribute> = Vec::new();
+
+    let exclude = glob::GlobSet::new(&options.exclude);
+    let attribute_count = aidbox_attributes.len();
+    let mut skipped: usize = 0;
+    let mut skipped_types: BTreeSet<String> = BTreeSet::new();
+    for aidbox_attribute in aidbox_attributes {
+        let included =
+            options.include.is_empty() || options.include.contains(&aidbox_attribute.resource.id);
+        let excluded = aidbox_attribute.resource.resource_type == "Entity"
+            && exclude.is_match(&aidbox_attribute.resource.id);
+        if !included || excluded {
+            skipped += 1;
+            if aidbox_attribute.resource.resource_type == "Entity"
+                && !resource_map::is_known_type(&aidbox_attribute.resource.id)
+            {
+                skipped_types.insert(aidbox_attribute.resource.id.clone());
+            }
+            continue;
+        } else if aidbox_attribute.resource.resource_type == "Entity"
+            && !resource_map::is_known_type(&aidbox_attribute.resource.id)
+        {
+            result.error(Error::NotAllowedTargetResource {
+                resource_type: aidbox_attribute.resource.id.clone(),
+            });
+        }
+
+        let (typed_attribute, errors) = if options.keep_going {
+            let id = aidbox_attribute.id.clone();
+            let path = aidbox_attribute.path.clone();
+            match std::panic::catch_unwind(|| build_attribute(aidbox_attribute)) {
+                Ok(result) => result,
+                Err(payload) => {
+                    result.error(Error::ConversionPanicked {
+                        id,
+                        path,
+                        message: panic_message(payload.as_ref()),
+                    });
+                    continue;
+                }
+            }
+        } else {
+            build_attribute(aidbox_attribute)
+        };
+
+        // Only extensions can be modifiers, base elements keep their definition
+        let emits_modifier = options.emit_modifier
+            && typed_attribute
+                .as_ref()
+                .is_some_and(|attribute| attribute.fce.is_some());
+        let errors = errors
+            .into_iter()
+            .filter(|error| !is_suppressed(&error.source, options, emits_modifier));
+
+        for error in errors {
+            if error.severity() == Some(miette::Severity::Warning) {
+                result.warning(options.strict, error);
+            } else {
+                result.error(error);
+            }
+        }
+
+        let Some(mut typed_attribute) = typed_attribute else {
+            continue;
+        };
+
+        if let attribute::typed::AttributeKind::Concrete(kind) = &mut typed_attribute.kind
+            && let Some(reference) = kind.content_reference.take()
+        {
+            // Without --allow-recursive the reference is already reported as an error
+            if !options.allow_recursive {
+                continue;
+            }
+            let Some(path) = element_paths.get(&reference) else {
+                result.error(Error::UnknownContentReference {
+                    id: typed_attribute.id,
+                    reference,
+                });
+                continue;
+            };
+            kind.content_reference = Some(format!("#{path}"));
+        }
+
+        typed_attributes.push(typed_attribute);
+    }
+
+    if attribute_count > 0 && skipped == attribute_count {
+        result.warning(
+            options.strict,
+            Error::AllAttributesSkipped {
+                count: attribute_count,
+            },
+        );
+    }
+
+    for error in check_type_references(&typed_attributes, &skipped_types) {
+        result.warning(options.strict, error);
+    }
+
+    let mut value_sets = value_set::make_value_sets(&typed_attributes);
+    let mut code_systems = value_set::make_code_systems(&typed_attributes);
+    let mut concept_maps = concept_map::make_concept_maps(&typed_attributes, version);
+
+    let (raw_forest, errors) =
+        trie::raw::Forest::build_from_attributes(&typed_attributes, options.merge_duplicates);
+    for error in errors {
+        result.error(error);
+    }
+
+    let (path_forest, errors) = trie::path::Forest::build_from(raw_forest);
+    for error in errors {
+        result.error(error);
+    }
+
+    let (extension_separated_forest, errors) =
+        trie::extension_separated::Forest::build_from(path_forest);
+    for error in errors {
+        match error {
+            trie::extension_separated::Error::InferredElement { .. } => {
+                if options.strict {
+                    result.warning(options.strict, error);
+                }
+            }
+            error => result.error(error),
+        }
+    }
+
+    let (mut inverted_forest, errors) =
+        trie::inverted::Forest::build_from(extension_separated_forest);
+    for error in errors {
+        result.error(error);
+    }
+
+    result.had_structural_errors = result.had_errors;
+
+    let emit = EmitOptions {
+        modifiers: typed_attributes
+            .iter()
+            .filter(|attribute| options.emit_modifier && attribute.modifier)
+            .filter(|attribute| attribute.fce.is_some())
+            .map(|attribute| attribute.id.clone())
+            .collect(),
+        summaries: typed_attributes
+            .iter()
+            .filter(|attribute| options.emit_summary && attribute.summary)
+            .map(|attribute| attribute.id.clone())
+            .collect(),
+        source_order: declared_order.or_else(|| options.emit.source_order.clone()),
+        ..options.emit.clone()
+    };
+
+    if options.trim_empty_inferred {
+        trie::fhir::trim_empty_inferred(&mut inverted_forest, &emit);
+    }
+
+    let mut profiles = trie::fhir::make_profiles(&inverted_forest, &emit, version);
+
+    for pattern in &emit.must_support {
+        let matched = profiles
+            .iter()
+            .flat_map(|profile| &profile.differential.element)
+            .any(|element| element.path == *pattern || element.id == *pattern);
+        if !matched {
+            result.warning(
+                options.strict,
+                Error::UnmatchedMustSupport {
+                    pattern: pattern.clone(),
+                },
+            );
+        }
+    }
+
+    let (mut exts, errors) = trie::fhir::collect_extensions(inverted_forest, &emit, version);
+    for error in errors {
+        result.error(error);
+    }
+
+    for error in check_duplicate_urls(exts.iter().chain(profiles.iter())) {
+        result.error(error);
+    }
+
+    for warning in disambiguate_ids(exts.iter_mut().chain(profiles.iter_mut())) {
+        result.warning(options.strict, warning);
+    }
+
+    for warning in check_extension_references(&exts, &profiles) {
+        result.warning(options.strict, warning);
+    }
+
+    if options.prefer_local_profiles {
+        let local = trie::fhir::local_profile_urls(&profiles);
+        for sd in exts.iter_mut().chain(profiles.iter_mut()) {
+            trie::fhir::prefer_local_profiles(sd, &local);
+        }
+    }
+
+    for sd in exts.iter().chain(profiles.iter()) {
+        for warning in trie::fhir::check_element_ids(sd) {
+            result.warning(options.strict, warning);
+        }
+        for error in trie::fhir::check_cardinalities(sd) {
+            result.error(error);
+        }
+    }
+
+    if options.snapshot {
+        let base = snapshot::BaseDefinitions::build_from(&builtin_attributes);
+        for sd in exts.iter_mut().chain(profiles.iter_mut()) {
+            match base.make_snapshot(sd) {
+                Ok(snapshot) => sd.snapshot = Some(snapshot),
+                Err(warning) => result.warning(options.strict, warning),
+            }
+        }
+    }
+
+    for sd in exts.iter_mut().chain(profiles.iter_mut()) {
+        sd.stamp(options);
+    }
+    stamp_all(&mut result.search_params, options);
+    stamp_all(&mut result.compartments, options);
+    stamp_all(&mut value_sets, options);
+    stamp_all(&mut code_systems, options);
+    stamp_all(&mut concept_maps, options);
+
+    result.profiles = profiles;
+    result.extensions = exts;
+    result.value_sets = value_sets;
+    result.code_systems = code_systems;
+    result.concept_maps = concept_maps;
+
+    if options.check_urls {
+        let builtin_types: BTreeSet<&str> = builtin_attributes
+            .iter()
+            .map(|attr| attr.resource.id.as_str())
+            .collect();
+        for error in canonical::check_references(&result, &builtin_types) {
+            result.error(error);
+        }
+    }
+
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+
+    use serde_json::json;
+
+    use crate::{
+        ConversionInput, ConversionResult, ConvertOptions, FhirVersion, StructureDefinition,
+        attribute::aidbox::Attribute,
+        attribute::typed,
+        check_extension_references, convert, convert_attributes, convert_with,
+        trie::fhir::{EmitOptions, PublicationStatus, UsageContext},
+    };
+
+    #[test]
+    fn test_convert_attributes() {
+        let attribute = |id: &str, path: &str| -> Attribute {
+            serde_json::from_value(json!({
+                "id": id,
+                "resourceType": "Attribute",
+                "path": [path],
+                "resource": {"id": "Patient", "resourceType": "Entity"},
+                "type": {"id": "string", "resourceType": "Entity"},
+                "extensionUrl": format!("http://example.org/{path}"),
+            }))
+            .unwrap()
+        };
+
+        let result = convert_attributes(
+            vec![
+                attribute("Patient.nickname", "nickname"),
+                attribute("Patient.nickname", "alias"),
+            ],
+            Vec::new(),
+            FhirVersion::V4_0_1,
+        );
+
+        assert_eq!(result.extensions.len(), 2);
+        assert_eq!(result.profiles.len(), 1);
+        // The duplicate id is only a warning
+        assert_eq!(result.errors.len(), 1);
+        assert!(!result.had_errors);
+    }
+
+    #[test]
+    fn test_keep_going() {
+        let attribute = |path: &str| -> Attribute {
+            serde_json::from_value(json!({
+                "id": format!("Patient.{path}"),
+                "path": [path],
+                "resource": {"id": "Patient", "resourceType": "Entity"},
+                "type": {"id": "string", "resourceType": "Entity"},
+                "extensionUrl": format!("http://example.org/{path}"),
+            }))
+            .unwrap()
+        };
+        let build_attribute = |attribute: Attribute| {
+            if attribute.id.as_deref() == Some("Patient.broken") {
+                panic!("malformed attribute");
+            }
+            typed::Attribute::build_from(attribute)
+        };
+        let input = ConversionInput {
+            attributes: vec![
+                attribute("nickname"),
+                attribute("broken"),
+                attribute("alias"),
+            ],
+            ..Default::default()
+        };
+        let options = ConvertOptions {
+            keep_going: true,
+            ..Default::default()
+        };
+
+        let result = convert_with(input, FhirVersion::V4_0_1, &options, build_attribute);
+
+        assert!(result.had_errors);
+        let panicked: Vec<_> = result
+            .report
+            .iter()
+            .filter(|entry| entry.code.as_deref() == Some("convert::conversion_panicked"))
+            .collect();
+        assert_eq!(panicked.len(), 1);
+        assert_eq!(panicked[0].attribute_id.as_deref(), Some("Patient.broken"));
+        assert!(panicked[0].message.contains("malformed attribute"));
+
+        // The other attributes still convert
+        let urls: BTreeSet<&str> = result.extensions.iter().map(|sd| sd.url.as_str()).collect();
+        assert_eq!(
+            urls,
+            BTreeSet::from(["http://example.org/alias", "http://example.org/nickname"])
+        );
+        assert_eq!(result.profiles.len(), 1);
+    }
+
+    #[test]
+    fn test_duplicate_resource_id() {
+        let attribute = |path: &str, url: &str| -> Attribute {
+            serde_json::from_value(json!({
+                "id": format!("Patient.{path}"),
+                "path": [path],
+                "resource": {"id": "Patient", "resourceType": "Entity"},
+                "type": {"id": "string", "resourceType": "Entity"},
+                "extensionUrl": url,
+            }))
+            .unwrap()
+        };
+
+        let result = convert_attributes(
+            vec![
+                attribute(
+                    "nickname",
+                    "http://a.example.org/StructureDefinition/nickname",
+                ),
+                attribute("alias", "http://b.example.org/StructureDefinition/nickname"),
+            ],
+            Vec::new(),
+            FhirVersion::V4_0_1,
+        );
+
+        let ids: BTreeSet<&str> = result
+            .extensions
+            .iter()
+            .filter_map(|sd| sd.id.as_deref())
+            .collect();
+        assert_eq!(ids.len(), 2);
+        assert!(ids.iter().all(|id| id.starts_with("nickname-")));
+        let codes: Vec<&str> = result
+            .report
+            .iter()
+            .filter_map(|entry| entry.code.as_deref())
+            .collect();
+        assert_eq!(codes, ["convert::duplicate_resource_id"]);
+        assert!(!result.had_errors);
+    }
+
+    #[test]
+    fn test_resource_version() {
+        let attribute: Attribute = serde_json::from_value(json!({
+            "id": "Patient.nickname",
+            "path": ["nickname"],
+            "resource": {"id": "Patient", "resourceType": "Entity"},
+            "type": {"id": "string", "resourceType": "Entity"},
+            "extensionUrl": "http://example.org/nickname",
+        }))
+        .unwrap();
+        let search_param = serde_json::from_value(json!({
+            "id": "Patient.nickname",
+            "name": "nickname",
+            "type": "string",
+            "resource": {"id": "Patient", "resourceType": "Entity"},
+            "expression": [["nickname"]],
+        }))
+        .unwrap();
+        let input = ConversionInput {
+            attributes: vec![attribute],
+            search_params: vec![search_param],
+            ..Default::default()
+        };
+        let options = ConvertOptions {
+            resource_version: Some("1.2.0".to_owned()),
+            ..Default::default()
+        };
+
+        let result = convert(input, FhirVersion::V4_0_1, &options);
+        assert!(!result.had_errors, "{:?}", result.errors);
+        assert_eq!(result.search_params.len(), 1);
+        for sd in result.extensions.iter().chain(&result.profiles) {
+            assert_eq!(serde_json::to_value(sd).unwrap()["version"], "1.2.0");
+        }
+        assert_eq!(
+            serde_json::to_value(&result.search_params[0]).unwrap()["version"],
+            "1.2.0"
+        );
+    }
+
+    #[test]
+    fn test_publication_metadata() {
+        let attribute: Attribute = serde_json::from_value(json!({
+            "id": "Patient.nickname",
+            "path": ["nickname"],
+            "resource": {"id": "Patient", "resourceType": "Entity"},
+            "type": {"id": "string", "resourceType": "Entity"},
+            "extensionUrl": "http://example.org/nickname",
+        }))
+        .unwrap();
+        let coded: Attribute = serde_json::from_value(json!({
+            "id": "Patient.sex",
+            "path": ["sex"],
+            "resource": {"id": "Patient", "resourceType": "Entity"},
+            "type": {"id": "code", "resourceType": "Entity"},
+            "enum": ["F", "M"],
+            "conceptMap": {
+                "target": "http://hl7.org/fhir/administrative-gender",
+                "element": {"F": "female", "M": "male"},
+            },
+        }))
+        .unwrap();
+        let search_param = serde_json::from_value(json!({
+            "id": "Patient.nickname",
+            "name": "nickname",
+            "type": "string",
+            "resource": {"id": "Patient", "resourceType": "Entity"},
+            "expression": [["nickname"]],
+        }))
+        .unwrap();
+        let input = ConversionInput {
+            attributes: vec![attribute, coded],
+            search_params: vec![search_param],
+            ..Default::default()
+        };
+        let options = ConvertOptions {
+            status: PublicationStatus::Draft,
+            publisher: Some("Example Clinic".to_owned()),
+            date: Some("2024-05-01".to_owned()),
+            language: Some("de".to_owned()),
+            use_context: vec![UsageContext::new("program", "oncology")],
+            ..Default::default()
+        };
+
+        let result = convert(input, FhirVersion::V4_0_1, &options);
+        assert!(!result.had_errors, "{:?}", result.errors);
+        let resources = result
+            .extensions
+            .iter()
+            .chain(&result.profiles)
+            .map(|sd| serde_json::to_value(sd).unwrap())
+            .chain(
+                result
+                    .search_params
+                    .iter()
+                    .map(|sp| serde_json::to_value(sp).unwrap()),
+            )
+            .chain(
+                result
+                    .value_sets
+                    .iter()
+                    .map(|vs| serde_json::to_value(vs).unwrap()),
+            )
+            .chain(
+                result
+                    .code_systems
+                    .iter()
+                    .map(|cs| serde_json::to_value(cs).unwrap()),
+            )
+            .chain(
+                result
+                    .concept_maps
+                    .iter()
+                    .map(|cm| serde_json::to_value(cm).unwrap()),
+            );
+        assert_eq!(resources.clone().count(), 6);
+        for resource in resources {
+            assert_eq!(resource["status"], "draft");
+            assert_eq!(resource["publisher"], "Example Clinic");
+            assert_eq!(resource["date"], "2024-05-01");
+            assert_eq!(resource["language"], "de");
+            assert_eq!(resource["useContext"][0]["code"]["code"], "program");
+        }
+    }
+
+    #[test]
+    fn test_prefer_local_profiles() {
+        let attribute: Attribute = serde_json::from_value(json!({
+            "id": "Patient.relative",
+            "path": ["relative"],
+            "resource": {"id": "Patient", "resourceType": "Entity"},
+            "type": {"id": "Reference", "resourceType": "Entity"},
+            "refers": ["Patient", "Practitioner"],
+            "extensionUrl": "http://example.org/relative",
+        }))
+        .unwrap();
+
+        let target_profiles = |prefer_local_profiles| {
+            let options = ConvertOptions {
+                prefer_local_profiles,
+                ..Default::default()
+            };
+            let input = ConversionInput {
+                attributes: vec![attribute.clone()],
+                ..Default::default()
+            };
+            let result = convert(input, FhirVersion::V4_0_1, &options);
+            assert!(!result.had_errors, "{:?}", result.errors);
+            result.extensions[0]
+                .differential
+                .element
+                .iter()
+                .flat_map(|element| element.r#type.iter().flatten())
+                .flat_map(|r#type| r#type.target_profile.iter().flatten())
+                .cloned()
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            target_profiles(false),
+            [
+                "http://hl7.org/fhir/StructureDefinition/Patient",
+                "http://hl7.org/fhir/StructureDefinition/Practitioner"
+            ]
+        );
+        // Only Patient has a generated profile
+        assert_eq!(
+            target_profiles(true),
+            [
+                "http://legacy.aidbox.app/fhir/StructureDefinition/Patient-fce",
+                "http://hl7.org/fhir/StructureDefinition/Practitioner"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_duplicate_canonical_url() {
+        // The same extension url on different resource types gives two extension definitions
+        let attribute = |resource_type: &str, name: &str| -> Attribute {
+            serde_json::from_value(json!({
+                "id": format!("{resource_type}.{name}"),
+                "path": [name],
+                "resource": {"id": resource_type, "resourceType": "Entity"},
+                "type": {"id": "string", "resourceType": "Entity"},
+                "extensionUrl": "http://example.org/nickname",
+            }))
+            .unwrap()
+        };
+
+        let result = convert_attributes(
+            vec![
+                attribute("Patient", "nickname"),
+                attribute("Practitioner", "alias"),
+            ],
+            Vec::new(),
+            FhirVersion::V4_0_1,
+        );
+        assert!(result.had_errors);
+        let messages: Vec<String> = result.errors.iter().map(ToString::to_string).collect();
+        assert!(
+            messages.contains(
+                &"StructureDefinition url http://example.org/nickname is used by several definitions: nickname, alias"
+                    .to_owned()
+            ),
+            "{messages:?}"
+        );
+    }
+
+    #[test]
+    fn test_check_extension_references() {
+        let attribute: Attribute = serde_json::from_value(json!({
+            "id": "Patient.nickname",
+            "path": ["nickname"],
+            "resource": {"id": "Patient", "resourceType": "Entity"},
+            "type": {"id": "string", "resourceType": "Entity"},
+            "extensionUrl": "http://example.org/nickname",
+        }))
+        .unwrap();
+        let result = convert_attributes(vec![attribute], Vec::new(), FhirVersion::V4_0_1);
+        assert!(result.errors.is_empty(), "{:?}", result.errors);
+
+        let messages = |exts: &[StructureDefinition], profiles: &[StructureDefinition]| {
+            check_extension_references(exts, profiles)
+                .iter()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>()
+        };
+        assert!(messages(&result.extensions, &result.profiles).is_empty());
+        assert_eq!(
+            messages(&result.extensions, &[]),
+            ["Extension http://example.org/nickname is not referenced by any profile"]
+        );
+        assert_eq!(
+            messages(&[], &result.profiles),
+            [
+                "Profile http://legacy.aidbox.app/fhir/StructureDefinition/Patient-fce references extension http://example.org/nickname, which is not generated"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_use_context() {
+        let attribute: Attribute = serde_json::from_value(json!({
+            "id": "Patient.nickname",
+            "path": ["nickname"],
+            "resource": {"id": "Patient", "resourceType": "Entity"},
+            "type": {"id": "string", "resourceType": "Entity"},
+            "extensionUrl": "http://example.org/nickname",
+        }))
+        .unwrap();
+        let options = ConvertOptions {
+            use_context: vec![UsageContext::new("program", "http://example.org|oncology")],
+            ..Default::default()
+        };
+        let input = ConversionInput {
+            attributes: vec![attribute],
+            ..Default::default()
+        };
+
+        let result = convert(input, FhirVersion::V4_0_1, &options);
+        for sd in result.extensions.iter().chain(result.profiles.iter()) {
+            assert_eq!(
+                serde_json::to_value(sd).unwrap()["useContext"],
+                json!([{
+                    "code": {
+                        "system": "http://terminology.hl7.org/CodeSystem/usage-context-type",
+                        "code": "program",
+                    },
+                    "valueCodeableConcept": {
+                        "coding": [{"system": "http://example.org", "code": "oncology"}],
+                    },
+                }])
+            );
+        }
+    }
+
+    #[test]
+    fn test_allow_recursive() {
+        let attributes = |reference: &str| -> Vec<Attribute> {
+            vec![
+                serde_json::from_value(json!({
+                    "id": "Patient.tree",
+                    "path": ["tree"],
+                    "resource": {"id": "Patient", "resourceType": "Entity"},
+                })),
+                serde_json::from_value(json!({
+                    "id": "Patient.tree.child",
+                    "path": ["tree", "child"],
+                    "resource": {"id": "Patient", "resourceType": "Entity"},
+                    "type": {"id": reference, "resourceType": "Attribute"},
+                    "isCollection": true,
+                })),
+            ]
+            .into_iter()
+            .map(Result::unwrap)
+            .collect()
+        };
+        let run = |reference: &str, allow_recursive: bool| {
+            let input = ConversionInput {
+                attributes: attributes(reference),
+                ..Default::default()
+            };
+            let options = ConvertOptions {
+                allow_recursive,
+                ..Default::default()
+            };
+            convert(input, FhirVersion::V4_0_1, &options)
+        };
+
+        let result = run("Patient.tree", false);
+        assert!(result.had_errors);
+        assert!(result.profiles.is_empty());
+
+        let result = run("Patient.tree", true);
+        assert!(!result.had_errors, "{:?}", result.errors);
+        let child = result.profiles[0]
+            .differential
+            .element
+            .iter()
+            .find(|element| element.id == "Patient.tree.child")
+            .unwrap();
+        assert_eq!(child.content_reference.as_deref(), Some("#Patient.tree"));
+        assert!(child.r#type.is_none());
+
+        let result = run("Patient.forest", true);
+        assert!(result.had_errors);
+        assert!(result.profiles.is_empty());
+    }
+
+    #[test]
+    fn test_emit_summary() {
+        let attributes: Vec<Attribute> = [
+            json!({
+                "id": "Patient.nickname",
+                "path": ["nickname"],
+                "resource": {"id": "Patient", "resourceType": "Entity"},
+                "type": {"id": "string", "resourceType": "Entity"},
+                "extensionUrl": "http://example.org/nickname",
+                "isSummary": true,
+            }),
+            json!({
+                "id": "Patient.gender",
+                "path": ["gender"],
+                "resource": {"id": "Patient", "resourceType": "Entity"},
+                "type": {"id": "code", "resourceType": "Entity"},
+                "isSummary": true,
+            }),
+        ]
+        .into_iter()
+        .map(|value| serde_json::from_value(value).unwrap())
+        .collect();
+        let run = |emit_summary: bool| {
+            let input = ConversionInput {
+                attributes: attributes.clone(),
+                ..Default::default()
+            };
+            let options = ConvertOptions {
+                emit_summary,
+                ..Default::default()
+            };
+            convert(input, FhirVersion::V4_0_1, &options)
+        };
+
+        assert!(run(false).had_errors);
+
+        let result = run(true);
+        assert!(!result.had_errors, "{:?}", result.errors);
+        let summary_ids: Vec<&str> = result.profiles[0]
+            .differential
+            .element
+            .iter()
+            .filter(|element| element.is_summary == Some(true))
+            .map(|element| element.id.as_str())
+            .collect();
+        assert_eq!(
+            summary_ids,
+            ["Patient.extension:nickname", "Patient.gender"]
+        );
+    }
+
+    #[test]
+    fn test_must_support() {
+        let attribute: Attribute = serde_json::from_value(json!({
+            "id": "Patient.nickname",
+            "path": ["nickname"],
+            "resource": {"id": "Patient", "resourceType": "Entity"},
+            "type": {"id": "string", "resourceType": "Entity"},
+            "extensionUrl": "http://example.org/nickname",
+        }))
+        .unwrap();
+        let input = ConversionInput {
+            attributes: vec![attribute],
+            ..Default::default()
+        };
+        let options = ConvertOptions {
+            emit: EmitOptions {
+                must_support: BTreeSet::from([
+                    "Patient.extension:nickname".to_owned(),
+                    "Patient.name".to_owned(),
+                ]),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let result = convert(input, FhirVersion::V4_0_1, &options);
+
+        let must_support_ids: Vec<&str> = result.profiles[0]
+            .differential
+            .element
+            .iter()
+            .filter(|element| element.must_support == Some(true))
+            .map(|element| element.id.as_str())
+            .collect();
+        assert_eq!(must_support_ids, ["Patient.extension:nickname"]);
+
+        // The typo is reported, but only as a warning
+        assert!(!result.had_errors);
+        assert_eq!(result.errors.len(), 1);
+        assert!(result.errors[0].to_string().contains("Patient.name"));
+    }
+
+    #[test]
+    fn test_respect_order() {
+        let attribute = |name: &str, order: Option<i64>| -> Attribute {
+            let mut value = json!({
+                "id": format!("Patient.{name}"),
+                "path": [name],
+                "resource": {"id": "Patient", "resourceType": "Entity"},
+                "type": {"id": "string", "resourceType": "Entity"},
+                "extensionUrl": format!("http://example.org/{name}"),
+            });
+            if let Some(order) = order {
+                value["order"] = json!(order);
+            }
+            serde_json::from_value(value).unwrap()
+        };
+        let input = ConversionInput {
+            attributes: vec![
+                attribute("alpha", Some(20)),
+                attribute("beta", None),
+                attribute("gamma", Some(-1)),
+                attribute("delta", Some(-1)),
+            ],
+            ..Default::default()
+        };
+        let options = ConvertOptions {
+            respect_order: true,
+            ..Default::default()
+        };
+
+        let result = convert(input, FhirVersion::V4_0_1, &options);
+        assert!(!result.had_errors, "{:?}", result.errors);
+        let slices: Vec<&str> = result.profiles[0]
+            .differential
+            .element
+            .iter()
+            .filter_map(|element| element.slice_name.as_deref())
+            .collect();
+        assert_eq!(slices, ["delta", "gamma", "alpha", "beta"]);
+    }
+
+    #[test]
+    fn test_include_and_exclude() {
+        let attribute = |rt: &str| -> Attribute {
+            serde_json::from_value(json!({
+                "id": format!("{rt}.nickname"),
+                "path": ["nickname"],
+                "resource": {"id": rt, "resourceType": "Entity"},
+                "type": {"id": "string", "resourceType": "Entity"},
+                "extensionUrl": format!("http://example.org/{rt}-nickname"),
+            }))
+            .unwrap()
+        };
+        let run = |include: &[&str], exclude: &[&str]| {
+            let options = ConvertOptions {
+                include: include.iter().map(|rt| rt.to_string()).collect(),
+                exclude: exclude.iter().map(|rt| rt.to_string()).collect(),
+                ..Default::default()
+            };
+            let input = ConversionInput {
+                attributes: vec![
+                    attribute("Patient"),
+                    attribute("Practitioner"),
+                    attribute("Organization"),
+                ],
+                ..Default::default()
+            };
+            convert(input, FhirVersion::V4_0_1, &options)
+        };
+        let types = |result: &ConversionResult| -> Vec<String> {
+            result
+                .profiles
+                .iter()
+                .map(|profile| profile.r#type.clone())
+                .collect()
+        };
+
+        let result = run(&["Patient", "Practitioner"], &["Practitioner"]);
+        assert_eq!(types(&result), ["Patient"]);
+        assert!(result.errors.is_empty());
+
+        let result = run(&["Patient"], &["Patient"]);
+        assert!(result.profiles.is_empty());
+        assert_eq!(result.errors.len(), 1);
+        assert!(!result.had_errors);
+    }
+
+    #[test]
+    fn test_dangling_type_reference() {
+        let attributes = vec![
+            serde_json::from_value(json!({
+                "id": "Patient.coach",
+                "path": ["coach"],
+                "resource": {"id": "Patient", "resourceType": "Entity"},
+                "type": {"id": "Reference", "resourceType": "Entity"},
+                "refers": ["Practitioner", "Coach", "Trainer"],
+                "extensionUrl": "http://example.org/coach",
+            }))
+            .unwrap(),
+            serde_json::from_value(json!({
+                "id": "Coach.name",
+                "path": ["name"],
+                "resource": {"id": "Coach", "resourceType": "Entity"},
+                "type": {"id": "string", "resourceType": "Entity"},
+            }))
+            .unwrap(),
+        ];
+        let options = ConvertOptions {
+            exclude: vec!["Coach".to_owned()],
+            ..Default::default()
+        };
+        let input = ConversionInput {
+            attributes,
+            ..Default::default()
+        };
+        let result = convert(input, FhirVersion::V4_0_1, &options);
+
+        let messages: Vec<&str> = result
+            .report
+            .iter()
+            .filter(|entry| entry.code.as_deref() == Some("convert::dangling_type_reference"))
+            .map(|entry| entry.message.as_str())
+            .collect();
+        assert_eq!(
+            messages,
+            [
+                "Attribute Patient.coach refers to excluded type Coach",
+                "Attribute Patient.coach refers to unknown type Trainer",
+            ]
+        );
+        assert!(!result.had_errors);
+    }
+}
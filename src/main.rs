@@ -1,32 +1,40 @@
-pub mod attribute;
-pub mod builtin;
-pub mod paths;
-pub mod resource_map;
-pub mod search_param;
-pub mod trie;
-
-use flate2::{Compression, write::GzEncoder};
+mod config;
+
 use miette::Diagnostic;
-use serde_json::json;
 use std::{
-    fs::File,
-    io::{BufReader, Write},
+    collections::BTreeMap,
+    io::{BufReader, Read},
     path::{Path, PathBuf},
     process,
 };
 
-use clap::{Parser, ValueEnum};
+use clap::{
+    ArgAction, ArgMatches, CommandFactory, FromArgMatches, Parser, ValueEnum, error::ErrorKind,
+    parser::ValueSource,
+};
+use flate2::bufread::GzDecoder;
+use serde::{Deserialize, Serialize};
 use thiserror::Error;
 use walkdir::WalkDir;
 
-use crate::{search_param::SearchParameter, trie::fhir::StructureDefinition};
+use fhir_schema_migration_tool::{
+    ConversionInput, ConversionResult, ConvertOptions, FhirVersion, SearchParameter, attribute,
+    compartment, fsh, package, push, report, search_param, trie,
+};
 
 /// Generate structure definition from Aidbox attributes
 #[derive(Debug, Parser)]
-#[command(arg_required_else_help = true)]
+#[command(
+    arg_required_else_help = true,
+    after_help = "Exit status: 0 on success, 1 if there were errors in the input data, 3 if the output could not be written."
+)]
 struct Args {
-    /// Path to Attribute files
-    path: PathBuf,
+    /// Paths to Attribute files (optionally .gz compressed), FHIR packages (.tgz) containing them, or - to read a JSON array or NDJSON of resources from stdin. Resources of all paths are converted together.
+    path: Vec<PathBuf>,
+
+    /// Read options from a TOML config file (e.g. fhir-migration.toml). Command line arguments take precedence, flags set in the file are turned off with e.g. --strict=false.
+    #[arg(long)]
+    config: Option<PathBuf>,
 
     /// Try to generate StructureDefinition resources even if there were errors
     #[arg(long)]
@@ -36,17 +44,474 @@ struct Args {
     #[arg(long)]
     ignore_flags: bool,
 
-    /// Target FHIR version.
+    /// Emit extensions of attributes marked with isModifier as modifier extensions instead of reporting isModifier.
+    #[arg(long)]
+    emit_modifier: bool,
+
+    /// Mark profile elements of attributes with isSummary as summary elements instead of reporting isSummary.
+    #[arg(long)]
+    emit_summary: bool,
+
+    /// Mark the profile element at this FHIR path (ex. Patient.name) or with this element id with mustSupport, can be repeated.
+    #[arg(long, value_name = "PATH")]
+    must_support: Vec<String>,
+
+    /// Target FHIR version, can be repeated. Several versions produce one output per version, suffixed with the version.
     #[arg(short, long, value_enum)]
-    fhir_version: FhirVersion,
+    fhir_version: Vec<FhirVersion>,
+
+    /// Take the FHIR version from package.json of the input package if --fhir-version is not given.
+    #[arg(long)]
+    assume_version_from_package: bool,
 
     /// Target IG package file (ex. fce.tgz). If not specified, all resources are written to stdout.
     #[arg(short, long)]
     output: Option<PathBuf>,
 
-    /// Exclude type from generating (e.g. for custom resources).
+    /// Name of the generated package, written to its package.json [default: legacy-fce.aidbox]
+    #[arg(long, value_name = "NAME")]
+    package_name: Option<String>,
+
+    /// Version of the generated package, written to its package.json. Must be a semantic version (e.g. 1.0.0) [default: 0.0.0]
+    #[arg(long, value_name = "VERSION")]
+    package_version: Option<String>,
+
+    /// Declare another package dependency in package.json (e.g. --dependency hl7.fhir.us.core@6.1.0), can be repeated.
+    #[arg(long, value_name = "NAME@VERSION", value_parser = parse_dependency)]
+    dependency: Vec<(String, String)>,
+
+    /// Directory of the package entries inside the --output tarball, empty for no directory.
+    #[arg(long, value_name = "DIR")]
+    tar_prefix: Option<String>,
+
+    /// Target directory for loose resource files and .index.json, as an alternative to the IG package file.
+    #[arg(long, conflicts_with = "output")]
+    output_dir: Option<PathBuf>,
+
+    /// Also write all generated resources into a single NDJSON file.
+    #[arg(long)]
+    ndjson_out: Option<PathBuf>,
+
+    /// Also write all errors and warnings as a JSON array, with their code, message, attribute id and file.
+    #[arg(long, value_name = "PATH")]
+    error_report: Option<PathBuf>,
+
+    /// Format of generated profiles and extensions, on stdout and in --output-dir [default: json]
+    #[arg(long, value_enum)]
+    output_format: Option<OutputFormat>,
+
+    /// Upload generated StructureDefinitions and SearchParameters to this FHIR server base url (ex. http://localhost:8080/fhir). Only http:// is supported, https:// urls are rejected: push through a local proxy instead.
+    /// Requests, including the --token, are sent in cleartext.
+    #[arg(long, value_name = "BASE_URL")]
+    push: Option<String>,
+
+    /// Bearer token for --push. Only sent to loopback hosts, see --allow-insecure-token.
+    #[arg(long, requires = "push")]
+    token: Option<String>,
+
+    /// Send the --token to hosts other than localhost, in cleartext.
+    #[arg(long, requires = "token")]
+    allow_insecure_token: bool,
+
+    /// Only generate for this type, can be repeated. Applied before --exclude.
+    #[arg(long)]
+    include: Vec<String>,
+
+    /// Exclude type from generating (e.g. for custom resources). Accepts glob patterns with * and ? (e.g. 'Custom*').
+    /// Kept attributes still referring to an excluded type are reported.
     #[arg(short, long)]
     exclude: Vec<String>,
+
+    /// Treat warnings as errors. Also reports elements without an attribute, which are
+    /// inferred from their children.
+    #[arg(long)]
+    strict: bool,
+
+    /// Do not mark generated extension elements with the legacy-fce extension.
+    #[arg(long)]
+    no_fce_marker: bool,
+
+    /// Do not start profile differentials with the unconstrained root element of the resource type.
+    #[arg(long)]
+    no_root_element: bool,
+
+    /// Emit one extension definition per context, instead of merging identical extensions used on several resource types.
+    #[arg(long)]
+    per_context_extensions: bool,
+
+    /// Keep one of several identical attributes with the same path (e.g. from several Aidbox modules) instead of reporting them. Conflicting ones are still errors.
+    #[arg(long)]
+    merge_duplicates: bool,
+
+    /// Point reference targets of the generated extensions at the generated profiles of the referenced types, instead of the core resources.
+    #[arg(long)]
+    prefer_local_profiles: bool,
+
+    /// Also emit snapshots, overlaying differentials onto the base FHIR definitions.
+    #[arg(long, visible_alias = "with-snapshot")]
+    snapshot: bool,
+
+    /// Report canonical urls referenced by generated profiles and extensions which are neither core types of the FHIR version nor defined in the output.
+    #[arg(long)]
+    check_urls_resolvable: bool,
+
+    /// Name the value element of single-type extensions value<Type> (e.g. valueString) instead of value[x].
+    #[arg(long)]
+    typed_value_element: bool,
+
+    /// Drop profiles constraining fewer than this number of elements [default: 1]
+    #[arg(long)]
+    min_constraints: Option<usize>,
+
+    /// Report extensions with more than this number of elements and do not generate them [default: 1000]
+    #[arg(long)]
+    max_extension_elements: Option<usize>,
+
+    /// List differential elements in the order attributes are declared in the source files instead of by name.
+    #[arg(long)]
+    preserve_source_order: bool,
+
+    /// List differential elements by the order property of their attributes instead of reporting it. Attributes with equal order are listed by name, attributes without order go last.
+    #[arg(long, conflicts_with = "preserve_source_order")]
+    respect_order: bool,
+
+    /// Sort generated extensions: by url, simple before complex (kind), or by context. By default they follow the resource type and path order.
+    #[arg(long, value_enum)]
+    sort_extensions: Option<trie::fhir::ExtensionOrder>,
+
+    /// Strength of the emitted bindings, to value sets of attributes and of enumerations [default: required]
+    #[arg(long, value_enum)]
+    binding_strength: Option<trie::fhir::BindingStrength>,
+
+    /// Description of the generated profiles whose resource has no root attribute with a description.
+    #[arg(long, value_name = "TEXT")]
+    default_description: Option<String>,
+
+    /// Base a profiled extension on another extension instead of the core Extension (e.g. --extension-base http://example.org/nickname=http://example.org/name).
+    #[arg(long, value_name = "URL=BASE", value_parser = parse_extension_base)]
+    extension_base: Vec<(String, String)>,
+
+    /// Only report how many profiles and extensions would be generated per resource type.
+    #[arg(long)]
+    count: bool,
+
+    /// Run the conversion and print the canonical urls of the resources which would be generated, grouped by kind, without writing or pushing any output.
+    #[arg(long, conflicts_with = "count")]
+    dry_run: bool,
+
+    /// Do not print diagnostics and the summary of the run to stderr, only set the exit status. Failures to write the output are still printed.
+    #[arg(short, long)]
+    quiet: bool,
+
+    /// Print the summary at the end of the run as JSON.
+    #[arg(long, conflicts_with = "quiet")]
+    summary_json: bool,
+
+    /// Report builtin attributes not referenced by any search parameter: their number, or also the list.
+    #[arg(long, value_enum, num_args = 0..=1, default_missing_value = "count")]
+    report_unused_builtins: Option<UnusedBuiltinsReport>,
+
+    /// Slice nested extensions of a complex extension by another discriminator than url (e.g. --slicing-discriminator http://example.org/name=value:value.ofType(string)).
+    #[arg(long, value_name = "URL=TYPE:PATH", value_parser = parse_slicing_discriminator)]
+    slicing_discriminator: Vec<(String, trie::fhir::ElementSlicingDiscriminator)>,
+
+    /// Language of the generated resources (e.g. de), set as their language element.
+    #[arg(long)]
+    language: Option<String>,
+
+    /// Business version of the generated resources, set as their version element [default: the package version]
+    #[arg(long, value_name = "VERSION")]
+    resource_version: Option<String>,
+
+    /// Status of all generated resources [default: active]
+    #[arg(long, value_enum)]
+    status: Option<trie::fhir::PublicationStatus>,
+
+    /// Publisher of all generated resources.
+    #[arg(long)]
+    publisher: Option<String>,
+
+    /// Date of all generated resources, as YYYY, YYYY-MM or YYYY-MM-DD [default: today, or the day of SOURCE_DATE_EPOCH if set]
+    #[arg(long)]
+    date: Option<String>,
+
+    /// Add a useContext to all generated resources, the value is a code or system|code (e.g. --use-context program=oncology). Can be repeated.
+    #[arg(long, value_name = "TYPE=VALUE", value_parser = parse_use_context)]
+    use_context: Vec<(String, String)>,
+
+    /// Report attributes whose conversion panics as errors and convert the remaining ones.
+    #[arg(long)]
+    keep_going: bool,
+
+    /// Convert attributes whose type refers to another attribute (recursive structures) to elements with a contentReference to the referenced element.
+    #[arg(long)]
+    allow_recursive: bool,
+
+    /// Remove intermediate path segments which produce no profile elements before emitting the profiles.
+    #[arg(long)]
+    trim_empty_inferred: bool,
+
+    /// Number of threads reading input files [default: number of CPUs]
+    #[arg(short, long, value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+    jobs: Option<usize>,
+}
+
+/// Numbers of the resources read and emitted in a run, and of the diagnostics found
+#[derive(Debug, Default, Serialize)]
+struct Summary {
+    read: ReadSummary,
+    emitted: EmittedSummary,
+    errors: usize,
+    warnings: usize,
+}
+
+#[derive(Debug, Default, Serialize)]
+struct ReadSummary {
+    attributes: usize,
+    search_params: usize,
+    compartments: usize,
+}
+
+/// Summed over all target FHIR versions
+#[derive(Debug, Default, Serialize)]
+struct EmittedSummary {
+    profiles: usize,
+    extensions: usize,
+    search_params: usize,
+}
+
+impl std::fmt::Display for Summary {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Read {} attributes, {} search params, {} compartments; emitted {} profiles, {} extensions, {} search params; {} errors, {} warnings",
+            self.read.attributes,
+            self.read.search_params,
+            self.read.compartments,
+            self.emitted.profiles,
+            self.emitted.extensions,
+            self.emitted.search_params,
+            self.errors,
+            self.warnings
+        )
+    }
+}
+
+/// Print the number of extensions and profiles per resource type they apply to
+fn print_counts(
+    exts: &[trie::fhir::StructureDefinition],
+    profiles: &[trie::fhir::StructureDefinition],
+) {
+    let mut counts: BTreeMap<&str, (usize, usize)> = BTreeMap::new();
+    for ext in exts {
+        let rt = ext
+            .context
+            .iter()
+            .flatten()
+            .filter_map(|context| context.expression.split('.').next())
+            .next()
+            .unwrap_or(&ext.r#type);
+        counts.entry(rt).or_default().0 += 1;
+    }
+    for profile in profiles {
+        counts.entry(&profile.r#type).or_default().1 += 1;
+    }
+
+    for (rt, (exts, profiles)) in &counts {
+        println!("{rt}: Extensions: {exts}; Profiles: {profiles}");
+    }
+    println!(
+        "Extensions: {}; Profiles: {} would be generated",
+        exts.len(),
+        profiles.len()
+    );
+}
+
+/// Print the canonical url of every generated resource, grouped by resource type
+fn print_manifest(result: &ConversionResult) {
+    let groups: [(&str, Vec<&str>); 7] = [
+        (
+            "Profiles",
+            result.profiles.iter().map(|sd| sd.url.as_str()).collect(),
+        ),
+        (
+            "Extensions",
+            result.extensions.iter().map(|sd| sd.url.as_str()).collect(),
+        ),
+        (
+            "SearchParameters",
+            result
+                .search_params
+                .iter()
+                .map(|sp| sp.url.as_str())
+                .collect(),
+        ),
+        (
+            "CompartmentDefinitions",
+            result
+                .compartments
+                .iter()
+                .map(|cd| cd.url.as_str())
+                .collect(),
+        ),
+        (
+            "ValueSets",
+            result.value_sets.iter().map(|vs| vs.url.as_str()).collect(),
+        ),
+        (
+            "CodeSystems",
+            result
+                .code_systems
+                .iter()
+                .map(|cs| cs.url.as_str())
+                .collect(),
+        ),
+        (
+            "ConceptMaps",
+            result
+                .concept_maps
+                .iter()
+                .map(|cm| cm.url.as_str())
+                .collect(),
+        ),
+    ];
+    for (kind, urls) in groups {
+        if urls.is_empty() {
+            continue;
+        }
+        println!("{kind}:");
+        for url in urls {
+            println!("  {url}");
+        }
+    }
+}
+
+/// FHIR `date`: a year, a month or a day, without a time
+fn is_fhir_date(value: &str) -> bool {
+    let parts: Vec<&str> = value.split('-').collect();
+    let number = |part: &str, length: usize, range: std::ops::RangeInclusive<u32>| {
+        part.len() == length
+            && part.chars().all(|c| c.is_ascii_digit())
+            && part.parse().is_ok_and(|number| range.contains(&number))
+    };
+    match parts.as_slice() {
+        [year] => number(year, 4, 0..=9999),
+        [year, month] => number(year, 4, 0..=9999) && number(month, 2, 1..=12),
+        [year, month, day] => {
+            number(year, 4, 0..=9999) && number(month, 2, 1..=12) && number(day, 2, 1..=31)
+        }
+        _ => false,
+    }
+}
+
+/// Current day as YYYY-MM-DD, taken from `SOURCE_DATE_EPOCH` if set so that the output is
+/// reproducible
+fn today() -> String {
+    let seconds = std::env::var("SOURCE_DATE_EPOCH")
+        .ok()
+        .and_then(|epoch| epoch.trim().parse().ok())
+        .unwrap_or_else(|| {
+            std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .map_or(0, |duration| duration.as_secs())
+        });
+
+    // Civil date of the day since 1970-01-01, in 400 year eras starting on March 1st
+    let days = seconds / 86400 + 719468;
+    let era = days / 146097;
+    let day_of_era = days % 146097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 {
+        shifted_month + 3
+    } else {
+        shifted_month - 9
+    };
+    let year = era * 400 + year_of_era + u64::from(month <= 2);
+    format!("{year:04}-{month:02}-{day:02}")
+}
+
+fn parse_dependency(value: &str) -> Result<(String, String), String> {
+    match value.split_once('@') {
+        Some((name, version)) if !name.is_empty() && !version.is_empty() => {
+            Ok((name.to_owned(), version.to_owned()))
+        }
+        _ => Err("expected <package name>@<version>".to_owned()),
+    }
+}
+
+fn parse_extension_base(value: &str) -> Result<(String, String), String> {
+    match value.split_once('=') {
+        Some((url, base)) if !url.is_empty() && !base.is_empty() => {
+            Ok((url.to_owned(), base.to_owned()))
+        }
+        _ => Err("expected <extension url>=<base definition url>".to_owned()),
+    }
+}
+
+fn parse_use_context(value: &str) -> Result<(String, String), String> {
+    match value.split_once('=') {
+        Some((r#type, value)) if !r#type.is_empty() && !value.is_empty() => {
+            Ok((r#type.to_owned(), value.to_owned()))
+        }
+        _ => Err("expected <usage context type>=<code or system|code>".to_owned()),
+    }
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum UnusedBuiltinsReport {
+    Count,
+    List,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputFormat {
+    /// StructureDefinition resources
+    Json,
+    /// FHIR Shorthand definitions of profiles and extensions, other resources stay JSON
+    Fsh,
+}
+
+const DISCRIMINATOR_TYPES: [&str; 6] =
+    ["value", "exists", "pattern", "type", "profile", "position"];
+
+/// Discriminators are also read from the config file, so they are checked apart from parsing
+fn check_slicing_discriminator(
+    discriminator: &trie::fhir::ElementSlicingDiscriminator,
+) -> Result<(), String> {
+    if !DISCRIMINATOR_TYPES.contains(&discriminator.r#type.as_str()) {
+        return Err(format!(
+            "unknown discriminator type {}, expected one of {}",
+            discriminator.r#type,
+            DISCRIMINATOR_TYPES.join(", ")
+        ));
+    }
+    if discriminator.path.is_empty() {
+        return Err("empty discriminator path".to_owned());
+    }
+    Ok(())
+}
+
+fn parse_slicing_discriminator(
+    value: &str,
+) -> Result<(String, trie::fhir::ElementSlicingDiscriminator), String> {
+    let error = || "expected <extension url>=<discriminator type>:<discriminator path>".to_owned();
+    let (url, discriminator) = value.split_once('=').ok_or_else(error)?;
+    let (r#type, path) = discriminator.split_once(':').ok_or_else(error)?;
+    if url.is_empty() || path.is_empty() {
+        return Err(error());
+    }
+    let discriminator = trie::fhir::ElementSlicingDiscriminator {
+        r#type: r#type.to_owned(),
+        path: path.to_owned(),
+    };
+    check_slicing_discriminator(&discriminator)?;
+    Ok((url.to_owned(), discriminator))
 }
 
 fn is_json(path: &Path) -> bool {
@@ -61,14 +526,33 @@ fn is_yaml(path: &Path) -> bool {
         .is_some_and(|ext| ext.eq_ignore_ascii_case("yaml") || ext.eq_ignore_ascii_case("yml"))
 }
 
+fn is_gzip(path: &Path) -> bool {
+    path.extension()
+        .and_then(|ext| ext.to_str())
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("gz"))
+}
+
+/// Path without the `.gz` extension of a compressed file, e.g. `attr.json` for `attr.json.gz`
+fn uncompressed_path(path: &Path) -> &Path {
+    match path.file_stem() {
+        Some(stem) if is_gzip(path) => Path::new(stem),
+        _ => path,
+    }
+}
+
+/// JSON or YAML file, possibly gzip-compressed
 fn is_json_or_yaml(path: &Path) -> bool {
+    let path = uncompressed_path(path);
     is_json(path) || is_yaml(path)
 }
 
 #[derive(Debug, Error, Diagnostic)]
 enum Error {
     #[error("Error while searching for JSON and YAML files in {base_path}")]
-    #[diagnostic(help("Ensure the directory name is correct and you have access rights"))]
+    #[diagnostic(
+        code(cli::walk),
+        help("Ensure the directory name is correct and you have access rights")
+    )]
     Walk {
         base_path: PathBuf,
         #[source]
@@ -76,6 +560,7 @@ enum Error {
     },
 
     #[error("Could not read contents of the file {filename}")]
+    #[diagnostic(code(cli::read_file))]
     ReadFile {
         filename: PathBuf,
         #[source]
@@ -83,6 +568,7 @@ enum Error {
     },
 
     #[error("Could not read {filename} as Aidbox attribute")]
+    #[diagnostic(code(cli::bad_attribute))]
     BadAttribute {
         filename: PathBuf,
         #[source]
@@ -90,13 +576,23 @@ enum Error {
     },
 
     #[error("Could not read {filename} as Aidbox search parameter")]
+    #[diagnostic(code(cli::bad_search_parameter))]
     BadSearchParameter {
         filename: PathBuf,
         #[source]
         source: serde_json::Error,
     },
 
+    #[error("Could not read {filename} as Aidbox compartment definition")]
+    #[diagnostic(code(cli::bad_compartment_definition))]
+    BadCompartmentDefinition {
+        filename: PathBuf,
+        #[source]
+        source: serde_json::Error,
+    },
+
     #[error("Could not parse {filename} as JSON")]
+    #[diagnostic(code(cli::bad_json))]
     BadJson {
         filename: PathBuf,
         #[source]
@@ -104,133 +600,262 @@ enum Error {
     },
 
     #[error("Could not parse {filename} as YAML")]
+    #[diagnostic(code(cli::bad_yaml))]
     BadYaml {
         filename: PathBuf,
         #[source]
         source: serde_yaml::Error,
     },
 
-    #[error("Not allowed target resource type {resource_type}")]
-    NotAllowedTargetResource { resource_type: String },
-
     #[error("Not supported resource type {resource_type} in {filename}")]
+    #[diagnostic(code(cli::not_supported_resource_type))]
     NotSupportedResourceType {
         filename: PathBuf,
         resource_type: String,
     },
 
     #[error("Missing resource type in {filename}")]
+    #[diagnostic(code(cli::missing_resource_type))]
     MissingResourceType { filename: PathBuf },
-}
 
-#[derive(Debug, Clone, Copy, ValueEnum)]
-pub enum FhirVersion {
-    #[value(name = "4.0.0")]
-    V4_0_0,
-    #[value(name = "4.0.1")]
-    V4_0_1,
-    #[value(name = "4.3.0")]
-    V4_3_0,
-    #[value(name = "5.0.0")]
-    V5_0_0,
-}
-
-pub fn make_package_json(fhir_version: FhirVersion) -> String {
-    let version_string: &'static str = match fhir_version {
-        FhirVersion::V4_0_0 => "4.0.0",
-        FhirVersion::V4_0_1 => "4.0.1",
-        FhirVersion::V4_3_0 => "4.3.0",
-        FhirVersion::V5_0_0 => "5.0.0",
-    };
-
-    let pkg_name: &'static str = match fhir_version {
-        FhirVersion::V4_0_0 => "hl7.fhir.r4.core",
-        FhirVersion::V4_0_1 => "hl7.fhir.r4.core",
-        FhirVersion::V4_3_0 => "hl7.fhir.r4b.core",
-        FhirVersion::V5_0_0 => "hl7.fhir.r5.core",
-    };
+    #[error("Could not read package {filename}")]
+    #[diagnostic(code(cli::read_package))]
+    ReadPackage {
+        filename: PathBuf,
+        #[source]
+        source: std::io::Error,
+    },
 
-    serde_json::to_string_pretty(&json!({
-        "name": "legacy-fce.aidbox",
-        "version": "0.0.0",
-        "type": "IG",
-        "dependencies": {
-            pkg_name: version_string
-        }
-    }))
-    .unwrap()
-}
-
-fn write_to_archive<T: Write>(
-    archive: &mut tar::Builder<T>,
-    path: &Path,
-    payload: &[u8],
-) -> anyhow::Result<()> {
-    let mut header = tar::Header::new_gnu();
-    header.set_size(payload.len() as u64);
-    header.set_mode(0o644);
-    header.set_mtime(
-        std::time::SystemTime::now()
-            .duration_since(std::time::SystemTime::UNIX_EPOCH)
-            .map(|duration| duration.as_secs())
-            .unwrap_or(0),
-    );
-    header.set_cksum();
-    archive.append_data(&mut header, path, payload)?;
-    Ok(())
-}
+    #[error("Could not read stdin")]
+    #[diagnostic(code(cli::read_stdin))]
+    ReadStdin {
+        #[source]
+        source: std::io::Error,
+    },
 
-pub fn make_package(
-    output: PathBuf,
-    exts: &Vec<StructureDefinition>,
-    profiles: &Vec<StructureDefinition>,
-    search_params: &Vec<search_param::fhir::SearchParameter>,
-    fhir_version: FhirVersion,
-) -> anyhow::Result<()> {
-    let file = File::create(output)?;
-    let gzip = GzEncoder::new(file, Compression::default());
-    let mut tar = tar::Builder::new(gzip);
+    #[error("Could not parse line {line} of stdin as JSON")]
+    #[diagnostic(
+        code(cli::bad_stdin_line),
+        help("stdin must contain either a JSON array of resources or one resource per line")
+    )]
+    BadStdinLine {
+        line: usize,
+        #[source]
+        source: serde_json::Error,
+    },
 
-    {
-        let package_json = make_package_json(fhir_version);
-        write_to_archive(
-            &mut tar,
-            Path::new("package/package.json"),
-            package_json.as_bytes(),
-        )?
-    }
-
-    for (i, ext) in exts.into_iter().enumerate() {
-        let name = format!(
-            "package/StructureDefinition-Extension-{}-{}.json",
-            &ext.name, i
-        );
-        let sd = serde_json::to_string_pretty(&ext).expect("Bug: invalid genereated SD");
+    #[error("Could not read entry {index} of the bundle {filename}")]
+    #[diagnostic(code(cli::bundle_entry))]
+    BundleEntry {
+        filename: PathBuf,
+        index: usize,
+        #[source]
+        source: Box<Error>,
+    },
 
-        write_to_archive(&mut tar, Path::new(&name), sd.as_bytes())?
-    }
+    #[error("Output file {} does not end with .tgz or .tar.gz", .output.display())]
+    #[diagnostic(
+        code(cli::output_extension),
+        severity(Warning),
+        help(
+            "The output is a gzipped tarball (FHIR package). Consider naming it e.g. package.tgz"
+        )
+    )]
+    OutputExtension { output: PathBuf },
 
-    for (i, profile) in profiles.into_iter().enumerate() {
-        let name = format!("package/StructureDefinition-{}-{}.json", &profile.name, i);
-        let sd = serde_json::to_string_pretty(&profile).expect("Bug: invalid genereated SD");
+    #[error("Could not write the error report {filename}")]
+    #[diagnostic(code(cli::write_error_report))]
+    WriteErrorReport {
+        filename: PathBuf,
+        #[source]
+        source: std::io::Error,
+    },
+}
 
-        write_to_archive(&mut tar, Path::new(&name), sd.as_bytes())?
+impl report::Subject for Error {
+    fn filename(&self) -> Option<&Path> {
+        match self {
+            Error::ReadFile { filename, .. }
+            | Error::BadAttribute { filename, .. }
+            | Error::BadSearchParameter { filename, .. }
+            | Error::BadCompartmentDefinition { filename, .. }
+            | Error::BadJson { filename, .. }
+            | Error::BadYaml { filename, .. }
+            | Error::NotSupportedResourceType { filename, .. }
+            | Error::MissingResourceType { filename }
+            | Error::ReadPackage { filename, .. }
+            | Error::BundleEntry { filename, .. }
+            | Error::WriteErrorReport { filename, .. } => Some(filename),
+            Error::Walk { base_path, .. } => Some(base_path),
+            Error::OutputExtension { output } => Some(output),
+            Error::ReadStdin { .. } | Error::BadStdinLine { .. } => None,
+        }
     }
+}
 
-    for (i, sp) in search_params.into_iter().enumerate() {
-        let name = format!(
-            "package/SearchParameter-{}-{}-{}.json",
-            &sp.base[0], &sp.name, i
-        );
-        let sp = serde_json::to_string_pretty(&sp).expect("Bug: invalid genereated SP");
+impl Args {
+    /// [`Args::command`] with flags also taking a value, so that e.g. `--strict=false` turns
+    /// off a flag set in the config file
+    fn flag_command() -> clap::Command {
+        Args::command().mut_args(|arg| {
+            if matches!(arg.get_action(), ArgAction::SetTrue) {
+                arg.action(ArgAction::Set)
+                    .num_args(0..=1)
+                    .require_equals(true)
+                    .default_value("false")
+                    .default_missing_value("true")
+                    .value_name("BOOL")
+                    .hide_default_value(true)
+                    .hide_possible_values(true)
+            } else {
+                arg
+            }
+        })
+    }
 
-        write_to_archive(&mut tar, Path::new(&name), sp.as_bytes())?
+    /// Parse the arguments, with the matches telling given arguments from defaults
+    fn parse_with_matches<T: Into<std::ffi::OsString> + Clone>(
+        args: impl IntoIterator<Item = T>,
+    ) -> (Self, ArgMatches) {
+        let matches = Self::flag_command().get_matches_from(args);
+        let args = Self::from_arg_matches(&matches).unwrap_or_else(|error| error.exit());
+        (args, matches)
     }
 
-    let gzip = tar.into_inner()?;
-    let _file = gzip.finish()?;
+    /// Fill arguments not given on the command line from the config file
+    fn merge(mut self, config: config::Config, matches: &ArgMatches) -> Self {
+        // Flags given on the command line, also as --flag=false, take precedence
+        let flag = |id: &str, value: bool, config_value: Option<bool>| {
+            if matches.value_source(id) == Some(ValueSource::CommandLine) {
+                value
+            } else {
+                config_value.unwrap_or(value)
+            }
+        };
 
-    Ok(())
+        if self.path.is_empty() {
+            self.path = config.path.map(Vec::from).unwrap_or_default();
+        }
+        self.ignore_errors = flag("ignore_errors", self.ignore_errors, config.ignore_errors);
+        self.ignore_flags = flag("ignore_flags", self.ignore_flags, config.ignore_flags);
+        self.emit_modifier = flag("emit_modifier", self.emit_modifier, config.emit_modifier);
+        self.emit_summary = flag("emit_summary", self.emit_summary, config.emit_summary);
+        if self.fhir_version.is_empty() {
+            self.fhir_version = config.fhir_version.map(Vec::from).unwrap_or_default();
+        }
+        self.assume_version_from_package = flag(
+            "assume_version_from_package",
+            self.assume_version_from_package,
+            config.assume_version_from_package,
+        );
+        self.output = self.output.or(config.output);
+        self.package_name = self.package_name.or(config.package_name);
+        self.package_version = self.package_version.or(config.package_version);
+        if self.dependency.is_empty() {
+            self.dependency = config.dependency.unwrap_or_default().into_iter().collect();
+        }
+        self.tar_prefix = self.tar_prefix.or(config.tar_prefix);
+        self.output_dir = self.output_dir.or(config.output_dir);
+        self.ndjson_out = self.ndjson_out.or(config.ndjson_out);
+        self.error_report = self.error_report.or(config.error_report);
+        self.output_format = self.output_format.or(config.output_format);
+        self.push = self.push.or(config.push);
+        if self.must_support.is_empty() {
+            self.must_support = config.must_support.unwrap_or_default();
+        }
+        if self.include.is_empty() {
+            self.include = config.include.unwrap_or_default();
+        }
+        if self.exclude.is_empty() {
+            self.exclude = config.exclude.unwrap_or_default();
+        }
+        self.strict = flag("strict", self.strict, config.strict);
+        self.no_fce_marker = flag("no_fce_marker", self.no_fce_marker, config.no_fce_marker);
+        self.no_root_element = flag(
+            "no_root_element",
+            self.no_root_element,
+            config.no_root_element,
+        );
+        self.per_context_extensions = flag(
+            "per_context_extensions",
+            self.per_context_extensions,
+            config.per_context_extensions,
+        );
+        self.merge_duplicates = flag(
+            "merge_duplicates",
+            self.merge_duplicates,
+            config.merge_duplicates,
+        );
+        self.quiet = flag("quiet", self.quiet, config.quiet);
+        self.summary_json = flag("summary_json", self.summary_json, config.summary_json);
+        self.prefer_local_profiles = flag(
+            "prefer_local_profiles",
+            self.prefer_local_profiles,
+            config.prefer_local_profiles,
+        );
+        self.snapshot = flag("snapshot", self.snapshot, config.snapshot);
+        self.check_urls_resolvable = flag(
+            "check_urls_resolvable",
+            self.check_urls_resolvable,
+            config.check_urls_resolvable,
+        );
+        self.typed_value_element = flag(
+            "typed_value_element",
+            self.typed_value_element,
+            config.typed_value_element,
+        );
+        self.min_constraints = self.min_constraints.or(config.min_constraints);
+        self.max_extension_elements = self
+            .max_extension_elements
+            .or(config.max_extension_elements);
+        self.preserve_source_order = flag(
+            "preserve_source_order",
+            self.preserve_source_order,
+            config.preserve_source_order,
+        );
+        self.respect_order = flag("respect_order", self.respect_order, config.respect_order);
+        self.sort_extensions = self.sort_extensions.or(config.sort_extensions);
+        self.binding_strength = self.binding_strength.or(config.binding_strength);
+        self.default_description = self.default_description.or(config.default_description);
+        self.keep_going = flag("keep_going", self.keep_going, config.keep_going);
+        self.allow_recursive = flag(
+            "allow_recursive",
+            self.allow_recursive,
+            config.allow_recursive,
+        );
+        self.trim_empty_inferred = flag(
+            "trim_empty_inferred",
+            self.trim_empty_inferred,
+            config.trim_empty_inferred,
+        );
+        self.jobs = self.jobs.or(config.jobs);
+        self.language = self.language.or(config.language);
+        self.resource_version = self.resource_version.or(config.resource_version);
+        self.status = self.status.or(config.status);
+        self.publisher = self.publisher.or(config.publisher);
+        self.date = self.date.or(config.date);
+        if self.use_context.is_empty() {
+            self.use_context = config.use_context.unwrap_or_default().into_iter().collect();
+        }
+        if self.slicing_discriminator.is_empty() {
+            self.slicing_discriminator = config
+                .slicing_discriminator
+                .unwrap_or_default()
+                .into_iter()
+                .collect();
+        }
+        self.report_unused_builtins = self
+            .report_unused_builtins
+            .or(config.report_unused_builtins);
+        if self.extension_base.is_empty() {
+            self.extension_base = config
+                .extension_base
+                .unwrap_or_default()
+                .into_iter()
+                .collect();
+        }
+        self
+    }
 }
 
 fn read_file(path: &Path) -> Result<serde_json::Value, Error> {
@@ -239,13 +864,18 @@ fn read_file(path: &Path) -> Result<serde_json::Value, Error> {
         source: error,
     })?;
     let file = BufReader::new(file);
-    if is_json(path) {
-        serde_json::from_reader(file).map_err(|error| Error::BadJson {
+    let reader: Box<dyn Read> = if is_gzip(path) {
+        Box::new(GzDecoder::new(file))
+    } else {
+        Box::new(file)
+    };
+    if is_json(uncompressed_path(path)) {
+        serde_json::from_reader(reader).map_err(|error| Error::BadJson {
             filename: path.to_owned(),
             source: error,
         })
     } else {
-        serde_yaml::from_reader(file).map_err(|error| Error::BadYaml {
+        serde_yaml::from_reader(reader).map_err(|error| Error::BadYaml {
             filename: path.to_owned(),
             source: error,
         })
@@ -256,231 +886,1097 @@ fn read_file(path: &Path) -> Result<serde_json::Value, Error> {
 enum Data {
     Attribute(Box<attribute::aidbox::Attribute>),
     SearchParameter(SearchParameter),
+    CompartmentDefinition(compartment::CompartmentDefinition),
 }
 
-fn read_data(path: &Path) -> Result<Data, Error> {
-    let raw_data: serde_json::Value = read_file(path)?;
-    match raw_data["resourceType"].as_str() {
-        Some("Attribute") => serde_json::from_value::<attribute::aidbox::Attribute>(raw_data)
-            .map(|attrs| Data::Attribute(Box::new(attrs)))
-            .map_err(|error| Error::BadAttribute {
-                filename: path.to_owned(),
-                source: error,
-            }),
-        Some("SearchParameter") => {
-            serde_json::from_value::<search_param::SearchParameter>(raw_data)
-                .map(Data::SearchParameter)
-                .map_err(|error| Error::BadSearchParameter {
-                    filename: path.to_owned(),
-                    source: error,
-                })
-        }
-        Some(resource_type) => Err(Error::NotSupportedResourceType {
-            filename: path.to_path_buf(),
-            resource_type: (resource_type.to_owned()),
-        }),
-        None => Err(Error::MissingResourceType {
-            filename: path.to_owned(),
-        }),
+/// Read a resource file, or each entry of a Bundle file
+fn read_data(path: &Path) -> (Vec<Data>, Vec<Error>) {
+    match read_file(path) {
+        Ok(raw_data) => read_value(path, raw_data),
+        Err(error) => (Vec::new(), vec![error]),
     }
 }
 
-fn main() {
-    // println!("{:#?}", get_builtin_resources(FhirVersion::V4_0_1));
-    _ = miette::set_hook(Box::new(|_| {
-        Box::new(
-            miette::MietteHandlerOpts::new()
-                .break_words(true)
-                .width(120)
-                .with_cause_chain()
-                .build(),
-        )
-    }));
-
-    let mut had_errors = false;
-    let args = Args::parse();
-    let path = args.path;
-
-    let walker = WalkDir::new(&path).into_iter();
+/// Read all JSON and YAML files under the directory using up to `jobs` threads.
+///
+/// Files are visited in file name order and their resources are returned in that order
+/// regardless of the number of threads, so the output is reproducible.
+fn read_directory(base_path: &Path, jobs: usize) -> (Vec<Data>, Vec<Error>) {
+    let mut data: Vec<Data> = Vec::new();
+    let mut errors: Vec<Error> = Vec::new();
 
-    let mut aidbox_attributes: Vec<attribute::aidbox::Attribute> = Vec::new();
-    let mut aidbox_search_params: Vec<search_param::SearchParameter> = Vec::new();
-
-    for entry in walker {
+    let mut paths: Vec<PathBuf> = Vec::new();
+    for entry in WalkDir::new(base_path).sort_by_file_name() {
         let entry = match entry {
             Ok(entry) => entry,
             Err(error) => {
-                had_errors = true;
-                eprintln!(
-                    "{:?}",
-                    miette::Report::new(Error::Walk {
-                        base_path: path.clone(),
-                        source: error
-                    })
-                );
+                errors.push(Error::Walk {
+                    base_path: base_path.to_owned(),
+                    source: error,
+                });
                 continue;
             }
         };
 
-        let path = entry.path();
-        if !is_json_or_yaml(path) {
-            continue;
+        if is_json_or_yaml(entry.path()) {
+            paths.push(entry.into_path());
         }
+    }
 
-        match read_data(path) {
-            Ok(Data::Attribute(data)) => {
-                aidbox_attributes.push(*data);
-            }
-            Ok(Data::SearchParameter(data)) => {
-                aidbox_search_params.push(data);
-            }
-            Err(error) => {
-                had_errors = true;
-                eprintln!("{:?}", miette::Report::new(error));
+    // Contiguous chunks keep the file order when the results are joined
+    let chunk_size = paths.len().div_ceil(jobs.max(1)).max(1);
+    let results: Vec<(Vec<Data>, Vec<Error>)> = std::thread::scope(|scope| {
+        let workers: Vec<_> = paths
+            .chunks(chunk_size)
+            .map(|chunk| {
+                scope.spawn(move || {
+                    let mut data: Vec<Data> = Vec::new();
+                    let mut errors: Vec<Error> = Vec::new();
+                    for path in chunk {
+                        let (mut file_data, mut file_errors) = read_data(path);
+                        data.append(&mut file_data);
+                        errors.append(&mut file_errors);
+                    }
+                    (data, errors)
+                })
+            })
+            .collect();
+        workers
+            .into_iter()
+            .map(|worker| worker.join().expect("Reading thread panicked"))
+            .collect()
+    });
+
+    for (mut chunk_data, mut chunk_errors) in results {
+        data.append(&mut chunk_data);
+        errors.append(&mut chunk_errors);
+    }
+
+    (data, errors)
+}
+
+/// Read resources from a FHIR package: every JSON file except package.json and .index.json.
+///
+/// Also returns the FHIR version declared by package.json, if any.
+fn read_package(path: &Path) -> (Vec<Data>, Vec<Error>, Option<FhirVersion>) {
+    let mut data: Vec<Data> = Vec::new();
+    let mut errors: Vec<Error> = Vec::new();
+    let mut fhir_version: Option<FhirVersion> = None;
+
+    let to_error = |error| Error::ReadPackage {
+        filename: path.to_owned(),
+        source: error,
+    };
+
+    let file = match std::fs::File::open(path) {
+        Ok(file) => file,
+        Err(error) => {
+            return (
+                data,
+                vec![Error::ReadFile {
+                    filename: path.to_owned(),
+                    source: error,
+                }],
+                None,
+            );
+        }
+    };
+    let mut archive = tar::Archive::new(flate2::read::GzDecoder::new(BufReader::new(file)));
+    let entries = match archive.entries() {
+        Ok(entries) => entries,
+        Err(error) => return (data, vec![to_error(error)], None),
+    };
+
+    for entry in entries {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(error) => {
+                errors.push(to_error(error));
+                break;
+            }
+        };
+        let entry_path = match entry.path() {
+            Ok(entry_path) => entry_path.into_owned(),
+            Err(error) => {
+                errors.push(to_error(error));
+                continue;
+            }
+        };
+        if !is_json(&entry_path) {
+            continue;
+        }
+
+        // Entries are reported as paths inside the package file
+        let filename = path.join(&entry_path);
+        let value: serde_json::Value = match serde_json::from_reader(entry) {
+            Ok(value) => value,
+            Err(error) => {
+                errors.push(Error::BadJson {
+                    filename,
+                    source: error,
+                });
+                continue;
+            }
+        };
+
+        match entry_path.file_name().and_then(|name| name.to_str()) {
+            Some("package.json") => {
+                fhir_version = package::fhir_version_from_package_json(&value);
+            }
+            Some(".index.json") => (),
+            _ => {
+                let (mut entry_data, mut entry_errors) = read_value(&filename, value);
+                data.append(&mut entry_data);
+                errors.append(&mut entry_errors);
             }
         }
     }
 
-    let mut all_attributes = aidbox_attributes.clone();
-    all_attributes.extend(builtin::get_builtin_resources(args.fhir_version).attribute);
+    (data, errors, fhir_version)
+}
+
+/// Read resources from stdin, either as a JSON array (or a single resource) or as NDJSON
+fn read_stdin() -> (Vec<Data>, Vec<Error>) {
+    let path = Path::new("<stdin>");
 
-    let mut fhir_search_params: Vec<search_param::fhir::SearchParameter> = Vec::new();
-    for aidbox_sp in aidbox_search_params {
-        match search_param::fhir::convert(&all_attributes, &aidbox_sp) {
-            Ok(sp) => fhir_search_params.push(sp),
+    let mut contents = String::new();
+    if let Err(error) = std::io::stdin().read_to_string(&mut contents) {
+        return (Vec::new(), vec![Error::ReadStdin { source: error }]);
+    }
+
+    let values: Vec<(usize, Result<serde_json::Value, serde_json::Error>)> =
+        match serde_json::from_str::<serde_json::Value>(&contents) {
+            Ok(serde_json::Value::Array(values)) => {
+                values.into_iter().map(|value| (0, Ok(value))).collect()
+            }
+            Ok(value) => vec![(0, Ok(value))],
+            Err(_) => contents
+                .lines()
+                .enumerate()
+                .filter(|(_, line)| !line.trim().is_empty())
+                .map(|(index, line)| (index + 1, serde_json::from_str(line)))
+                .collect(),
+        };
+
+    let mut data: Vec<Data> = Vec::new();
+    let mut errors: Vec<Error> = Vec::new();
+    for (line, value) in values {
+        match value {
+            Ok(value) => {
+                let (mut value_data, mut value_errors) = read_value(path, value);
+                data.append(&mut value_data);
+                errors.append(&mut value_errors);
+            }
+            Err(error) => errors.push(Error::BadStdinLine {
+                line,
+                source: error,
+            }),
+        }
+    }
+
+    (data, errors)
+}
+
+/// Read a resource, or each entry of a Bundle.
+///
+/// Errors in Bundle entries do not prevent reading the remaining entries.
+fn read_value(path: &Path, raw_data: serde_json::Value) -> (Vec<Data>, Vec<Error>) {
+    if raw_data["resourceType"].as_str() != Some("Bundle") {
+        return match parse_data(path, raw_data) {
+            Ok(data) => (vec![data], Vec::new()),
+            Err(error) => (Vec::new(), vec![error]),
+        };
+    }
+
+    let mut data: Vec<Data> = Vec::new();
+    let mut errors: Vec<Error> = Vec::new();
+    let entries = match raw_data {
+        serde_json::Value::Object(mut bundle) => bundle.remove("entry"),
+        _ => None,
+    };
+    let entries = match entries {
+        Some(serde_json::Value::Array(entries)) => entries,
+        _ => Vec::new(),
+    };
+    for (index, mut entry) in entries.into_iter().enumerate() {
+        match parse_data(path, entry["resource"].take()) {
+            Ok(entry_data) => data.push(entry_data),
+            Err(error) => errors.push(Error::BundleEntry {
+                filename: path.to_owned(),
+                index,
+                source: Box::new(error),
+            }),
+        }
+    }
+    (data, errors)
+}
+
+fn parse_data(path: &Path, raw_data: serde_json::Value) -> Result<Data, Error> {
+    match raw_data["resourceType"].as_str() {
+        Some("Attribute") => serde_json::from_value::<attribute::aidbox::Attribute>(raw_data)
+            .map(|mut attr| {
+                attr.filename = Some(path.to_owned());
+                Data::Attribute(Box::new(attr))
+            })
+            .map_err(|error| Error::BadAttribute {
+                filename: path.to_owned(),
+                source: error,
+            }),
+        Some("SearchParameter") => {
+            serde_json::from_value::<search_param::SearchParameter>(raw_data)
+                .map(Data::SearchParameter)
+                .map_err(|error| Error::BadSearchParameter {
+                    filename: path.to_owned(),
+                    source: error,
+                })
+        }
+        Some("CompartmentDefinition") => {
+            serde_json::from_value::<compartment::CompartmentDefinition>(raw_data)
+                .map(Data::CompartmentDefinition)
+                .map_err(|error| Error::BadCompartmentDefinition {
+                    filename: path.to_owned(),
+                    source: error,
+                })
+        }
+        Some(resource_type) => Err(Error::NotSupportedResourceType {
+            filename: path.to_path_buf(),
+            resource_type: (resource_type.to_owned()),
+        }),
+        None => Err(Error::MissingResourceType {
+            filename: path.to_owned(),
+        }),
+    }
+}
+
+fn is_package(path: &Path) -> bool {
+    path.file_name()
+        .map(|name| name.to_string_lossy())
+        .is_some_and(|name| name.ends_with(".tgz") || name.ends_with(".tar.gz"))
+}
+
+fn check_output_extension(output: &Path) -> Option<Error> {
+    if is_package(output) {
+        None
+    } else {
+        Some(Error::OutputExtension {
+            output: output.to_owned(),
+        })
+    }
+}
+
+/// Report the resources skipped while writing the output, returns whether any was skipped.
+///
+/// A resource missing from the output always fails the run.
+fn report_skipped(
+    errors: Vec<package::Error>,
+    args: &Args,
+    error_report: &mut Vec<report::Entry>,
+) -> bool {
+    let failed = !errors.is_empty();
+    for error in errors {
+        report(error, args, error_report);
+    }
+    failed
+}
+
+fn write_error_report(filename: &Path, entries: &[report::Entry]) -> Result<(), Error> {
+    let contents = serde_json::to_string_pretty(entries).unwrap();
+    std::fs::write(filename, contents).map_err(|error| Error::WriteErrorReport {
+        filename: filename.to_owned(),
+        source: error,
+    })
+}
+
+/// Output path for one of several target versions: `out.tgz` becomes `out-4.0.1.tgz`, paths
+/// without a known extension (e.g. directories) get the version appended.
+fn with_version_suffix(path: &Path, version: FhirVersion) -> PathBuf {
+    let name = path
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let (stem, extension) = [".tar.gz", ".tgz", ".ndjson", ".json"]
+        .iter()
+        .find_map(|extension| name.strip_suffix(extension).map(|stem| (stem, *extension)))
+        .unwrap_or((&name, ""));
+    path.with_file_name(format!("{stem}-{}{extension}", version.as_str()))
+}
+
+/// Exit code for failures to write the output, as opposed to 1 for errors in the input data
+const WRITE_FAILURE_EXIT_CODE: i32 = 3;
+
+/// Parse the command line and the config file, exits on invalid arguments
+fn parse_args() -> Args {
+    let (mut args, matches) = Args::parse_with_matches(std::env::args_os());
+
+    if let Some(config_file) = &args.config {
+        match config::Config::read(config_file) {
+            Ok(config) => args = args.merge(config, &matches),
             Err(error) => {
-                had_errors = true;
                 eprintln!("{:?}", miette::Report::new(error));
+                process::exit(1)
             }
         }
     }
 
-    let mut typed_attributes: Vec<attribute::typed::Attribute> = Vec::new();
+    if args.output.is_some() && args.output_dir.is_some() {
+        Args::command()
+            .error(
+                ErrorKind::ArgumentConflict,
+                "the argument '--output' cannot be used with '--output-dir'",
+            )
+            .exit()
+    }
+
+    if args.output_format == Some(OutputFormat::Fsh)
+        && (args.output.is_some() || args.ndjson_out.is_some())
+    {
+        Args::command()
+            .error(
+                ErrorKind::ArgumentConflict,
+                "'--output-format fsh' can only be used with '--output-dir' or stdout",
+            )
+            .exit()
+    }
 
-    for aidbox_attribute in aidbox_attributes {
-        if aidbox_attribute.resource.resource_type == "Entity"
-            && args.exclude.contains(&aidbox_attribute.resource.id)
-        {
-            continue;
-        } else if aidbox_attribute.resource.resource_type == "Entity"
-            && !resource_map::is_known_type(&aidbox_attribute.resource.id)
-        {
-            had_errors = true;
-            eprintln!(
-                "{:?}",
-                miette::Report::new(Error::NotAllowedTargetResource {
-                    resource_type: aidbox_attribute.resource.id.clone()
-                })
+    if let Some(date) = &args.date
+        && !is_fhir_date(date)
+    {
+        Args::command()
+            .error(
+                ErrorKind::InvalidValue,
+                format!("invalid date '{date}', expected YYYY, YYYY-MM or YYYY-MM-DD"),
             )
+            .exit()
+    }
+
+    for (url, discriminator) in &args.slicing_discriminator {
+        if let Err(message) = check_slicing_discriminator(discriminator) {
+            Args::command()
+                .error(
+                    ErrorKind::InvalidValue,
+                    format!("invalid slicing discriminator of {url}: {message}"),
+                )
+                .exit()
         }
+    }
 
-        let (typed_attribute, errors) = attribute::typed::Attribute::build_from(aidbox_attribute);
+    if args.path.is_empty() {
+        Args::command()
+            .error(
+                ErrorKind::MissingRequiredArgument,
+                "the path to Attribute files is required, either as an argument or in the config file",
+            )
+            .exit()
+    }
 
-        let errors = if args.ignore_flags {
-            errors
-                .into_iter()
-                .filter(|error| {
-                    !matches!(
-                        error.source,
-                        attribute::typed::InvalidAttributeError::SummaryPresent
-                            | attribute::typed::InvalidAttributeError::ModifierPresent
-                            | attribute::typed::InvalidAttributeError::OrderPresent
-                    )
-                })
-                .collect()
+    if args.fhir_version.is_empty()
+        && !(args.assume_version_from_package && args.path.iter().any(|path| is_package(path)))
+    {
+        Args::command()
+            .error(
+                ErrorKind::MissingRequiredArgument,
+                "the argument '--fhir-version' is required, either as an argument or in the config file",
+            )
+            .exit()
+    }
+
+    args
+}
+
+/// Name, version and dependencies of the generated package, exits if they are invalid
+fn make_package_info(args: &Args) -> package::PackageInfo {
+    let dependencies = package::collect_dependencies(&args.dependency).unwrap_or_else(|error| {
+        eprintln!("{:?}", miette::Report::new(error));
+        process::exit(1)
+    });
+    let package_info = package::PackageInfo {
+        name: args
+            .package_name
+            .clone()
+            .unwrap_or_else(|| package::PACKAGE_NAME.to_owned()),
+        version: args
+            .package_version
+            .clone()
+            .unwrap_or_else(|| package::PACKAGE_VERSION.to_owned()),
+        dependencies,
+    };
+    if let Err(error) = package::check_version(&package_info.version) {
+        eprintln!("{:?}", miette::Report::new(error));
+        process::exit(1)
+    }
+    package_info
+}
+
+/// Server of --push, exits if the url is invalid or the token may not be sent to it
+fn parse_push_server(args: &Args) -> Option<push::Server> {
+    args.push.as_deref().map(|url| {
+        push::Server::parse(url)
+            .and_then(|server| {
+                if args.token.is_some() {
+                    server.check_token(args.allow_insecure_token)?;
+                }
+                Ok(server)
+            })
+            .unwrap_or_else(|error| {
+                eprintln!("{:?}", miette::Report::new(error));
+                process::exit(1)
+            })
+    })
+}
+
+/// Read all input paths, returns the version of the first input package declaring one
+fn read_inputs(args: &Args) -> (Vec<Data>, Vec<Error>, Option<FhirVersion>) {
+    let mut package_fhir_version: Option<FhirVersion> = None;
+    let mut data: Vec<Data> = Vec::new();
+    let mut errors: Vec<Error> = Vec::new();
+    for path in &args.path {
+        let (mut path_data, mut path_errors) = if path == Path::new("-") {
+            read_stdin()
+        } else if is_package(path) {
+            let (data, errors, version) = read_package(path);
+            package_fhir_version = package_fhir_version.or(version);
+            (data, errors)
         } else {
-            errors
+            let jobs = args.jobs.unwrap_or_else(|| {
+                std::thread::available_parallelism().map_or(1, std::num::NonZeroUsize::get)
+            });
+            read_directory(path, jobs)
         };
+        data.append(&mut path_data);
+        errors.append(&mut path_errors);
+    }
+    (data, errors, package_fhir_version)
+}
+
+/// Versions to convert to: the given ones without repetitions, or the version of the input
+/// package. Exits if there is none, after printing the errors of the input.
+fn target_versions(
+    args: &Args,
+    package_fhir_version: Option<FhirVersion>,
+    errors: &mut Vec<Error>,
+) -> Vec<FhirVersion> {
+    let mut fhir_versions: Vec<FhirVersion> = Vec::new();
+    for version in &args.fhir_version {
+        if !fhir_versions.contains(version) {
+            fhir_versions.push(*version);
+        }
+    }
+    if fhir_versions.is_empty() {
+        fhir_versions.extend(package_fhir_version);
+    }
+
+    if fhir_versions.is_empty() {
+        if !args.quiet {
+            for error in errors.drain(..) {
+                eprintln!("{:?}", miette::Report::new(error));
+            }
+        }
+        Args::command()
+            .error(
+                ErrorKind::MissingRequiredArgument,
+                "the package does not declare a FHIR version through its core dependency, the argument '--fhir-version' is required",
+            )
+            .exit()
+    }
+    fhir_versions
+}
 
-        if !errors.is_empty() {
-            had_errors = true;
+fn make_input(data: Vec<Data>) -> ConversionInput {
+    let mut input = ConversionInput::default();
+    for data in data {
+        match data {
+            Data::Attribute(data) => {
+                input.attributes.push(*data);
+            }
+            Data::SearchParameter(data) => {
+                input.search_params.push(data);
+            }
+            Data::CompartmentDefinition(data) => {
+                input.compartments.push(data);
+            }
         }
+    }
+    input
+}
 
-        for error in errors {
-            eprintln!("{:?}", miette::Report::new(error))
+fn make_convert_options(
+    args: &Args,
+    package_info: &package::PackageInfo,
+    attributes: &[attribute::aidbox::Attribute],
+) -> ConvertOptions {
+    ConvertOptions {
+        include: args.include.clone(),
+        exclude: args.exclude.clone(),
+        ignore_flags: args.ignore_flags,
+        emit_modifier: args.emit_modifier,
+        emit_summary: args.emit_summary,
+        respect_order: args.respect_order,
+        strict: args.strict,
+        merge_duplicates: args.merge_duplicates,
+        keep_going: args.keep_going,
+        allow_recursive: args.allow_recursive,
+        trim_empty_inferred: args.trim_empty_inferred,
+        prefer_local_profiles: args.prefer_local_profiles,
+        snapshot: args.snapshot && !args.count,
+        check_urls: args.check_urls_resolvable,
+        language: args.language.clone(),
+        resource_version: Some(
+            args.resource_version
+                .clone()
+                .unwrap_or_else(|| package_info.version.clone()),
+        ),
+        status: args.status.unwrap_or_default(),
+        publisher: args.publisher.clone(),
+        date: Some(args.date.clone().unwrap_or_else(today)),
+        use_context: args
+            .use_context
+            .iter()
+            .map(|(r#type, value)| trie::fhir::UsageContext::new(r#type, value))
+            .collect(),
+        emit: trie::fhir::EmitOptions {
+            fce_marker: !args.no_fce_marker,
+            typed_value_element: args.typed_value_element,
+            min_constraints: args.min_constraints.unwrap_or(1),
+            extension_bases: args.extension_base.iter().cloned().collect(),
+            slicing_discriminators: args.slicing_discriminator.iter().cloned().collect(),
+            source_order: args
+                .preserve_source_order
+                .then(|| trie::fhir::source_order(attributes)),
+            extension_order: args.sort_extensions,
+            // Filled from the attributes during conversion
+            modifiers: Default::default(),
+            summaries: Default::default(),
+            must_support: args.must_support.iter().cloned().collect(),
+            max_extension_elements: args
+                .max_extension_elements
+                .unwrap_or(trie::fhir::DEFAULT_MAX_EXTENSION_ELEMENTS),
+            root_element: !args.no_root_element,
+            merge_contexts: !args.per_context_extensions,
+            binding_strength: args.binding_strength.unwrap_or_default(),
+            default_description: args.default_description.clone(),
+        },
+    }
+}
+
+/// Add a diagnostic to the error report and print it unless --quiet
+fn report(
+    diagnostic: impl Diagnostic + report::Subject + Send + Sync + 'static,
+    args: &Args,
+    error_report: &mut Vec<report::Entry>,
+) {
+    error_report.push(report::Entry::new(&diagnostic));
+    if !args.quiet {
+        eprintln!("{:?}", miette::Report::new(diagnostic));
+    }
+}
+
+/// Where the output of every target FHIR version goes
+struct Outputs<'a> {
+    args: &'a Args,
+    package_info: &'a package::PackageInfo,
+    push_server: Option<&'a push::Server>,
+    several_versions: bool,
+}
+
+impl Outputs<'_> {
+    /// Prefix of the summary lines of a version, only needed if there are several
+    fn summary_prefix(&self, fhir_version: FhirVersion) -> String {
+        if self.several_versions {
+            format!("{}: ", fhir_version.as_str())
+        } else {
+            String::new()
         }
+    }
 
-        let Some(typed_attribute) = typed_attribute else {
-            continue;
-        };
+    fn path(&self, path: &Path, fhir_version: FhirVersion) -> PathBuf {
+        if self.several_versions {
+            with_version_suffix(path, fhir_version)
+        } else {
+            path.to_owned()
+        }
+    }
+}
 
-        typed_attributes.push(typed_attribute);
+/// Print the diagnostics of one version's conversion and add them to the report and summary
+fn report_conversion(
+    result: &ConversionResult,
+    fhir_version: FhirVersion,
+    outputs: &Outputs,
+    summary: &mut Summary,
+    error_report: &mut Vec<report::Entry>,
+) {
+    let args = outputs.args;
+    if !args.quiet {
+        for error in &result.errors {
+            eprintln!("{:?}", error);
+        }
     }
+    error_report.extend(result.report.iter().cloned());
+    summary.emitted.profiles += result.profiles.len();
+    summary.emitted.extensions += result.extensions.len();
+    summary.emitted.search_params += result.search_params.len();
 
-    let (raw_forest, errors) = trie::raw::Forest::build_from_attributes(&typed_attributes);
-    if !errors.is_empty() {
-        had_errors = true;
+    if let Some(report) = args.report_unused_builtins
+        && !args.quiet
+    {
+        eprintln!(
+            "{}Unused builtin attributes: {}",
+            outputs.summary_prefix(fhir_version),
+            result.unused_builtin_attributes.len()
+        );
+        if let UnusedBuiltinsReport::List = report {
+            for attribute in &result.unused_builtin_attributes {
+                eprintln!("  {attribute}");
+            }
+        }
     }
-    for error in errors {
-        eprintln!("{}", error);
+}
+
+/// What happened to the output of one version
+struct Written {
+    /// Resources were left out of the output
+    skipped: bool,
+    push_failed: bool,
+}
+
+/// Write the resources of one version to the requested outputs, or to stdout if none is.
+///
+/// All outputs are attempted before the first write error is returned.
+fn write_outputs(
+    result: &ConversionResult,
+    fhir_version: FhirVersion,
+    outputs: &Outputs,
+    error_report: &mut Vec<report::Entry>,
+) -> anyhow::Result<Written> {
+    let args = outputs.args;
+    let mut written: Vec<anyhow::Result<()>> = Vec::new();
+
+    // The same entries go to every output, so skipped resources are reported once
+    let writes_entries = args.output.is_some()
+        || args.output_dir.is_some()
+        || args.ndjson_out.is_some()
+        || outputs.push_server.is_some();
+    let (entries, skipped) = if writes_entries {
+        let (entries, skipped) = package::make_entries(result);
+        (entries, report_skipped(skipped, args, error_report))
+    } else {
+        (Vec::new(), false)
+    };
+
+    if let Some(out_file) = &args.output {
+        written.push(package::make_package(
+            &outputs.path(out_file, fhir_version),
+            &entries,
+            fhir_version,
+            outputs.package_info,
+            args.tar_prefix
+                .as_deref()
+                .unwrap_or(package::DEFAULT_TAR_PREFIX),
+        ));
     }
 
-    let path_forest = trie::path::Forest::build_from(raw_forest);
-    let (extension_separated_forest, errors) =
-        trie::extension_separated::Forest::build_from(path_forest);
+    if let Some(out_dir) = &args.output_dir {
+        let out_dir = outputs.path(out_dir, fhir_version);
+        written.push(match args.output_format {
+            Some(OutputFormat::Fsh) => package::make_fsh_directory(&out_dir, &entries, result),
+            _ => package::make_directory(&out_dir, &entries, fhir_version, outputs.package_info),
+        });
+    }
 
-    if !errors.is_empty() {
-        had_errors = true;
+    if let Some(ndjson_file) = &args.ndjson_out {
+        written.push(package::make_ndjson(
+            &outputs.path(ndjson_file, fhir_version),
+            &entries,
+        ));
     }
-    for error in errors {
-        eprintln!("{:?}", miette::Report::new(error))
+
+    let push_failed = outputs.push_server.is_some_and(|server| {
+        push_outputs(
+            server,
+            &entries,
+            args,
+            &outputs.summary_prefix(fhir_version),
+        )
+    });
+
+    if written.is_empty() && outputs.push_server.is_none() {
+        print_resources(result, args.output_format);
     }
 
-    let (inverted_forest, errors) = trie::inverted::Forest::build_from(extension_separated_forest);
-    if !errors.is_empty() {
-        had_errors = true;
+    for written in written {
+        written?;
     }
-    for error in errors {
-        eprintln!("{}", error);
+    Ok(Written {
+        skipped,
+        push_failed,
+    })
+}
+
+/// Push the entries to the server and print the outcomes, returns whether any upload failed
+fn push_outputs(
+    server: &push::Server,
+    entries: &[package::Entry],
+    args: &Args,
+    summary_prefix: &str,
+) -> bool {
+    let outcomes = push::push(server, args.token.as_deref(), entries);
+    let failed = outcomes
+        .iter()
+        .filter(|outcome| !outcome.is_success())
+        .count();
+    if !args.quiet {
+        for outcome in &outcomes {
+            match (&outcome.status, &outcome.details) {
+                (Ok(code), Some(details)) => eprintln!("PUT {}: {code} {details}", outcome.path),
+                (Ok(code), None) => eprintln!("PUT {}: {code}", outcome.path),
+                (Err(reason), _) => eprintln!("PUT {}: {reason}", outcome.path),
+            }
+        }
+        eprintln!(
+            "{summary_prefix}Pushed: {}; Failed: {failed}",
+            outcomes.len() - failed
+        );
     }
+    failed > 0
+}
 
-    let profiles = trie::fhir::make_profiles(&inverted_forest);
+fn print_resources(result: &ConversionResult, output_format: Option<OutputFormat>) {
+    for sd in result.extensions.iter().chain(&result.profiles) {
+        match output_format {
+            Some(OutputFormat::Fsh) => println!("{}", fsh::render(sd)),
+            _ => println!("{}", serde_json::to_string_pretty(&sd).unwrap()),
+        }
+    }
+    for sp in &result.search_params {
+        println!("{}", serde_json::to_string_pretty(&sp).unwrap());
+    }
+    for cd in &result.compartments {
+        println!("{}", serde_json::to_string_pretty(&cd).unwrap());
+    }
+    for vs in &result.value_sets {
+        println!("{}", serde_json::to_string_pretty(&vs).unwrap());
+    }
+    for cs in &result.code_systems {
+        println!("{}", serde_json::to_string_pretty(&cs).unwrap());
+    }
+    for cm in &result.concept_maps {
+        println!("{}", serde_json::to_string_pretty(&cm).unwrap());
+    }
+}
 
-    let (exts, errors) = trie::fhir::collect_extensions(inverted_forest);
+/// Outcome of the run deciding its exit status
+#[derive(Debug, Default)]
+struct Status {
+    had_errors: bool,
+    had_structural_errors: bool,
+    push_failed: bool,
+}
 
-    if !errors.is_empty() {
-        had_errors = true;
+impl Status {
+    fn merge(&mut self, other: Status) {
+        self.had_errors |= other.had_errors;
+        self.had_structural_errors |= other.had_structural_errors;
+        self.push_failed |= other.push_failed;
     }
-    for error in errors {
-        eprintln!("{}", error);
-    }
-
-    if !had_errors || args.ignore_errors {
-        if let Some(out_file) = args.output {
-            match make_package(
-                out_file,
-                &exts,
-                &profiles,
-                &fhir_search_params,
-                args.fhir_version,
-            ) {
-                Ok(_) => (),
-                Err(error) => {
-                    eprintln!("{:?}", error);
-                    process::exit(1)
-                }
-            };
+}
+
+/// Convert the input to one version and write its output, exits if the output can not be
+/// written.
+///
+/// Errors of the input, `input_had_errors`, apply to every version, errors of the conversion
+/// only to its own output.
+fn convert_version(
+    input: &ConversionInput,
+    fhir_version: FhirVersion,
+    options: &ConvertOptions,
+    outputs: &Outputs,
+    input_had_errors: bool,
+    summary: &mut Summary,
+    error_report: &mut Vec<report::Entry>,
+) -> Status {
+    let args = outputs.args;
+    let result = fhir_schema_migration_tool::convert(input.clone(), fhir_version, options);
+    report_conversion(&result, fhir_version, outputs, summary, error_report);
+
+    if args.count || args.dry_run {
+        if outputs.several_versions {
+            println!("{}:", fhir_version.as_str());
+        }
+        return if args.count {
+            print_counts(&result.extensions, &result.profiles);
+            Status {
+                had_structural_errors: result.had_structural_errors,
+                ..Default::default()
+            }
         } else {
-            for ext in &exts {
-                println!("{}", serde_json::to_string_pretty(&ext).unwrap());
+            print_manifest(&result);
+            Status {
+                had_errors: result.had_errors,
+                ..Default::default()
             }
-            for profile in &profiles {
-                println!("{}", serde_json::to_string_pretty(&profile).unwrap());
+        };
+    }
+
+    let mut status = Status {
+        had_errors: input_had_errors || result.had_errors,
+        ..Default::default()
+    };
+    if !status.had_errors || args.ignore_errors {
+        match write_outputs(&result, fhir_version, outputs, error_report) {
+            Ok(written) => {
+                status.had_errors |= written.skipped;
+                status.push_failed = written.push_failed;
             }
-            for sp in &fhir_search_params {
-                println!("{}", serde_json::to_string_pretty(&sp).unwrap());
+            Err(error) => {
+                eprintln!("{:?}", error);
+                if let Some(filename) = &args.error_report {
+                    _ = write_error_report(filename, error_report);
+                }
+                if status.had_errors {
+                    eprintln!(
+                        "Writing the output was attempted despite conversion errors because of --ignore-errors"
+                    );
+                }
+                process::exit(WRITE_FAILURE_EXIT_CODE)
             }
         }
     }
+    status
+}
 
-    println!(
-        "Extensions: {}; Profiles: {}; SearchParameters: {} generated",
-        exts.len(),
-        profiles.len(),
-        fhir_search_params.len()
-    );
+/// Exit code of the run, see the `after_help` of [`Args`]
+fn exit_code(status: &Status, args: &Args) -> i32 {
+    if status.push_failed {
+        return WRITE_FAILURE_EXIT_CODE;
+    }
+    if args.count {
+        return i32::from(status.had_errors || status.had_structural_errors);
+    }
+    if status.had_errors {
+        if args.ignore_errors && !args.dry_run {
+            eprintln!("Output was written despite conversion errors because of --ignore-errors");
+        }
+        return 1;
+    }
+    0
+}
+
+/// Print the summary of the run, counting the diagnostics of the report
+fn print_summary(mut summary: Summary, error_report: &[report::Entry], args: &Args) {
+    summary.errors = error_report
+        .iter()
+        .filter(|entry| entry.severity == "error")
+        .count();
+    summary.warnings = error_report.len() - summary.errors;
+    if args.summary_json {
+        eprintln!("{}", serde_json::to_string(&summary).unwrap());
+    } else if !args.quiet {
+        eprintln!("{summary}");
+    }
+}
+
+fn main() {
+    _ = miette::set_hook(Box::new(|_| {
+        Box::new(
+            miette::MietteHandlerOpts::new()
+                .break_words(true)
+                .width(120)
+                .with_cause_chain()
+                .build(),
+        )
+    }));
+
+    let args = parse_args();
+    let package_info = make_package_info(&args);
+    let push_server = parse_push_server(&args);
+
+    let mut had_errors = false;
+    let mut error_report: Vec<report::Entry> = Vec::new();
+
+    if let Some(warning) = args.output.as_deref().and_then(check_output_extension) {
+        had_errors |= args.strict;
+        report(warning, &args, &mut error_report);
+    }
+
+    let (data, mut errors, package_fhir_version) = read_inputs(&args);
+    let fhir_versions = target_versions(&args, package_fhir_version, &mut errors);
+    for error in errors {
+        had_errors = true;
+        report(error, &args, &mut error_report);
+    }
+
+    let input = make_input(data);
+    let options = make_convert_options(&args, &package_info, &input.attributes);
+    let mut summary = Summary {
+        read: ReadSummary {
+            attributes: input.attributes.len(),
+            search_params: input.search_params.len(),
+            compartments: input.compartments.len(),
+        },
+        ..Default::default()
+    };
+    let outputs = Outputs {
+        args: &args,
+        package_info: &package_info,
+        push_server: push_server.as_ref(),
+        several_versions: fhir_versions.len() > 1,
+    };
+
+    // The attributes are parsed once, typing and emission depend on the version
+    let mut status = Status {
+        had_errors,
+        ..Default::default()
+    };
+    for fhir_version in fhir_versions {
+        status.merge(convert_version(
+            &input,
+            fhir_version,
+            &options,
+            &outputs,
+            had_errors,
+            &mut summary,
+            &mut error_report,
+        ));
+    }
+
+    print_summary(summary, &error_report, &args);
+
+    if let Some(filename) = &args.error_report
+        && let Err(error) = write_error_report(filename, &error_report)
+    {
+        eprintln!("{:?}", miette::Report::new(error));
+        process::exit(WRITE_FAILURE_EXIT_CODE);
+    }
+
+    let code = exit_code(&status, &args);
+    if code != 0 {
+        process::exit(code);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use clap::Parser;
 
-    if had_errors {
-        process::exit(1);
+    use crate::{
+        Args, Status, WRITE_FAILURE_EXIT_CODE, check_slicing_discriminator, config::Config,
+        exit_code, parse_slicing_discriminator, report_skipped,
+    };
+    use fhir_schema_migration_tool::{
+        FhirVersion, package, trie::fhir::ElementSlicingDiscriminator,
+    };
+
+    #[test]
+    fn test_exit_code() {
+        let args = Args::parse_from(["fhir-schema-migration-tool", "attributes"]);
+        let count = Args::parse_from(["fhir-schema-migration-tool", "attributes", "--count"]);
+        let status = |had_errors, had_structural_errors, push_failed| Status {
+            had_errors,
+            had_structural_errors,
+            push_failed,
+        };
+
+        assert_eq!(exit_code(&status(false, false, false), &args), 0);
+        assert_eq!(exit_code(&status(true, false, false), &args), 1);
+        // Structural errors only fail --count
+        assert_eq!(exit_code(&status(false, true, false), &args), 0);
+        assert_eq!(exit_code(&status(false, true, false), &count), 1);
+        assert_eq!(
+            exit_code(&status(true, false, true), &args),
+            WRITE_FAILURE_EXIT_CODE
+        );
+    }
+
+    #[test]
+    fn test_report_skipped() {
+        let args = Args::parse_from(["fhir-schema-migration-tool", "attributes", "--quiet"]);
+        let mut error_report = Vec::new();
+
+        assert!(!report_skipped(Vec::new(), &args, &mut error_report));
+        assert!(error_report.is_empty());
+
+        let skipped = package::Error::FilenameCollision {
+            filename: "StructureDefinition-Extension-example.org-nickname.json".to_owned(),
+            url: "http://example.org/nickname".to_owned(),
+            existing: "http://example.org/nickname".to_owned(),
+        };
+        assert!(report_skipped(vec![skipped], &args, &mut error_report));
+        assert_eq!(error_report.len(), 1);
+        assert_eq!(error_report[0].severity, "error");
+    }
+
+    #[test]
+    fn test_merge_config() {
+        let config: Config = toml::from_str(
+            r#"
+            path = "attributes"
+            fhir_version = "4.0.1"
+            output = "config.tgz"
+            package_name = "example.config"
+            quiet = true
+            include = ["Patient"]
+            "#,
+        )
+        .unwrap();
+        let (args, matches) = Args::parse_with_matches([
+            "fhir-schema-migration-tool",
+            "--fhir-version",
+            "5.0.0",
+            "--output",
+            "cli.tgz",
+        ]);
+        let args = args.merge(config, &matches);
+
+        // Command line arguments take precedence, the rest comes from the file
+        assert_eq!(args.fhir_version, [FhirVersion::V5_0_0]);
+        assert_eq!(args.output, Some(PathBuf::from("cli.tgz")));
+        assert_eq!(args.path, [PathBuf::from("attributes")]);
+        assert_eq!(args.package_name.as_deref(), Some("example.config"));
+        assert!(args.quiet);
+        assert_eq!(args.include, ["Patient"]);
+
+        let config: Config = toml::from_str(r#"include = ["Patient"]"#).unwrap();
+        let (args, matches) =
+            Args::parse_with_matches(["fhir-schema-migration-tool", "--include", "Practitioner"]);
+        let args = args.merge(config, &matches);
+        assert_eq!(args.include, ["Practitioner"]);
+
+        // Flags set in the file are turned off on the command line
+        let config: Config = toml::from_str("strict = true\nsnapshot = true").unwrap();
+        let (args, matches) = Args::parse_with_matches([
+            "fhir-schema-migration-tool",
+            "attributes",
+            "--strict=false",
+        ]);
+        let args = args.merge(config, &matches);
+        assert!(!args.strict);
+        assert!(args.snapshot);
+    }
+
+    #[test]
+    fn test_config_slicing_discriminator() {
+        let config: Config = toml::from_str(
+            r#"
+            [slicing_discriminator]
+            "http://example.org/nested" = { type = "valu", path = "url" }
+            "#,
+        )
+        .unwrap();
+        let (args, matches) =
+            Args::parse_with_matches(["fhir-schema-migration-tool", "attributes"]);
+        let args = args.merge(config, &matches);
+        let (_, discriminator) = &args.slicing_discriminator[0];
+        assert!(check_slicing_discriminator(discriminator).is_err());
+
+        assert!(
+            check_slicing_discriminator(&ElementSlicingDiscriminator {
+                r#type: "value".to_owned(),
+                path: "url".to_owned(),
+            })
+            .is_ok()
+        );
+        assert!(parse_slicing_discriminator("http://example.org/nested=valu:url").is_err());
     }
 }
@@ -0,0 +1,713 @@
+use std::{
+    collections::BTreeMap,
+    fs::{self, File},
+    io::{BufWriter, Write},
+    path::Path,
+};
+
+use flate2::{Compression, write::GzEncoder};
+use miette::Diagnostic;
+use serde::Serialize;
+use serde_json::{Value, json};
+use thiserror::Error;
+
+use crate::{
+    ConversionResult, FhirVersion, fsh,
+    report::Subject,
+    trie::fhir::{StructureDefinition, slugify, stable_hash},
+};
+
+#[derive(Debug, Error, Diagnostic)]
+pub enum Error {
+    #[error("Could not serialize {name}, it is not included in the output")]
+    #[diagnostic(code(package::serialize))]
+    Serialize {
+        name: String,
+        #[source]
+        source: serde_json::Error,
+    },
+
+    #[error(
+        "File name {filename} of {url} is already used by {existing}, it is not included in the output"
+    )]
+    #[diagnostic(
+        code(package::filename_collision),
+        help("File names are derived from resource urls. Give every resource its own url.")
+    )]
+    FilenameCollision {
+        filename: String,
+        url: String,
+        existing: String,
+    },
+
+    #[error("Package version {version} is not a semantic version")]
+    #[diagnostic(
+        code(package::invalid_version),
+        help("Package versions are written as MAJOR.MINOR.PATCH, e.g. 1.0.0 or 1.0.0-beta.1")
+    )]
+    InvalidVersion { version: String },
+
+    #[error("Package dependency {name} is already declared")]
+    #[diagnostic(
+        code(package::duplicate_dependency),
+        help(
+            "Every dependency is given once. The core FHIR package of the target version is always declared."
+        )
+    )]
+    DuplicateDependency { name: String },
+}
+
+impl Subject for Error {}
+
+/// Serialized resource together with the metadata needed to place it into the output
+#[derive(Debug, Clone)]
+pub struct Entry {
+    /// File name relative to the package directory
+    pub filename: String,
+    pub resource_type: String,
+    pub id: Option<String>,
+    pub url: Option<String>,
+    pub resource: Value,
+}
+
+/// Default name of the generated package
+pub const PACKAGE_NAME: &str = "legacy-fce.aidbox";
+
+/// Default version of the generated package
+pub const PACKAGE_VERSION: &str = "0.0.0";
+
+/// Core FHIR packages, one of them is the dependency of every generated package
+const CORE_PACKAGES: [&str; 3] = ["hl7.fhir.r4.core", "hl7.fhir.r4b.core", "hl7.fhir.r5.core"];
+
+/// Directory of the entries inside the package tarball, as in npm FHIR packages
+pub const DEFAULT_TAR_PREFIX: &str = "package";
+
+/// Name, version and dependencies of the generated package, written to `package.json`
+#[derive(Debug, Clone)]
+pub struct PackageInfo {
+    pub name: String,
+    pub version: String,
+    /// Package name to version, declared in addition to the core FHIR package
+    pub dependencies: BTreeMap<String, String>,
+}
+
+impl Default for PackageInfo {
+    fn default() -> Self {
+        Self {
+            name: PACKAGE_NAME.to_owned(),
+            version: PACKAGE_VERSION.to_owned(),
+            dependencies: BTreeMap::new(),
+        }
+    }
+}
+
+/// Additional dependencies of the package from `(name, version)` pairs. A name given twice, or
+/// the name of a core FHIR package, is an error.
+pub fn collect_dependencies(
+    dependencies: &[(String, String)],
+) -> Result<BTreeMap<String, String>, Error> {
+    let mut result: BTreeMap<String, String> = BTreeMap::new();
+    for (name, version) in dependencies {
+        if CORE_PACKAGES.contains(&name.as_str())
+            || result.insert(name.clone(), version.clone()).is_some()
+        {
+            return Err(Error::DuplicateDependency { name: name.clone() });
+        }
+    }
+    Ok(result)
+}
+
+/// Check that a package version is `MAJOR.MINOR.PATCH`, optionally followed by `-prerelease`
+/// and `+build` identifiers, as package registries require
+pub fn check_version(version: &str) -> Result<(), Error> {
+    let identifiers_valid = |identifiers: &str| {
+        identifiers.split('.').all(|identifier| {
+            !identifier.is_empty()
+                && identifier
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-')
+        })
+    };
+
+    let (rest, build) = match version.split_once('+') {
+        Some((rest, build)) => (rest, Some(build)),
+        None => (version, None),
+    };
+    let (core, prerelease) = match rest.split_once('-') {
+        Some((core, prerelease)) => (core, Some(prerelease)),
+        None => (rest, None),
+    };
+
+    let numbers: Vec<&str> = core.split('.').collect();
+    let numbers_valid = numbers.len() == 3
+        && numbers.iter().all(|number| {
+            !number.is_empty()
+                && number.chars().all(|c| c.is_ascii_digit())
+                && (*number == "0" || !number.starts_with('0'))
+        });
+    if numbers_valid
+        && prerelease.is_none_or(identifiers_valid)
+        && build.is_none_or(identifiers_valid)
+    {
+        Ok(())
+    } else {
+        Err(Error::InvalidVersion {
+            version: version.to_owned(),
+        })
+    }
+}
+
+pub fn make_package_json(fhir_version: FhirVersion, info: &PackageInfo) -> String {
+    let version_string = fhir_version.as_str();
+
+    let pkg_name: &'static str = match fhir_version {
+        FhirVersion::V4_0_0 => "hl7.fhir.r4.core",
+        FhirVersion::V4_0_1 => "hl7.fhir.r4.core",
+        FhirVersion::V4_3_0 => "hl7.fhir.r4b.core",
+        FhirVersion::V5_0_0 => "hl7.fhir.r5.core",
+    };
+
+    let mut dependencies = serde_json::Map::new();
+    dependencies.insert(pkg_name.to_owned(), version_string.into());
+    for (name, version) in &info.dependencies {
+        dependencies.insert(name.clone(), version.as_str().into());
+    }
+
+    serde_json::to_string_pretty(&json!({
+        "name": info.name,
+        "version": info.version,
+        "type": "IG",
+        "dependencies": dependencies
+    }))
+    .unwrap()
+}
+
+/// FHIR version declared by a package manifest through its core package dependency, as written
+/// by [`make_package_json`], or through `fhirVersions`
+pub fn fhir_version_from_package_json(package_json: &Value) -> Option<FhirVersion> {
+    let core_version = CORE_PACKAGES
+        .into_iter()
+        .find_map(|name| package_json["dependencies"][name].as_str());
+    let version = core_version.or_else(|| package_json["fhirVersions"][0].as_str())?;
+    serde_json::from_value(json!(version)).ok()
+}
+
+/// Serialize a resource, its file name is assigned by [`make_entries`]
+fn make_entry<T: Serialize>(name: &str, resource: &T) -> Result<Entry, Error> {
+    let to_error = |error| Error::Serialize {
+        name: name.to_owned(),
+        source: error,
+    };
+    let value = serde_json::to_value(resource).map_err(to_error)?;
+    let field = |key: &str| value[key].as_str().map(|s| s.to_owned());
+
+    Ok(Entry {
+        filename: String::new(),
+        resource_type: field("resourceType").unwrap_or_default(),
+        id: field("id"),
+        url: field("url"),
+        resource: value,
+    })
+}
+
+/// File name component derived from the resource url, so that it is stable across runs
+fn url_slug(url: &str) -> String {
+    let url = url
+        .strip_prefix("https://")
+        .or_else(|| url.strip_prefix("http://"))
+        .unwrap_or(url);
+    slugify(url.trim_end_matches('/'))
+}
+
+/// Serialize generated resources and assign file names to them.
+///
+/// The same names are used for tar entries and for loose files. File names are derived from
+/// resource urls. Urls which differ only in punctuation make the same name, such names end with
+/// a hash of the url. Resources which could not be serialized, or which share the url of another
+/// resource, are returned as errors.
+pub fn make_entries(resources: &ConversionResult) -> (Vec<Entry>, Vec<Error>) {
+    // File name without `.json`, url and the serialized resource
+    let mut candidates: Vec<(String, &str, Result<Entry, Error>)> = Vec::new();
+
+    for ext in &resources.extensions {
+        let stem = format!("StructureDefinition-Extension-{}", url_slug(&ext.url));
+        candidates.push((stem, &ext.url, make_entry(&ext.name, ext)));
+    }
+
+    for profile in &resources.profiles {
+        let stem = format!("StructureDefinition-{}", url_slug(&profile.url));
+        candidates.push((stem, &profile.url, make_entry(&profile.name, profile)));
+    }
+
+    for sp in &resources.search_params {
+        let stem = format!("SearchParameter-{}", url_slug(&sp.url));
+        candidates.push((stem, &sp.url, make_entry(&sp.name, sp)));
+    }
+
+    for cd in &resources.compartments {
+        let stem = format!("CompartmentDefinition-{}", url_slug(&cd.url));
+        candidates.push((stem, &cd.url, make_entry(&cd.name, cd)));
+    }
+
+    for vs in &resources.value_sets {
+        let stem = format!("ValueSet-{}", url_slug(&vs.url));
+        candidates.push((stem, &vs.url, make_entry(&vs.name, vs)));
+    }
+
+    for cs in &resources.code_systems {
+        let stem = format!("CodeSystem-{}", url_slug(&cs.url));
+        candidates.push((stem, &cs.url, make_entry(&cs.name, cs)));
+    }
+
+    for cm in &resources.concept_maps {
+        let stem = format!("ConceptMap-{}", url_slug(&cm.url));
+        candidates.push((stem, &cm.url, make_entry(&cm.name, cm)));
+    }
+
+    let mut stem_counts: BTreeMap<&str, usize> = BTreeMap::new();
+    for (stem, _, _) in &candidates {
+        *stem_counts.entry(stem).or_default() += 1;
+    }
+    let filenames: Vec<String> = candidates
+        .iter()
+        .map(|(stem, url, _)| {
+            if stem_counts[stem.as_str()] > 1 {
+                format!("{stem}-{:08x}.json", stable_hash(url))
+            } else {
+                format!("{stem}.json")
+            }
+        })
+        .collect();
+
+    let mut entries: Vec<Entry> = Vec::new();
+    let mut errors: Vec<Error> = Vec::new();
+    let mut urls: BTreeMap<String, &str> = BTreeMap::new();
+    for ((_, url, entry), filename) in candidates.into_iter().zip(filenames) {
+        if let Some(existing) = urls.get(&filename) {
+            errors.push(Error::FilenameCollision {
+                filename,
+                url: url.to_owned(),
+                existing: existing.to_string(),
+            });
+            continue;
+        }
+        match entry {
+            Ok(entry) => {
+                urls.insert(filename.clone(), url);
+                entries.push(Entry { filename, ..entry });
+            }
+            Err(error) => errors.push(error),
+        }
+    }
+
+    (entries, errors)
+}
+
+/// Make `.index.json` as used by the IG publisher and FHIR package tooling
+pub fn make_index_json(entries: &[Entry]) -> String {
+    let files: Vec<Value> = entries
+        .iter()
+        .map(|entry| {
+            let mut file = json!({
+                "filename": entry.filename,
+                "resourceType": entry.resource_type,
+            });
+            if let Some(id) = &entry.id {
+                file["id"] = json!(id);
+            }
+            if let Some(url) = &entry.url {
+                file["url"] = json!(url);
+            }
+            file
+        })
+        .collect();
+
+    serde_json::to_string_pretty(&json!({
+        "index-version": 1,
+        "files": files,
+    }))
+    .unwrap()
+}
+
+/// Modification time of package entries.
+///
+/// Taken from `SOURCE_DATE_EPOCH` if set, so that packages are reproducible.
+fn package_mtime() -> u64 {
+    std::env::var("SOURCE_DATE_EPOCH")
+        .ok()
+        .and_then(|epoch| epoch.trim().parse().ok())
+        .unwrap_or(0)
+}
+
+fn write_to_archive<T: Write>(
+    archive: &mut tar::Builder<T>,
+    path: &Path,
+    payload: &[u8],
+) -> anyhow::Result<()> {
+    let mut header = tar::Header::new_gnu();
+    header.set_size(payload.len() as u64);
+    header.set_mode(0o644);
+    header.set_mtime(package_mtime());
+    header.set_cksum();
+    archive.append_data(&mut header, path, payload)?;
+    Ok(())
+}
+
+/// Write the entries made by [`make_entries`] into a gzipped FHIR package.
+///
+/// Entries, including `package.json`, are placed in the `prefix` directory of the tarball
+/// ([`DEFAULT_TAR_PREFIX`] for FHIR packages), or at its top level if the prefix is empty.
+pub fn make_package(
+    output: &Path,
+    entries: &[Entry],
+    fhir_version: FhirVersion,
+    info: &PackageInfo,
+    prefix: &str,
+) -> anyhow::Result<()> {
+    let file = File::create(output)?;
+    let gzip = GzEncoder::new(file, Compression::default());
+    let mut tar = tar::Builder::new(gzip);
+
+    {
+        let package_json = make_package_json(fhir_version, info);
+        write_to_archive(
+            &mut tar,
+            &Path::new(prefix).join("package.json"),
+            package_json.as_bytes(),
+        )?
+    }
+
+    for entry in entries {
+        let path = Path::new(prefix).join(&entry.filename);
+        let payload = serde_json::to_string_pretty(&entry.resource).unwrap();
+        write_to_archive(&mut tar, &path, payload.as_bytes())?
+    }
+
+    let gzip = tar.into_inner()?;
+    let _file = gzip.finish()?;
+
+    Ok(())
+}
+
+/// Write the entries as loose files into a directory, together with `package.json` and
+/// `.index.json`.
+///
+/// The directory is created if missing.
+pub fn make_directory(
+    output_dir: &Path,
+    entries: &[Entry],
+    fhir_version: FhirVersion,
+    info: &PackageInfo,
+) -> anyhow::Result<()> {
+    fs::create_dir_all(output_dir)?;
+
+    fs::write(
+        output_dir.join("package.json"),
+        make_package_json(fhir_version, info),
+    )?;
+
+    for entry in entries {
+        let payload = serde_json::to_string_pretty(&entry.resource).unwrap();
+        fs::write(output_dir.join(&entry.filename), payload)?;
+    }
+
+    fs::write(output_dir.join(".index.json"), make_index_json(entries))?;
+
+    Ok(())
+}
+
+/// Write the entries as loose files into a directory, with profiles and extensions of
+/// `resources` as FSH definitions (`.fsh`) for SUSHI instead of JSON, see [`fsh::render`].
+///
+/// Other resources are written as JSON, same as [`make_directory`]. There is no `package.json`,
+/// SUSHI makes the package from its own configuration.
+pub fn make_fsh_directory(
+    output_dir: &Path,
+    entries: &[Entry],
+    resources: &ConversionResult,
+) -> anyhow::Result<()> {
+    let definitions: BTreeMap<&str, &StructureDefinition> = resources
+        .extensions
+        .iter()
+        .chain(&resources.profiles)
+        .map(|sd| (sd.url.as_str(), sd))
+        .collect();
+
+    fs::create_dir_all(output_dir)?;
+
+    for entry in entries {
+        let definition = entry
+            .url
+            .as_deref()
+            .filter(|_| entry.resource_type == "StructureDefinition")
+            .and_then(|url| definitions.get(url));
+        match definition {
+            Some(sd) => {
+                let filename = Path::new(&entry.filename).with_extension("fsh");
+                fs::write(output_dir.join(filename), fsh::render(sd))?;
+            }
+            None => {
+                let payload = serde_json::to_string_pretty(&entry.resource).unwrap();
+                fs::write(output_dir.join(&entry.filename), payload)?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Write the entries into a single NDJSON file, one resource per line.
+pub fn make_ndjson(output: &Path, entries: &[Entry]) -> anyhow::Result<()> {
+    let mut file = BufWriter::new(File::create(output)?);
+    for entry in entries {
+        serde_json::to_writer(&mut file, &entry.resource)?;
+        file.write_all(b"\n")?;
+    }
+    file.flush()?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+
+    use serde_json::json;
+
+    use crate::{ConversionResult, FhirVersion, convert_attributes, package};
+
+    #[test]
+    fn test_make_entries_filenames() {
+        let attribute = |id: &str, url: &str| {
+            serde_json::from_value(json!({
+                "id": id,
+                "path": [id],
+                "resource": {"id": "Patient", "resourceType": "Entity"},
+                "type": {"id": "string", "resourceType": "Entity"},
+                "extensionUrl": url,
+            }))
+            .unwrap()
+        };
+        let result = convert_attributes(
+            vec![
+                attribute("nickname", "http://example.org/nick_name"),
+                attribute("alias", "http://example.org/nick-name"),
+                attribute("title", "https://example.org/title/"),
+            ],
+            Vec::new(),
+            FhirVersion::V4_0_1,
+        );
+
+        // Only the extensions, profile file names are not checked here. The title extension is
+        // given twice, as with a duplicate url.
+        let title = result
+            .extensions
+            .iter()
+            .find(|ext| ext.url == "https://example.org/title/")
+            .unwrap()
+            .clone();
+        let result = ConversionResult {
+            extensions: result.extensions.into_iter().chain([title]).collect(),
+            ..Default::default()
+        };
+        let (entries, errors) = package::make_entries(&result);
+        let filenames: BTreeSet<&str> = entries.iter().map(|e| e.filename.as_str()).collect();
+        assert_eq!(filenames.len(), 3, "{filenames:?}");
+        let with_prefix = |prefix: &str| -> Vec<&str> {
+            filenames
+                .iter()
+                .copied()
+                .filter(|filename| filename.starts_with(prefix))
+                .collect()
+        };
+
+        // Urls differing only in punctuation get names with a hash of the url
+        assert_eq!(
+            with_prefix("StructureDefinition-Extension-example.org-nick-name-").len(),
+            2
+        );
+
+        // The same url gives the same name, so the second title extension is not written
+        let title = with_prefix("StructureDefinition-Extension-example.org-title");
+        assert!(matches!(
+            errors.as_slice(),
+            [package::Error::FilenameCollision { filename, .. }] if title == [filename.as_str()]
+        ));
+    }
+
+    #[test]
+    fn test_make_package_is_reproducible() {
+        let attributes = vec![
+            serde_json::from_value(json!({
+                "id": "Patient.nickname",
+                "path": ["nickname"],
+                "resource": {"id": "Patient", "resourceType": "Entity"},
+                "type": {"id": "string", "resourceType": "Entity"},
+                "extensionUrl": "http://example.org/nickname",
+            }))
+            .unwrap(),
+            serde_json::from_value(json!({
+                "id": "Patient.sex",
+                "path": ["sex"],
+                "resource": {"id": "Patient", "resourceType": "Entity"},
+                "type": {"id": "code", "resourceType": "Entity"},
+                "enum": ["F", "M"],
+                "conceptMap": {
+                    "target": "http://hl7.org/fhir/administrative-gender",
+                    "element": {"F": "female", "M": "male"},
+                },
+            }))
+            .unwrap(),
+        ];
+        let search_param = serde_json::from_value(json!({
+            "id": "Patient.nickname",
+            "name": "nickname",
+            "type": "string",
+            "resource": {"id": "Patient", "resourceType": "Entity"},
+            "expression": [["nickname"]],
+        }))
+        .unwrap();
+        let result = convert_attributes(attributes, vec![search_param], FhirVersion::V4_0_1);
+        assert!(!result.had_errors, "{:?}", result.errors);
+        let (entries, errors) = package::make_entries(&result);
+        assert!(errors.is_empty(), "{errors:?}");
+        assert!(entries.len() >= 6, "{entries:?}");
+
+        let dir = std::env::temp_dir();
+        let first = dir.join(format!("reproducible-{}-1.tgz", std::process::id()));
+        let second = dir.join(format!("reproducible-{}-2.tgz", std::process::id()));
+        for output in [&first, &second] {
+            package::make_package(
+                output,
+                &entries,
+                FhirVersion::V4_0_1,
+                &Default::default(),
+                package::DEFAULT_TAR_PREFIX,
+            )
+            .unwrap();
+        }
+
+        let first_bytes = std::fs::read(&first).unwrap();
+        let second_bytes = std::fs::read(&second).unwrap();
+        let mut archive = tar::Archive::new(flate2::read::GzDecoder::new(&first_bytes[..]));
+        // Entries are stamped with SOURCE_DATE_EPOCH (or 0), never with the current time
+        for entry in archive.entries().unwrap() {
+            let header = entry.unwrap().header().clone();
+            assert_eq!(header.mtime().unwrap(), package::package_mtime());
+        }
+        _ = std::fs::remove_file(&first);
+        _ = std::fs::remove_file(&second);
+        assert_eq!(first_bytes, second_bytes);
+    }
+
+    #[test]
+    fn test_make_package_tar_prefix() {
+        let attribute = serde_json::from_value(json!({
+            "id": "nickname",
+            "path": ["nickname"],
+            "resource": {"id": "Patient", "resourceType": "Entity"},
+            "type": {"id": "string", "resourceType": "Entity"},
+            "extensionUrl": "http://example.org/nickname",
+        }))
+        .unwrap();
+        let result = convert_attributes(vec![attribute], Vec::new(), FhirVersion::V4_0_1);
+        let (entries, _) = package::make_entries(&result);
+
+        let entry_names = |prefix: &str| {
+            let output = std::env::temp_dir().join(format!(
+                "tar-prefix-{}-{}.tgz",
+                std::process::id(),
+                prefix.replace('/', "-")
+            ));
+            package::make_package(
+                &output,
+                &entries,
+                FhirVersion::V4_0_1,
+                &Default::default(),
+                prefix,
+            )
+            .unwrap();
+            let file = std::fs::File::open(&output).unwrap();
+            let mut archive = tar::Archive::new(flate2::read::GzDecoder::new(file));
+            let names: Vec<String> = archive
+                .entries()
+                .unwrap()
+                .map(|entry| entry.unwrap().path().unwrap().display().to_string())
+                .collect();
+            _ = std::fs::remove_file(&output);
+            names
+        };
+
+        let names = entry_names("fce/1.0.0");
+        assert_eq!(names.len(), 3);
+        assert_eq!(names[0], "fce/1.0.0/package.json");
+        assert!(names.iter().all(|name| name.starts_with("fce/1.0.0/")));
+
+        let names = entry_names("");
+        assert_eq!(names[0], "package.json");
+        assert!(names.iter().all(|name| !name.contains('/')));
+    }
+
+    #[test]
+    fn test_check_version() {
+        for version in [
+            "0.0.0",
+            "1.2.3",
+            "10.0.1-beta.1",
+            "1.0.0+20240101",
+            "1.0.0-rc-1+b.2",
+        ] {
+            assert!(package::check_version(version).is_ok(), "{version}");
+        }
+        for version in [
+            "1.0",
+            "1.0.0.0",
+            "v1.0.0",
+            "01.0.0",
+            "1.0.0-",
+            "1.0.0-beta..1",
+            "",
+        ] {
+            assert!(
+                matches!(
+                    package::check_version(version),
+                    Err(package::Error::InvalidVersion { .. })
+                ),
+                "{version}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_package_json_dependencies() {
+        let dependencies = [
+            ("hl7.fhir.us.core".to_owned(), "6.1.0".to_owned()),
+            ("example.internal".to_owned(), "1.0.0".to_owned()),
+        ];
+        let info = package::PackageInfo {
+            dependencies: package::collect_dependencies(&dependencies).unwrap(),
+            ..Default::default()
+        };
+        let package_json: serde_json::Value =
+            serde_json::from_str(&package::make_package_json(FhirVersion::V4_0_1, &info)).unwrap();
+        assert_eq!(
+            package_json["dependencies"],
+            json!({
+                "hl7.fhir.r4.core": "4.0.1",
+                "example.internal": "1.0.0",
+                "hl7.fhir.us.core": "6.1.0",
+            })
+        );
+
+        for name in ["hl7.fhir.us.core", "hl7.fhir.r4.core"] {
+            let dependencies = [
+                dependencies[0].clone(),
+                (name.to_owned(), "7.0.0".to_owned()),
+            ];
+            assert!(matches!(
+                package::collect_dependencies(&dependencies),
+                Err(package::Error::DuplicateDependency { name: duplicate }) if duplicate == name
+            ));
+        }
+    }
+}
@@ -0,0 +1,514 @@
+//! Upload generated StructureDefinitions and SearchParameters to a FHIR server.
+//!
+//! Every resource is sent with `PUT <base>/<type>/<id>`, so pushing the same output twice
+//! updates the resources instead of duplicating them. The id is the resource id, or derived from
+//! the last segment of its url. Only plain `http://` servers are supported: the tool has no TLS
+//! implementation, use a local proxy for `https://` servers. As the token travels in cleartext, it
+//! is only sent to loopback hosts unless explicitly allowed. The server's diagnostics of rejected
+//! resources are taken from the OperationOutcome in the response.
+
+use std::{
+    io::{BufRead, BufReader, Read, Write},
+    net::{IpAddr, TcpStream},
+    time::Duration,
+};
+
+use miette::Diagnostic;
+use thiserror::Error;
+
+use crate::{package, trie::fhir::slugify};
+
+const PUSHED_RESOURCE_TYPES: [&str; 2] = ["StructureDefinition", "SearchParameter"];
+
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Error, Diagnostic)]
+pub enum Error {
+    #[error("Can not push to {url}, only http:// base urls are supported")]
+    #[diagnostic(
+        code(push::unsupported_scheme),
+        help(
+            "Resources are uploaded without TLS. Push to a local proxy forwarding to the https:// server."
+        )
+    )]
+    UnsupportedScheme { url: String },
+
+    #[error("Invalid base url {url}")]
+    #[diagnostic(
+        code(push::invalid_url),
+        help(
+            "The base url is written as http://host[:port][/path], e.g. http://localhost:8080/fhir. IPv6 hosts are written in brackets, e.g. http://[::1]:8080/fhir"
+        )
+    )]
+    InvalidUrl { url: String },
+
+    #[error("Refusing to send the token to {host} in cleartext")]
+    #[diagnostic(
+        code(push::insecure_token),
+        help(
+            "Without TLS anyone on the network can read the token. Push through a local proxy forwarding to an https:// server, or pass --allow-insecure-token."
+        )
+    )]
+    InsecureToken { host: String },
+}
+
+/// FHIR server the resources are pushed to
+#[derive(Debug, Clone)]
+pub struct Server {
+    /// Host name or address, without the brackets of IPv6 addresses
+    host: String,
+    /// `host:port`, used to connect and as the Host header
+    authority: String,
+    /// Path of the FHIR base without the trailing slash, e.g. `/fhir`
+    base_path: String,
+}
+
+impl Server {
+    pub fn parse(url: &str) -> Result<Self, Error> {
+        let Some(rest) = url.strip_prefix("http://") else {
+            return Err(if url.contains("://") {
+                Error::UnsupportedScheme {
+                    url: url.to_owned(),
+                }
+            } else {
+                Error::InvalidUrl {
+                    url: url.to_owned(),
+                }
+            });
+        };
+        let (authority, path) = rest.split_at(rest.find('/').unwrap_or(rest.len()));
+        // The port follows the host, which is bracketed if it is an IPv6 address
+        let (host, port) = match authority.strip_prefix('[') {
+            Some(host) => match host.split_once(']') {
+                Some((host, port))
+                    if !host.is_empty() && (port.is_empty() || port.starts_with(':')) =>
+                {
+                    (host, port.strip_prefix(':'))
+                }
+                _ => {
+                    return Err(Error::InvalidUrl {
+                        url: url.to_owned(),
+                    });
+                }
+            },
+            None => match authority.split_once(':') {
+                Some((host, port)) => (host, Some(port)),
+                None => (authority, None),
+            },
+        };
+        if authority.is_empty() || authority.starts_with(':') {
+            return Err(Error::InvalidUrl {
+                url: url.to_owned(),
+            });
+        }
+        let authority = if port.is_some() {
+            authority.to_owned()
+        } else {
+            format!("{authority}:80")
+        };
+        Ok(Self {
+            host: host.to_owned(),
+            authority,
+            base_path: path.trim_end_matches('/').to_owned(),
+        })
+    }
+
+    /// Whether requests stay on this machine: `localhost`, 127.0.0.0/8 or `::1`
+    pub fn is_loopback(&self) -> bool {
+        self.host.eq_ignore_ascii_case("localhost")
+            || self
+                .host
+                .parse::<IpAddr>()
+                .is_ok_and(|address| address.is_loopback())
+    }
+
+    /// Check that a token may be sent to the server, which is only allowed for loopback hosts
+    /// unless `allow_insecure` is set
+    pub fn check_token(&self, allow_insecure: bool) -> Result<(), Error> {
+        if allow_insecure || self.is_loopback() {
+            Ok(())
+        } else {
+            Err(Error::InsecureToken {
+                host: self.host.clone(),
+            })
+        }
+    }
+
+    /// Send one resource, returning the HTTP status code and the response body
+    fn put(&self, path: &str, body: &[u8], token: Option<&str>) -> Result<(u16, String), String> {
+        let mut stream = TcpStream::connect(&self.authority).map_err(|error| error.to_string())?;
+        stream
+            .set_read_timeout(Some(TIMEOUT))
+            .and_then(|_| stream.set_write_timeout(Some(TIMEOUT)))
+            .map_err(|error| error.to_string())?;
+
+        let mut request = format!(
+            "PUT {}{path} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/fhir+json\r\nAccept: application/fhir+json\r\nContent-Length: {}\r\nConnection: close\r\n",
+            self.base_path,
+            self.authority,
+            body.len()
+        );
+        if let Some(token) = token {
+            request.push_str(&format!("Authorization: Bearer {token}\r\n"));
+        }
+        request.push_str("\r\n");
+        stream
+            .write_all(request.as_bytes())
+            .and_then(|_| stream.write_all(body))
+            .map_err(|error| error.to_string())?;
+
+        let mut reader = BufReader::new(stream);
+        let mut status_line = String::new();
+        reader
+            .read_line(&mut status_line)
+            .map_err(|error| error.to_string())?;
+        let code = status_line
+            .split_whitespace()
+            .nth(1)
+            .and_then(|code| code.parse().ok())
+            .ok_or_else(|| format!("invalid response {:?}", status_line.trim_end()))?;
+
+        let mut content_length: Option<usize> = None;
+        let mut chunked = false;
+        loop {
+            let mut line = String::new();
+            if reader
+                .read_line(&mut line)
+                .map_err(|error| error.to_string())?
+                == 0
+                || line == "\r\n"
+            {
+                break;
+            }
+            let Some((name, value)) = line.split_once(':') else {
+                continue;
+            };
+            let value = value.trim();
+            if name.eq_ignore_ascii_case("Content-Length") {
+                content_length = value.parse().ok();
+            } else if name.eq_ignore_ascii_case("Transfer-Encoding") {
+                chunked = value.to_ascii_lowercase().ends_with("chunked");
+            }
+        }
+
+        let body = if code == 204 || code == 304 {
+            Vec::new()
+        } else if chunked {
+            read_chunked(&mut reader).map_err(|error| error.to_string())?
+        } else if let Some(length) = content_length {
+            let mut body = vec![0; length];
+            reader
+                .read_exact(&mut body)
+                .map_err(|error| error.to_string())?;
+            body
+        } else {
+            // Without a length the body ends with the connection
+            let mut body = Vec::new();
+            reader
+                .read_to_end(&mut body)
+                .map_err(|error| error.to_string())?;
+            body
+        };
+        Ok((code, String::from_utf8_lossy(&body).into_owned()))
+    }
+}
+
+/// Body sent with `Transfer-Encoding: chunked`: chunks prefixed with their hexadecimal size, up
+/// to a chunk of size zero and the trailer
+fn read_chunked(reader: &mut impl BufRead) -> std::io::Result<Vec<u8>> {
+    let invalid = || std::io::Error::new(std::io::ErrorKind::InvalidData, "invalid chunk size");
+    let mut body = Vec::new();
+    loop {
+        let mut line = String::new();
+        reader.read_line(&mut line)?;
+        // Chunk extensions follow the size after a semicolon
+        let size = line.split(';').next().unwrap_or_default().trim();
+        let size = usize::from_str_radix(size, 16).map_err(|_| invalid())?;
+        if size == 0 {
+            break;
+        }
+        let start = body.len();
+        body.resize(start + size, 0);
+        reader.read_exact(&mut body[start..])?;
+        let mut crlf = [0; 2];
+        reader.read_exact(&mut crlf)?;
+    }
+    // Trailer fields up to the empty line
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 || line == "\r\n" {
+            break;
+        }
+    }
+    Ok(body)
+}
+
+/// Diagnostics of the OperationOutcome in a response body, or the body itself if it is not one
+fn response_details(body: &str) -> Option<String> {
+    let body = body.trim();
+    if body.is_empty() {
+        return None;
+    }
+    let diagnostics: Vec<String> = serde_json::from_str::<serde_json::Value>(body)
+        .ok()
+        .and_then(|outcome| outcome["issue"].as_array().cloned())
+        .into_iter()
+        .flatten()
+        .filter_map(|issue| issue["diagnostics"].as_str().map(str::to_owned))
+        .collect();
+    if diagnostics.is_empty() {
+        Some(body.to_owned())
+    } else {
+        Some(diagnostics.join("; "))
+    }
+}
+
+/// Result of uploading one resource
+#[derive(Debug, Clone)]
+pub struct Outcome {
+    /// Path relative to the base, e.g. `StructureDefinition/nickname`
+    pub path: String,
+    /// HTTP status code, or the reason the request failed
+    pub status: Result<u16, String>,
+    /// Why the server rejected the resource, from the OperationOutcome it returned
+    pub details: Option<String>,
+}
+
+impl Outcome {
+    pub fn is_success(&self) -> bool {
+        self.status
+            .as_ref()
+            .is_ok_and(|code| (200..300).contains(code))
+    }
+}
+
+/// Id of the pushed resource: its own id, or the last segment of its url
+fn resource_id(entry: &package::Entry) -> Option<String> {
+    entry.id.clone().or_else(|| {
+        let url = entry.url.as_deref()?.trim_end_matches('/');
+        Some(slugify(url.rsplit('/').next().unwrap_or(url)))
+    })
+}
+
+/// Push the StructureDefinitions and SearchParameters among the entries made by
+/// [`package::make_entries`], one request per resource.
+pub fn push(server: &Server, token: Option<&str>, entries: &[package::Entry]) -> Vec<Outcome> {
+    entries
+        .iter()
+        .filter(|entry| PUSHED_RESOURCE_TYPES.contains(&entry.resource_type.as_str()))
+        .cloned()
+        .filter_map(|mut entry| {
+            let id = resource_id(&entry)?;
+            entry.resource["id"] = id.clone().into();
+            let path = format!("/{}/{id}", entry.resource_type);
+            let body = serde_json::to_vec(&entry.resource).unwrap();
+            let (status, details) = match server.put(&path, &body, token) {
+                Ok((code, response)) if !(200..300).contains(&code) => {
+                    (Ok(code), response_details(&response))
+                }
+                Ok((code, _)) => (Ok(code), None),
+                Err(reason) => (Err(reason), None),
+            };
+            Some(Outcome {
+                status,
+                details,
+                path: path[1..].to_owned(),
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{BufRead, BufReader, Read, Write},
+        net::{TcpListener, TcpStream},
+        time::{Duration, Instant},
+    };
+
+    use serde_json::json;
+
+    use crate::{
+        FhirVersion, convert_attributes, package,
+        push::{self, Error, Server},
+    };
+
+    /// Head lines and JSON body of a request received by a test server
+    fn read_request(reader: &mut BufReader<TcpStream>) -> (Vec<String>, serde_json::Value) {
+        let mut head = Vec::new();
+        let mut content_length = 0;
+        loop {
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            if line == "\r\n" {
+                break;
+            }
+            if let Some(length) = line.strip_prefix("Content-Length: ") {
+                content_length = length.trim().parse().unwrap();
+            }
+            head.push(line.trim_end().to_owned());
+        }
+        let mut body = vec![0; content_length];
+        reader.read_exact(&mut body).unwrap();
+        (head, serde_json::from_slice(&body).unwrap())
+    }
+
+    #[test]
+    fn test_parse_server() {
+        assert!(Server::parse("http://localhost:8080/fhir/").is_ok());
+        let server = Server::parse("http://[::1]/fhir").unwrap();
+        assert_eq!(server.authority, "[::1]:80");
+        let server = Server::parse("http://[::1]:8080").unwrap();
+        assert_eq!(server.authority, "[::1]:8080");
+        assert!(matches!(
+            Server::parse("http://[::1/fhir"),
+            Err(Error::InvalidUrl { .. })
+        ));
+        assert!(matches!(
+            Server::parse("https://example.org/fhir"),
+            Err(Error::UnsupportedScheme { .. })
+        ));
+        assert!(matches!(
+            Server::parse("localhost:8080"),
+            Err(Error::InvalidUrl { .. })
+        ));
+    }
+
+    #[test]
+    fn test_check_token() {
+        for url in [
+            "http://localhost:8080/fhir",
+            "http://127.0.0.2/fhir",
+            "http://[::1]:8080",
+        ] {
+            assert!(
+                Server::parse(url).unwrap().check_token(false).is_ok(),
+                "{url}"
+            );
+        }
+        let server = Server::parse("http://fhir.example.org/fhir").unwrap();
+        assert!(matches!(
+            server.check_token(false),
+            Err(Error::InsecureToken { host }) if host == "fhir.example.org"
+        ));
+        assert!(server.check_token(true).is_ok());
+    }
+
+    #[test]
+    fn test_push() {
+        let attribute = serde_json::from_value(json!({
+            "id": "Patient.nickname",
+            "path": ["nickname"],
+            "resource": {"id": "Patient", "resourceType": "Entity"},
+            "type": {"id": "string", "resourceType": "Entity"},
+            "extensionUrl": "http://example.org/nickname",
+        }))
+        .unwrap();
+        let result = convert_attributes(vec![attribute], Vec::new(), FhirVersion::V4_0_1);
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        // Accept the extension, reject the profile
+        let server = std::thread::spawn(move || {
+            let mut requests = Vec::new();
+            let outcome = r#"{"resourceType":"OperationOutcome","issue":[{"severity":"error","code":"invalid","diagnostics":"Unknown base definition"}]}"#;
+            for (status, response) in [("201 Created", ""), ("422 Unprocessable Entity", outcome)] {
+                let (stream, _) = listener.accept().unwrap();
+                let mut reader = BufReader::new(stream);
+                requests.push(read_request(&mut reader));
+                write!(
+                    reader.get_mut(),
+                    "HTTP/1.1 {status}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{response}",
+                    response.len()
+                )
+                .unwrap();
+            }
+            requests
+        });
+
+        let server_url = format!("http://127.0.0.1:{port}/fhir");
+        let (entries, _) = package::make_entries(&result);
+        let outcomes = push::push(
+            &Server::parse(&server_url).unwrap(),
+            Some("secret"),
+            &entries,
+        );
+        let requests = server.join().unwrap();
+
+        let statuses: Vec<(&str, Result<u16, String>)> = outcomes
+            .iter()
+            .map(|outcome| (outcome.path.as_str(), outcome.status.clone()))
+            .collect();
+        assert_eq!(
+            statuses,
+            [
+                ("StructureDefinition/nickname", Ok(201)),
+                ("StructureDefinition/Patient-fce", Ok(422)),
+            ]
+        );
+        assert!(outcomes[0].is_success());
+        assert!(!outcomes[1].is_success());
+        assert_eq!(outcomes[0].details, None);
+        assert_eq!(
+            outcomes[1].details.as_deref(),
+            Some("Unknown base definition")
+        );
+
+        let (head, body) = &requests[0];
+        assert_eq!(head[0], "PUT /fhir/StructureDefinition/nickname HTTP/1.1");
+        assert!(head.contains(&"Authorization: Bearer secret".to_owned()));
+        assert_eq!(body["url"], "http://example.org/nickname");
+    }
+
+    #[test]
+    fn test_push_chunked_response() {
+        let attribute = serde_json::from_value(json!({
+            "id": "Patient.nickname",
+            "path": ["nickname"],
+            "resource": {"id": "Patient", "resourceType": "Entity"},
+            "type": {"id": "string", "resourceType": "Entity"},
+            "extensionUrl": "http://example.org/nickname",
+        }))
+        .unwrap();
+        let result = convert_attributes(vec![attribute], Vec::new(), FhirVersion::V4_0_1);
+        let (entries, _) = package::make_entries(&result);
+        let extension: Vec<package::Entry> = entries
+            .into_iter()
+            .filter(|entry| entry.url.as_deref() == Some("http://example.org/nickname"))
+            .collect();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        // Reply in chunks and keep the connection open, as keep-alive servers do
+        let server = std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream);
+            read_request(&mut reader);
+            let outcome = r#"{"resourceType":"OperationOutcome","issue":[{"severity":"error","diagnostics":"Bad slice"}]}"#;
+            let (first, second) = outcome.split_at(20);
+            write!(
+                reader.get_mut(),
+                "HTTP/1.1 400 Bad Request\r\nTransfer-Encoding: chunked\r\n\r\n{:x}\r\n{first}\r\n{:x};name=value\r\n{second}\r\n0\r\n\r\n",
+                first.len(),
+                second.len()
+            )
+            .unwrap();
+            // Wait for the client to close the connection
+            let mut rest = Vec::new();
+            reader.read_to_end(&mut rest).unwrap();
+        });
+
+        let started = Instant::now();
+        let outcomes = push::push(
+            &Server::parse(&format!("http://127.0.0.1:{port}")).unwrap(),
+            None,
+            &extension,
+        );
+        server.join().unwrap();
+
+        assert!(started.elapsed() < Duration::from_secs(10));
+        assert_eq!(outcomes.len(), 1);
+        assert_eq!(outcomes[0].status, Ok(400));
+        assert_eq!(outcomes[0].details.as_deref(), Some("Bad slice"));
+    }
+}
@@ -0,0 +1,55 @@
+//! Machine-readable report of the errors and warnings of a run, see `--error-report`.
+//!
+//! Each entry holds the diagnostic code of the error, its message followed by the messages of
+//! its causes, and the attribute and file the error is about when they are known. The report
+//! is written in addition to the human-readable diagnostics, it does not replace them.
+
+use std::path::{Path, PathBuf};
+
+use miette::{Diagnostic, Severity};
+use serde::Serialize;
+
+/// Attribute and file an error is about
+pub trait Subject {
+    fn attribute_id(&self) -> Option<&str> {
+        None
+    }
+
+    fn filename(&self) -> Option<&Path> {
+        None
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Entry {
+    /// Diagnostic code, e.g. `trie::raw::already_exists`
+    pub code: Option<String>,
+    /// `error` or `warning`
+    pub severity: &'static str,
+    pub message: String,
+    pub attribute_id: Option<String>,
+    pub file: Option<PathBuf>,
+}
+
+impl Entry {
+    pub fn new<E: Diagnostic + Subject>(error: &E) -> Self {
+        let mut message = error.to_string();
+        let mut source = error.source();
+        while let Some(cause) = source {
+            message.push_str(": ");
+            message.push_str(&cause.to_string());
+            source = cause.source();
+        }
+
+        Self {
+            code: error.code().map(|code| code.to_string()),
+            severity: match error.severity() {
+                Some(Severity::Warning) | Some(Severity::Advice) => "warning",
+                Some(Severity::Error) | None => "error",
+            },
+            message,
+            attribute_id: error.attribute_id().map(str::to_owned),
+            file: error.filename().map(Path::to_owned),
+        }
+    }
+}
@@ -238,7 +238,7 @@ const AIDBOX_CUSTOM_TYPES: [(&str, &str); 84] = [
     ("ui_snippet", "ui_snippet"),
 ];
 
-const FHIR_TYPES: [&str; 231] = [
+const FHIR_DATA_TYPES: [&str; 69] = [
     "Base",
     "Element",
     "BackboneElement",
@@ -308,6 +308,9 @@ const FHIR_TYPES: [&str; 231] = [
     "UsageContext",
     "VirtualServiceDetail",
     "xhtml",
+];
+
+const FHIR_RESOURCE_TYPES: [&str; 162] = [
     "Resource",
     "Binary",
     "Bundle",
@@ -472,6 +475,15 @@ const FHIR_TYPES: [&str; 231] = [
     "Parameters",
 ];
 
+/// Canonical url of the core definition of a FHIR type.
+///
+/// Core canonical urls carry no version, the definitions of a type in R4 (4.0.0 and 4.0.1), R4B
+/// and R5 share the url. Which one a reference resolves to is decided by the core package the
+/// generated package depends on.
+pub fn core_definition_url(typename: &str) -> String {
+    format!("http://hl7.org/fhir/StructureDefinition/{typename}")
+}
+
 pub fn get_type_url(typename: &str) -> Option<String> {
     if let Some((_typename, url)) = AIDBOX_CUSTOM_TYPES
         .into_iter()
@@ -480,21 +492,68 @@ pub fn get_type_url(typename: &str) -> Option<String> {
         return Some(url.to_owned());
     }
 
-    if let Some(fhir_typename) = FHIR_TYPES
+    if let Some(fhir_typename) = FHIR_DATA_TYPES
         .into_iter()
+        .chain(FHIR_RESOURCE_TYPES)
         .find(|fhir_typename| *fhir_typename == typename)
     {
-        return Some(format!(
-            "http://hl7.org/fhir/StructureDefinition/{fhir_typename}"
-        ));
+        return Some(core_definition_url(fhir_typename));
     }
 
     None
 }
 
+/// Url of an Aidbox custom type, as returned by [`get_type_url`]
+pub fn is_aidbox_type_url(url: &str) -> bool {
+    AIDBOX_CUSTOM_TYPES
+        .into_iter()
+        .any(|(_typename, type_url)| type_url == url)
+}
+
+/// FHIR data type or resource type, i.e. not an Aidbox custom type
+pub fn is_fhir_type(typename: &str) -> bool {
+    FHIR_DATA_TYPES.contains(&typename) || FHIR_RESOURCE_TYPES.contains(&typename)
+}
+
 pub fn is_known_type(typename: &str) -> bool {
-    FHIR_TYPES.contains(&typename)
+    FHIR_DATA_TYPES.contains(&typename)
+        || FHIR_RESOURCE_TYPES.contains(&typename)
         || AIDBOX_CUSTOM_TYPES
             .into_iter()
             .any(|(aidbox_typename, _url)| typename == aidbox_typename)
 }
+
+/// Kind of a type as in `StructureDefinition.kind`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TypeKind {
+    PrimitiveType,
+    ComplexType,
+    Resource,
+}
+
+impl TypeKind {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            TypeKind::PrimitiveType => "primitive-type",
+            TypeKind::ComplexType => "complex-type",
+            TypeKind::Resource => "resource",
+        }
+    }
+}
+
+/// Get `StructureDefinition.kind` of a known type.
+///
+/// Aidbox custom types are all resources. FHIR primitive types are the lowercase ones.
+pub fn get_type_kind(typename: &str) -> Option<TypeKind> {
+    if FHIR_DATA_TYPES.contains(&typename) {
+        if typename.starts_with(char::is_lowercase) {
+            Some(TypeKind::PrimitiveType)
+        } else {
+            Some(TypeKind::ComplexType)
+        }
+    } else if is_known_type(typename) {
+        Some(TypeKind::Resource)
+    } else {
+        None
+    }
+}
@@ -47,6 +47,8 @@ pub enum SearchParameterType {
     Number,
     Quantity,
     Reference,
+    Resource,
+    Special,
     String,
     Token,
     Uri,
@@ -58,3 +60,14 @@ pub struct Reference {
     #[serde(rename = "resourceType")]
     pub resource_type: String,
 }
+
+/// Find search parameter by its name and the resource type it is attached to
+pub fn find<'a>(
+    search_params: &'a [SearchParameter],
+    resource_type: &str,
+    name: &str,
+) -> Option<&'a SearchParameter> {
+    search_params
+        .iter()
+        .find(|sp| sp.resource.id == resource_type && sp.name == name)
+}
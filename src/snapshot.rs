@@ -0,0 +1,422 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use miette::Diagnostic;
+use thiserror::Error;
+
+use crate::{
+    attribute::aidbox,
+    report::Subject,
+    resource_map::{self, TypeKind},
+    trie::fhir::{
+        ElementDefinition, ElementType, StructureDefinition, StructureDefinitionSnapshot,
+        capitalize,
+    },
+};
+
+#[derive(Debug, Error, Diagnostic)]
+pub enum Error {
+    #[error(
+        "Could not generate snapshot for {url}: base definition of {base_type} is not available"
+    )]
+    #[diagnostic(code(snapshot::missing_base), severity(Warning))]
+    #[diagnostic(help(
+        "Only FHIR types can be used as a base for snapshot generation. The StructureDefinition is emitted without a snapshot."
+    ))]
+    MissingBase { url: String, base_type: String },
+}
+
+impl Subject for Error {}
+
+/// Element lists of base types, built from the bundled Aidbox attributes.
+///
+/// Only direct elements of each type are stored. Elements of nested data types are unfolded on
+/// demand while overlaying a differential.
+///
+/// Base elements only carry what the attributes define: `id` and `path` (with `[x]` for choice
+/// elements), `min` from isRequired, `max` from isCollection, and `type` from the attribute type
+/// or the choice targets, with `targetProfile` from refers. Everything else in the snapshot comes
+/// from the differential, whose fields replace the base ones when set.
+pub struct BaseDefinitions {
+    elements: BTreeMap<String, Vec<ElementDefinition>>,
+}
+
+/// Split element id into the parent id and the separator: `.` for children, `:` for slices
+fn split_last(id: &str) -> Option<(&str, char)> {
+    let idx = id.rfind(['.', ':'])?;
+    Some((&id[..idx], id[idx..].chars().next()?))
+}
+
+fn is_descendant(id: &str, ancestor: &str) -> bool {
+    id.strip_prefix(ancestor)
+        .is_some_and(|rest| rest.starts_with(['.', ':']))
+}
+
+fn make_type(code: &str, refers: Option<&Vec<String>>) -> ElementType {
+    ElementType {
+        code: code.to_owned(),
+        target_profile: refers.map(|refs| {
+            refs.iter()
+                .map(|tref| resource_map::core_definition_url(tref))
+                .collect()
+        }),
+        profile: None,
+    }
+}
+
+fn make_element(id: String, path: String, min: usize, max: &str) -> ElementDefinition {
+    ElementDefinition {
+        id,
+        path,
+        min: Some(min),
+        max: Some(max.to_owned()),
+        ..Default::default()
+    }
+}
+
+fn make_base_elements(rt: &str, attributes: &[&aidbox::Attribute]) -> Vec<ElementDefinition> {
+    // Polymorphic roots either list their targets in union,
+    // or only have children named after the target type (e.g. Extension.value.string)
+    let mut poly_roots: BTreeSet<&[String]> = attributes
+        .iter()
+        .filter(|attr| attr.union.is_some())
+        .map(|attr| attr.path.as_slice())
+        .collect();
+    for attr in attributes {
+        if let (Some(target), Some((name, parent))) = (&attr.r#type, attr.path.split_last())
+            && *name == target.id
+            && attributes
+                .iter()
+                .any(|parent_attr| parent_attr.path == parent && parent_attr.r#type.is_none())
+        {
+            poly_roots.insert(parent);
+        }
+    }
+
+    let is_choice = |attr: &aidbox::Attribute| {
+        let Some((name, parent)) = attr.path.split_last() else {
+            return false;
+        };
+        if poly_roots.contains(parent) {
+            return true;
+        }
+        // Denormalized choices, e.g. Extension.valueString next to Extension.value
+        let Some(target) = &attr.r#type else {
+            return false;
+        };
+        poly_roots.iter().any(|root| {
+            root.split_last().is_some_and(|(root_name, root_parent)| {
+                root_parent == parent && *name == format!("{root_name}{}", capitalize(&target.id))
+            })
+        })
+    };
+
+    let complex_type = match resource_map::get_type_kind(rt) {
+        Some(TypeKind::Resource) => "BackboneElement",
+        _ => "Element",
+    };
+
+    let mut choice_targets: BTreeMap<&[String], Vec<String>> = BTreeMap::new();
+    for attr in attributes {
+        if let (Some(target), Some((_, parent))) = (&attr.r#type, attr.path.split_last())
+            && poly_roots.contains(parent)
+        {
+            choice_targets
+                .entry(parent)
+                .or_default()
+                .push(target.id.clone());
+        }
+    }
+
+    let mut attributes: Vec<&aidbox::Attribute> = attributes
+        .iter()
+        .copied()
+        .filter(|attr| !attr.path.is_empty() && !is_choice(attr))
+        .collect();
+    attributes.sort_by(|a, b| {
+        (a.order.unwrap_or(i64::MAX), &a.path).cmp(&(b.order.unwrap_or(i64::MAX), &b.path))
+    });
+
+    let mut elements = vec![make_element(rt.to_owned(), rt.to_owned(), 0, "*")];
+    for attr in attributes {
+        let poly = poly_roots.contains(attr.path.as_slice());
+        let mut path = format!("{rt}.{}", attr.path.join("."));
+        if poly {
+            path.push_str("[x]");
+        }
+
+        let min = if attr.is_required.unwrap_or(false) {
+            1
+        } else {
+            0
+        };
+        let max = if attr.is_collection.unwrap_or(false) {
+            "*"
+        } else {
+            "1"
+        };
+        let mut element = make_element(path.clone(), path, min, max);
+
+        element.r#type = if poly {
+            let targets: Vec<String> = match &attr.union {
+                Some(union) => union.iter().map(|target| target.id.clone()).collect(),
+                None => choice_targets
+                    .get(attr.path.as_slice())
+                    .cloned()
+                    .unwrap_or_default(),
+            };
+            Some(
+                targets
+                    .iter()
+                    .map(|target| make_type(target, None))
+                    .collect(),
+            )
+        } else {
+            match &attr.r#type {
+                Some(target) => Some(vec![make_type(&target.id, attr.refers.as_ref())]),
+                None => Some(vec![make_type(complex_type, None)]),
+            }
+        };
+
+        elements.push(element);
+    }
+
+    elements
+}
+
+fn merge(base: &mut ElementDefinition, diff: &ElementDefinition) {
+    fn overlay<T: Clone>(base: &mut Option<T>, diff: &Option<T>) {
+        if diff.is_some() {
+            base.clone_from(diff);
+        }
+    }
+
+    overlay(&mut base.slice_name, &diff.slice_name);
+    overlay(&mut base.min, &diff.min);
+    overlay(&mut base.max, &diff.max);
+    overlay(&mut base.fixed_url, &diff.fixed_url);
+    overlay(&mut base.slicing, &diff.slicing);
+    if diff.content_reference.is_some() {
+        // The referenced element defines the type
+        base.r#type = None;
+    }
+    overlay(&mut base.content_reference, &diff.content_reference);
+    overlay(&mut base.r#type, &diff.r#type);
+    overlay(&mut base.max_length, &diff.max_length);
+    overlay(&mut base.binding, &diff.binding);
+    overlay(&mut base.extension, &diff.extension);
+    overlay(&mut base.is_modifier, &diff.is_modifier);
+    overlay(&mut base.is_modifier_reason, &diff.is_modifier_reason);
+    overlay(&mut base.is_summary, &diff.is_summary);
+    overlay(&mut base.must_support, &diff.must_support);
+    if let Some(constraint) = &diff.constraint {
+        base.constraint
+            .get_or_insert_with(Vec::new)
+            .extend(constraint.iter().cloned());
+    }
+    overlay(&mut base.source, &diff.source);
+}
+
+impl BaseDefinitions {
+    pub fn build_from(attributes: &[aidbox::Attribute]) -> Self {
+        let mut by_type: BTreeMap<&str, Vec<&aidbox::Attribute>> = BTreeMap::new();
+        for attr in attributes {
+            if attr.resource.resource_type == "Entity" {
+                by_type.entry(&attr.resource.id).or_default().push(attr);
+            }
+        }
+
+        let elements = by_type
+            .into_iter()
+            .map(|(rt, attributes)| (rt.to_owned(), make_base_elements(rt, &attributes)))
+            .collect();
+
+        Self { elements }
+    }
+
+    /// Overlay the differential onto the base element list
+    pub fn make_snapshot(
+        &self,
+        sd: &StructureDefinition,
+    ) -> Result<StructureDefinitionSnapshot, Error> {
+        let Some(base) = self.elements.get(&sd.r#type) else {
+            return Err(Error::MissingBase {
+                url: sd.url.clone(),
+                base_type: sd.r#type.clone(),
+            });
+        };
+
+        let mut snapshot = base.clone();
+        for diff in &sd.differential.element {
+            if let Some(idx) = self.unfold(&mut snapshot, &diff.id) {
+                merge(&mut snapshot[idx], diff);
+                continue;
+            }
+
+            let anchor = split_last(&diff.id).and_then(|(parent, separator)| {
+                Some((self.unfold(&mut snapshot, parent)?, separator))
+            });
+            match anchor {
+                Some((idx, separator)) => {
+                    let anchor_id = snapshot[idx].id.clone();
+                    let end = snapshot[idx + 1..]
+                        .iter()
+                        .position(|element| !is_descendant(&element.id, &anchor_id))
+                        .map(|offset| idx + 1 + offset)
+                        .unwrap_or(snapshot.len());
+
+                    // Slices start from the definition of the sliced element
+                    let element = if separator == ':' {
+                        let mut element = ElementDefinition {
+                            id: diff.id.clone(),
+                            slicing: None,
+                            ..snapshot[idx].clone()
+                        };
+                        merge(&mut element, diff);
+                        element
+                    } else {
+                        diff.clone()
+                    };
+                    snapshot.insert(end, element);
+                }
+                None => snapshot.push(diff.clone()),
+            }
+        }
+
+        Ok(StructureDefinitionSnapshot { element: snapshot })
+    }
+
+    /// Find element by id, unfolding elements of data types on the way if needed
+    fn unfold(&self, snapshot: &mut Vec<ElementDefinition>, id: &str) -> Option<usize> {
+        if let Some(idx) = snapshot.iter().position(|element| element.id == id) {
+            return Some(idx);
+        }
+
+        let (parent, separator) = split_last(id)?;
+        if separator == ':' {
+            // Slices are never implied by the base, they come from the differential
+            return None;
+        }
+
+        let parent_idx = self.unfold(snapshot, parent)?;
+        let has_children = snapshot
+            .get(parent_idx + 1)
+            .is_some_and(|element| is_descendant(&element.id, &snapshot[parent_idx].id));
+        if !has_children {
+            self.expand(snapshot, parent_idx);
+        }
+
+        snapshot.iter().position(|element| element.id == id)
+    }
+
+    /// Insert elements of the element data type right after it
+    fn expand(&self, snapshot: &mut Vec<ElementDefinition>, idx: usize) {
+        let parent = &snapshot[idx];
+        let Some([element_type]) = parent.r#type.as_deref() else {
+            return;
+        };
+        let Some(base) = self.elements.get(&element_type.code) else {
+            return;
+        };
+
+        let prefix_len = element_type.code.len();
+        let children: Vec<ElementDefinition> = base
+            .iter()
+            .skip(1)
+            .map(|child| ElementDefinition {
+                id: format!("{}{}", parent.id, &child.id[prefix_len..]),
+                path: format!("{}{}", parent.path, &child.path[prefix_len..]),
+                ..child.clone()
+            })
+            .collect();
+
+        snapshot.splice(idx + 1..idx + 1, children);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        FhirVersion, builtin,
+        snapshot::{BaseDefinitions, Error, make_element},
+        trie::fhir::{ElementDefinition, StructureDefinition, StructureDefinitionDifferential},
+    };
+
+    fn make_profile(rt: &str, element: Vec<ElementDefinition>) -> StructureDefinition {
+        StructureDefinition {
+            resource_type: "StructureDefinition".to_owned(),
+            id: None,
+            language: None,
+            version: None,
+            use_context: None,
+            status: "active".to_owned(),
+            date: None,
+            publisher: None,
+            base_definition: format!("http://hl7.org/fhir/StructureDefinition/{rt}"),
+            r#abstract: false,
+            url: format!("http://example.org/{rt}"),
+            name: rt.to_owned(),
+            title: None,
+            description: None,
+            derivation: "constraint".to_owned(),
+            context: None,
+            differential: StructureDefinitionDifferential { element },
+            snapshot: None,
+            fhir_version: "4.0.1".to_owned(),
+            kind: "resource".to_owned(),
+            r#type: rt.to_owned(),
+        }
+    }
+
+    fn base() -> BaseDefinitions {
+        BaseDefinitions::build_from(&builtin::get_builtin_resources(FhirVersion::V4_0_1).attribute)
+    }
+
+    #[test]
+    fn test_snapshot_overlays_differential() {
+        let mut slice = make_element(
+            "Patient.contact.name.extension:nickname".to_owned(),
+            "Patient.contact.name.extension".to_owned(),
+            0,
+            "1",
+        );
+        slice.slice_name = Some("nickname".to_owned());
+        let mut gender = make_element(
+            "Patient.gender".to_owned(),
+            "Patient.gender".to_owned(),
+            1,
+            "1",
+        );
+        gender.max_length = Some(10);
+
+        let profile = make_profile("Patient", vec![gender, slice]);
+        let snapshot = base().make_snapshot(&profile).unwrap();
+        let ids: Vec<&str> = snapshot.element.iter().map(|e| e.id.as_str()).collect();
+
+        let gender = &snapshot.element[ids.iter().position(|id| *id == "Patient.gender").unwrap()];
+        assert_eq!(gender.min, Some(1));
+        assert_eq!(gender.max_length, Some(10));
+        assert_eq!(gender.r#type.as_ref().unwrap()[0].code, "code");
+
+        // HumanName is unfolded to place the slice after Patient.contact.name.extension
+        let extension = ids
+            .iter()
+            .position(|id| *id == "Patient.contact.name.extension")
+            .unwrap();
+        assert_eq!(
+            ids[extension + 1],
+            "Patient.contact.name.extension:nickname"
+        );
+        assert!(ids.contains(&"Patient.contact.name.family"));
+        assert!(ids.contains(&"Patient.deceased[x]"));
+        assert!(!ids.contains(&"Patient.deceased[x].boolean"));
+    }
+
+    #[test]
+    fn test_snapshot_missing_base() {
+        let profile = make_profile("AidboxConfig", Vec::new());
+        assert!(matches!(
+            base().make_snapshot(&profile),
+            Err(Error::MissingBase { .. })
+        ));
+    }
+}
@@ -1,5 +1,15 @@
+use std::path::PathBuf;
+
 pub mod extension_separated;
 pub mod fhir;
 pub mod inverted;
 pub mod path;
 pub mod raw;
+
+/// ` in <filename>` for errors about attributes read from a file, empty otherwise
+pub(crate) fn format_filename(filename: &Option<PathBuf>) -> String {
+    match filename {
+        Some(filename) => format!(" in {}", filename.display()),
+        None => String::new(),
+    }
+}
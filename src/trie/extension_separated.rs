@@ -1,9 +1,15 @@
-use std::collections::BTreeMap;
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+};
 
 use miette::Diagnostic;
 use thiserror::Error;
 
-use crate::trie::path;
+use crate::{
+    report::Subject,
+    trie::{fhir::capitalize, format_filename, path},
+};
 
 pub struct Forest {
     pub forest: BTreeMap<String, Trie>,
@@ -36,9 +42,13 @@ pub struct ConcreteNode {
     pub refers: Option<Vec<String>>,
     pub required: bool,
     pub resource_type: String,
+    pub filename: Option<PathBuf>,
     pub target: String,
     pub value_set: Option<String>,
     pub enumeration: Option<Vec<String>>,
+    pub max_length: Option<u32>,
+    pub description: Option<String>,
+    pub content_reference: Option<String>,
 }
 
 #[derive(Debug, Clone)]
@@ -49,9 +59,13 @@ pub struct ConcreteExtension {
     pub refers: Option<Vec<String>>,
     pub required: bool,
     pub resource_type: String,
+    pub filename: Option<PathBuf>,
     pub target: String,
     pub value_set: Option<String>,
     pub enumeration: Option<Vec<String>>,
+    pub max_length: Option<u32>,
+    pub description: Option<String>,
+    pub content_reference: Option<String>,
 }
 
 #[derive(Debug, Clone)]
@@ -62,6 +76,7 @@ pub struct PolymorphicNode {
     pub path: Vec<String>,
     pub required: bool,
     pub resource_type: String,
+    pub filename: Option<PathBuf>,
     pub targets: Vec<String>,
 }
 
@@ -74,6 +89,7 @@ pub struct PolymorphicExtension {
     pub path: Vec<String>,
     pub required: bool,
     pub resource_type: String,
+    pub filename: Option<PathBuf>,
     pub targets: Vec<String>,
 }
 
@@ -82,9 +98,13 @@ pub struct PolymorphicLeaf {
     pub id: String,
     pub refers: Option<Vec<String>>,
     pub resource_type: String,
+    pub filename: Option<PathBuf>,
     pub target: String,
     pub value_set: Option<String>,
     pub enumeration: Option<Vec<String>>,
+    pub max_length: Option<u32>,
+    pub description: Option<String>,
+    pub content_reference: Option<String>,
 }
 
 #[derive(Debug, Clone)]
@@ -92,8 +112,10 @@ pub struct ComplexNode {
     pub array: bool,
     pub id: String,
     pub open: bool,
+    pub description: Option<String>,
     pub required: bool,
     pub resource_type: String,
+    pub filename: Option<PathBuf>,
     pub children: BTreeMap<String, NormalNode>,
     pub extension: BTreeMap<String, Extension>,
 }
@@ -104,8 +126,10 @@ pub struct ComplexExtension {
     pub fce: String,
     pub id: String,
     pub open: bool,
+    pub description: Option<String>,
     pub required: bool,
     pub resource_type: String,
+    pub filename: Option<PathBuf>,
     pub extension: BTreeMap<String, Extension>,
 }
 
@@ -118,58 +142,180 @@ pub struct InferredNode {
 #[derive(Debug, Error, Diagnostic)]
 pub enum Error {
     #[error(
-        "Attribute {node_id} defines a concrete element. Concrete elements must not have children, but this element has."
+        "Attribute {node_id}{} at {path} defines a concrete element. Concrete elements must not have children, but this element has.",
+        format_filename(.filename)
     )]
-    ConcreteHasChild { node_id: String },
+    #[diagnostic(code(trie::extension_separated::concrete_has_child))]
+    ConcreteHasChild {
+        node_id: String,
+        path: String,
+        filename: Option<PathBuf>,
+    },
 
     #[error(
-        "Attribute {attr_id} defines a polymorphic elements. It has child {child_id} with extensionUrl set. Children of polymorphic elements must not have extensionUrl."
+        "Attribute {attr_id}{} at {path} defines extension {url} with a value of type {target}, and it has nested extension {child_id}. The nested extensions are not included in the extension.",
+        format_filename(.filename)
+    )]
+    #[diagnostic(
+        code(trie::extension_separated::extension_with_value_and_extensions),
+        help(
+            "A FHIR extension has either a value or nested extensions, not both (invariant ext-1). Make {attr_id} a complex attribute without type and move its value into a nested extension, e.g. an attribute {attr_id}.value with type {target} and its own extensionUrl."
+        )
     )]
-    #[diagnostic(help(
-        "This leads to invalid conversion Aidbox->FHIR format. Aidbox->FHIR converter represents this situation as valueExtension field, which is impossible in FHIR."
-    ))]
-    PolymorphicChildExtension { attr_id: String, child_id: String },
+    ExtensionWithValueAndExtensions {
+        attr_id: String,
+        url: String,
+        target: String,
+        child_id: String,
+        path: String,
+        filename: Option<PathBuf>,
+    },
 
     #[error(
-        "Attribute {attr_id} defines a polymorphic element. It has child {child_id} which is not a concrete element (i.e. does not have type set). Every child of a polymorphic must be a concrete element."
+        "Attribute {attr_id} defines a polymorphic elements. It has child {child_id}{} at {path} with extensionUrl set. Children of polymorphic elements must not have extensionUrl.",
+        format_filename(.filename)
+    )]
+    #[diagnostic(
+        code(trie::extension_separated::polymorphic_child_extension),
+        help(
+            "This leads to invalid conversion Aidbox->FHIR format. Aidbox->FHIR converter represents this situation as valueExtension field, which is impossible in FHIR."
+        )
     )]
-    PolymorphicNonConcreteChild { attr_id: String, child_id: String },
+    PolymorphicChildExtension {
+        attr_id: String,
+        child_id: String,
+        path: String,
+        filename: Option<PathBuf>,
+    },
 
     #[error(
-        "Attribute {attr_id} defines a polymorphic element. It has an inferred complex child under {child_prop} property. Polymorphic elements must only have concrete, explicity children."
+        "Attribute {attr_id} defines a polymorphic element. It has child {child_id}{} at {path} which is not a concrete element (i.e. does not have type set). Every child of a polymorphic must be a concrete element.",
+        format_filename(.filename)
     )]
-    PolymorphicInferredChild { attr_id: String, child_prop: String },
+    #[diagnostic(code(trie::extension_separated::polymorphic_non_concrete_child))]
+    PolymorphicNonConcreteChild {
+        attr_id: String,
+        child_id: String,
+        path: String,
+        filename: Option<PathBuf>,
+    },
 
     #[error(
-        "Attribute {attr_id} is a root attribute (empty path) and it has extensionUrl set. Root cannot be an extension."
+        "Attribute {attr_id}{} defines a polymorphic element. It has an inferred complex child under {child_prop} property at {path}. Polymorphic elements must only have concrete, explicity children.",
+        format_filename(.filename)
     )]
-    RootIsExtension { attr_id: String },
+    #[diagnostic(code(trie::extension_separated::polymorphic_inferred_child))]
+    PolymorphicInferredChild {
+        attr_id: String,
+        child_prop: String,
+        path: String,
+        filename: Option<PathBuf>,
+    },
 
     #[error(
-        "Attribute {parent_id} defines an extension. Its children must be extensions, but child {child_id} is not an extension."
+        "Attribute {attr_id}{} is a root attribute (empty path) of {path} and it has extensionUrl set. Root cannot be an extension.",
+        format_filename(.filename)
     )]
-    #[diagnostic(help("Consider assigning extensionUrl to the {child_id} attribute."))]
-    NonExtensionInsideExtension { parent_id: String, child_id: String },
+    #[diagnostic(code(trie::extension_separated::root_is_extension))]
+    RootIsExtension {
+        attr_id: String,
+        path: String,
+        filename: Option<PathBuf>,
+    },
 
     #[error(
-        "{} {}",
-        "Attribute {parent_id} defines an extension.",
-        format!("Its children must be explicitly specified, but child {child_property} has no corresponding attribute.")
+        "Attribute {parent_id} defines an extension. Its children must be extensions, but child {child_id}{} at {path} is not an extension.",
+        format_filename(.filename)
     )]
+    #[diagnostic(
+        code(trie::extension_separated::non_extension_inside_extension),
+        help("Consider assigning extensionUrl to the {child_id} attribute.")
+    )]
+    NonExtensionInsideExtension {
+        parent_id: String,
+        child_id: String,
+        path: String,
+        filename: Option<PathBuf>,
+    },
+
+    #[error(
+        "Attribute {parent_id}{} defines an extension. Its children must be explicitly specified, but child {child_property} at {path} has no corresponding attribute.",
+        format_filename(.filename)
+    )]
+    #[diagnostic(code(trie::extension_separated::missing_child))]
     MissingChild {
         parent_id: String,
         child_property: String,
+        path: String,
+        filename: Option<PathBuf>,
     },
 
     #[error(
-        "Attribute {attr_id} is a child of a polymorphic Attribute. Such attributes must not set isArray (it is controlled at the polymorphic root level)."
+        "Attribute {attr_id}{} at {path} is a child of a polymorphic Attribute. Such attributes must not set isArray (it is controlled at the polymorphic root level).",
+        format_filename(.filename)
     )]
-    PolymorphicChildHasArray { attr_id: String },
+    #[diagnostic(code(trie::extension_separated::polymorphic_child_has_array))]
+    PolymorphicChildHasArray {
+        attr_id: String,
+        path: String,
+        filename: Option<PathBuf>,
+    },
 
     #[error(
-        "Attribute {attr_id} is a child of a polymorphic Attribute. Such attributes must not set isRequired (it is controlled at the polymorphic root level)."
+        "Attribute {attr_id}{} at {path} is a child of a polymorphic Attribute. Such attributes must not set isRequired (it is controlled at the polymorphic root level).",
+        format_filename(.filename)
     )]
-    PolymorphicChildIsRequired { attr_id: String },
+    #[diagnostic(code(trie::extension_separated::polymorphic_child_is_required))]
+    PolymorphicChildIsRequired {
+        attr_id: String,
+        path: String,
+        filename: Option<PathBuf>,
+    },
+
+    #[error("No attribute defines {path}, the element is inferred from its children")]
+    #[diagnostic(
+        code(trie::extension_separated::inferred_element),
+        severity(Warning),
+        help(
+            "Only reported with --strict. The attribute of the element may be missing from the export."
+        )
+    )]
+    InferredElement { path: String },
+}
+
+impl Subject for Error {
+    /// The attribute the file is reported for
+    fn attribute_id(&self) -> Option<&str> {
+        match self {
+            Error::ConcreteHasChild { node_id: id, .. }
+            | Error::ExtensionWithValueAndExtensions { attr_id: id, .. }
+            | Error::PolymorphicChildExtension { child_id: id, .. }
+            | Error::PolymorphicNonConcreteChild { child_id: id, .. }
+            | Error::PolymorphicInferredChild { attr_id: id, .. }
+            | Error::RootIsExtension { attr_id: id, .. }
+            | Error::NonExtensionInsideExtension { child_id: id, .. }
+            | Error::MissingChild { parent_id: id, .. }
+            | Error::PolymorphicChildHasArray { attr_id: id, .. }
+            | Error::PolymorphicChildIsRequired { attr_id: id, .. } => Some(id),
+            Error::InferredElement { .. } => None,
+        }
+    }
+
+    fn filename(&self) -> Option<&Path> {
+        match self {
+            Error::ConcreteHasChild { filename, .. }
+            | Error::ExtensionWithValueAndExtensions { filename, .. }
+            | Error::PolymorphicChildExtension { filename, .. }
+            | Error::PolymorphicNonConcreteChild { filename, .. }
+            | Error::PolymorphicInferredChild { filename, .. }
+            | Error::RootIsExtension { filename, .. }
+            | Error::NonExtensionInsideExtension { filename, .. }
+            | Error::MissingChild { filename, .. }
+            | Error::PolymorphicChildHasArray { filename, .. }
+            | Error::PolymorphicChildIsRequired { filename, .. } => filename.as_deref(),
+            Error::InferredElement { .. } => None,
+        }
+    }
 }
 
 impl Default for Forest {
@@ -190,7 +336,7 @@ impl Forest {
         let mut forest = Self::new();
 
         for (resource_type, trie) in source_forest.forest {
-            let (trie, mut build_errors) = Trie::build_from(trie);
+            let (trie, mut build_errors) = Trie::build_from(&resource_type, trie);
             errors.append(&mut build_errors);
             forest.forest.insert(resource_type, trie);
         }
@@ -200,15 +346,17 @@ impl Forest {
 }
 
 impl Trie {
-    pub fn build_from(source_trie: path::Trie) -> (Self, Vec<Error>) {
+    pub fn build_from(resource_type: &str, source_trie: path::Trie) -> (Self, Vec<Error>) {
         let mut errors: Vec<Error> = Vec::new();
         let (root, errors) = match source_trie.root {
-            path::Node::Normal(normal_node) => NormalNode::build_from(normal_node),
+            path::Node::Normal(normal_node) => NormalNode::build_from(resource_type, normal_node),
             path::Node::Extension(extension) => {
                 errors.push(Error::RootIsExtension {
                     attr_id: extension.get_id().to_owned(),
+                    path: resource_type.to_owned(),
+                    filename: extension.get_filename().cloned(),
                 });
-                NormalNode::build_from(extension.convert_to_normal_node())
+                NormalNode::build_from(resource_type, extension.convert_to_normal_node())
             }
         };
         let trie = Self { root };
@@ -217,26 +365,27 @@ impl Trie {
 }
 
 impl NormalNode {
-    pub fn build_from(source_node: path::NormalNode) -> (Self, Vec<Error>) {
+    pub fn build_from(fhir_path: &str, source_node: path::NormalNode) -> (Self, Vec<Error>) {
         let mut errors: Vec<Error> = Vec::new();
         match source_node {
             path::NormalNode::Concrete(concrete_node) => {
-                let (node, mut build_errors) = ConcreteNode::build_from(concrete_node);
+                let (node, mut build_errors) = ConcreteNode::build_from(fhir_path, concrete_node);
                 errors.append(&mut build_errors);
                 (NormalNode::Concrete(node), errors)
             }
             path::NormalNode::Polymorphic(polymorphic_node) => {
-                let (node, mut build_errors) = PolymorphicNode::build_from(polymorphic_node);
+                let (node, mut build_errors) =
+                    PolymorphicNode::build_from(fhir_path, polymorphic_node);
                 errors.append(&mut build_errors);
                 (NormalNode::Polymorphic(node), errors)
             }
             path::NormalNode::Complex(complex_node) => {
-                let (node, mut build_errors) = ComplexNode::build_from(complex_node);
+                let (node, mut build_errors) = ComplexNode::build_from(fhir_path, complex_node);
                 errors.append(&mut build_errors);
                 (NormalNode::Complex(node), errors)
             }
             path::NormalNode::Inferred(inferred_node) => {
-                let (node, mut build_errors) = InferredNode::build_from(inferred_node);
+                let (node, mut build_errors) = InferredNode::build_from(fhir_path, inferred_node);
                 errors.append(&mut build_errors);
                 (NormalNode::Inferred(node), errors)
             }
@@ -253,21 +402,32 @@ impl Extension {
         }
     }
 
-    pub fn build_from(source_node: path::Extension) -> (Self, Vec<Error>) {
+    pub fn get_filename(&self) -> Option<&PathBuf> {
+        match &self {
+            Extension::Concrete(extension) => extension.filename.as_ref(),
+            Extension::Polymorphic(extension) => extension.filename.as_ref(),
+            Extension::Complex(extension) => extension.filename.as_ref(),
+        }
+    }
+
+    pub fn build_from(fhir_path: &str, source_node: path::Extension) -> (Self, Vec<Error>) {
         let mut errors: Vec<Error> = Vec::new();
         match source_node {
             path::Extension::Concrete(concrete_node) => {
-                let (node, mut build_errors) = ConcreteExtension::build_from(concrete_node);
+                let (node, mut build_errors) =
+                    ConcreteExtension::build_from(fhir_path, concrete_node);
                 errors.append(&mut build_errors);
                 (Extension::Concrete(node), errors)
             }
             path::Extension::Polymorphic(polymorphic_node) => {
-                let (node, mut build_errors) = PolymorphicExtension::build_from(polymorphic_node);
+                let (node, mut build_errors) =
+                    PolymorphicExtension::build_from(fhir_path, polymorphic_node);
                 errors.append(&mut build_errors);
                 (Extension::Polymorphic(node), errors)
             }
             path::Extension::Complex(complex_node) => {
-                let (node, mut build_errors) = ComplexExtension::build_from(complex_node);
+                let (node, mut build_errors) =
+                    ComplexExtension::build_from(fhir_path, complex_node);
                 errors.append(&mut build_errors);
                 (Extension::Complex(node), errors)
             }
@@ -276,11 +436,13 @@ impl Extension {
 }
 
 impl ConcreteNode {
-    pub fn build_from(source_node: path::ConcreteNode) -> (Self, Vec<Error>) {
+    pub fn build_from(fhir_path: &str, source_node: path::ConcreteNode) -> (Self, Vec<Error>) {
         let mut errors: Vec<Error> = Vec::new();
         if !source_node.children.is_empty() {
             errors.push(Error::ConcreteHasChild {
                 node_id: source_node.id.clone(),
+                path: fhir_path.to_owned(),
+                filename: source_node.filename.clone(),
             });
         }
 
@@ -290,19 +452,28 @@ impl ConcreteNode {
             refers: source_node.refers,
             required: source_node.required,
             resource_type: source_node.resource_type,
+            filename: source_node.filename,
             target: source_node.target,
             value_set: source_node.value_set,
             enumeration: source_node.enumeration,
+            max_length: source_node.max_length,
+            description: source_node.description,
+            content_reference: source_node.content_reference,
         };
 
         (node, errors)
     }
 
-    pub fn build_from_extension(source_node: path::ConcreteExtension) -> (Self, Vec<Error>) {
+    pub fn build_from_extension(
+        fhir_path: &str,
+        source_node: path::ConcreteExtension,
+    ) -> (Self, Vec<Error>) {
         let mut errors: Vec<Error> = Vec::new();
         if !source_node.children.is_empty() {
             errors.push(Error::ConcreteHasChild {
                 node_id: source_node.id.to_owned(),
+                path: fhir_path.to_owned(),
+                filename: source_node.filename.clone(),
             });
         }
 
@@ -312,9 +483,13 @@ impl ConcreteNode {
             refers: source_node.refers,
             required: source_node.required,
             resource_type: source_node.resource_type,
+            filename: source_node.filename,
             target: source_node.target,
             value_set: source_node.value_set,
             enumeration: source_node.enumeration,
+            max_length: source_node.max_length,
+            description: source_node.description,
+            content_reference: source_node.content_reference,
         };
 
         (node, errors)
@@ -322,11 +497,26 @@ impl ConcreteNode {
 }
 
 impl ConcreteExtension {
-    pub fn build_from(source_node: path::ConcreteExtension) -> (Self, Vec<Error>) {
+    pub fn build_from(fhir_path: &str, source_node: path::ConcreteExtension) -> (Self, Vec<Error>) {
         let mut errors: Vec<Error> = Vec::new();
-        if !source_node.children.is_empty() {
+        let child_extension = source_node.children.values().find_map(|child| match child {
+            path::Node::Extension(extension) => Some(extension),
+            path::Node::Normal(_) => None,
+        });
+        if let Some(child_extension) = child_extension {
+            errors.push(Error::ExtensionWithValueAndExtensions {
+                attr_id: source_node.id.to_owned(),
+                url: source_node.fce.to_owned(),
+                target: source_node.target.to_owned(),
+                child_id: child_extension.get_id().to_owned(),
+                path: fhir_path.to_owned(),
+                filename: source_node.filename.clone(),
+            });
+        } else if !source_node.children.is_empty() {
             errors.push(Error::ConcreteHasChild {
                 node_id: source_node.id.to_owned(),
+                path: fhir_path.to_owned(),
+                filename: source_node.filename.clone(),
             });
         }
 
@@ -337,9 +527,13 @@ impl ConcreteExtension {
             refers: source_node.refers,
             required: source_node.required,
             resource_type: source_node.resource_type,
+            filename: source_node.filename,
             target: source_node.target,
             value_set: source_node.value_set,
             enumeration: source_node.enumeration,
+            max_length: source_node.max_length,
+            description: source_node.description,
+            content_reference: source_node.content_reference,
         };
 
         (node, errors)
@@ -347,17 +541,21 @@ impl ConcreteExtension {
 }
 
 impl PolymorphicLeaf {
-    pub fn build_from(source_node: path::ConcreteNode) -> (Self, Vec<Error>) {
+    pub fn build_from(fhir_path: &str, source_node: path::ConcreteNode) -> (Self, Vec<Error>) {
         let mut errors: Vec<Error> = Vec::new();
         if source_node.array {
             errors.push(Error::PolymorphicChildHasArray {
                 attr_id: source_node.id.clone(),
+                path: fhir_path.to_owned(),
+                filename: source_node.filename.clone(),
             })
         }
 
         if source_node.required {
             errors.push(Error::PolymorphicChildIsRequired {
                 attr_id: source_node.id.clone(),
+                path: fhir_path.to_owned(),
+                filename: source_node.filename.clone(),
             })
         }
 
@@ -365,25 +563,36 @@ impl PolymorphicLeaf {
             id: source_node.id,
             refers: source_node.refers,
             resource_type: source_node.resource_type,
+            filename: source_node.filename,
             target: source_node.target,
             value_set: source_node.value_set,
             enumeration: source_node.enumeration,
+            max_length: source_node.max_length,
+            description: source_node.description,
+            content_reference: source_node.content_reference,
         };
 
         (node, errors)
     }
 
-    pub fn build_from_extension(source_node: path::ConcreteExtension) -> (Self, Vec<Error>) {
+    pub fn build_from_extension(
+        fhir_path: &str,
+        source_node: path::ConcreteExtension,
+    ) -> (Self, Vec<Error>) {
         let mut errors: Vec<Error> = Vec::new();
         if source_node.array {
             errors.push(Error::PolymorphicChildHasArray {
                 attr_id: source_node.id.clone(),
+                path: fhir_path.to_owned(),
+                filename: source_node.filename.clone(),
             })
         }
 
         if source_node.required {
             errors.push(Error::PolymorphicChildIsRequired {
                 attr_id: source_node.id.clone(),
+                path: fhir_path.to_owned(),
+                filename: source_node.filename.clone(),
             })
         }
 
@@ -391,9 +600,13 @@ impl PolymorphicLeaf {
             id: source_node.id,
             refers: source_node.refers,
             resource_type: source_node.resource_type,
+            filename: source_node.filename,
             target: source_node.target,
             value_set: source_node.value_set,
             enumeration: source_node.enumeration,
+            max_length: source_node.max_length,
+            description: source_node.description,
+            content_reference: source_node.content_reference,
         };
 
         (node, errors)
@@ -401,14 +614,17 @@ impl PolymorphicLeaf {
 }
 
 impl PolymorphicNode {
-    pub fn build_from(source_node: path::PolymorphicNode) -> (Self, Vec<Error>) {
+    pub fn build_from(fhir_path: &str, source_node: path::PolymorphicNode) -> (Self, Vec<Error>) {
         let mut errors: Vec<Error> = Vec::new();
         let mut children: BTreeMap<String, PolymorphicLeaf> = BTreeMap::new();
 
         for (name, source_child) in source_node.children {
+            // Typed children are the value[x] style elements, e.g. valueString for value
+            let child_path = format!("{fhir_path}{}", capitalize(&name));
             match source_child {
                 path::Node::Normal(path::NormalNode::Concrete(source_child)) => {
-                    let (node, mut build_errors) = PolymorphicLeaf::build_from(source_child);
+                    let (node, mut build_errors) =
+                        PolymorphicLeaf::build_from(&child_path, source_child);
                     errors.append(&mut build_errors);
                     children.insert(name, node);
                 }
@@ -416,9 +632,11 @@ impl PolymorphicNode {
                     errors.push(Error::PolymorphicChildExtension {
                         attr_id: source_node.id.clone(),
                         child_id: source_child.id.clone(),
+                        path: child_path.clone(),
+                        filename: source_child.filename.clone(),
                     });
                     let (node, mut build_errors) =
-                        PolymorphicLeaf::build_from_extension(source_child);
+                        PolymorphicLeaf::build_from_extension(&child_path, source_child);
                     errors.append(&mut build_errors);
                     children.insert(name, node);
                 }
@@ -428,11 +646,15 @@ impl PolymorphicNode {
                         errors.push(Error::PolymorphicNonConcreteChild {
                             attr_id: source_node.id.clone(),
                             child_id: child_id.to_owned(),
+                            path: child_path,
+                            filename: node.get_filename().cloned(),
                         })
                     } else {
                         errors.push(Error::PolymorphicInferredChild {
                             attr_id: source_node.id.clone(),
                             child_prop: name,
+                            path: child_path,
+                            filename: source_node.filename.clone(),
                         })
                     }
                 }
@@ -446,6 +668,7 @@ impl PolymorphicNode {
             path: source_node.path,
             required: source_node.required,
             resource_type: source_node.resource_type,
+            filename: source_node.filename,
             targets: source_node.targets,
         };
 
@@ -454,14 +677,20 @@ impl PolymorphicNode {
 }
 
 impl PolymorphicExtension {
-    pub fn build_from(source_node: path::PolymorphicExtension) -> (Self, Vec<Error>) {
+    pub fn build_from(
+        fhir_path: &str,
+        source_node: path::PolymorphicExtension,
+    ) -> (Self, Vec<Error>) {
         let mut errors: Vec<Error> = Vec::new();
         let mut children: BTreeMap<String, PolymorphicLeaf> = BTreeMap::new();
 
         for (name, source_child) in source_node.children {
+            // Typed children are the value[x] style elements, e.g. valueString for value
+            let child_path = format!("{fhir_path}{}", capitalize(&name));
             match source_child {
                 path::Node::Normal(path::NormalNode::Concrete(source_child)) => {
-                    let (node, mut build_errors) = PolymorphicLeaf::build_from(source_child);
+                    let (node, mut build_errors) =
+                        PolymorphicLeaf::build_from(&child_path, source_child);
                     errors.append(&mut build_errors);
                     children.insert(name, node);
                 }
@@ -469,9 +698,11 @@ impl PolymorphicExtension {
                     errors.push(Error::PolymorphicChildExtension {
                         attr_id: source_node.id.clone(),
                         child_id: source_child.id.clone(),
+                        path: child_path.clone(),
+                        filename: source_child.filename.clone(),
                     });
                     let (node, mut build_errors) =
-                        PolymorphicLeaf::build_from_extension(source_child);
+                        PolymorphicLeaf::build_from_extension(&child_path, source_child);
                     errors.append(&mut build_errors);
                     children.insert(name, node);
                 }
@@ -481,11 +712,15 @@ impl PolymorphicExtension {
                         errors.push(Error::PolymorphicNonConcreteChild {
                             attr_id: source_node.id.clone(),
                             child_id: child_id.to_owned(),
+                            path: child_path,
+                            filename: child.get_filename().cloned(),
                         })
                     } else {
                         errors.push(Error::PolymorphicInferredChild {
                             attr_id: source_node.id.clone(),
                             child_prop: name,
+                            path: child_path,
+                            filename: source_node.filename.clone(),
                         })
                     }
                 }
@@ -499,6 +734,7 @@ impl PolymorphicExtension {
             path: source_node.path,
             required: source_node.required,
             resource_type: source_node.resource_type,
+            filename: source_node.filename,
             targets: source_node.targets,
             fce: source_node.fce,
         };
@@ -508,19 +744,21 @@ impl PolymorphicExtension {
 }
 
 impl ComplexNode {
-    pub fn build_from(source_node: path::ComplexNode) -> (Self, Vec<Error>) {
+    pub fn build_from(fhir_path: &str, source_node: path::ComplexNode) -> (Self, Vec<Error>) {
         let mut errors: Vec<Error> = Vec::new();
         let mut children: BTreeMap<String, NormalNode> = BTreeMap::new();
         let mut extension: BTreeMap<String, Extension> = BTreeMap::new();
         for (name, source_child) in source_node.children {
+            let child_path = format!("{fhir_path}.{name}");
             match source_child {
                 path::Node::Normal(normal_node) => {
-                    let (node, mut build_errors) = NormalNode::build_from(normal_node);
+                    let (node, mut build_errors) = NormalNode::build_from(&child_path, normal_node);
                     errors.append(&mut build_errors);
                     children.insert(name, node);
                 }
                 path::Node::Extension(extension_node) => {
-                    let (node, mut build_errors) = Extension::build_from(extension_node);
+                    let (node, mut build_errors) =
+                        Extension::build_from(&child_path, extension_node);
                     errors.append(&mut build_errors);
                     extension.insert(name, node);
                 }
@@ -531,8 +769,10 @@ impl ComplexNode {
             array: source_node.array,
             id: source_node.id,
             open: source_node.open,
+            description: source_node.description,
             required: source_node.required,
             resource_type: source_node.resource_type,
+            filename: source_node.filename,
             children,
             extension,
         };
@@ -542,10 +782,11 @@ impl ComplexNode {
 }
 
 impl ComplexExtension {
-    pub fn build_from(source_node: path::ComplexExtension) -> (Self, Vec<Error>) {
+    pub fn build_from(fhir_path: &str, source_node: path::ComplexExtension) -> (Self, Vec<Error>) {
         let mut errors: Vec<Error> = Vec::new();
         let mut extension: BTreeMap<String, Extension> = BTreeMap::new();
         for (name, source_child) in source_node.children {
+            let child_path = format!("{fhir_path}.{name}");
             match source_child {
                 path::Node::Normal(source_child) => {
                     match source_child.get_id() {
@@ -553,6 +794,8 @@ impl ComplexExtension {
                             errors.push(Error::NonExtensionInsideExtension {
                                 parent_id: source_node.id.clone(),
                                 child_id: child_id.to_owned(),
+                                path: child_path,
+                                filename: source_child.get_filename().cloned(),
                             });
                         }
                         None => {
@@ -560,12 +803,15 @@ impl ComplexExtension {
                             errors.push(Error::MissingChild {
                                 parent_id: source_node.id.clone(),
                                 child_property: name.clone(),
+                                path: child_path,
+                                filename: source_node.filename.clone(),
                             })
                         }
                     }
                 }
                 path::Node::Extension(extension_node) => {
-                    let (node, mut build_errors) = Extension::build_from(extension_node);
+                    let (node, mut build_errors) =
+                        Extension::build_from(&child_path, extension_node);
                     errors.append(&mut build_errors);
                     extension.insert(name, node);
                 }
@@ -576,8 +822,10 @@ impl ComplexExtension {
             array: source_node.array,
             id: source_node.id,
             open: source_node.open,
+            description: source_node.description,
             required: source_node.required,
             resource_type: source_node.resource_type,
+            filename: source_node.filename,
             extension,
             fce: source_node.fce,
         };
@@ -587,19 +835,28 @@ impl ComplexExtension {
 }
 
 impl InferredNode {
-    pub fn build_from(source_node: path::InferredNode) -> (Self, Vec<Error>) {
+    /// Inferred nodes below the root are reported with [`Error::InferredElement`], which
+    /// [`crate::convert`] only keeps in strict mode
+    pub fn build_from(fhir_path: &str, source_node: path::InferredNode) -> (Self, Vec<Error>) {
         let mut errors: Vec<Error> = Vec::new();
+        if fhir_path.contains('.') {
+            errors.push(Error::InferredElement {
+                path: fhir_path.to_owned(),
+            });
+        }
         let mut children: BTreeMap<String, NormalNode> = BTreeMap::new();
         let mut extension: BTreeMap<String, Extension> = BTreeMap::new();
         for (name, source_child) in source_node.children {
+            let child_path = format!("{fhir_path}.{name}");
             match source_child {
                 path::Node::Normal(normal_node) => {
-                    let (node, mut build_errors) = NormalNode::build_from(normal_node);
+                    let (node, mut build_errors) = NormalNode::build_from(&child_path, normal_node);
                     errors.append(&mut build_errors);
                     children.insert(name, node);
                 }
                 path::Node::Extension(extension_node) => {
-                    let (node, mut build_errors) = Extension::build_from(extension_node);
+                    let (node, mut build_errors) =
+                        Extension::build_from(&child_path, extension_node);
                     errors.append(&mut build_errors);
                     extension.insert(name, node);
                 }
@@ -614,3 +871,172 @@ impl InferredNode {
         (node, errors)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use crate::attribute::typed::{
+        Attribute, AttributeKind, AttributeKindComplex, AttributeKindConcrete, AttributeKindPoly,
+    };
+    use crate::trie::{extension_separated, path, raw};
+
+    fn attribute(path: &[&str], kind: AttributeKind, fce: Option<&str>) -> Attribute {
+        Attribute {
+            id: format!("Patient.{}", path.join(".")),
+            path: path.iter().map(|s| s.to_string()).collect(),
+            resource_type: "Patient".to_owned(),
+            kind,
+            array: false,
+            required: false,
+            modifier: false,
+            summary: false,
+            fce: fce.map(|s| s.to_owned()),
+            filename: None,
+            module: None,
+        }
+    }
+
+    fn concrete(target: &str) -> AttributeKind {
+        AttributeKind::Concrete(AttributeKindConcrete {
+            target: target.to_owned(),
+            value_set: None,
+            refers: None,
+            enumeration: None,
+            max_length: None,
+            description: None,
+            content_reference: None,
+            concept_map: None,
+        })
+    }
+
+    fn build_errors(attrs: &[Attribute]) -> Vec<String> {
+        let (raw_forest, errors) = raw::Forest::build_from_attributes(attrs, false);
+        assert!(errors.is_empty());
+        let (path_forest, errors) = path::Forest::build_from(raw_forest);
+        assert!(errors.is_empty());
+        let (_, errors) = extension_separated::Forest::build_from(path_forest);
+        errors
+            .iter()
+            .filter(|error| !matches!(error, extension_separated::Error::InferredElement { .. }))
+            .map(ToString::to_string)
+            .collect()
+    }
+
+    #[test]
+    fn test_inferred_element() {
+        let (raw_forest, _) = raw::Forest::build_from_attributes(
+            &[attribute(&["contact", "name"], concrete("string"), None)],
+            false,
+        );
+        let (path_forest, _) = path::Forest::build_from(raw_forest);
+        let (_, errors) = extension_separated::Forest::build_from(path_forest);
+        let paths: Vec<&str> = errors
+            .iter()
+            .filter_map(|error| match error {
+                extension_separated::Error::InferredElement { path } => Some(path.as_str()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(paths, ["Patient.contact"]);
+    }
+
+    #[test]
+    fn test_polymorphic_child_extension_path() {
+        let errors = build_errors(&[
+            attribute(
+                &["contact", "value"],
+                AttributeKind::Poly(AttributeKindPoly {
+                    targets: vec!["string".to_owned()],
+                }),
+                None,
+            ),
+            attribute(
+                &["contact", "value", "string"],
+                concrete("string"),
+                Some("http://example.org/value"),
+            ),
+        ]);
+        assert_eq!(errors.len(), 1, "{errors:?}");
+        assert!(
+            errors[0].contains("at Patient.contact.valueString"),
+            "{}",
+            errors[0]
+        );
+    }
+
+    #[test]
+    fn test_non_extension_inside_extension_path() {
+        let errors = build_errors(&[
+            attribute(
+                &["origin"],
+                AttributeKind::Complex(AttributeKindComplex {
+                    open: false,
+                    description: None,
+                }),
+                Some("http://example.org/origin"),
+            ),
+            attribute(&["origin", "country"], concrete("string"), None),
+        ]);
+        assert_eq!(errors.len(), 1, "{errors:?}");
+        assert!(
+            errors[0].contains("at Patient.origin.country"),
+            "{}",
+            errors[0]
+        );
+    }
+
+    #[test]
+    fn test_extension_with_value_and_extensions() {
+        let errors = build_errors(&[
+            attribute(
+                &["origin"],
+                concrete("string"),
+                Some("http://example.org/origin"),
+            ),
+            attribute(
+                &["origin", "country"],
+                concrete("string"),
+                Some("http://example.org/country"),
+            ),
+        ]);
+        assert_eq!(errors.len(), 1, "{errors:?}");
+        assert!(
+            errors[0].contains(
+                "defines extension http://example.org/origin with a value of type string, and it has nested extension Patient.origin.country"
+            ),
+            "{}",
+            errors[0]
+        );
+    }
+
+    #[test]
+    fn test_polymorphic_child_extension_filename() {
+        let child = Attribute {
+            filename: Some(PathBuf::from("attrs/contact-value-string.yaml")),
+            ..attribute(
+                &["contact", "value", "string"],
+                concrete("string"),
+                Some("http://example.org/value"),
+            )
+        };
+        let errors = build_errors(&[
+            attribute(
+                &["contact", "value"],
+                AttributeKind::Poly(AttributeKindPoly {
+                    targets: vec!["string".to_owned()],
+                }),
+                None,
+            ),
+            child,
+        ]);
+        assert_eq!(errors.len(), 1, "{errors:?}");
+        assert!(
+            errors[0].contains(
+                "child Patient.contact.value.string in attrs/contact-value-string.yaml at"
+            ),
+            "{}",
+            errors[0]
+        );
+    }
+}
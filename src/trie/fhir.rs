@@ -1,12 +1,23 @@
-use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet};
+
+use clap::ValueEnum;
+use miette::Diagnostic;
+use serde::{Deserialize, Serialize};
 use thiserror::Error;
 
 use crate::{
+    FhirVersion,
+    attribute::{
+        aidbox,
+        typed::{self, AttributeKind},
+    },
+    report::Subject,
     resource_map,
     trie::inverted::{self, ExtUrl, NormalNode},
+    value_set,
 };
 
-#[derive(Debug, Clone, Serialize)]
+#[derive(Debug, Clone, Default, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct ElementDefinition {
     pub id: String,
@@ -21,14 +32,30 @@ pub struct ElementDefinition {
     pub fixed_url: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub slicing: Option<ElementSlicing>,
+    /// Element defining the content of this one, instead of a type (e.g. `#Questionnaire.item`)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub content_reference: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub r#type: Option<Vec<ElementType>>,
     #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_length: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub binding: Option<Binding>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub extension: Option<Vec<Extension>>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub constraint: Option<Vec<ElementDefinitionConstraint>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub is_modifier: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub is_modifier_reason: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub is_summary: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub must_support: Option<bool>,
+    /// Id of the attribute this element is generated from
+    #[serde(skip)]
+    pub source: Option<String>,
 }
 
 #[derive(Debug, Clone, Serialize)]
@@ -42,14 +69,17 @@ pub struct ElementDefinitionConstraint {
 #[derive(Debug, Clone, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct Extension {
-    url: String,
-    value_string: String,
+    pub url: String,
+    pub value_string: String,
 }
 
 #[derive(Debug, Clone, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct Binding {
+    pub strength: String,
     pub value_set: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
 }
 
 #[derive(Debug, Clone, Serialize)]
@@ -68,29 +98,96 @@ pub struct ElementSlicing {
     pub discriminator: Vec<ElementSlicingDiscriminator>,
 }
 
-#[derive(Debug, Clone, Serialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ElementSlicingDiscriminator {
-    r#type: String,
-    path: String,
+    pub r#type: String,
+    pub path: String,
 }
 
 #[derive(Debug, Clone, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct StructureDefinition {
     pub resource_type: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub language: Option<String>,
     pub status: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub date: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub publisher: Option<String>,
     pub base_definition: String,
     pub r#abstract: bool,
     pub url: String,
+    /// Business version of the resource, not the FHIR version
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub version: Option<String>,
     pub name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub use_context: Option<Vec<UsageContext>>,
     pub derivation: String,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub context: Option<Vec<StructureDefinitionContext>>,
     pub differential: StructureDefinitionDifferential,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub snapshot: Option<StructureDefinitionSnapshot>,
+    pub fhir_version: String,
     pub kind: String,
     pub r#type: String,
 }
 
+#[derive(Debug, Clone, Serialize)]
+pub struct Coding {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub system: Option<String>,
+    pub code: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct CodeableConcept {
+    pub coding: Vec<Coding>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UsageContext {
+    pub code: Coding,
+    pub value_codeable_concept: CodeableConcept,
+}
+
+const USAGE_CONTEXT_TYPE_SYSTEM: &str = "http://terminology.hl7.org/CodeSystem/usage-context-type";
+
+impl UsageContext {
+    /// Usage context of the given type (e.g. `program`) with a coded value, written either as
+    /// `code` or as `system|code`
+    pub fn new(r#type: &str, value: &str) -> Self {
+        let value = match value.split_once('|') {
+            Some((system, code)) => Coding {
+                system: Some(system.to_owned()),
+                code: code.to_owned(),
+            },
+            None => Coding {
+                system: None,
+                code: value.to_owned(),
+            },
+        };
+        Self {
+            code: Coding {
+                system: Some(USAGE_CONTEXT_TYPE_SYSTEM.to_owned()),
+                code: r#type.to_owned(),
+            },
+            value_codeable_concept: CodeableConcept {
+                coding: vec![value],
+            },
+        }
+    }
+}
+
 #[derive(Debug, Clone, Serialize)]
 pub struct StructureDefinitionContext {
     pub r#type: String,
@@ -102,10 +199,345 @@ pub struct StructureDefinitionDifferential {
     pub element: Vec<ElementDefinition>,
 }
 
-#[derive(Debug, Clone, Error)]
+#[derive(Debug, Clone, Serialize)]
+pub struct StructureDefinitionSnapshot {
+    pub element: Vec<ElementDefinition>,
+}
+
+/// Options controlling the shape of emitted StructureDefinition resources
+#[derive(Debug, Clone)]
+pub struct EmitOptions {
+    /// Mark extension elements with the legacy-fce extension holding the original property name
+    pub fce_marker: bool,
+    /// Name the value element of single-type extensions `value<Type>` instead of `value[x]`
+    pub typed_value_element: bool,
+    /// Drop profiles constraining fewer elements than this
+    pub min_constraints: usize,
+    /// Base definitions of profiled extensions, keyed by extension url.
+    /// Extensions not listed here are based on the core Extension.
+    pub extension_bases: BTreeMap<String, String>,
+    /// Discriminators of the nested extension slicing of complex extensions, keyed by extension
+    /// url. Complex extensions not listed here are sliced by url.
+    pub slicing_discriminators: BTreeMap<String, ElementSlicingDiscriminator>,
+    /// Declaration position of attributes, keyed by attribute id, see [`source_order`] and
+    /// [`declared_order`]. If set, differential elements follow the declaration order instead of
+    /// the name order.
+    pub source_order: Option<BTreeMap<String, usize>>,
+    /// Order of the collected extensions. If not set, extensions follow the trie order.
+    pub extension_order: Option<ExtensionOrder>,
+    /// Ids of extension attributes marked with isModifier. Their extensions are modifier
+    /// extensions, sliced on `modifierExtension` in profiles.
+    pub modifiers: BTreeSet<String>,
+    /// Ids of attributes marked with isSummary. Their profile elements get `isSummary`.
+    pub summaries: BTreeSet<String>,
+    /// FHIR paths (e.g. `Patient.name`) or element ids (e.g. `Patient.extension:nickname`) of
+    /// profile elements marked with `mustSupport`
+    pub must_support: BTreeSet<String>,
+    /// Extensions with more elements are reported and not generated, see
+    /// [`Error::TooManyExtensionElements`]
+    pub max_extension_elements: usize,
+    /// Start profile differentials with the root element of the resource type. It carries no
+    /// constraints, differentials are valid without it.
+    pub root_element: bool,
+    /// Emit an extension used on several elements once, with all of them in its context, see
+    /// [`merge_extension_contexts`]. Otherwise there is one definition per context.
+    pub merge_contexts: bool,
+    /// Strength of all emitted bindings
+    pub binding_strength: BindingStrength,
+    /// Description of profiles whose resource has no root attribute with a description
+    pub default_description: Option<String>,
+}
+
+/// `Binding.strength` of the emitted elements
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BindingStrength {
+    #[default]
+    Required,
+    Extensible,
+    Preferred,
+    Example,
+}
+
+impl BindingStrength {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            BindingStrength::Required => "required",
+            BindingStrength::Extensible => "extensible",
+            BindingStrength::Preferred => "preferred",
+            BindingStrength::Example => "example",
+        }
+    }
+}
+
+/// `status` of the generated StructureDefinitions and SearchParameters
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PublicationStatus {
+    Draft,
+    #[default]
+    Active,
+    Retired,
+    Unknown,
+}
+
+impl PublicationStatus {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            PublicationStatus::Draft => "draft",
+            PublicationStatus::Active => "active",
+            PublicationStatus::Retired => "retired",
+            PublicationStatus::Unknown => "unknown",
+        }
+    }
+}
+
+/// Order of the extensions returned by [`collect_extensions`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ExtensionOrder {
+    /// By url
+    Url,
+    /// Simple extensions before complex ones, then by url
+    Kind,
+    /// By the context expression, then by url
+    Context,
+}
+
+impl Default for EmitOptions {
+    fn default() -> Self {
+        Self {
+            fce_marker: true,
+            typed_value_element: false,
+            min_constraints: 1,
+            extension_bases: BTreeMap::new(),
+            slicing_discriminators: BTreeMap::new(),
+            source_order: None,
+            extension_order: None,
+            modifiers: BTreeSet::new(),
+            summaries: BTreeSet::new(),
+            must_support: BTreeSet::new(),
+            max_extension_elements: DEFAULT_MAX_EXTENSION_ELEMENTS,
+            root_element: true,
+            merge_contexts: true,
+            binding_strength: BindingStrength::Required,
+            default_description: None,
+        }
+    }
+}
+
+/// Number attributes in the order they were read, for [`EmitOptions::source_order`]
+pub fn source_order(attributes: &[aidbox::Attribute]) -> BTreeMap<String, usize> {
+    let mut order = BTreeMap::new();
+    for (position, attribute) in attributes.iter().enumerate() {
+        if let Some(id) = &attribute.id {
+            order.entry(id.clone()).or_insert(position);
+        }
+    }
+    order
+}
+
+/// Rank attributes by their `order` property, for [`EmitOptions::source_order`].
+///
+/// Attributes with equal `order` get the same rank, so they keep the name order. Attributes
+/// without `order` are not ranked and go last.
+pub fn declared_order(attributes: &[aidbox::Attribute]) -> BTreeMap<String, usize> {
+    let orders: BTreeSet<i64> = attributes.iter().filter_map(|attr| attr.order).collect();
+    let rank: BTreeMap<i64, usize> = orders
+        .into_iter()
+        .enumerate()
+        .map(|(rank, order)| (order, rank))
+        .collect();
+    attributes
+        .iter()
+        .filter_map(|attr| Some((attr.id.clone()?, rank[&attr.order?])))
+        .collect()
+}
+
+fn declared_at(order: &BTreeMap<String, usize>, id: &str) -> usize {
+    order.get(id).copied().unwrap_or(usize::MAX)
+}
+
+/// Position of the first declared attribute of the subtree
+fn node_position(order: &BTreeMap<String, usize>, node: &NormalNode) -> usize {
+    match node {
+        NormalNode::Concrete(node) => declared_at(order, &node.id),
+        NormalNode::Polymorphic(node) => node
+            .children
+            .values()
+            .map(|leaf| declared_at(order, &leaf.id))
+            .fold(declared_at(order, &node.id), usize::min),
+        NormalNode::Complex(node) => node
+            .children
+            .values()
+            .map(|child| node_position(order, child))
+            .chain(
+                node.extension
+                    .values()
+                    .map(|ext| extension_position(order, ext)),
+            )
+            .fold(declared_at(order, &node.id), usize::min),
+        NormalNode::Inferred(node) => node
+            .children
+            .values()
+            .map(|child| node_position(order, child))
+            .chain(
+                node.extension
+                    .values()
+                    .map(|ext| extension_position(order, ext)),
+            )
+            .fold(usize::MAX, usize::min),
+    }
+}
+
+/// Position of the first declared attribute of the extension
+fn extension_position(order: &BTreeMap<String, usize>, extension: &inverted::Extension) -> usize {
+    match extension {
+        inverted::Extension::Simple(ext) => ext
+            .targets
+            .values()
+            .map(|target| declared_at(order, &target.id))
+            .fold(declared_at(order, &ext.id), usize::min),
+        inverted::Extension::Complex(ext) => ext
+            .extension
+            .values()
+            .map(|child| extension_position(order, child))
+            .fold(declared_at(order, &ext.id), usize::min),
+    }
+}
+
+/// Entries in the order of [`EmitOptions::source_order`] if it is set, otherwise unchanged
+fn in_source_order<K, V>(
+    options: &EmitOptions,
+    entries: impl IntoIterator<Item = (K, V)>,
+    position: impl Fn(&BTreeMap<String, usize>, &V) -> usize,
+) -> Vec<(K, V)> {
+    let mut entries: Vec<(K, V)> = entries.into_iter().collect();
+    if let Some(order) = &options.source_order {
+        entries.sort_by_cached_key(|(_, value)| position(order, value));
+    }
+    entries
+}
+
+/// Discriminator of the nested extension slicing of the complex extension
+fn make_nested_discriminator(options: &EmitOptions, url: &str) -> ElementSlicingDiscriminator {
+    options
+        .slicing_discriminators
+        .get(url)
+        .cloned()
+        .unwrap_or_else(|| ElementSlicingDiscriminator {
+            r#type: "value".to_owned(),
+            path: "url".to_owned(),
+        })
+}
+
+const EXTENSION_BASE_URL: &str = "http://hl7.org/fhir/StructureDefinition/Extension";
+
+const FCE_MARKER_URL: &str = "http://fhir.aidbox.app/fhir/StructureDefinition/legacy-fce";
+
+fn make_fce_marker(options: &EmitOptions, fce_property: &str) -> Option<Vec<Extension>> {
+    if !options.fce_marker {
+        return None;
+    }
+
+    Some(vec![Extension {
+        url: FCE_MARKER_URL.to_owned(),
+        value_string: fce_property.to_owned(),
+    }])
+}
+
+/// Some FHIR validators reject element ids with longer components
+pub const MAX_ELEMENT_ID_COMPONENT_LENGTH: usize = 64;
+
+/// Default of [`EmitOptions::max_extension_elements`]
+pub const DEFAULT_MAX_EXTENSION_ELEMENTS: usize = 1000;
+
+#[derive(Debug, Clone, Error, Diagnostic)]
 pub enum Error {
-    #[error("Todo")]
-    Todo,
+    #[error("Extension {url} has no value types")]
+    #[diagnostic(
+        code(trie::fhir::empty_extension_targets),
+        help(
+            "The extension is generated from attribute {attribute_id}. Set its type or add typed children to the polymorphic attribute."
+        )
+    )]
+    EmptyExtensionTargets { url: String, attribute_id: String },
+
+    #[error("Complex extension {url} has no nested extensions")]
+    #[diagnostic(
+        code(trie::fhir::extension_without_children),
+        help(
+            "The extension is generated from attribute {attribute_id}. Add child attributes with extensionUrl or set the attribute type."
+        )
+    )]
+    ExtensionWithoutChildren { url: String, attribute_id: String },
+
+    #[error(
+        "Element {element_id} in {url} has an id component longer than {} characters",
+        MAX_ELEMENT_ID_COMPONENT_LENGTH
+    )]
+    #[diagnostic(
+        code(trie::fhir::element_id_too_long),
+        severity(Warning),
+        help(
+            "The element is generated from attribute {attribute_id}. Some FHIR validators reject such element ids, consider shortening the attribute path."
+        )
+    )]
+    ElementIdTooLong {
+        url: String,
+        element_id: String,
+        attribute_id: String,
+    },
+
+    #[error("Attribute {attribute_id} can not be converted in isolation")]
+    #[diagnostic(
+        code(trie::fhir::not_supported_in_isolation),
+        help(
+            "Only concrete attributes (with type set) are supported without the rest of the attributes. Polymorphic and complex attributes depend on their children."
+        )
+    )]
+    NotSupportedInIsolation { attribute_id: String },
+
+    #[error("Extension {url} has more than the maximum of {max} elements, it is not generated")]
+    #[diagnostic(
+        code(trie::fhir::too_many_extension_elements),
+        help(
+            "The extension is generated from attribute {attribute_id}. So many elements usually indicate a modeling error or a cycle in the nested attributes. Raise the maximum with --max-extension-elements if the extension is intended."
+        )
+    )]
+    TooManyExtensionElements {
+        url: String,
+        attribute_id: String,
+        max: usize,
+    },
+
+    #[error("Element {element_id} in {url} has max {max} less than min {min}")]
+    #[diagnostic(
+        code(trie::fhir::max_below_min),
+        help(
+            "The element is generated from attribute {attribute_id}. Validators reject such a cardinality, check whether the attribute is both required and excluded."
+        )
+    )]
+    MaxBelowMin {
+        url: String,
+        element_id: String,
+        attribute_id: String,
+        min: usize,
+        max: String,
+    },
+}
+
+impl Subject for Error {
+    fn attribute_id(&self) -> Option<&str> {
+        match self {
+            Error::EmptyExtensionTargets { attribute_id, .. }
+            | Error::ExtensionWithoutChildren { attribute_id, .. }
+            | Error::ElementIdTooLong { attribute_id, .. }
+            | Error::NotSupportedInIsolation { attribute_id }
+            | Error::TooManyExtensionElements { attribute_id, .. }
+            | Error::MaxBelowMin { attribute_id, .. } => Some(attribute_id),
+        }
+    }
 }
 
 pub fn escape_fp_string(s: &str) -> String {
@@ -140,6 +572,8 @@ fn collect_extensions_recursive(
     rt: &str,
     path: &[String],
     node: inverted::NormalNode,
+    options: &EmitOptions,
+    version: FhirVersion,
 ) -> (Vec<StructureDefinition>, Vec<Error>) {
     let mut result: Vec<StructureDefinition> = Vec::new();
     let mut errors: Vec<Error> = Vec::new();
@@ -152,14 +586,21 @@ fn collect_extensions_recursive(
                 let mut child_path = path.to_owned();
                 child_path.push(field.to_owned());
                 let (mut child_res, mut child_errors) =
-                    collect_extensions_recursive(rt, &child_path, child);
+                    collect_extensions_recursive(rt, &child_path, child, options, version);
                 result.append(&mut child_res);
                 errors.append(&mut child_errors);
             }
 
             for (url, ext) in complex_node.extension {
-                let ext = emit_extension(rt, path, url.0, ext);
-                result.push(ext);
+                result.extend(emit_extension(
+                    rt,
+                    path,
+                    url.0,
+                    ext,
+                    options,
+                    version,
+                    &mut errors,
+                ));
             }
         }
         inverted::NormalNode::Inferred(inferred_node) => {
@@ -167,13 +608,20 @@ fn collect_extensions_recursive(
                 let mut child_path = path.to_owned();
                 child_path.push(field.to_owned());
                 let (mut child_res, mut child_errors) =
-                    collect_extensions_recursive(rt, &child_path, child);
+                    collect_extensions_recursive(rt, &child_path, child, options, version);
                 result.append(&mut child_res);
                 errors.append(&mut child_errors);
             }
             for (url, ext) in inferred_node.extension {
-                let ext = emit_extension(rt, path, url.0, ext);
-                result.push(ext);
+                result.extend(emit_extension(
+                    rt,
+                    path,
+                    url.0,
+                    ext,
+                    options,
+                    version,
+                    &mut errors,
+                ));
             }
         }
     }
@@ -181,29 +629,202 @@ fn collect_extensions_recursive(
     (result, errors)
 }
 
-pub fn collect_extensions(forest: inverted::Forest) -> (Vec<StructureDefinition>, Vec<Error>) {
+pub fn collect_extensions(
+    forest: inverted::Forest,
+    options: &EmitOptions,
+    version: FhirVersion,
+) -> (Vec<StructureDefinition>, Vec<Error>) {
     let mut errors: Vec<Error> = Vec::new();
     let mut sds: Vec<StructureDefinition> = Vec::new();
     for (rt, trie) in forest.forest {
         let (mut extensions, mut collect_errors) =
-            collect_extensions_recursive(&rt, &[], trie.root);
+            collect_extensions_recursive(&rt, &[], trie.root, options, version);
         sds.append(&mut extensions);
         errors.append(&mut collect_errors);
     }
+    for sd in &mut sds {
+        mark_modifier_extension(sd, &options.modifiers);
+    }
+    if options.merge_contexts {
+        sds = merge_extension_contexts(sds);
+    }
+    if let Some(order) = options.extension_order {
+        sort_extensions(&mut sds, order);
+    }
     (sds, errors)
 }
 
+/// Merge extensions with the same url which only differ by their context, e.g. the same
+/// extension attribute on Patient and Practitioner. The merged extension is used in all the
+/// contexts. Extensions with the same url and different definitions are kept, they are reported
+/// as duplicate canonical urls.
+pub fn merge_extension_contexts(sds: Vec<StructureDefinition>) -> Vec<StructureDefinition> {
+    let definition = |sd: &StructureDefinition| {
+        serde_json::to_value(StructureDefinition {
+            context: None,
+            ..sd.clone()
+        })
+        .unwrap()
+    };
+
+    let mut merged: Vec<StructureDefinition> = Vec::new();
+    for sd in sds {
+        let same = merged
+            .iter_mut()
+            .find(|existing| existing.url == sd.url && definition(existing) == definition(&sd));
+        match same {
+            Some(existing) => existing
+                .context
+                .get_or_insert_with(Vec::new)
+                .extend(sd.context.into_iter().flatten()),
+            None => merged.push(sd),
+        }
+    }
+    merged
+}
+
+/// Urls of the generated profiles, keyed by the core url of the type they constrain
+pub fn local_profile_urls(profiles: &[StructureDefinition]) -> BTreeMap<String, String> {
+    profiles
+        .iter()
+        .filter_map(|profile| {
+            Some((
+                resource_map::get_type_url(&profile.r#type)?,
+                profile.url.clone(),
+            ))
+        })
+        .collect()
+}
+
+/// Point reference targets at the generated profiles of the referenced types, see
+/// [`local_profile_urls`]. Targets of types without a generated profile keep the core url.
+pub fn prefer_local_profiles(sd: &mut StructureDefinition, local: &BTreeMap<String, String>) {
+    let target_profiles = sd
+        .differential
+        .element
+        .iter_mut()
+        .flat_map(|element| element.r#type.iter_mut().flatten())
+        .flat_map(|r#type| r#type.target_profile.iter_mut().flatten());
+    for url in target_profiles {
+        if let Some(local_url) = local.get(url) {
+            url.clone_from(local_url);
+        }
+    }
+}
+
+/// Mark the root element of extensions defined by isModifier attributes
+fn mark_modifier_extension(sd: &mut StructureDefinition, modifiers: &BTreeSet<String>) {
+    let Some(root) = sd.differential.element.first_mut() else {
+        return;
+    };
+    if root.id == "Extension"
+        && root
+            .source
+            .as_ref()
+            .is_some_and(|id| modifiers.contains(id))
+    {
+        root.is_modifier = Some(true);
+        root.is_modifier_reason =
+            Some("Marked with isModifier in the source Aidbox Attribute".to_owned());
+    }
+}
+
+/// Complex extensions slice their nested extensions, simple ones have a value
+fn is_complex_extension(sd: &StructureDefinition) -> bool {
+    sd.differential
+        .element
+        .iter()
+        .any(|element| element.id == "Extension.extension" && element.slicing.is_some())
+}
+
+fn context_expression(sd: &StructureDefinition) -> Option<&str> {
+    sd.context
+        .as_ref()
+        .and_then(|context| context.first())
+        .map(|context| context.expression.as_str())
+}
+
+pub fn sort_extensions(extensions: &mut [StructureDefinition], order: ExtensionOrder) {
+    match order {
+        ExtensionOrder::Url => extensions.sort_by(|a, b| a.url.cmp(&b.url)),
+        ExtensionOrder::Kind => extensions.sort_by(|a, b| {
+            (is_complex_extension(a), &a.url).cmp(&(is_complex_extension(b), &b.url))
+        }),
+        ExtensionOrder::Context => extensions
+            .sort_by(|a, b| (context_expression(a), &a.url).cmp(&(context_expression(b), &b.url))),
+    }
+}
+
 pub struct ElementPointer {
     pub path: String,
     pub id: String,
 }
 
+/// Make a FHIR id from a name by replacing characters not allowed in ids with `-`.
+///
+/// Only depends on the name, so ids are stable across runs.
+pub fn slugify(name: &str) -> String {
+    name.chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '-' || c == '.' {
+                c
+            } else {
+                '-'
+            }
+        })
+        .collect()
+}
+
+/// Maximum length of FHIR resource ids
+pub const MAX_ID_LENGTH: usize = 64;
+
+/// FNV-1a, a hash which does not change between builds, unlike the std hashers
+pub(crate) fn stable_hash(value: &str) -> u32 {
+    value.bytes().fold(0x811c9dc5, |hash, byte| {
+        (hash ^ u32::from(byte)).wrapping_mul(0x01000193)
+    })
+}
+
+/// Make a valid FHIR resource id from a name, see [`slugify`].
+///
+/// Ids longer than [`MAX_ID_LENGTH`] are truncated and end with a hash of the whole name, so
+/// that names sharing a long prefix still get distinct ids.
+pub fn make_id(name: &str) -> String {
+    let id = slugify(name);
+    if id.is_empty() {
+        return format!("{:08x}", stable_hash(name));
+    }
+    if id.len() <= MAX_ID_LENGTH {
+        return id;
+    }
+    let hash = format!("{:08x}", stable_hash(name));
+    format!("{}-{hash}", &id[..MAX_ID_LENGTH - hash.len() - 1])
+}
+
+/// Id for one of several definitions sharing the id `id`, ending with a hash of its url
+pub fn disambiguated_id(id: &str, url: &str) -> String {
+    let hash = format!("{:08x}", stable_hash(url));
+    format!(
+        "{}-{hash}",
+        &id[..id.len().min(MAX_ID_LENGTH - hash.len() - 1)]
+    )
+}
+
+/// Id of an extension definition, from the last segment of its url
+pub fn extension_id(url: &str) -> String {
+    let url = url.trim_end_matches('/');
+    make_id(url.rsplit('/').next().unwrap_or(url))
+}
+
 pub fn emit_extension(
     rt: &str,
     path: &[String],
     url: String,
     extension: inverted::Extension,
-) -> StructureDefinition {
+    options: &EmitOptions,
+    version: FhirVersion,
+    errors: &mut Vec<Error>,
+) -> Option<StructureDefinition> {
     let mut base_path = "Extension".to_owned();
     for path_element in path {
         base_path.push('.');
@@ -218,16 +839,48 @@ pub fn emit_extension(
     };
 
     let mut counter: usize = 1;
+    let mut emitted: usize = 0;
+    let attribute_id = extension.get_id().to_owned();
+    let element = emit_differential(
+        &mut counter,
+        &mut emitted,
+        url.clone(),
+        extension,
+        options,
+        errors,
+    );
+    // Descending stops once the maximum is exceeded, so the differential is incomplete
+    if element.len() > options.max_extension_elements {
+        errors.push(Error::TooManyExtensionElements {
+            url,
+            attribute_id,
+            max: options.max_extension_elements,
+        });
+        return None;
+    }
 
-    StructureDefinition {
+    Some(StructureDefinition {
         resource_type: "StructureDefinition".to_owned(),
-        base_definition: "http://hl7.org/fhir/StructureDefinition/Extension".to_owned(),
+        id: Some(extension_id(&url)),
+        language: None,
+        version: None,
+        use_context: None,
+        base_definition: options
+            .extension_bases
+            .get(&url)
+            .map_or(EXTENSION_BASE_URL, String::as_str)
+            .to_owned(),
         r#abstract: false,
         status: "active".to_owned(),
+        date: None,
+        publisher: None,
         url: url.to_owned(),
-        differential: StructureDefinitionDifferential {
-            element: emit_differential(&mut counter, url, extension),
-        },
+        differential: StructureDefinitionDifferential { element },
+        snapshot: None,
+        title: Some(name.clone()),
+        description: Some(format!(
+            "Auto-converted from Aidbox first-class extension {name}"
+        )),
         name,
         derivation: "constraint".to_owned(),
         context: Some(vec![StructureDefinitionContext {
@@ -238,18 +891,67 @@ pub fn emit_extension(
                 acc
             }),
         }]),
+        fhir_version: version.as_str().to_owned(),
         kind: "complex-type".to_owned(),
         r#type: "Extension".to_owned(),
+    })
+}
+
+/// Name of the value element for a single-type extension, e.g. `valueString`,
+/// if requested by [`EmitOptions::typed_value_element`]
+fn typed_value_name(
+    targets: &BTreeMap<String, inverted::ExtensionTarget>,
+    options: &EmitOptions,
+) -> Option<String> {
+    match targets.keys().collect::<Vec<_>>().as_slice() {
+        [type_name] if options.typed_value_element => {
+            Some(format!("value{}", capitalize(type_name)))
+        }
+        _ => None,
+    }
+}
+
+/// Type of the value element of an extension for one of its targets
+fn value_type(target_type: &str, target: &inverted::ExtensionTarget) -> ElementType {
+    ElementType {
+        code: target_type.to_owned(),
+        profile: None,
+        target_profile: target.refers.as_ref().map(|refs| {
+            refs.iter()
+                .map(|tref| resource_map::core_definition_url(tref))
+                .collect()
+        }),
+    }
+}
+
+/// Slicing of the value element of an extension with several targets, one slice per type.
+/// Every slice restricts the value to its type and carries the binding of its target.
+fn value_type_slicing() -> ElementSlicing {
+    ElementSlicing {
+        rules: "closed".to_owned(),
+        discriminator: vec![ElementSlicingDiscriminator {
+            r#type: "type".to_owned(),
+            path: "$this".to_owned(),
+        }],
     }
 }
 
 pub fn emit_differential(
     counter: &mut usize,
+    emitted: &mut usize,
     url: String,
     extension: inverted::Extension,
+    options: &EmitOptions,
+    errors: &mut Vec<Error>,
 ) -> Vec<ElementDefinition> {
     match extension {
         inverted::Extension::Simple(simple_extension) => {
+            if simple_extension.targets.is_empty() {
+                errors.push(Error::EmptyExtensionTargets {
+                    url: url.clone(),
+                    attribute_id: simple_extension.id.clone(),
+                });
+            }
             let min = if simple_extension.required { 1 } else { 0 };
             let max = if simple_extension.array {
                 "*".to_owned()
@@ -259,68 +961,62 @@ pub fn emit_differential(
             let root = ElementDefinition {
                 id: "Extension".to_owned(),
                 path: "Extension".to_owned(),
-                slice_name: None,
                 min: Some(min),
                 max: Some(max),
-                fixed_url: None,
-                slicing: None,
-                r#type: None,
-                binding: None,
-                extension: Some(vec![Extension {
-                    url: "http://fhir.aidbox.app/fhir/StructureDefinition/legacy-fce".to_owned(),
-                    value_string: simple_extension.fce_property,
-                }]),
-                constraint: None,
+                extension: make_fce_marker(options, &simple_extension.fce_property),
+                source: Some(simple_extension.id.clone()),
+                ..Default::default()
             };
 
             let url_elem = ElementDefinition {
                 id: "Extension.url".to_owned(),
                 path: "Extension.url".to_owned(),
-                slice_name: None,
                 min: Some(1),
                 max: Some("1".to_owned()),
                 fixed_url: Some(url),
-                slicing: None,
-                r#type: None,
-                binding: None,
-                extension: None,
-                constraint: None,
+                source: Some(simple_extension.id.clone()),
+                ..Default::default()
             };
 
+            let typed_value = typed_value_name(&simple_extension.targets, options);
+            let value_name = typed_value.as_deref().unwrap_or("value[x]");
+            let sliced_by_type = simple_extension.targets.len() > 1;
             let value_elem = ElementDefinition {
-                id: "Extension.value[x]".to_owned(),
-                path: "Extension.value[x]".to_owned(),
-                slice_name: None,
+                id: format!("Extension.{value_name}"),
+                path: format!("Extension.{value_name}"),
                 min: Some(1),
                 max: Some("1".to_owned()),
-                fixed_url: None,
-                slicing: None,
+                slicing: sliced_by_type.then(value_type_slicing),
                 r#type: Some(
                     simple_extension
                         .targets
                         .iter()
-                        .map(|(target_type, target_info)| ElementType {
-                            code: target_type.to_owned(),
-                            profile: None,
-                            target_profile: target_info.refers.as_ref().map(|refs| {
-                                refs.iter()
-                                    .map(|tref| {
-                                        format!("http://hl7.org/fhir/StructureDefinition/{}", tref)
-                                    })
-                                    .collect()
-                            }),
-                        })
+                        .map(|(target_type, target_info)| value_type(target_type, target_info))
                         .collect(),
                 ),
-                binding: None,
-                extension: None,
-                constraint: None,
+                source: Some(simple_extension.id.clone()),
+                ..Default::default()
             };
+            let value_elem_id = value_elem.id.clone();
 
             let mut differential = vec![root, url_elem, value_elem];
 
             for (type_name, target) in simple_extension.targets {
-                let binding = target.value_set.map(|vs| Binding { value_set: vs });
+                let r#type = value_type(&type_name, &target);
+                let binding = target
+                    .value_set
+                    .map(|vs| Binding {
+                        strength: options.binding_strength.as_str().to_owned(),
+                        value_set: vs,
+                        description: target.description,
+                    })
+                    .or_else(|| {
+                        value_set::make_enum_binding(
+                            &target.id,
+                            target.enumeration.as_deref(),
+                            options.binding_strength,
+                        )
+                    });
                 let constraint = target.enumeration.map(|e| {
                     let constraint = ElementDefinitionConstraint {
                         key: format!("enum-{counter}"),
@@ -332,19 +1028,32 @@ pub fn emit_differential(
                     vec![constraint]
                 });
 
-                if binding.is_some() || constraint.is_some() {
+                if typed_value.is_some() {
+                    let value_elem = differential
+                        .iter_mut()
+                        .find(|elem| elem.id == value_elem_id)
+                        .unwrap();
+                    value_elem.max_length = target.max_length;
+                    value_elem.binding = binding;
+                    value_elem.constraint = constraint;
+                } else if sliced_by_type
+                    || binding.is_some()
+                    || constraint.is_some()
+                    || target.max_length.is_some()
+                {
+                    let slice_name = format!("value{}", capitalize(&type_name));
                     let elem = ElementDefinition {
-                        id: format!("Extension.value[x]:value{}", type_name),
+                        id: format!("Extension.value[x]:{slice_name}"),
                         path: "Extension.value[x]".to_owned(),
-                        slice_name: Some(format!("value{}", type_name)),
-                        min: None,
-                        max: None,
-                        fixed_url: None,
-                        slicing: None,
-                        r#type: None,
+                        slice_name: Some(slice_name),
+                        min: sliced_by_type.then_some(0),
+                        max: sliced_by_type.then(|| "1".to_owned()),
+                        r#type: sliced_by_type.then(|| vec![r#type]),
+                        max_length: target.max_length,
                         binding,
                         constraint,
-                        extension: None,
+                        source: Some(target.id.clone()),
+                        ..Default::default()
                     };
                     differential.push(elem);
                 }
@@ -353,6 +1062,12 @@ pub fn emit_differential(
             differential
         }
         inverted::Extension::Complex(complex_extension) => {
+            if complex_extension.extension.is_empty() {
+                errors.push(Error::ExtensionWithoutChildren {
+                    url: url.clone(),
+                    attribute_id: complex_extension.id.clone(),
+                });
+            }
             let min = if complex_extension.required { 1 } else { 0 };
             let max = if complex_extension.array {
                 "*".to_owned()
@@ -362,66 +1077,44 @@ pub fn emit_differential(
             let root = ElementDefinition {
                 id: "Extension".to_owned(),
                 path: "Extension".to_owned(),
-                slice_name: None,
                 min: Some(min),
                 max: Some(max),
-                fixed_url: None,
-                slicing: None,
-                r#type: None,
-                binding: None,
-                extension: Some(vec![Extension {
-                    url: "http://fhir.aidbox.app/fhir/StructureDefinition/legacy-fce".to_owned(),
-                    value_string: complex_extension.fce_property,
-                }]),
-                constraint: None,
+                extension: make_fce_marker(options, &complex_extension.fce_property),
+                source: Some(complex_extension.id.clone()),
+                ..Default::default()
             };
 
             let base_elem = ElementDefinition {
                 id: "Extension.extension".to_owned(),
                 path: "Extension.extension".to_owned(),
-                slice_name: None,
                 min: Some(1),
-                max: None,
-                fixed_url: None,
                 slicing: Some(ElementSlicing {
                     rules: "closed".to_owned(),
-                    discriminator: vec![ElementSlicingDiscriminator {
-                        r#type: "value".to_owned(),
-                        path: "url".to_owned(),
-                    }],
+                    discriminator: vec![make_nested_discriminator(options, &url)],
                 }),
-                r#type: None,
-                binding: None,
-                extension: None,
-                constraint: None,
+                source: Some(complex_extension.id.clone()),
+                ..Default::default()
             };
 
             let url_elem = ElementDefinition {
                 id: "Extension.url".to_owned(),
                 path: "Extension.url".to_owned(),
-                slice_name: None,
                 min: Some(1),
                 max: Some("1".to_owned()),
                 fixed_url: Some(url.to_owned()),
-                slicing: None,
-                r#type: None,
-                binding: None,
-                extension: None,
-                constraint: None,
+                source: Some(complex_extension.id.clone()),
+                ..Default::default()
             };
 
+            // Extensions with both a value and nested extensions are reported by
+            // extension_separated::Error::ExtensionWithValueAndExtensions, so there is no value
             let value_elem = ElementDefinition {
                 id: "Extension.value[x]".to_owned(),
                 path: "Extension.value[x]".to_owned(),
-                slice_name: None,
                 min: Some(0),
                 max: Some("0".to_owned()),
-                fixed_url: None,
-                slicing: None,
-                r#type: None,
-                binding: None,
-                extension: None,
-                constraint: None,
+                source: Some(complex_extension.id.clone()),
+                ..Default::default()
             };
 
             let mut nested: Vec<ElementDefinition> = Vec::new();
@@ -431,8 +1124,18 @@ pub fn emit_differential(
                 id: "Extension.extension".to_owned(),
             };
 
-            for (url, child) in complex_extension.extension {
-                nested.append(&mut emit_nested(counter, &ptr, url, child));
+            let children = in_source_order(options, complex_extension.extension, |order, ext| {
+                extension_position(order, ext)
+            });
+            *emitted += 4;
+            for (url, child) in children {
+                // Stop descending, the extension is dropped by emit_extension
+                if *emitted > options.max_extension_elements {
+                    break;
+                }
+                nested.append(&mut emit_nested(
+                    counter, emitted, &ptr, url, child, options, errors,
+                ));
             }
 
             let mut res = Vec::new();
@@ -449,12 +1152,21 @@ pub fn emit_differential(
 
 pub fn emit_nested(
     counter: &mut usize,
+    emitted: &mut usize,
     ptr: &ElementPointer,
     url: ExtUrl,
     extension: inverted::Extension,
+    options: &EmitOptions,
+    errors: &mut Vec<Error>,
 ) -> Vec<ElementDefinition> {
     match extension {
         inverted::Extension::Simple(simple_extension) => {
+            if simple_extension.targets.is_empty() {
+                errors.push(Error::EmptyExtensionTargets {
+                    url: url.0.clone(),
+                    attribute_id: simple_extension.id.clone(),
+                });
+            }
             let min = if simple_extension.required {
                 Some(1)
             } else {
@@ -471,15 +1183,9 @@ pub fn emit_nested(
                 slice_name: Some(simple_extension.fce_property.to_owned()),
                 min,
                 max,
-                fixed_url: None,
-                slicing: None,
-                r#type: None,
-                binding: None,
-                extension: Some(vec![Extension {
-                    url: "http://fhir.aidbox.app/fhir/StructureDefinition/legacy-fce".to_owned(),
-                    value_string: simple_extension.fce_property.to_owned(),
-                }]),
-                constraint: None,
+                extension: make_fce_marker(options, &simple_extension.fce_property),
+                source: Some(simple_extension.id.clone()),
+                ..Default::default()
             };
 
             let base_elem_ptr = ElementPointer {
@@ -490,45 +1196,31 @@ pub fn emit_nested(
             let url_elem = ElementDefinition {
                 id: format!("{}.url", base_elem_ptr.id),
                 path: format!("{}.url", base_elem_ptr.path),
-                slice_name: None,
                 min: Some(1),
                 max: Some("1".to_owned()),
                 fixed_url: Some(url.0.to_owned()),
-                slicing: None,
-                r#type: None,
-                binding: None,
-                extension: None,
-                constraint: None,
+                source: Some(simple_extension.id.clone()),
+                ..Default::default()
             };
 
+            let typed_value = typed_value_name(&simple_extension.targets, options);
+            let value_name = typed_value.as_deref().unwrap_or("value[x]");
+            let sliced_by_type = simple_extension.targets.len() > 1;
             let value_elem = ElementDefinition {
-                id: format!("{}.value[x]", base_elem_ptr.id),
-                path: format!("{}.value[x]", base_elem_ptr.path),
-                slice_name: None,
+                id: format!("{}.{value_name}", base_elem_ptr.id),
+                path: format!("{}.{value_name}", base_elem_ptr.path),
                 min: Some(1),
                 max: Some("1".to_owned()),
-                fixed_url: None,
-                slicing: None,
+                slicing: sliced_by_type.then(value_type_slicing),
                 r#type: Some(
                     simple_extension
                         .targets
                         .iter()
-                        .map(|(target_type, target_info)| ElementType {
-                            code: target_type.to_owned(),
-                            profile: None,
-                            target_profile: target_info.refers.as_ref().map(|refs| {
-                                refs.iter()
-                                    .map(|tref| {
-                                        format!("http://hl7.org/fhir/StructureDefinition/{}", tref)
-                                    })
-                                    .collect()
-                            }),
-                        })
+                        .map(|(target_type, target_info)| value_type(target_type, target_info))
                         .collect(),
                 ),
-                binding: None,
-                extension: None,
-                constraint: None,
+                source: Some(simple_extension.id.clone()),
+                ..Default::default()
             };
 
             let value_elem_ptr = ElementPointer {
@@ -539,7 +1231,21 @@ pub fn emit_nested(
             let mut differential = vec![base_elem, url_elem, value_elem];
 
             for (type_name, target) in simple_extension.targets {
-                let binding = target.value_set.map(|vs| Binding { value_set: vs });
+                let r#type = value_type(&type_name, &target);
+                let binding = target
+                    .value_set
+                    .map(|vs| Binding {
+                        strength: options.binding_strength.as_str().to_owned(),
+                        value_set: vs,
+                        description: target.description,
+                    })
+                    .or_else(|| {
+                        value_set::make_enum_binding(
+                            &target.id,
+                            target.enumeration.as_deref(),
+                            options.binding_strength,
+                        )
+                    });
                 let constraint = target.enumeration.map(|e| {
                     let constraint = ElementDefinitionConstraint {
                         key: format!("enum-{counter}"),
@@ -550,27 +1256,47 @@ pub fn emit_nested(
                     *counter += 1;
                     vec![constraint]
                 });
-                if binding.is_some() || constraint.is_some() {
+                if typed_value.is_some() {
+                    let value_elem = differential
+                        .iter_mut()
+                        .find(|elem| elem.id == value_elem_ptr.id)
+                        .unwrap();
+                    value_elem.max_length = target.max_length;
+                    value_elem.binding = binding;
+                    value_elem.constraint = constraint;
+                } else if sliced_by_type
+                    || binding.is_some()
+                    || constraint.is_some()
+                    || target.max_length.is_some()
+                {
+                    let slice_name = format!("value{}", capitalize(&type_name));
                     let elem = ElementDefinition {
-                        id: format!("{}:value{}", value_elem_ptr.id, type_name),
+                        id: format!("{}:{slice_name}", value_elem_ptr.id),
                         path: value_elem_ptr.path.to_owned(),
-                        slice_name: Some(format!("value{}", type_name)),
-                        min: None,
-                        max: None,
-                        fixed_url: None,
-                        slicing: None,
-                        r#type: None,
+                        slice_name: Some(slice_name),
+                        min: sliced_by_type.then_some(0),
+                        max: sliced_by_type.then(|| "1".to_owned()),
+                        r#type: sliced_by_type.then(|| vec![r#type]),
+                        max_length: target.max_length,
                         binding,
-                        extension: None,
                         constraint,
+                        source: Some(target.id.clone()),
+                        ..Default::default()
                     };
                     differential.push(elem);
                 }
             }
 
+            *emitted += differential.len();
             differential
         }
         inverted::Extension::Complex(complex_extension) => {
+            if complex_extension.extension.is_empty() {
+                errors.push(Error::ExtensionWithoutChildren {
+                    url: url.0.clone(),
+                    attribute_id: complex_extension.id.clone(),
+                });
+            }
             let min = if complex_extension.required {
                 Some(1)
             } else {
@@ -587,15 +1313,9 @@ pub fn emit_nested(
                 slice_name: Some(complex_extension.fce_property.to_owned()),
                 min,
                 max,
-                fixed_url: None,
-                slicing: None,
-                r#type: None,
-                binding: None,
-                extension: Some(vec![Extension {
-                    url: "http://fhir.aidbox.app/fhir/StructureDefinition/legacy-fce".to_owned(),
-                    value_string: complex_extension.fce_property.to_owned(),
-                }]),
-                constraint: None,
+                extension: make_fce_marker(options, &complex_extension.fce_property),
+                source: Some(complex_extension.id.clone()),
+                ..Default::default()
             };
 
             let base_elem_ptr = ElementPointer {
@@ -606,21 +1326,13 @@ pub fn emit_nested(
             let extension_elem = ElementDefinition {
                 id: format!("{}.extension", base_elem_ptr.id),
                 path: format!("{}.extension", base_elem_ptr.path),
-                slice_name: None,
                 min: Some(1),
-                max: None,
-                fixed_url: None,
                 slicing: Some(ElementSlicing {
                     rules: "closed".to_owned(),
-                    discriminator: vec![ElementSlicingDiscriminator {
-                        r#type: "value".to_owned(),
-                        path: "url".to_owned(),
-                    }],
+                    discriminator: vec![make_nested_discriminator(options, &url.0)],
                 }),
-                r#type: None,
-                binding: None,
-                extension: None,
-                constraint: None,
+                source: Some(complex_extension.id.clone()),
+                ..Default::default()
             };
 
             let extension_elem_ptr = ElementPointer {
@@ -631,35 +1343,41 @@ pub fn emit_nested(
             let url_elem = ElementDefinition {
                 id: format!("{}.url", base_elem_ptr.id),
                 path: format!("{}.url", base_elem_ptr.path),
-                slice_name: None,
                 min: Some(1),
                 max: Some("1".to_owned()),
                 fixed_url: Some(url.0.to_owned()),
-                slicing: None,
-                r#type: None,
-                binding: None,
-                extension: None,
-                constraint: None,
+                source: Some(complex_extension.id.clone()),
+                ..Default::default()
             };
 
             let value_elem = ElementDefinition {
                 id: format!("{}.value[x]", base_elem_ptr.id),
                 path: format!("{}.value[x]", base_elem_ptr.path),
-                slice_name: None,
                 min: Some(0),
                 max: Some("0".to_owned()),
-                fixed_url: None,
-                slicing: None,
-                r#type: None,
-                binding: None,
-                extension: None,
-                constraint: None,
+                source: Some(complex_extension.id.clone()),
+                ..Default::default()
             };
 
             let mut nested: Vec<ElementDefinition> = Vec::new();
 
-            for (url, child) in complex_extension.extension {
-                nested.append(&mut emit_nested(counter, &extension_elem_ptr, url, child));
+            let children = in_source_order(options, complex_extension.extension, |order, ext| {
+                extension_position(order, ext)
+            });
+            *emitted += 4;
+            for (url, child) in children {
+                if *emitted > options.max_extension_elements {
+                    break;
+                }
+                nested.append(&mut emit_nested(
+                    counter,
+                    emitted,
+                    &extension_elem_ptr,
+                    url,
+                    child,
+                    options,
+                    errors,
+                ));
             }
 
             let mut res = Vec::new();
@@ -674,11 +1392,55 @@ pub fn emit_nested(
     }
 }
 
-pub fn make_profiles(forest: &inverted::Forest) -> Vec<StructureDefinition> {
+/// Check that every component of every element id (separated by `.` and `:`) fits
+/// into [`MAX_ELEMENT_ID_COMPONENT_LENGTH`].
+pub fn check_element_ids(sd: &StructureDefinition) -> Vec<Error> {
+    sd.differential
+        .element
+        .iter()
+        .filter(|element| {
+            element
+                .id
+                .split(['.', ':'])
+                .any(|component| component.len() > MAX_ELEMENT_ID_COMPONENT_LENGTH)
+        })
+        .map(|element| Error::ElementIdTooLong {
+            url: sd.url.to_owned(),
+            element_id: element.id.to_owned(),
+            attribute_id: element.source.clone().unwrap_or(String::from("<unknown>")),
+        })
+        .collect()
+}
+
+/// Check that no element has a `max` less than its `min`, `*` being unbounded
+pub fn check_cardinalities(sd: &StructureDefinition) -> Vec<Error> {
+    sd.differential
+        .element
+        .iter()
+        .filter_map(|element| {
+            let min = element.min?;
+            let max = element.max.as_deref()?;
+            let bounded_max: usize = max.parse().ok()?;
+            (bounded_max < min).then(|| Error::MaxBelowMin {
+                url: sd.url.to_owned(),
+                element_id: element.id.to_owned(),
+                attribute_id: element.source.clone().unwrap_or(String::from("<unknown>")),
+                min,
+                max: max.to_owned(),
+            })
+        })
+        .collect()
+}
+
+pub fn make_profiles(
+    forest: &inverted::Forest,
+    options: &EmitOptions,
+    version: FhirVersion,
+) -> Vec<StructureDefinition> {
     let mut result: Vec<StructureDefinition> = Vec::new();
     for (rt, trie) in &forest.forest {
         let node = &trie.root;
-        let profile = make_profile_for(rt, node);
+        let profile = make_profile_for(rt, node, options, version);
         if let Some(profile) = profile {
             result.push(profile);
         }
@@ -687,101 +1449,286 @@ pub fn make_profiles(forest: &inverted::Forest) -> Vec<StructureDefinition> {
     result
 }
 
-pub fn make_profile_for(rt: &str, node: &inverted::NormalNode) -> Option<StructureDefinition> {
-    make_profile_recursive(rt, &[], node)
+pub fn make_profile_for(
+    rt: &str,
+    node: &inverted::NormalNode,
+    options: &EmitOptions,
+    version: FhirVersion,
+) -> Option<StructureDefinition> {
+    make_profile_recursive(rt, &[], node, options, version)
 }
 
 pub fn make_profile_recursive(
     rt: &str,
     path: &[String],
     node: &inverted::NormalNode,
+    options: &EmitOptions,
+    version: FhirVersion,
 ) -> Option<StructureDefinition> {
-    let mut elements = make_profile_differential(rt, path, node);
+    let mut elements = make_profile_differential(rt, path, node, options);
 
-    if elements.is_empty() {
+    // The sliced extension elements only make room for the slices, they do not constrain anything
+    let constraints = elements
+        .iter()
+        .filter(|element| element.slicing.is_none() || element.slice_name.is_some())
+        .count();
+    if constraints < options.min_constraints {
         return None;
     }
 
-    let mut differential = vec![ElementDefinition {
-        id: rt.to_owned(),
-        path: rt.to_owned(),
-        slice_name: None,
-        min: None,
-        max: None,
-        fixed_url: None,
-        slicing: None,
-        r#type: None,
-        binding: None,
-        extension: None,
-        constraint: None,
-    }];
+    let mut differential = Vec::new();
+    if options.root_element {
+        differential.push(ElementDefinition {
+            id: rt.to_owned(),
+            path: rt.to_owned(),
+            ..Default::default()
+        });
+    }
     differential.append(&mut elements);
 
+    let name = format!("{rt}_fce");
+    let description = match node {
+        inverted::NormalNode::Complex(complex_node) => complex_node.description.clone(),
+        _ => None,
+    };
+
     Some(StructureDefinition {
         resource_type: "StructureDefinition".to_owned(),
+        id: Some(make_id(&name)),
+        language: None,
+        version: None,
+        use_context: None,
         status: "active".to_string(),
+        date: None,
+        publisher: None,
         base_definition: resource_map::get_type_url(rt).expect(
             "Internal error: could not get url for type. This must have been checked earlier.",
         ),
         r#abstract: false,
         url: format!("http://legacy.aidbox.app/fhir/StructureDefinition/{rt}-fce"),
-        name: format!("{rt}_fce"),
+        title: Some(name.clone()),
+        description: description.or_else(|| options.default_description.clone()),
+        name,
         derivation: "constraint".to_owned(),
         context: None,
         differential: StructureDefinitionDifferential {
             element: differential,
         },
-        kind: "resource".to_owned(),
+        snapshot: None,
+        fhir_version: version.as_str().to_owned(),
+        kind: resource_map::get_type_kind(rt)
+            .expect("Internal error: unknown type. This must have been checked earlier.")
+            .as_str()
+            .to_owned(),
         r#type: rt.to_owned(),
     })
 }
 
+fn make_fhir_path(rt: &str, path: &[String]) -> String {
+    let mut fhir_path = rt.to_owned();
+    for path_component in path {
+        fhir_path.push('.');
+        fhir_path.push_str(path_component);
+    }
+    fhir_path
+}
+
+/// Uppercase the first letter, as in polymorphic choice names (`value` + `string` = `valueString`)
+pub fn capitalize(name: &str) -> String {
+    let mut chars = name.chars();
+    match chars.next() {
+        Some(first) => first.to_uppercase().chain(chars).collect(),
+        None => String::new(),
+    }
+}
+
+/// Element constraining a concrete element in a profile, if there is anything to constrain
+fn make_concrete_element(
+    rt: &str,
+    path: &[String],
+    node: &inverted::ConcreteNode,
+    options: &EmitOptions,
+) -> Option<ElementDefinition> {
+    let binding = value_set::make_enum_binding(
+        &node.id,
+        node.enumeration.as_deref(),
+        options.binding_strength,
+    )
+    .filter(|_| node.value_set.is_none());
+    let is_summary = options.summaries.contains(&node.id).then_some(true);
+    if node.max_length.is_none()
+        && binding.is_none()
+        && node.content_reference.is_none()
+        && is_summary.is_none()
+    {
+        return None;
+    }
+
+    let fhir_path = make_fhir_path(rt, path);
+    Some(ElementDefinition {
+        id: fhir_path.clone(),
+        path: fhir_path,
+        content_reference: node.content_reference.clone(),
+        max_length: node.max_length,
+        binding,
+        is_summary,
+        source: Some(node.id.clone()),
+        ..Default::default()
+    })
+}
+
+/// Element of a polymorphic or complex attribute marked with isSummary, which is otherwise
+/// defined by its children only
+fn make_summary_element(fhir_path: String, id: &str) -> ElementDefinition {
+    ElementDefinition {
+        id: fhir_path.clone(),
+        path: fhir_path,
+        is_summary: Some(true),
+        source: Some(id.to_owned()),
+        ..Default::default()
+    }
+}
+
+/// The sliced `extension` element at `fhir_path`.
+///
+/// Validators require it to precede the extension slices.
+fn make_extension_slicing(fhir_path: &str) -> ElementDefinition {
+    ElementDefinition {
+        id: fhir_path.to_owned(),
+        path: fhir_path.to_owned(),
+        slicing: Some(ElementSlicing {
+            rules: "open".to_owned(),
+            discriminator: vec![ElementSlicingDiscriminator {
+                r#type: "value".to_owned(),
+                path: "url".to_owned(),
+            }],
+        }),
+        ..Default::default()
+    }
+}
+
+/// Slice of the `extension` element at `fhir_path` using the extension definition
+fn make_extension_slice(
+    fhir_path: &str,
+    url: &ExtUrl,
+    ext: &inverted::Extension,
+) -> ElementDefinition {
+    let fce_property = ext.get_fce_property();
+
+    let min = if ext.is_required() { Some(1) } else { None };
+    let max = if ext.is_array() {
+        Some("*".to_owned())
+    } else {
+        Some("1".to_owned())
+    };
+
+    ElementDefinition {
+        id: format!("{fhir_path}:{fce_property}"),
+        path: fhir_path.to_owned(),
+        slice_name: Some(fce_property.to_owned()),
+        min,
+        max,
+        r#type: Some(vec![ElementType {
+            code: "Extension".to_owned(),
+            target_profile: None,
+            profile: Some(vec![url.0.to_owned()]),
+        }]),
+        source: Some(ext.get_id().to_owned()),
+        ..Default::default()
+    }
+}
+
 pub fn make_profile_differential(
     rt: &str,
     path: &[String],
     node: &inverted::NormalNode,
+    options: &EmitOptions,
 ) -> Vec<ElementDefinition> {
     let mut result: Vec<ElementDefinition> = Vec::new();
+
+    match node {
+        NormalNode::Concrete(node) => {
+            if let Some(element) = make_concrete_element(rt, path, node, options) {
+                result.push(element);
+            }
+        }
+        NormalNode::Polymorphic(node) => {
+            let fhir_path = format!("{}[x]", make_fhir_path(rt, path));
+            if options.summaries.contains(&node.id) {
+                result.push(make_summary_element(fhir_path.clone(), &node.id));
+            }
+            let name = path.last().map(String::as_str).unwrap_or_default();
+            let leaves = in_source_order(options, &node.children, |order, leaf| {
+                declared_at(order, &leaf.id)
+            });
+            for (type_name, leaf) in leaves {
+                let binding = value_set::make_enum_binding(
+                    &leaf.id,
+                    leaf.enumeration.as_deref(),
+                    options.binding_strength,
+                )
+                .filter(|_| leaf.value_set.is_none());
+                let is_summary = options.summaries.contains(&leaf.id).then_some(true);
+                if leaf.max_length.is_none() && binding.is_none() && is_summary.is_none() {
+                    continue;
+                }
+                let slice_name = format!("{name}{}", capitalize(type_name));
+                result.push(ElementDefinition {
+                    id: format!("{fhir_path}:{slice_name}"),
+                    path: fhir_path.clone(),
+                    slice_name: Some(slice_name),
+                    max_length: leaf.max_length,
+                    binding,
+                    is_summary,
+                    source: Some(leaf.id.clone()),
+                    ..Default::default()
+                });
+            }
+        }
+        NormalNode::Complex(node) if options.summaries.contains(&node.id) => {
+            result.push(make_summary_element(make_fhir_path(rt, path), &node.id));
+        }
+        _ => (),
+    }
+
     let extensions = match node {
         NormalNode::Complex(node) => Some(&node.extension),
         NormalNode::Inferred(node) => Some(&node.extension),
         _ => None,
     };
     if let Some(extensions) = extensions {
-        let mut fhir_path = rt.to_owned();
-        for path_component in path {
-            fhir_path.push('.');
-            fhir_path.push_str(path_component);
+        let extensions = in_source_order(options, extensions, |order, ext| {
+            extension_position(order, ext)
+        });
+        let (modifier_extensions, extensions): (Vec<_>, Vec<_>) = extensions
+            .into_iter()
+            .partition(|(_, ext)| options.modifiers.contains(ext.get_id()));
+
+        for (element, extensions) in [
+            ("extension", extensions),
+            ("modifierExtension", modifier_extensions),
+        ] {
+            if extensions.is_empty() {
+                continue;
+            }
+            let fhir_path = format!("{}.{element}", make_fhir_path(rt, path));
+            result.push(make_extension_slicing(&fhir_path));
+            for (url, ext) in extensions {
+                let mut slice = make_extension_slice(&fhir_path, url, ext);
+                if options.summaries.contains(ext.get_id()) {
+                    slice.is_summary = Some(true);
+                }
+                result.push(slice);
+            }
         }
-        fhir_path.push_str(".extension");
-
-        for (url, ext) in extensions {
-            let fce_property = ext.get_fce_property();
-
-            let min = if ext.is_required() { Some(1) } else { None };
-            let max = if ext.is_array() {
-                Some("*".to_owned())
-            } else {
-                Some("1".to_owned())
-            };
+    }
 
-            result.push(ElementDefinition {
-                id: format!("{fhir_path}:{fce_property}"),
-                path: fhir_path.clone(),
-                slice_name: Some(fce_property.to_owned()),
-                min,
-                max,
-                fixed_url: None,
-                slicing: None,
-                r#type: Some(vec![ElementType {
-                    code: "Extension".to_owned(),
-                    target_profile: None,
-                    profile: Some(vec![url.0.to_owned()]),
-                }]),
-                binding: None,
-                extension: None,
-                constraint: None,
-            })
+    // Elements of the children are marked by the recursive calls
+    for element in &mut result {
+        if options.must_support.contains(&element.path)
+            || options.must_support.contains(&element.id)
+        {
+            element.must_support = Some(true);
         }
     }
 
@@ -792,12 +1739,1266 @@ pub fn make_profile_differential(
     };
 
     if let Some(children) = children {
+        let children = in_source_order(options, children, |order, child| {
+            node_position(order, child)
+        });
         for (name, child) in children {
             let mut subpath = path.to_owned();
             subpath.push(name.to_owned());
-            let mut subres = make_profile_differential(rt, &subpath, child);
+            let mut subres = make_profile_differential(rt, &subpath, child, options);
             result.append(&mut subres);
         }
     }
     result
 }
+
+fn trim_empty_inferred_recursive(
+    rt: &str,
+    path: &[String],
+    node: &mut inverted::NormalNode,
+    options: &EmitOptions,
+) -> usize {
+    let children = match node {
+        NormalNode::Complex(node) => &mut node.children,
+        NormalNode::Inferred(node) => &mut node.children,
+        _ => return 0,
+    };
+    let mut trimmed = 0;
+    children.retain(|name, child| {
+        let mut subpath = path.to_owned();
+        subpath.push(name.to_owned());
+        if matches!(child, NormalNode::Inferred(_))
+            && make_profile_differential(rt, &subpath, child, options).is_empty()
+        {
+            trimmed += 1;
+            return false;
+        }
+        trimmed += trim_empty_inferred_recursive(rt, &subpath, child, options);
+        true
+    });
+    trimmed
+}
+
+/// Remove inferred nodes whose subtree produces no profile elements, returning how many were
+/// removed. Such a subtree has no extensions either, every extension is sliced in the profile.
+pub fn trim_empty_inferred(forest: &mut inverted::Forest, options: &EmitOptions) -> usize {
+    forest
+        .forest
+        .iter_mut()
+        .map(|(rt, trie)| trim_empty_inferred_recursive(rt, &[], &mut trie.root, options))
+        .sum()
+}
+
+/// Elements produced by a single attribute, see [`emit_attribute`]
+#[derive(Debug, Clone)]
+pub struct AttributeElements {
+    /// Elements added to the profile of the attribute resource type (without the root element)
+    pub profile: Vec<ElementDefinition>,
+    /// Differential of the extension definition if the attribute is an extension
+    pub extension: Option<Vec<ElementDefinition>>,
+}
+
+/// Convert one attribute to the elements it produces, without building a forest.
+///
+/// Only concrete attributes are supported. A concrete extension produces an extension
+/// definition and a slice in the profile. A concrete non-extension attribute produces a profile
+/// element only if it constrains the base element (e.g. with maxLength). Polymorphic and complex
+/// attributes are rejected, they are defined by their children and need the whole forest.
+pub fn emit_attribute(
+    attribute: &typed::Attribute,
+    options: &EmitOptions,
+) -> Result<AttributeElements, Error> {
+    let AttributeKind::Concrete(kind) = &attribute.kind else {
+        return Err(Error::NotSupportedInIsolation {
+            attribute_id: attribute.id.clone(),
+        });
+    };
+
+    let Some(url) = &attribute.fce else {
+        let node = inverted::ConcreteNode {
+            array: attribute.array,
+            id: attribute.id.clone(),
+            refers: kind.refers.clone(),
+            required: attribute.required,
+            target: kind.target.clone(),
+            value_set: kind.value_set.clone(),
+            enumeration: kind.enumeration.clone(),
+            max_length: kind.max_length,
+            description: kind.description.clone(),
+            content_reference: kind.content_reference.clone(),
+        };
+        return Ok(AttributeElements {
+            profile: make_concrete_element(
+                &attribute.resource_type,
+                &attribute.path,
+                &node,
+                options,
+            )
+            .into_iter()
+            .collect(),
+            extension: None,
+        });
+    };
+
+    let Some((fce_property, parent_path)) = attribute.path.split_last() else {
+        return Err(Error::NotSupportedInIsolation {
+            attribute_id: attribute.id.clone(),
+        });
+    };
+
+    let extension = inverted::Extension::Simple(inverted::SimpleExtension {
+        array: attribute.array,
+        targets: BTreeMap::from([(
+            kind.target.clone(),
+            inverted::ExtensionTarget {
+                id: attribute.id.clone(),
+                refers: kind.refers.clone(),
+                value_set: kind.value_set.clone(),
+                enumeration: kind.enumeration.clone(),
+                max_length: kind.max_length,
+                description: kind.description.clone(),
+                content_reference: kind.content_reference.clone(),
+            },
+        )]),
+        fce_property: fce_property.clone(),
+        id: attribute.id.clone(),
+        required: attribute.required,
+    });
+
+    let fhir_path = format!(
+        "{}.extension",
+        make_fhir_path(&attribute.resource_type, parent_path)
+    );
+    let slice = make_extension_slice(&fhir_path, &ExtUrl(url.clone()), &extension);
+
+    let mut counter: usize = 1;
+    let mut emitted: usize = 0;
+    let mut errors: Vec<Error> = Vec::new();
+    let differential = emit_differential(
+        &mut counter,
+        &mut emitted,
+        url.clone(),
+        extension,
+        options,
+        &mut errors,
+    );
+    if let Some(error) = errors.pop() {
+        return Err(error);
+    }
+    Ok(AttributeElements {
+        profile: vec![make_extension_slicing(&fhir_path), slice],
+        extension: Some(differential),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::FhirVersion;
+    use crate::attribute::typed::{
+        Attribute, AttributeKind, AttributeKindComplex, AttributeKindConcrete, AttributeKindPoly,
+    };
+    use crate::trie::{extension_separated, fhir, inverted, path, raw};
+    use std::collections::{BTreeMap, BTreeSet};
+
+    fn concrete_attribute(path: &[&str], target: &str, fce: Option<&str>) -> Attribute {
+        Attribute {
+            id: format!("Patient.{}", path.join(".")),
+            path: path.iter().map(|s| s.to_string()).collect(),
+            resource_type: "Patient".to_owned(),
+            kind: AttributeKind::Concrete(AttributeKindConcrete {
+                target: target.to_owned(),
+                value_set: None,
+                refers: None,
+                enumeration: None,
+                max_length: None,
+                description: None,
+                content_reference: None,
+                concept_map: None,
+            }),
+            array: false,
+            required: false,
+            modifier: false,
+            summary: false,
+            fce: fce.map(|s| s.to_owned()),
+            filename: None,
+            module: None,
+        }
+    }
+
+    fn build_forest(attrs: &[Attribute]) -> inverted::Forest {
+        let (raw_forest, errors) = raw::Forest::build_from_attributes(attrs, false);
+        assert!(errors.is_empty());
+        let (path_forest, errors) = path::Forest::build_from(raw_forest);
+        assert!(errors.is_empty());
+        let (forest, errors) = extension_separated::Forest::build_from(path_forest);
+        assert!(
+            errors
+                .iter()
+                .all(|error| matches!(error, extension_separated::Error::InferredElement { .. }))
+        );
+        let (forest, errors) = inverted::Forest::build_from(forest);
+        assert!(errors.is_empty());
+        forest
+    }
+
+    #[test]
+    fn test_long_element_id_component() {
+        let long_name = "a".repeat(fhir::MAX_ELEMENT_ID_COMPONENT_LENGTH + 1);
+        let forest = build_forest(&[concrete_attribute(
+            &["contact", "name", &long_name],
+            "string",
+            Some("http://example.org/long"),
+        )]);
+
+        let profiles =
+            fhir::make_profiles(&forest, &fhir::EmitOptions::default(), FhirVersion::V4_0_1);
+        assert_eq!(profiles.len(), 1);
+
+        let errors = fhir::check_element_ids(&profiles[0]);
+        assert_eq!(errors.len(), 1);
+        let fhir::Error::ElementIdTooLong {
+            element_id,
+            attribute_id,
+            ..
+        } = &errors[0]
+        else {
+            panic!("Expected ElementIdTooLong error");
+        };
+        assert_eq!(
+            element_id,
+            &format!("Patient.contact.name.extension:{long_name}")
+        );
+        assert_eq!(attribute_id, &format!("Patient.contact.name.{long_name}"));
+    }
+
+    #[test]
+    fn test_max_below_min() {
+        let mut attr = concrete_attribute(&["nickname"], "string", Some("http://example.org/nick"));
+        attr.required = true;
+        let (mut exts, _) = fhir::collect_extensions(
+            build_forest(&[attr]),
+            &fhir::EmitOptions::default(),
+            FhirVersion::V4_0_1,
+        );
+        assert!(fhir::check_cardinalities(&exts[0]).is_empty());
+
+        let value = &mut exts[0].differential.element[2];
+        assert_eq!(value.min, Some(1));
+        value.max = Some("0".to_owned());
+        let errors = fhir::check_cardinalities(&exts[0]);
+        assert!(
+            matches!(
+                errors.as_slice(),
+                [fhir::Error::MaxBelowMin { element_id, min: 1, max, .. }]
+                    if element_id == "Extension.value[x]" && max == "0"
+            ),
+            "{errors:?}"
+        );
+
+        exts[0].differential.element[2].max = Some("*".to_owned());
+        assert!(fhir::check_cardinalities(&exts[0]).is_empty());
+    }
+
+    #[test]
+    fn test_short_element_id_components() {
+        let forest = build_forest(&[concrete_attribute(
+            &["contact", "name", "nickname"],
+            "string",
+            Some("http://example.org/nickname"),
+        )]);
+
+        let profiles =
+            fhir::make_profiles(&forest, &fhir::EmitOptions::default(), FhirVersion::V4_0_1);
+        assert!(fhir::check_element_ids(&profiles[0]).is_empty());
+    }
+
+    #[test]
+    fn test_max_length_on_concrete_element() {
+        let mut attr = concrete_attribute(&["nickname"], "string", None);
+        if let AttributeKind::Concrete(kind) = &mut attr.kind {
+            kind.max_length = Some(50);
+        }
+        let forest = build_forest(&[attr]);
+
+        let profiles =
+            fhir::make_profiles(&forest, &fhir::EmitOptions::default(), FhirVersion::V4_0_1);
+        assert_eq!(profiles.len(), 1);
+
+        let element = &profiles[0].differential.element[1];
+        assert_eq!(element.id, "Patient.nickname");
+        assert_eq!(element.max_length, Some(50));
+        assert_eq!(element.source.as_deref(), Some("Patient.nickname"));
+    }
+
+    #[test]
+    fn test_complex_type_profile() {
+        let mut attr = concrete_attribute(&["nickname"], "string", Some("http://example.org/nick"));
+        attr.id = "HumanName.nickname".to_owned();
+        attr.resource_type = "HumanName".to_owned();
+        let forest = build_forest(&[attr]);
+
+        let profiles =
+            fhir::make_profiles(&forest, &fhir::EmitOptions::default(), FhirVersion::V4_0_1);
+        assert_eq!(profiles.len(), 1);
+        assert_eq!(profiles[0].kind, "complex-type");
+        assert_eq!(profiles[0].r#type, "HumanName");
+        assert_eq!(
+            profiles[0].base_definition,
+            "http://hl7.org/fhir/StructureDefinition/HumanName"
+        );
+    }
+
+    #[test]
+    fn test_binding_description() {
+        let mut attr = concrete_attribute(&["status"], "code", Some("http://example.org/status"));
+        if let AttributeKind::Concrete(kind) = &mut attr.kind {
+            kind.value_set = Some("http://example.org/ValueSet/status".to_owned());
+            kind.description = Some("Status of the patient record".to_owned());
+        }
+        let forest = build_forest(&[attr]);
+
+        let (exts, errors) =
+            fhir::collect_extensions(forest, &fhir::EmitOptions::default(), FhirVersion::V4_0_1);
+        assert!(errors.is_empty());
+        assert_eq!(exts.len(), 1);
+
+        let binding = exts[0]
+            .differential
+            .element
+            .iter()
+            .find_map(|element| element.binding.as_ref())
+            .expect("Expected a binding");
+        assert_eq!(binding.value_set, "http://example.org/ValueSet/status");
+        assert_eq!(
+            binding.description.as_deref(),
+            Some("Status of the patient record")
+        );
+        assert_eq!(binding.strength, "required");
+    }
+
+    #[test]
+    fn test_binding_strength() {
+        let mut attr = concrete_attribute(&["status"], "code", Some("http://example.org/status"));
+        if let AttributeKind::Concrete(kind) = &mut attr.kind {
+            kind.value_set = Some("http://example.org/ValueSet/status".to_owned());
+        }
+        let mut kind = concrete_attribute(&["kind"], "string", None);
+        if let AttributeKind::Concrete(concrete) = &mut kind.kind {
+            concrete.enumeration = Some(vec!["a".to_owned()]);
+        }
+        let forest = build_forest(&[attr, kind]);
+        let options = fhir::EmitOptions {
+            binding_strength: fhir::BindingStrength::Extensible,
+            ..Default::default()
+        };
+
+        let profiles = fhir::make_profiles(&forest, &options, FhirVersion::V4_0_1);
+        let (exts, _) = fhir::collect_extensions(forest, &options, FhirVersion::V4_0_1);
+        let strengths: Vec<&str> = exts
+            .iter()
+            .chain(&profiles)
+            .flat_map(|sd| &sd.differential.element)
+            .filter_map(|element| element.binding.as_ref())
+            .map(|binding| binding.strength.as_str())
+            .collect();
+        assert_eq!(strengths, ["extensible", "extensible"]);
+    }
+
+    #[test]
+    fn test_single_target_value_element() {
+        let attr = concrete_attribute(&["nickname"], "string", Some("http://example.org/nick"));
+
+        let (exts, _) = fhir::collect_extensions(
+            build_forest(std::slice::from_ref(&attr)),
+            &fhir::EmitOptions::default(),
+            FhirVersion::V4_0_1,
+        );
+        let value = &exts[0].differential.element[2];
+        assert_eq!(value.id, "Extension.value[x]");
+        let types = value.r#type.as_ref().unwrap();
+        assert_eq!(types.len(), 1);
+        assert_eq!(types[0].code, "string");
+
+        let options = fhir::EmitOptions {
+            typed_value_element: true,
+            ..Default::default()
+        };
+        let (exts, _) =
+            fhir::collect_extensions(build_forest(&[attr]), &options, FhirVersion::V4_0_1);
+        let value = &exts[0].differential.element[2];
+        assert_eq!(value.id, "Extension.valueString");
+        assert_eq!(value.path, "Extension.valueString");
+        assert_eq!(value.r#type.as_ref().unwrap()[0].code, "string");
+    }
+
+    #[test]
+    fn test_title_and_description() {
+        let root = Attribute {
+            id: "Patient".to_owned(),
+            path: Vec::new(),
+            resource_type: "Patient".to_owned(),
+            kind: AttributeKind::Complex(AttributeKindComplex {
+                open: false,
+                description: Some("Patient of the clinic".to_owned()),
+            }),
+            array: false,
+            required: false,
+            modifier: false,
+            summary: false,
+            fce: None,
+            filename: None,
+            module: None,
+        };
+        let nickname = concrete_attribute(&["nickname"], "string", Some("http://example.org/nick"));
+        let options = fhir::EmitOptions {
+            default_description: Some("Legacy profile".to_owned()),
+            ..Default::default()
+        };
+
+        let forest = build_forest(&[root, nickname.clone()]);
+        let profiles = fhir::make_profiles(&forest, &options, FhirVersion::V4_0_1);
+        assert_eq!(profiles[0].title.as_deref(), Some("Patient_fce"));
+        assert_eq!(
+            profiles[0].description.as_deref(),
+            Some("Patient of the clinic")
+        );
+        let (exts, _) = fhir::collect_extensions(forest, &options, FhirVersion::V4_0_1);
+        assert_eq!(exts[0].title.as_deref(), Some("nickname"));
+        assert_eq!(
+            exts[0].description.as_deref(),
+            Some("Auto-converted from Aidbox first-class extension nickname")
+        );
+
+        let forest = build_forest(&[nickname]);
+        let profiles = fhir::make_profiles(&forest, &options, FhirVersion::V4_0_1);
+        assert_eq!(profiles[0].description.as_deref(), Some("Legacy profile"));
+        let profiles =
+            fhir::make_profiles(&forest, &fhir::EmitOptions::default(), FhirVersion::V4_0_1);
+        let profile = serde_json::to_value(&profiles[0]).unwrap();
+        assert!(profile.get("description").is_none());
+    }
+
+    #[test]
+    fn test_emit_single_attribute() {
+        let attr = concrete_attribute(
+            &["contact", "nickname"],
+            "string",
+            Some("http://example.org/nick"),
+        );
+        let elements = fhir::emit_attribute(&attr, &fhir::EmitOptions::default()).unwrap();
+
+        assert_eq!(elements.profile.len(), 2);
+        assert_eq!(elements.profile[0].id, "Patient.contact.extension");
+        assert_eq!(elements.profile[1].id, "Patient.contact.extension:nickname");
+
+        // Same elements as produced by the whole pipeline
+        let (exts, _) = fhir::collect_extensions(
+            build_forest(&[attr]),
+            &fhir::EmitOptions::default(),
+            FhirVersion::V4_0_1,
+        );
+        let extension = elements.extension.unwrap();
+        let expected = &exts[0].differential.element;
+        assert_eq!(extension.len(), expected.len());
+        for (actual, expected) in extension.iter().zip(expected) {
+            assert_eq!(actual.id, expected.id);
+            assert_eq!(actual.fixed_url, expected.fixed_url);
+        }
+    }
+
+    #[test]
+    fn test_reference_target_profile_per_version() {
+        let mut attr = concrete_attribute(
+            &["careTeam"],
+            "Reference",
+            Some("http://example.org/care-team"),
+        );
+        if let AttributeKind::Concrete(kind) = &mut attr.kind {
+            kind.refers = Some(vec!["Practitioner".to_owned()]);
+        }
+
+        // Core canonical urls are the same in every version
+        for version in [
+            FhirVersion::V4_0_0,
+            FhirVersion::V4_0_1,
+            FhirVersion::V4_3_0,
+            FhirVersion::V5_0_0,
+        ] {
+            let (exts, _) = fhir::collect_extensions(
+                build_forest(std::slice::from_ref(&attr)),
+                &fhir::EmitOptions::default(),
+                version,
+            );
+            let value = exts[0]
+                .differential
+                .element
+                .iter()
+                .find(|element| element.id == "Extension.value[x]")
+                .unwrap();
+            let types = value.r#type.as_ref().unwrap();
+            assert_eq!(types[0].code, "Reference");
+            assert_eq!(
+                types[0].target_profile,
+                Some(vec![
+                    "http://hl7.org/fhir/StructureDefinition/Practitioner".to_owned()
+                ])
+            );
+        }
+    }
+
+    #[test]
+    fn test_required_polymorphic_extension() {
+        let root = Attribute {
+            id: "Patient.dose".to_owned(),
+            path: vec!["dose".to_owned()],
+            resource_type: "Patient".to_owned(),
+            kind: AttributeKind::Poly(AttributeKindPoly {
+                targets: vec!["integer".to_owned(), "string".to_owned()],
+            }),
+            array: false,
+            required: true,
+            modifier: false,
+            summary: false,
+            fce: Some("http://example.org/dose".to_owned()),
+            filename: None,
+            module: None,
+        };
+        let forest = build_forest(&[
+            root,
+            concrete_attribute(&["dose", "integer"], "integer", None),
+            concrete_attribute(&["dose", "string"], "string", None),
+        ]);
+
+        let profiles =
+            fhir::make_profiles(&forest, &fhir::EmitOptions::default(), FhirVersion::V4_0_1);
+        let slice = &profiles[0].differential.element[2];
+        assert_eq!(slice.id, "Patient.extension:dose");
+        assert_eq!(slice.min, Some(1));
+        assert_eq!(slice.max.as_deref(), Some("1"));
+
+        let (exts, errors) =
+            fhir::collect_extensions(forest, &fhir::EmitOptions::default(), FhirVersion::V4_0_1);
+        assert!(errors.is_empty());
+        let elements = &exts[0].differential.element;
+        assert_eq!(elements[0].id, "Extension");
+        assert_eq!(elements[0].min, Some(1));
+        assert_eq!(elements[0].max.as_deref(), Some("1"));
+        assert_eq!(elements[2].id, "Extension.value[x]");
+        assert_eq!(elements[2].min, Some(1));
+        assert_eq!(elements[2].r#type.as_ref().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_polymorphic_extension_value_slices() {
+        let root = Attribute {
+            id: "Patient.dose".to_owned(),
+            path: vec!["dose".to_owned()],
+            resource_type: "Patient".to_owned(),
+            kind: AttributeKind::Poly(AttributeKindPoly {
+                targets: vec!["integer".to_owned(), "string".to_owned()],
+            }),
+            array: false,
+            required: false,
+            modifier: false,
+            summary: false,
+            fce: Some("http://example.org/dose".to_owned()),
+            filename: None,
+            module: None,
+        };
+        let mut string = concrete_attribute(&["dose", "string"], "string", None);
+        if let AttributeKind::Concrete(kind) = &mut string.kind {
+            kind.value_set = Some("http://example.org/ValueSet/dose-units".to_owned());
+        }
+        let forest = build_forest(&[
+            root,
+            concrete_attribute(&["dose", "integer"], "integer", None),
+            string,
+        ]);
+
+        let (exts, errors) =
+            fhir::collect_extensions(forest, &fhir::EmitOptions::default(), FhirVersion::V4_0_1);
+        assert!(errors.is_empty());
+        let elements = serde_json::to_value(&exts[0].differential.element[2..]).unwrap();
+        assert_eq!(
+            elements,
+            serde_json::json!([
+                {
+                    "id": "Extension.value[x]",
+                    "path": "Extension.value[x]",
+                    "min": 1,
+                    "max": "1",
+                    "slicing": {
+                        "rules": "closed",
+                        "discriminator": [{"type": "type", "path": "$this"}],
+                    },
+                    "type": [{"code": "integer"}, {"code": "string"}],
+                },
+                {
+                    "id": "Extension.value[x]:valueInteger",
+                    "path": "Extension.value[x]",
+                    "sliceName": "valueInteger",
+                    "min": 0,
+                    "max": "1",
+                    "type": [{"code": "integer"}],
+                },
+                {
+                    "id": "Extension.value[x]:valueString",
+                    "path": "Extension.value[x]",
+                    "sliceName": "valueString",
+                    "min": 0,
+                    "max": "1",
+                    "type": [{"code": "string"}],
+                    "binding": {
+                        "strength": "required",
+                        "valueSet": "http://example.org/ValueSet/dose-units",
+                    },
+                },
+            ])
+        );
+    }
+
+    #[test]
+    fn test_array_polymorphic_extension() {
+        let root = Attribute {
+            id: "Patient.dose".to_owned(),
+            path: vec!["dose".to_owned()],
+            resource_type: "Patient".to_owned(),
+            kind: AttributeKind::Poly(AttributeKindPoly {
+                targets: vec!["integer".to_owned(), "string".to_owned()],
+            }),
+            array: true,
+            required: false,
+            modifier: false,
+            summary: false,
+            fce: Some("http://example.org/dose".to_owned()),
+            filename: None,
+            module: None,
+        };
+        let forest = build_forest(&[
+            root,
+            concrete_attribute(&["dose", "integer"], "integer", None),
+            concrete_attribute(&["dose", "string"], "string", None),
+        ]);
+
+        let profiles =
+            fhir::make_profiles(&forest, &fhir::EmitOptions::default(), FhirVersion::V4_0_1);
+        let slice = &profiles[0].differential.element[2];
+        assert_eq!(slice.id, "Patient.extension:dose");
+        assert_eq!(slice.max.as_deref(), Some("*"));
+
+        let (exts, errors) =
+            fhir::collect_extensions(forest, &fhir::EmitOptions::default(), FhirVersion::V4_0_1);
+        assert!(errors.is_empty());
+        let root = &exts[0].differential.element[0];
+        assert_eq!(root.id, "Extension");
+        assert_eq!(root.max.as_deref(), Some("*"));
+    }
+
+    #[test]
+    fn test_extension_slicing_precedes_slices() {
+        let forest = build_forest(&[
+            concrete_attribute(&["nickname"], "string", Some("http://example.org/nickname")),
+            concrete_attribute(&["alias"], "string", Some("http://example.org/alias")),
+            concrete_attribute(
+                &["contact", "nickname"],
+                "string",
+                Some("http://example.org/cn"),
+            ),
+        ]);
+
+        let profiles =
+            fhir::make_profiles(&forest, &fhir::EmitOptions::default(), FhirVersion::V4_0_1);
+        let elements = &profiles[0].differential.element;
+        let ids: Vec<&str> = elements.iter().map(|e| e.id.as_str()).collect();
+        assert_eq!(
+            ids,
+            [
+                "Patient",
+                "Patient.extension",
+                "Patient.extension:alias",
+                "Patient.extension:nickname",
+                "Patient.contact.extension",
+                "Patient.contact.extension:nickname",
+            ]
+        );
+
+        for idx in [1, 4] {
+            let slicing = elements[idx].slicing.as_ref().expect("Expected slicing");
+            assert_eq!(slicing.rules, "open");
+            assert_eq!(slicing.discriminator.len(), 1);
+            assert_eq!(slicing.discriminator[0].r#type, "value");
+            assert_eq!(slicing.discriminator[0].path, "url");
+        }
+        // One slicing element per parent, however many extensions share it
+        let slicings = elements.iter().filter(|e| e.slicing.is_some()).count();
+        assert_eq!(slicings, 2);
+    }
+
+    #[test]
+    fn test_stable_ids() {
+        let attrs = [
+            concrete_attribute(&["nick_name"], "string", Some("http://example.org/nick")),
+            concrete_attribute(&["alias"], "string", Some("http://example.org/alias")),
+        ];
+
+        let ids = |attrs: &[Attribute]| {
+            let forest = build_forest(attrs);
+            let profiles =
+                fhir::make_profiles(&forest, &fhir::EmitOptions::default(), FhirVersion::V4_0_1);
+            let (exts, _) = fhir::collect_extensions(
+                forest,
+                &fhir::EmitOptions::default(),
+                FhirVersion::V4_0_1,
+            );
+            exts.iter()
+                .chain(profiles.iter())
+                .map(|sd| sd.id.clone().unwrap())
+                .collect::<Vec<_>>()
+        };
+
+        let first = ids(&attrs);
+        // Extension ids follow the url, not the attribute name
+        assert_eq!(first, ["alias", "nick", "Patient-fce"]);
+
+        // Adding an unrelated attribute does not change the existing ids
+        let mut more = attrs.to_vec();
+        more.push(concrete_attribute(
+            &["zzz"],
+            "string",
+            Some("http://example.org/zzz"),
+        ));
+        assert_eq!(ids(&more)[..2], first[..2]);
+        assert_eq!(ids(&more)[3], first[2]);
+    }
+
+    #[test]
+    fn test_merge_extension_contexts() {
+        let attribute = |resource_type: &str| Attribute {
+            id: format!("{resource_type}.nickname"),
+            resource_type: resource_type.to_owned(),
+            ..concrete_attribute(&["nickname"], "string", Some("http://example.org/nickname"))
+        };
+        let attrs = [attribute("Patient"), attribute("Practitioner")];
+
+        let contexts = |merge_contexts| {
+            let options = fhir::EmitOptions {
+                merge_contexts,
+                ..Default::default()
+            };
+            let (exts, errors) =
+                fhir::collect_extensions(build_forest(&attrs), &options, FhirVersion::V4_0_1);
+            assert!(errors.is_empty());
+            exts.iter()
+                .map(|sd| {
+                    sd.context
+                        .iter()
+                        .flatten()
+                        .map(|context| context.expression.clone())
+                        .collect::<Vec<_>>()
+                })
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(contexts(true), [vec!["Patient", "Practitioner"]]);
+        assert_eq!(contexts(false), [vec!["Patient"], vec!["Practitioner"]]);
+
+        // Different definitions with the same url are not merged
+        let mut attrs = attrs;
+        attrs[1].kind = concrete_attribute(&[], "integer", None).kind;
+        let options = fhir::EmitOptions::default();
+        let (exts, _) =
+            fhir::collect_extensions(build_forest(&attrs), &options, FhirVersion::V4_0_1);
+        assert_eq!(exts.len(), 2);
+    }
+
+    #[test]
+    fn test_make_id() {
+        assert_eq!(fhir::make_id("Patient_fce"), "Patient-fce");
+        assert_eq!(
+            fhir::extension_id("http://example.org/ext/nick_name/"),
+            "nick-name"
+        );
+        assert_eq!(fhir::extension_id("urn:uuid:1234"), "urn-uuid-1234");
+
+        let long = |suffix: &str| fhir::make_id(&format!("{}{suffix}", "a".repeat(80)));
+        assert_eq!(long("x").len(), fhir::MAX_ID_LENGTH);
+        assert!(long("x").starts_with(&"a".repeat(55)));
+        assert_ne!(long("x"), long("y"));
+        assert_eq!(long("x"), long("x"));
+    }
+
+    #[test]
+    fn test_min_constraints() {
+        let forest = build_forest(&[
+            concrete_attribute(&["nickname"], "string", Some("http://example.org/nickname")),
+            concrete_attribute(&["alias"], "string", Some("http://example.org/alias")),
+        ]);
+
+        let options = |min_constraints| fhir::EmitOptions {
+            min_constraints,
+            ..Default::default()
+        };
+        assert_eq!(
+            fhir::make_profiles(&forest, &options(2), FhirVersion::V4_0_1).len(),
+            1
+        );
+        assert_eq!(
+            fhir::make_profiles(&forest, &options(3), FhirVersion::V4_0_1).len(),
+            0
+        );
+    }
+
+    #[test]
+    fn test_no_root_element() {
+        let forest = build_forest(&[concrete_attribute(
+            &["nickname"],
+            "string",
+            Some("http://example.org/nickname"),
+        )]);
+
+        let ids = |root_element| {
+            let options = fhir::EmitOptions {
+                root_element,
+                ..Default::default()
+            };
+            fhir::make_profiles(&forest, &options, FhirVersion::V4_0_1)[0]
+                .differential
+                .element
+                .iter()
+                .map(|element| element.id.clone())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            ids(true),
+            ["Patient", "Patient.extension", "Patient.extension:nickname"]
+        );
+        assert_eq!(
+            ids(false),
+            ["Patient.extension", "Patient.extension:nickname"]
+        );
+    }
+
+    #[test]
+    fn test_trim_empty_inferred() {
+        let mut forest = build_forest(&[
+            concrete_attribute(&["nickname"], "string", Some("http://example.org/nickname")),
+            concrete_attribute(&["contact", "name", "text"], "string", None),
+            concrete_attribute(
+                &["contact", "telecom", "note"],
+                "string",
+                Some("http://example.org/note"),
+            ),
+        ]);
+        let options = fhir::EmitOptions::default();
+        let differential = fhir::make_profiles(&forest, &options, FhirVersion::V4_0_1)[0]
+            .differential
+            .clone();
+
+        assert_eq!(fhir::trim_empty_inferred(&mut forest, &options), 1);
+        let inverted::NormalNode::Inferred(root) = &forest.forest["Patient"].root else {
+            panic!("Expected inferred root");
+        };
+        let inverted::NormalNode::Inferred(contact) = &root.children["contact"] else {
+            panic!("Expected inferred contact");
+        };
+        let names: Vec<&str> = contact.children.keys().map(String::as_str).collect();
+        assert_eq!(names, ["telecom"]);
+
+        let trimmed = fhir::make_profiles(&forest, &options, FhirVersion::V4_0_1)[0]
+            .differential
+            .clone();
+        assert_eq!(
+            serde_json::to_value(trimmed).unwrap(),
+            serde_json::to_value(differential).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_derived_extension() {
+        let base = "http://example.org/StructureDefinition/name";
+        let forest = build_forest(&[
+            concrete_attribute(&["nickname"], "string", Some("http://example.org/nickname")),
+            concrete_attribute(&["alias"], "string", Some("http://example.org/alias")),
+        ]);
+
+        let options = fhir::EmitOptions {
+            extension_bases: BTreeMap::from([(
+                "http://example.org/nickname".to_owned(),
+                base.to_owned(),
+            )]),
+            ..Default::default()
+        };
+        let (exts, _) = fhir::collect_extensions(forest, &options, FhirVersion::V4_0_1);
+        let base_of = |url: &str| {
+            exts.iter()
+                .find(|sd| sd.url == url)
+                .map(|sd| sd.base_definition.as_str())
+        };
+        assert_eq!(base_of("http://example.org/nickname"), Some(base));
+        assert_eq!(
+            base_of("http://example.org/alias"),
+            Some("http://hl7.org/fhir/StructureDefinition/Extension")
+        );
+    }
+
+    #[test]
+    fn test_primitive_element_extension() {
+        let forest = build_forest(&[concrete_attribute(
+            &["_birthDate", "birthTime"],
+            "dateTime",
+            Some("http://hl7.org/fhir/StructureDefinition/patient-birthTime"),
+        )]);
+
+        let profiles =
+            fhir::make_profiles(&forest, &fhir::EmitOptions::default(), FhirVersion::V4_0_1);
+        let ids: Vec<&str> = profiles[0]
+            .differential
+            .element
+            .iter()
+            .map(|element| element.id.as_str())
+            .collect();
+        assert_eq!(
+            ids,
+            [
+                "Patient",
+                "Patient.birthDate.extension",
+                "Patient.birthDate.extension:birthTime"
+            ]
+        );
+
+        let (exts, _) =
+            fhir::collect_extensions(forest, &fhir::EmitOptions::default(), FhirVersion::V4_0_1);
+        let context = exts[0].context.as_ref().unwrap();
+        assert_eq!(context[0].expression, "Patient.birthDate");
+    }
+
+    #[test]
+    fn test_custom_slicing_discriminator() {
+        let url = "http://example.org/name";
+        let attrs = [
+            Attribute {
+                id: "Patient.name".to_owned(),
+                path: vec!["name".to_owned()],
+                resource_type: "Patient".to_owned(),
+                kind: AttributeKind::Complex(AttributeKindComplex {
+                    open: false,
+                    description: None,
+                }),
+                array: false,
+                required: false,
+                modifier: false,
+                summary: false,
+                fce: Some(url.to_owned()),
+                filename: None,
+                module: None,
+            },
+            concrete_attribute(&["name", "given"], "string", Some("given")),
+        ];
+
+        let discriminator = |exts: &[fhir::StructureDefinition]| {
+            let slicing = exts[0].differential.element[1].slicing.as_ref().unwrap();
+            let discriminator = &slicing.discriminator[0];
+            (discriminator.r#type.clone(), discriminator.path.clone())
+        };
+
+        let (exts, _) = fhir::collect_extensions(
+            build_forest(&attrs),
+            &fhir::EmitOptions::default(),
+            FhirVersion::V4_0_1,
+        );
+        assert_eq!(exts[0].differential.element[1].id, "Extension.extension");
+        assert_eq!(discriminator(&exts), ("value".to_owned(), "url".to_owned()));
+
+        let options = fhir::EmitOptions {
+            slicing_discriminators: BTreeMap::from([(
+                url.to_owned(),
+                fhir::ElementSlicingDiscriminator {
+                    r#type: "value".to_owned(),
+                    path: "value.ofType(string)".to_owned(),
+                },
+            )]),
+            ..Default::default()
+        };
+        let (exts, _) =
+            fhir::collect_extensions(build_forest(&attrs), &options, FhirVersion::V4_0_1);
+        assert_eq!(
+            discriminator(&exts),
+            ("value".to_owned(), "value.ofType(string)".to_owned())
+        );
+    }
+
+    #[test]
+    fn test_complex_extension_without_children() {
+        let attrs = [Attribute {
+            id: "Patient.origin".to_owned(),
+            path: vec!["origin".to_owned()],
+            resource_type: "Patient".to_owned(),
+            kind: AttributeKind::Complex(AttributeKindComplex {
+                open: false,
+                description: None,
+            }),
+            array: false,
+            required: false,
+            modifier: false,
+            summary: false,
+            fce: Some("http://example.org/origin".to_owned()),
+            filename: None,
+            module: None,
+        }];
+
+        let (_, errors) = fhir::collect_extensions(
+            build_forest(&attrs),
+            &fhir::EmitOptions::default(),
+            FhirVersion::V4_0_1,
+        );
+        let [fhir::Error::ExtensionWithoutChildren { url, attribute_id }] = errors.as_slice()
+        else {
+            panic!("Expected a single ExtensionWithoutChildren error, got {errors:?}");
+        };
+        assert_eq!(url, "http://example.org/origin");
+        assert_eq!(attribute_id, "Patient.origin");
+    }
+
+    #[test]
+    fn test_too_many_extension_elements() {
+        // Every level of the complex extension nests the next one
+        let depth = 6;
+        let path: Vec<String> = (0..depth).map(|level| format!("level{level}")).collect();
+        let mut attrs: Vec<Attribute> = (1..=depth)
+            .map(|len| Attribute {
+                id: format!("Patient.{}", path[..len].join(".")),
+                path: path[..len].to_vec(),
+                resource_type: "Patient".to_owned(),
+                kind: AttributeKind::Complex(AttributeKindComplex {
+                    open: false,
+                    description: None,
+                }),
+                array: false,
+                required: false,
+                modifier: false,
+                summary: false,
+                fce: Some(if len == 1 {
+                    "http://example.org/nested".to_owned()
+                } else {
+                    path[len - 1].clone()
+                }),
+                filename: None,
+                module: None,
+            })
+            .collect();
+        let leaf: Vec<&str> = path.iter().map(String::as_str).chain(["value"]).collect();
+        attrs.push(concrete_attribute(&leaf, "string", Some("value")));
+
+        let (exts, errors) = fhir::collect_extensions(
+            build_forest(&attrs),
+            &fhir::EmitOptions::default(),
+            FhirVersion::V4_0_1,
+        );
+        assert!(errors.is_empty(), "{errors:?}");
+        let count = exts[0].differential.element.len();
+
+        let options = fhir::EmitOptions {
+            max_extension_elements: 20,
+            ..Default::default()
+        };
+        let (exts, errors) =
+            fhir::collect_extensions(build_forest(&attrs), &options, FhirVersion::V4_0_1);
+        let [fhir::Error::TooManyExtensionElements { url, max, .. }] = errors.as_slice() else {
+            panic!("Expected a single TooManyExtensionElements error, got {errors:?}");
+        };
+        assert!(count > 20);
+        assert_eq!(url, "http://example.org/nested");
+        assert_eq!(*max, 20);
+        assert!(exts.is_empty());
+    }
+
+    #[test]
+    fn test_modifier_extension() {
+        let attrs = [
+            concrete_attribute(&["nickname"], "string", Some("http://example.org/nickname")),
+            concrete_attribute(
+                &["deceased"],
+                "boolean",
+                Some("http://example.org/deceased"),
+            ),
+        ];
+        let options = fhir::EmitOptions {
+            modifiers: BTreeSet::from(["Patient.deceased".to_owned()]),
+            ..Default::default()
+        };
+
+        let profiles = fhir::make_profiles(&build_forest(&attrs), &options, FhirVersion::V4_0_1);
+        let ids: Vec<&str> = profiles[0]
+            .differential
+            .element
+            .iter()
+            .map(|element| element.id.as_str())
+            .collect();
+        assert_eq!(
+            ids,
+            [
+                "Patient",
+                "Patient.extension",
+                "Patient.extension:nickname",
+                "Patient.modifierExtension",
+                "Patient.modifierExtension:deceased"
+            ]
+        );
+
+        let (exts, _) =
+            fhir::collect_extensions(build_forest(&attrs), &options, FhirVersion::V4_0_1);
+        let is_modifier = |url: &str| {
+            exts.iter()
+                .find(|sd| sd.url == url)
+                .unwrap()
+                .differential
+                .element[0]
+                .is_modifier
+        };
+        assert_eq!(is_modifier("http://example.org/deceased"), Some(true));
+        assert_eq!(is_modifier("http://example.org/nickname"), None);
+    }
+
+    #[test]
+    fn test_extension_order() {
+        let attrs = [
+            concrete_attribute(&["zeta"], "string", Some("http://example.org/zeta")),
+            Attribute {
+                id: "Patient.alpha".to_owned(),
+                path: vec!["alpha".to_owned()],
+                resource_type: "Patient".to_owned(),
+                kind: AttributeKind::Complex(AttributeKindComplex {
+                    open: false,
+                    description: None,
+                }),
+                array: false,
+                required: false,
+                modifier: false,
+                summary: false,
+                fce: Some("http://example.org/alpha".to_owned()),
+                filename: None,
+                module: None,
+            },
+            concrete_attribute(&["alpha", "code"], "code", Some("code")),
+            concrete_attribute(&["mu"], "string", Some("http://example.org/mu")),
+        ];
+        let urls = |order: fhir::ExtensionOrder| {
+            let options = fhir::EmitOptions {
+                extension_order: Some(order),
+                ..Default::default()
+            };
+            let (exts, errors) =
+                fhir::collect_extensions(build_forest(&attrs), &options, FhirVersion::V4_0_1);
+            assert!(errors.is_empty());
+            exts.into_iter().map(|sd| sd.url).collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            urls(fhir::ExtensionOrder::Url),
+            [
+                "http://example.org/alpha",
+                "http://example.org/mu",
+                "http://example.org/zeta"
+            ]
+        );
+        assert_eq!(
+            urls(fhir::ExtensionOrder::Kind),
+            [
+                "http://example.org/mu",
+                "http://example.org/zeta",
+                "http://example.org/alpha"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_preserve_source_order() {
+        let attrs = [
+            concrete_attribute(&["zeta"], "string", Some("http://example.org/zeta")),
+            concrete_attribute(&["contact", "mu"], "string", Some("http://example.org/mu")),
+            concrete_attribute(&["alpha"], "string", Some("http://example.org/alpha")),
+        ];
+        let slice_ids = |options: &fhir::EmitOptions| {
+            let profiles = fhir::make_profiles(&build_forest(&attrs), options, FhirVersion::V4_0_1);
+            profiles[0]
+                .differential
+                .element
+                .iter()
+                .filter(|element| element.slice_name.is_some())
+                .map(|element| element.id.clone())
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            slice_ids(&fhir::EmitOptions::default()),
+            [
+                "Patient.extension:alpha",
+                "Patient.extension:zeta",
+                "Patient.contact.extension:mu"
+            ]
+        );
+
+        let options = fhir::EmitOptions {
+            source_order: Some(BTreeMap::from([
+                ("Patient.zeta".to_owned(), 0),
+                ("Patient.contact.mu".to_owned(), 1),
+                ("Patient.alpha".to_owned(), 2),
+            ])),
+            ..Default::default()
+        };
+        assert_eq!(
+            slice_ids(&options),
+            [
+                "Patient.extension:zeta",
+                "Patient.extension:alpha",
+                "Patient.contact.extension:mu"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_enum_binding() {
+        let with_enum = |mut attr: Attribute| {
+            if let AttributeKind::Concrete(kind) = &mut attr.kind {
+                kind.enumeration = Some(vec!["a".to_owned(), "b".to_owned()]);
+            }
+            attr
+        };
+        let forest = build_forest(&[
+            with_enum(concrete_attribute(&["kind"], "string", None)),
+            with_enum(concrete_attribute(
+                &["grade"],
+                "string",
+                Some("http://example.org/grade"),
+            )),
+        ]);
+
+        let profiles =
+            fhir::make_profiles(&forest, &fhir::EmitOptions::default(), FhirVersion::V4_0_1);
+        let element = profiles[0]
+            .differential
+            .element
+            .iter()
+            .find(|element| element.id == "Patient.kind")
+            .unwrap();
+        let binding = element.binding.as_ref().unwrap();
+        assert_eq!(binding.strength, "required");
+        assert_eq!(
+            binding.value_set,
+            "http://legacy.aidbox.app/fhir/ValueSet/Patient.kind"
+        );
+
+        let (exts, _) =
+            fhir::collect_extensions(forest, &fhir::EmitOptions::default(), FhirVersion::V4_0_1);
+        let element = exts[0]
+            .differential
+            .element
+            .iter()
+            .find(|element| element.binding.is_some())
+            .unwrap();
+        assert_eq!(
+            element.binding.as_ref().unwrap().value_set,
+            "http://legacy.aidbox.app/fhir/ValueSet/Patient.grade"
+        );
+        assert!(element.constraint.is_some());
+    }
+}
@@ -1,8 +1,15 @@
-use std::collections::{BTreeMap, HashSet};
+use std::{
+    collections::{BTreeMap, HashSet, btree_map::Entry},
+    path::{Path, PathBuf},
+};
 
+use miette::Diagnostic;
 use thiserror::Error;
 
-use crate::trie::extension_separated;
+use crate::{
+    report::Subject,
+    trie::{extension_separated, format_filename},
+};
 
 pub struct Forest {
     pub forest: BTreeMap<String, Trie>,
@@ -30,6 +37,9 @@ pub struct ConcreteNode {
     pub target: String,
     pub value_set: Option<String>,
     pub enumeration: Option<Vec<String>>,
+    pub max_length: Option<u32>,
+    pub description: Option<String>,
+    pub content_reference: Option<String>,
 }
 
 #[derive(Debug, Clone)]
@@ -49,6 +59,9 @@ pub struct PolymorphicLeaf {
     pub target: String,
     pub value_set: Option<String>,
     pub enumeration: Option<Vec<String>>,
+    pub max_length: Option<u32>,
+    pub description: Option<String>,
+    pub content_reference: Option<String>,
 }
 
 #[derive(Debug, Clone)]
@@ -56,6 +69,7 @@ pub struct ComplexNode {
     pub array: bool,
     pub id: String,
     pub open: bool,
+    pub description: Option<String>,
     pub required: bool,
     pub children: BTreeMap<String, NormalNode>,
     pub extension: BTreeMap<ExtUrl, Extension>,
@@ -91,6 +105,9 @@ pub struct ExtensionTarget {
     pub refers: Option<Vec<String>>,
     pub value_set: Option<String>,
     pub enumeration: Option<Vec<String>>,
+    pub max_length: Option<u32>,
+    pub description: Option<String>,
+    pub content_reference: Option<String>,
 }
 
 #[derive(Debug, Clone)]
@@ -99,17 +116,46 @@ pub struct ComplexExtension {
     pub fce_property: String,
     pub id: String,
     pub open: bool,
+    pub description: Option<String>,
     pub required: bool,
     pub extension: BTreeMap<ExtUrl, Extension>,
 }
 
-#[derive(Debug, Clone, Error)]
+#[derive(Debug, Clone, Error, Diagnostic)]
 pub enum Error {
-    #[error("Polymorphic has undeclared target")]
-    PolymorphicUndeclaredTarget { attr_id: String, target: String },
+    #[error(
+        "Polymorphic attribute {attr_id} has undeclared target {target}{}",
+        format_filename(.filename)
+    )]
+    #[diagnostic(code(trie::inverted::polymorphic_undeclared_target))]
+    PolymorphicUndeclaredTarget {
+        attr_id: String,
+        target: String,
+        filename: Option<PathBuf>,
+    },
+
+    #[error("Duplicate extension url {url}{}", format_filename(.filename))]
+    #[diagnostic(code(trie::inverted::duplicate_extension_url))]
+    DuplicateExtensionUrl {
+        url: String,
+        filename: Option<PathBuf>,
+    },
+}
 
-    #[error("Duplicate extension url {url}")]
-    DuplicateExtensionUrl { url: String },
+impl Subject for Error {
+    fn attribute_id(&self) -> Option<&str> {
+        match self {
+            Error::PolymorphicUndeclaredTarget { attr_id, .. } => Some(attr_id),
+            Error::DuplicateExtensionUrl { .. } => None,
+        }
+    }
+
+    fn filename(&self) -> Option<&Path> {
+        match self {
+            Error::PolymorphicUndeclaredTarget { filename, .. }
+            | Error::DuplicateExtensionUrl { filename, .. } => filename.as_deref(),
+        }
+    }
 }
 
 impl Default for Forest {
@@ -183,6 +229,9 @@ impl ConcreteNode {
             target: source_node.target,
             value_set: source_node.value_set,
             enumeration: source_node.enumeration,
+            max_length: source_node.max_length,
+            description: source_node.description,
+            content_reference: source_node.content_reference,
         }
     }
 }
@@ -195,6 +244,9 @@ impl PolymorphicLeaf {
             target: source_node.target,
             value_set: source_node.value_set,
             enumeration: source_node.enumeration,
+            max_length: source_node.max_length,
+            description: source_node.description,
+            content_reference: source_node.content_reference,
         }
     }
 }
@@ -231,12 +283,17 @@ impl ComplexNode {
 
         for (name, source_ext) in source_node.extension {
             let url = ExtUrl(source_ext.get_url().to_owned());
+            let filename = source_ext.get_filename().cloned();
             let (node, mut build_errors) = Extension::build_from(source_ext, name);
             errors.append(&mut build_errors);
-            if extension.contains_key(&url) {
-                errors.push(Error::DuplicateExtensionUrl { url: url.0 })
-            } else {
-                extension.insert(url, node);
+            match extension.entry(url) {
+                Entry::Occupied(entry) => errors.push(Error::DuplicateExtensionUrl {
+                    url: entry.key().0.to_owned(),
+                    filename,
+                }),
+                Entry::Vacant(entry) => {
+                    entry.insert(node);
+                }
             }
         }
 
@@ -244,6 +301,7 @@ impl ComplexNode {
             array: source_node.array,
             id: source_node.id,
             open: source_node.open,
+            description: source_node.description,
             required: source_node.required,
             children,
             extension,
@@ -266,12 +324,17 @@ impl InferredNode {
 
         for (name, source_ext) in source_node.extension {
             let url = ExtUrl(source_ext.get_url().to_owned());
+            let filename = source_ext.get_filename().cloned();
             let (node, mut build_errors) = Extension::build_from(source_ext, name);
             errors.append(&mut build_errors);
-            if extension.contains_key(&url) {
-                errors.push(Error::DuplicateExtensionUrl { url: url.0 })
-            } else {
-                extension.insert(url, node);
+            match extension.entry(url) {
+                Entry::Occupied(entry) => errors.push(Error::DuplicateExtensionUrl {
+                    url: entry.key().0.to_owned(),
+                    filename,
+                }),
+                Entry::Vacant(entry) => {
+                    entry.insert(node);
+                }
             }
         }
 
@@ -292,6 +355,13 @@ impl Extension {
         }
     }
 
+    pub fn get_id(&self) -> &str {
+        match &self {
+            Extension::Simple(simple_extension) => &simple_extension.id,
+            Extension::Complex(complex_extension) => &complex_extension.id,
+        }
+    }
+
     pub fn is_required(&self) -> bool {
         match &self {
             Extension::Simple(simple_extension) => simple_extension.required,
@@ -346,6 +416,9 @@ impl SimpleExtension {
                     refers: source_node.refers,
                     value_set: source_node.value_set,
                     enumeration: source_node.enumeration,
+                    max_length: source_node.max_length,
+                    description: source_node.description,
+                    content_reference: source_node.content_reference,
                 },
             )]),
             fce_property,
@@ -367,6 +440,7 @@ impl SimpleExtension {
                 errors.push(Error::PolymorphicUndeclaredTarget {
                     attr_id: source_node.id.clone(),
                     target: name.clone(),
+                    filename: target.filename.clone(),
                 })
             };
             let target = ExtensionTarget {
@@ -374,6 +448,9 @@ impl SimpleExtension {
                 refers: target.refers,
                 value_set: target.value_set,
                 enumeration: target.enumeration,
+                max_length: target.max_length,
+                description: target.description,
+                content_reference: target.content_reference,
             };
             targets.insert(name, target);
         }
@@ -400,12 +477,17 @@ impl ComplexExtension {
 
         for (name, source_ext) in source_node.extension {
             let url = ExtUrl(source_ext.get_url().to_owned());
+            let filename = source_ext.get_filename().cloned();
             let (node, mut build_errors) = Extension::build_from(source_ext, name);
             errors.append(&mut build_errors);
-            if extension.contains_key(&url) {
-                errors.push(Error::DuplicateExtensionUrl { url: url.0 })
-            } else {
-                extension.insert(url, node);
+            match extension.entry(url) {
+                Entry::Occupied(entry) => errors.push(Error::DuplicateExtensionUrl {
+                    url: entry.key().0.to_owned(),
+                    filename,
+                }),
+                Entry::Vacant(entry) => {
+                    entry.insert(node);
+                }
             }
         }
 
@@ -414,6 +496,7 @@ impl ComplexExtension {
             fce_property,
             id: source_node.id,
             open: source_node.open,
+            description: source_node.description,
             required: source_node.required,
             extension,
         };
@@ -1,6 +1,50 @@
-use std::collections::BTreeMap;
+use std::{
+    collections::{BTreeMap, btree_map},
+    path::{Path, PathBuf},
+};
 
-use crate::{attribute::typed::AttributeKind, trie::raw};
+use miette::Diagnostic;
+use thiserror::Error;
+
+use crate::{
+    attribute::typed::AttributeKind,
+    report::Subject,
+    trie::{format_filename, raw},
+};
+
+#[derive(Debug, Error, Diagnostic)]
+pub enum Error {
+    #[error(
+        "Attribute {id}{} collides with {existing_id} when merging _{element} into {element}",
+        format_filename(.filename)
+    )]
+    #[diagnostic(
+        code(trie::path::primitive_extension_collision),
+        help(
+            "Extensions of the primitive element {element} are merged from _{element} into {element}. Keep each child under only one of them."
+        )
+    )]
+    PrimitiveExtensionCollision {
+        element: String,
+        id: String,
+        existing_id: String,
+        filename: Option<PathBuf>,
+    },
+}
+
+impl Subject for Error {
+    fn attribute_id(&self) -> Option<&str> {
+        match self {
+            Error::PrimitiveExtensionCollision { id, .. } => Some(id),
+        }
+    }
+
+    fn filename(&self) -> Option<&Path> {
+        match self {
+            Error::PrimitiveExtensionCollision { filename, .. } => filename.as_deref(),
+        }
+    }
+}
 
 pub struct Forest {
     pub forest: BTreeMap<String, Trie>,
@@ -40,9 +84,13 @@ pub struct ConcreteNode {
     pub refers: Option<Vec<String>>,
     pub required: bool,
     pub resource_type: String,
+    pub filename: Option<PathBuf>,
     pub target: String,
     pub value_set: Option<String>,
     pub enumeration: Option<Vec<String>>,
+    pub max_length: Option<u32>,
+    pub description: Option<String>,
+    pub content_reference: Option<String>,
 }
 
 #[derive(Debug, Clone)]
@@ -54,9 +102,13 @@ pub struct ConcreteExtension {
     pub refers: Option<Vec<String>>,
     pub required: bool,
     pub resource_type: String,
+    pub filename: Option<PathBuf>,
     pub target: String,
     pub value_set: Option<String>,
     pub enumeration: Option<Vec<String>>,
+    pub max_length: Option<u32>,
+    pub description: Option<String>,
+    pub content_reference: Option<String>,
 }
 
 #[derive(Debug, Clone)]
@@ -67,6 +119,7 @@ pub struct PolymorphicNode {
     pub path: Vec<String>,
     pub required: bool,
     pub resource_type: String,
+    pub filename: Option<PathBuf>,
     pub targets: Vec<String>,
 }
 
@@ -79,6 +132,7 @@ pub struct PolymorphicExtension {
     pub path: Vec<String>,
     pub required: bool,
     pub resource_type: String,
+    pub filename: Option<PathBuf>,
     pub targets: Vec<String>,
 }
 
@@ -87,8 +141,10 @@ pub struct ComplexNode {
     pub array: bool,
     pub id: String,
     pub open: bool,
+    pub description: Option<String>,
     pub required: bool,
     pub resource_type: String,
+    pub filename: Option<PathBuf>,
     pub children: BTreeMap<String, Node>,
 }
 
@@ -98,8 +154,10 @@ pub struct ComplexExtension {
     pub fce: String,
     pub id: String,
     pub open: bool,
+    pub description: Option<String>,
     pub required: bool,
     pub resource_type: String,
+    pub filename: Option<PathBuf>,
     pub children: BTreeMap<String, Node>,
 }
 
@@ -121,22 +179,24 @@ impl Forest {
         }
     }
 
-    pub fn build_from(source_forest: raw::Forest) -> Self {
+    pub fn build_from(source_forest: raw::Forest) -> (Self, Vec<Error>) {
         let mut forest = Self::new();
+        let mut errors: Vec<Error> = Vec::new();
 
         for (resource_type, trie) in source_forest.forest {
-            let trie = Trie::build_from(trie);
+            let (trie, mut build_errors) = Trie::build_from(trie);
+            errors.append(&mut build_errors);
             forest.forest.insert(resource_type.to_owned(), trie);
         }
 
-        forest
+        (forest, errors)
     }
 }
 
 impl Trie {
-    pub fn build_from(source_trie: raw::Trie) -> Self {
-        let root = Node::build_from(source_trie.root);
-        Self { root }
+    pub fn build_from(source_trie: raw::Trie) -> (Self, Vec<Error>) {
+        let (root, errors) = Node::build_from(source_trie.root);
+        (Self { root }, errors)
     }
 }
 
@@ -147,14 +207,35 @@ impl Node {
             Node::Extension(extension) => Some(extension.get_id()),
         }
     }
-    pub fn build_from(source_node: raw::Node) -> Self {
-        let children: BTreeMap<String, Node> = source_node
-            .children
-            .into_iter()
-            .map(|(name, child)| (name.to_owned(), Self::build_from(child)))
-            .collect();
-
-        match source_node.attribute {
+    pub fn get_filename(&self) -> Option<&PathBuf> {
+        match &self {
+            Node::Normal(normal_node) => normal_node.get_filename(),
+            Node::Extension(extension) => extension.get_filename(),
+        }
+    }
+    fn children_mut(&mut self) -> &mut BTreeMap<String, Node> {
+        match self {
+            Node::Normal(NormalNode::Concrete(node)) => &mut node.children,
+            Node::Normal(NormalNode::Polymorphic(node)) => &mut node.children,
+            Node::Normal(NormalNode::Complex(node)) => &mut node.children,
+            Node::Normal(NormalNode::Inferred(node)) => &mut node.children,
+            Node::Extension(Extension::Concrete(node)) => &mut node.children,
+            Node::Extension(Extension::Polymorphic(node)) => &mut node.children,
+            Node::Extension(Extension::Complex(node)) => &mut node.children,
+        }
+    }
+
+    pub fn build_from(source_node: raw::Node) -> (Self, Vec<Error>) {
+        let mut errors: Vec<Error> = Vec::new();
+        let mut children: BTreeMap<String, Node> = BTreeMap::new();
+        for (name, child) in source_node.children {
+            let (child, mut build_errors) = Self::build_from(child);
+            errors.append(&mut build_errors);
+            children.insert(name, child);
+        }
+        let children = merge_primitive_extensions(children, &mut errors);
+
+        let node = match source_node.attribute {
             Some(attribute) => match (attribute.kind, attribute.fce) {
                 (AttributeKind::Poly(attribute_kind_poly), None) => {
                     Node::Normal(NormalNode::Polymorphic(PolymorphicNode {
@@ -164,6 +245,7 @@ impl Node {
                         path: attribute.path,
                         required: attribute.required,
                         resource_type: attribute.resource_type,
+                        filename: attribute.filename,
                         targets: attribute_kind_poly.targets,
                     }))
                 }
@@ -176,6 +258,7 @@ impl Node {
                         path: attribute.path,
                         required: attribute.required,
                         resource_type: attribute.resource_type,
+                        filename: attribute.filename,
                         targets: attribute_kind_poly.targets,
                         fce,
                     }))
@@ -189,9 +272,13 @@ impl Node {
                         refers: attribute_kind_concrete.refers,
                         required: attribute.required,
                         resource_type: attribute.resource_type,
+                        filename: attribute.filename,
                         target: attribute_kind_concrete.target,
                         value_set: attribute_kind_concrete.value_set,
                         enumeration: attribute_kind_concrete.enumeration,
+                        max_length: attribute_kind_concrete.max_length,
+                        description: attribute_kind_concrete.description,
+                        content_reference: attribute_kind_concrete.content_reference,
                     }))
                 }
 
@@ -203,9 +290,13 @@ impl Node {
                         refers: attribute_kind_concrete.refers,
                         required: attribute.required,
                         resource_type: attribute.resource_type,
+                        filename: attribute.filename,
                         target: attribute_kind_concrete.target,
                         value_set: attribute_kind_concrete.value_set,
                         enumeration: attribute_kind_concrete.enumeration,
+                        max_length: attribute_kind_concrete.max_length,
+                        description: attribute_kind_concrete.description,
+                        content_reference: attribute_kind_concrete.content_reference,
                         fce,
                     }))
                 }
@@ -215,8 +306,10 @@ impl Node {
                         array: attribute.array,
                         id: attribute.id,
                         open: attribute_kind_complex.open,
+                        description: attribute_kind_complex.description,
                         required: attribute.required,
                         resource_type: attribute.resource_type.to_owned(),
+                        filename: attribute.filename,
                         children,
                     }))
                 }
@@ -225,14 +318,95 @@ impl Node {
                         array: attribute.array,
                         id: attribute.id,
                         open: attribute_kind_complex.open,
+                        description: attribute_kind_complex.description,
                         required: attribute.required,
                         resource_type: attribute.resource_type,
+                        filename: attribute.filename,
                         children,
                         fce,
                     }))
                 }
             },
             None => Node::Normal(NormalNode::Inferred(InferredNode { children })),
+        };
+
+        (node, errors)
+    }
+}
+
+/// Extensions of primitive elements are stored in the `_element` sibling of the element
+/// (e.g. `_birthDate` for `birthDate`). Move them under the element itself, so they are emitted
+/// on its `extension` (e.g. `Patient.birthDate.extension`).
+///
+/// A child defined by attributes under both the element and its `_element` sibling is a
+/// collision: the child of the element is kept and the other attribute is reported.
+fn merge_primitive_extensions(
+    mut children: BTreeMap<String, Node>,
+    errors: &mut Vec<Error>,
+) -> BTreeMap<String, Node> {
+    let containers: Vec<String> = children
+        .iter()
+        .filter(|(name, node)| {
+            name.len() > 1
+                && name.starts_with('_')
+                && matches!(node, Node::Normal(NormalNode::Inferred(_)))
+        })
+        .map(|(name, _)| name.to_owned())
+        .collect();
+
+    for name in containers {
+        let Some(Node::Normal(NormalNode::Inferred(container))) = children.remove(&name) else {
+            unreachable!("Internal error: primitive extension container must be inferred");
+        };
+        let element = &name[1..];
+        let target = children
+            .entry(element.to_owned())
+            .or_insert_with(|| {
+                Node::Normal(NormalNode::Inferred(InferredNode {
+                    children: BTreeMap::new(),
+                }))
+            })
+            .children_mut();
+        merge_children(element, target, container.children, errors);
+    }
+
+    children
+}
+
+/// Merge the children of an `_element` container into the children of the element. Inferred
+/// nodes only group their children, so they are merged with any node of the same name.
+fn merge_children(
+    element: &str,
+    target: &mut BTreeMap<String, Node>,
+    source: BTreeMap<String, Node>,
+    errors: &mut Vec<Error>,
+) {
+    for (name, mut node) in source {
+        let existing = match target.entry(name) {
+            btree_map::Entry::Vacant(entry) => {
+                entry.insert(node);
+                continue;
+            }
+            btree_map::Entry::Occupied(entry) => entry.into_mut(),
+        };
+        match (existing.get_id(), node.get_id()) {
+            (_, None) => {
+                let children = std::mem::take(node.children_mut());
+                merge_children(element, existing.children_mut(), children, errors);
+            }
+            (None, Some(_)) => {
+                std::mem::swap(existing, &mut node);
+                let children = std::mem::take(node.children_mut());
+                merge_children(element, existing.children_mut(), children, errors);
+            }
+            (Some(existing_id), Some(id)) => {
+                errors.push(Error::PrimitiveExtensionCollision {
+                    element: element.to_owned(),
+                    id: id.to_owned(),
+                    existing_id: existing_id.to_owned(),
+                    filename: node.get_filename().cloned(),
+                });
+            }
         }
     }
 }
@@ -246,6 +420,14 @@ impl Extension {
         }
     }
 
+    pub fn get_filename(&self) -> Option<&PathBuf> {
+        match &self {
+            Extension::Concrete(extension) => extension.filename.as_ref(),
+            Extension::Polymorphic(extension) => extension.filename.as_ref(),
+            Extension::Complex(extension) => extension.filename.as_ref(),
+        }
+    }
+
     pub fn convert_to_normal_node(self) -> NormalNode {
         match self {
             Extension::Concrete(concrete_extension) => NormalNode::Concrete(ConcreteNode {
@@ -255,9 +437,13 @@ impl Extension {
                 refers: concrete_extension.refers,
                 required: concrete_extension.required,
                 resource_type: concrete_extension.resource_type,
+                filename: concrete_extension.filename,
                 target: concrete_extension.target,
                 value_set: concrete_extension.value_set,
                 enumeration: concrete_extension.enumeration,
+                max_length: concrete_extension.max_length,
+                description: concrete_extension.description,
+                content_reference: concrete_extension.content_reference,
             }),
             Extension::Polymorphic(polymorphic_extension) => {
                 NormalNode::Polymorphic(PolymorphicNode {
@@ -267,6 +453,7 @@ impl Extension {
                     path: polymorphic_extension.path,
                     required: polymorphic_extension.required,
                     resource_type: polymorphic_extension.resource_type,
+                    filename: polymorphic_extension.filename,
                     targets: polymorphic_extension.targets,
                 })
             }
@@ -274,8 +461,10 @@ impl Extension {
                 array: complex_extension.array,
                 id: complex_extension.id,
                 open: complex_extension.open,
+                description: complex_extension.description,
                 required: complex_extension.required,
                 resource_type: complex_extension.resource_type,
+                filename: complex_extension.filename,
                 children: complex_extension.children,
             }),
         }
@@ -291,4 +480,105 @@ impl NormalNode {
             NormalNode::Inferred(_) => None,
         }
     }
+
+    pub fn get_filename(&self) -> Option<&PathBuf> {
+        match &self {
+            NormalNode::Concrete(node) => node.filename.as_ref(),
+            NormalNode::Polymorphic(node) => node.filename.as_ref(),
+            NormalNode::Complex(node) => node.filename.as_ref(),
+            NormalNode::Inferred(_) => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::attribute::typed::{Attribute, AttributeKind, AttributeKindConcrete};
+    use crate::trie::{
+        path::{self, Error, Node},
+        raw,
+    };
+
+    fn attribute(path: &[&str], fce: Option<&str>) -> Attribute {
+        Attribute {
+            id: format!("Patient.{}", path.join(".")),
+            path: path.iter().map(|s| s.to_string()).collect(),
+            resource_type: "Patient".to_owned(),
+            kind: AttributeKind::Concrete(AttributeKindConcrete {
+                target: "string".to_owned(),
+                value_set: None,
+                refers: None,
+                enumeration: None,
+                max_length: None,
+                description: None,
+                content_reference: None,
+                concept_map: None,
+            }),
+            array: false,
+            required: false,
+            modifier: false,
+            summary: false,
+            fce: fce.map(|s| s.to_owned()),
+            filename: None,
+            module: None,
+        }
+    }
+
+    fn build(attrs: &[Attribute]) -> (path::Forest, Vec<Error>) {
+        let (raw_forest, errors) = raw::Forest::build_from_attributes(attrs, false);
+        assert!(errors.is_empty());
+        path::Forest::build_from(raw_forest)
+    }
+
+    #[test]
+    fn test_merge_primitive_extensions() {
+        let (forest, errors) = build(&[
+            attribute(&["birthDate"], None),
+            attribute(&["birthDate", "time"], Some("http://example.org/time")),
+            attribute(
+                &["_birthDate", "nickname"],
+                Some("http://example.org/nickname"),
+            ),
+        ]);
+        assert!(errors.is_empty(), "{errors:?}");
+
+        let Node::Normal(root) = &forest.forest["Patient"].root else {
+            panic!("root must be a normal node");
+        };
+        let path::NormalNode::Inferred(root) = root else {
+            panic!("root must be inferred");
+        };
+        assert!(!root.children.contains_key("_birthDate"));
+        let Node::Normal(path::NormalNode::Concrete(birth_date)) = &root.children["birthDate"]
+        else {
+            panic!("birthDate must stay concrete");
+        };
+        let names: Vec<&str> = birth_date.children.keys().map(String::as_str).collect();
+        assert_eq!(names, ["nickname", "time"]);
+    }
+
+    #[test]
+    fn test_primitive_extension_collision() {
+        let (_, errors) = build(&[
+            attribute(&["birthDate"], None),
+            attribute(
+                &["birthDate", "nickname"],
+                Some("http://example.org/nickname"),
+            ),
+            attribute(
+                &["_birthDate", "nickname"],
+                Some("http://example.org/alias"),
+            ),
+        ]);
+        assert_eq!(errors.len(), 1, "{errors:?}");
+        let Error::PrimitiveExtensionCollision {
+            element,
+            id,
+            existing_id,
+            ..
+        } = &errors[0];
+        assert_eq!(element, "birthDate");
+        assert_eq!(id, "Patient._birthDate.nickname");
+        assert_eq!(existing_id, "Patient.birthDate.nickname");
+    }
 }
@@ -1,11 +1,15 @@
 /// This is a forest (a collection of trees) of path tries of attributes
 /// This is a most direct construction whic takes into the account only
 /// resource type and path.
-use std::collections::BTreeMap;
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+};
 
+use miette::Diagnostic;
 use thiserror::Error;
 
-use crate::attribute::typed::Attribute;
+use crate::{attribute::typed::Attribute, report::Subject, trie::format_filename};
 
 #[derive(Debug, Clone)]
 pub struct Forest {
@@ -22,10 +26,84 @@ fn format_path(path: &[String]) -> String {
     path.join(".")
 }
 
-#[derive(Debug, Clone, Error)]
+/// Attribute conflicting at a path, with the file and the module it comes from when known
+#[derive(Debug, Clone)]
+pub struct Origin {
+    pub id: String,
+    pub filename: Option<PathBuf>,
+    pub module: Option<String>,
+}
+
+impl Origin {
+    fn of(attr: &Attribute) -> Box<Self> {
+        Box::new(Self {
+            id: attr.id.to_owned(),
+            filename: attr.filename.to_owned(),
+            module: attr.module.to_owned(),
+        })
+    }
+}
+
+impl std::fmt::Display for Origin {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}{}", self.id, format_filename(&self.filename))?;
+        if let Some(module) = &self.module {
+            write!(f, " (module {module})")?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Error, Diagnostic)]
 pub enum Error {
-    #[error("The node at path {} already exists", format_path(.0))]
-    AlreadyExists(Vec<String>),
+    #[error(
+        "The node at path {} of attribute {origin} is already defined by {existing}",
+        format_path(.path)
+    )]
+    #[diagnostic(
+        code(trie::raw::already_exists),
+        help(
+            "Attributes with the same path are merged by --merge-duplicates only when they define the same element. Remove one of them, or make them identical."
+        )
+    )]
+    AlreadyExists {
+        path: Vec<String>,
+        origin: Box<Origin>,
+        existing: Box<Origin>,
+    },
+
+    #[error(
+        "The path {}{} contains the array index {index}",
+        format_path(.path),
+        format_filename(.filename)
+    )]
+    #[diagnostic(
+        code(trie::raw::array_index),
+        help(
+            "Profiles constrain all elements of an array, remove the index from the attribute path"
+        )
+    )]
+    ArrayIndex {
+        path: Vec<String>,
+        index: String,
+        filename: Option<PathBuf>,
+    },
+}
+
+impl Subject for Error {
+    fn attribute_id(&self) -> Option<&str> {
+        match self {
+            Error::AlreadyExists { origin, .. } => Some(&origin.id),
+            Error::ArrayIndex { .. } => None,
+        }
+    }
+
+    fn filename(&self) -> Option<&Path> {
+        match self {
+            Error::AlreadyExists { origin, .. } => origin.filename.as_deref(),
+            Error::ArrayIndex { filename, .. } => filename.as_deref(),
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -43,14 +121,28 @@ impl Node {
     }
 }
 
+/// Some exports encode array positions in the path (e.g. `["name", "0", "given"]`)
+fn is_array_index(path_entry: &str) -> bool {
+    !path_entry.is_empty() && path_entry.chars().all(|c| c.is_ascii_digit())
+}
+
 impl Trie {
-    fn insert(&mut self, attr: Attribute) -> Result<(), Error> {
+    /// Insert the attribute at its path. With `merge_duplicates`, an attribute defining the same
+    /// element as the one already at the path is dropped, see [`Attribute::same_definition`].
+    fn insert(&mut self, attr: Attribute, merge_duplicates: bool) -> Result<(), Error> {
         assert_eq!(
             self.resource_type, attr.resource_type,
             "PathTrie resource type mismatch (trie type: {}; attribute type: {}",
             self.resource_type, attr.resource_type
         );
         let path = &attr.path;
+        if let Some(index) = path.iter().find(|entry| is_array_index(entry)) {
+            return Err(Error::ArrayIndex {
+                path: path.to_owned(),
+                index: index.to_owned(),
+                filename: attr.filename.clone(),
+            });
+        }
         let mut node = &mut self.root;
         for path_entry in path {
             node = node
@@ -59,7 +151,14 @@ impl Trie {
                 .or_insert(Node::new());
         }
         if let Some(existing) = &node.attribute {
-            Err(Error::AlreadyExists(existing.path.to_owned()))
+            if merge_duplicates && existing.same_definition(&attr) {
+                return Ok(());
+            }
+            Err(Error::AlreadyExists {
+                path: existing.path.to_owned(),
+                origin: Origin::of(&attr),
+                existing: Origin::of(existing),
+            })
         } else {
             node.attribute = Some(attr);
             Ok(())
@@ -87,20 +186,23 @@ impl Forest {
         }
     }
 
-    pub fn insert(&mut self, attr: Attribute) -> Result<(), Error> {
+    pub fn insert(&mut self, attr: Attribute, merge_duplicates: bool) -> Result<(), Error> {
         let trie = self
             .forest
             .entry(attr.resource_type.to_owned())
             .or_insert_with(|| Trie::new(attr.resource_type.to_owned()));
 
-        trie.insert(attr)
+        trie.insert(attr, merge_duplicates)
     }
 
-    pub fn build_from_attributes(attrs: &[Attribute]) -> (Self, Vec<Error>) {
+    pub fn build_from_attributes(
+        attrs: &[Attribute],
+        merge_duplicates: bool,
+    ) -> (Self, Vec<Error>) {
         let mut forest = Self::new();
         let mut errors: Vec<Error> = Vec::new();
         for attr in attrs {
-            match forest.insert(attr.to_owned()) {
+            match forest.insert(attr.to_owned(), merge_duplicates) {
                 Ok(_) => (),
                 Err(e) => errors.push(e),
             }
@@ -109,3 +211,114 @@ impl Forest {
         (forest, errors)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use crate::{
+        attribute::typed::{Attribute, AttributeKind, AttributeKindConcrete},
+        trie::raw::{Error, Forest},
+    };
+
+    #[test]
+    fn test_array_index_in_path() {
+        let attr = Attribute {
+            id: "Patient.name.0.given".to_owned(),
+            path: vec!["name".to_owned(), "0".to_owned(), "given".to_owned()],
+            resource_type: "Patient".to_owned(),
+            kind: AttributeKind::Concrete(AttributeKindConcrete {
+                target: "string".to_owned(),
+                value_set: None,
+                refers: None,
+                enumeration: None,
+                max_length: None,
+                description: None,
+                content_reference: None,
+                concept_map: None,
+            }),
+            array: false,
+            required: false,
+            modifier: false,
+            summary: false,
+            fce: None,
+            filename: None,
+            module: None,
+        };
+
+        let (forest, errors) = Forest::build_from_attributes(&[attr], false);
+        let [Error::ArrayIndex { path, index, .. }] = errors.as_slice() else {
+            panic!("Expected a single ArrayIndex error, got {errors:?}");
+        };
+        assert_eq!(path, &["name", "0", "given"]);
+        assert_eq!(index, "0");
+        assert!(forest.forest["Patient"].root.children.is_empty());
+    }
+
+    fn nickname(filename: &str, module: &str) -> Attribute {
+        Attribute {
+            id: "Patient.nickname".to_owned(),
+            path: vec!["nickname".to_owned()],
+            resource_type: "Patient".to_owned(),
+            kind: AttributeKind::Concrete(AttributeKindConcrete {
+                target: "string".to_owned(),
+                value_set: None,
+                refers: None,
+                enumeration: None,
+                max_length: None,
+                description: None,
+                content_reference: None,
+                concept_map: None,
+            }),
+            array: false,
+            required: false,
+            modifier: false,
+            summary: false,
+            fce: None,
+            filename: Some(PathBuf::from(filename)),
+            module: Some(module.to_owned()),
+        }
+    }
+
+    #[test]
+    fn test_already_exists_origin() {
+        let (_, errors) = Forest::build_from_attributes(
+            &[nickname("a.yaml", "core"), nickname("b.yaml", "app")],
+            false,
+        );
+        let [error @ Error::AlreadyExists { .. }] = errors.as_slice() else {
+            panic!("Expected a single AlreadyExists error, got {errors:?}");
+        };
+        assert_eq!(
+            error.to_string(),
+            "The node at path nickname of attribute Patient.nickname in b.yaml (module app) is already defined by Patient.nickname in a.yaml (module core)"
+        );
+    }
+
+    #[test]
+    fn test_merge_duplicates() {
+        let conflicting = Attribute {
+            required: true,
+            ..nickname("c.yaml", "other")
+        };
+        let (forest, errors) = Forest::build_from_attributes(
+            &[
+                nickname("a.yaml", "core"),
+                nickname("b.yaml", "app"),
+                conflicting,
+            ],
+            true,
+        );
+
+        // The identical attribute is merged, the one making the element required conflicts
+        let [Error::AlreadyExists { origin, .. }] = errors.as_slice() else {
+            panic!("Expected a single AlreadyExists error, got {errors:?}");
+        };
+        assert_eq!(origin.module.as_deref(), Some("other"));
+        let kept = forest.forest["Patient"].root.children["nickname"]
+            .attribute
+            .as_ref()
+            .unwrap();
+        assert_eq!(kept.module.as_deref(), Some("core"));
+    }
+}
@@ -0,0 +1,229 @@
+//! ValueSet resources for Attributes restricted with `enum`.
+//!
+//! Every enumerated attribute gets its own ValueSet listing the codes inline. Elements generated
+//! from the attribute are bound to it with a required binding. The codes are defined by a
+//! CodeSystem generated next to the ValueSet, so that terminology servers can expand it.
+
+use serde::Serialize;
+
+use crate::{
+    attribute::typed::{self, AttributeKind},
+    trie::fhir::{Binding, BindingStrength, UsageContext, capitalize, make_id, slugify},
+};
+
+const VALUE_SET_BASE_URL: &str = "http://legacy.aidbox.app/fhir/ValueSet";
+
+/// Codes of enumerations are not defined anywhere else, so each ValueSet includes them from the
+/// generated code system with the same name.
+const CODE_SYSTEM_BASE_URL: &str = "http://legacy.aidbox.app/fhir/CodeSystem";
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ValueSet {
+    pub resource_type: String,
+    pub id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub language: Option<String>,
+    pub url: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub version: Option<String>,
+    pub name: String,
+    pub status: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub date: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub publisher: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub use_context: Option<Vec<UsageContext>>,
+    pub compose: ValueSetCompose,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ValueSetCompose {
+    pub include: Vec<ValueSetInclude>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ValueSetInclude {
+    pub system: String,
+    pub concept: Vec<ValueSetConcept>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ValueSetConcept {
+    pub code: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CodeSystem {
+    pub resource_type: String,
+    pub id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub language: Option<String>,
+    pub url: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub version: Option<String>,
+    pub name: String,
+    pub status: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub date: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub publisher: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub use_context: Option<Vec<UsageContext>>,
+    /// Always `complete`, the enumeration is the whole code system
+    pub content: String,
+    pub concept: Vec<ValueSetConcept>,
+}
+
+/// Canonical url of the ValueSet generated for the attribute
+pub fn value_set_url(attribute_id: &str) -> String {
+    format!("{VALUE_SET_BASE_URL}/{}", slugify(attribute_id))
+}
+
+/// Binding to the ValueSet generated for the enumerated attribute
+pub fn make_enum_binding(
+    attribute_id: &str,
+    enumeration: Option<&[String]>,
+    strength: BindingStrength,
+) -> Option<Binding> {
+    enumeration?;
+    Some(Binding {
+        strength: strength.as_str().to_owned(),
+        value_set: value_set_url(attribute_id),
+        description: None,
+    })
+}
+
+/// Code system of the codes enumerated by the attribute
+pub fn code_system_url(attribute_id: &str) -> String {
+    format!("{CODE_SYSTEM_BASE_URL}/{}", slugify(attribute_id))
+}
+
+/// Computer friendly name of a resource generated for the attribute (e.g. `PatientStatus`)
+pub fn resource_name(attribute_id: &str) -> String {
+    attribute_id
+        .split(|c: char| !c.is_ascii_alphanumeric())
+        .map(capitalize)
+        .collect()
+}
+
+fn concepts(enumeration: &[String]) -> Vec<ValueSetConcept> {
+    enumeration
+        .iter()
+        .map(|code| ValueSetConcept {
+            code: code.to_owned(),
+        })
+        .collect()
+}
+
+pub fn make_value_set(attribute_id: &str, enumeration: &[String]) -> ValueSet {
+    ValueSet {
+        resource_type: "ValueSet".to_owned(),
+        id: make_id(attribute_id),
+        language: None,
+        version: None,
+        url: value_set_url(attribute_id),
+        name: resource_name(attribute_id),
+        status: "active".to_owned(),
+        date: None,
+        publisher: None,
+        use_context: None,
+        compose: ValueSetCompose {
+            include: vec![ValueSetInclude {
+                system: code_system_url(attribute_id),
+                concept: concepts(enumeration),
+            }],
+        },
+    }
+}
+
+/// CodeSystem defining the codes included by [`make_value_set`]
+pub fn make_code_system(attribute_id: &str, enumeration: &[String]) -> CodeSystem {
+    CodeSystem {
+        resource_type: "CodeSystem".to_owned(),
+        id: make_id(attribute_id),
+        language: None,
+        version: None,
+        url: code_system_url(attribute_id),
+        name: resource_name(attribute_id),
+        status: "active".to_owned(),
+        date: None,
+        publisher: None,
+        use_context: None,
+        content: "complete".to_owned(),
+        concept: concepts(enumeration),
+    }
+}
+
+/// ValueSets for all attributes with `enum`.
+///
+/// Attributes bound to an existing value set keep that binding and get no ValueSet.
+pub fn make_value_sets(attributes: &[typed::Attribute]) -> Vec<ValueSet> {
+    attributes
+        .iter()
+        .filter_map(|attribute| match &attribute.kind {
+            AttributeKind::Concrete(kind) if kind.value_set.is_none() => kind
+                .enumeration
+                .as_ref()
+                .map(|enumeration| make_value_set(&attribute.id, enumeration)),
+            _ => None,
+        })
+        .collect()
+}
+
+/// CodeSystems of the ValueSets made by [`make_value_sets`]
+pub fn make_code_systems(attributes: &[typed::Attribute]) -> Vec<CodeSystem> {
+    attributes
+        .iter()
+        .filter_map(|attribute| match &attribute.kind {
+            AttributeKind::Concrete(kind) if kind.value_set.is_none() => kind
+                .enumeration
+                .as_ref()
+                .map(|enumeration| make_code_system(&attribute.id, enumeration)),
+            _ => None,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{trie::fhir::MAX_ID_LENGTH, value_set};
+
+    #[test]
+    fn test_make_value_set() {
+        let value_set =
+            value_set::make_value_set("Patient.status", &["active".to_owned(), "gone".to_owned()]);
+
+        assert_eq!(value_set.id, "Patient.status");
+        assert_eq!(value_set.name, "PatientStatus");
+        assert_eq!(
+            value_set.url,
+            "http://legacy.aidbox.app/fhir/ValueSet/Patient.status"
+        );
+        let codes: Vec<&str> = value_set.compose.include[0]
+            .concept
+            .iter()
+            .map(|concept| concept.code.as_str())
+            .collect();
+        assert_eq!(codes, ["active", "gone"]);
+
+        let code_system = value_set::make_code_system(
+            "Patient.status",
+            &["active".to_owned(), "gone".to_owned()],
+        );
+        assert_eq!(code_system.url, value_set.compose.include[0].system);
+        assert_eq!(code_system.content, "complete");
+        assert_eq!(code_system.concept.len(), 2);
+    }
+
+    #[test]
+    fn test_long_value_set_id() {
+        let attribute_id = format!("Patient.{}", "a".repeat(80));
+        let value_set = value_set::make_value_set(&attribute_id, &["active".to_owned()]);
+        assert_eq!(value_set.id.len(), MAX_ID_LENGTH);
+        let code_system = value_set::make_code_system(&attribute_id, &["active".to_owned()]);
+        assert_eq!(code_system.id, value_set.id);
+    }
+}
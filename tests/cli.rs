@@ -0,0 +1,657 @@
+use std::{
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+/// Path in the temporary directory unique to this test run
+fn temp_path(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("{}-{name}", std::process::id()))
+}
+
+/// Write an attribute `Patient.{name}` converted to a string extension with the url
+fn write_attribute(dir: &Path, name: &str, url: &str) {
+    std::fs::write(
+        dir.join(format!("{name}.yaml")),
+        format!("resourceType: Attribute\nid: Patient.{name}\npath: [{name}]\nresource: {{id: Patient, resourceType: Entity}}\ntype: {{id: string, resourceType: Entity}}\nextensionUrl: {url}\n"),
+    )
+    .unwrap();
+}
+
+/// Write two extensions whose urls differ but make the same file name slug
+fn write_similar_urls(dir: &Path) {
+    write_attribute(dir, "nickname", "http://example.org/nick_name");
+    write_attribute(dir, "alias", "http://example.org/nick-name");
+}
+
+#[test]
+fn test_package_output_keeps_stdout_clean() {
+    let output_file = temp_path("searchparam.tgz");
+
+    let output = Command::new(env!("CARGO_BIN_EXE_fhir-schema-migration-tool"))
+        .args(["tests/searchparam", "--fhir-version", "4.0.1", "--output"])
+        .arg(&output_file)
+        .output()
+        .unwrap();
+    _ = std::fs::remove_file(&output_file);
+
+    assert!(output.status.success());
+    // Generated resources go to the package, the summary to stderr
+    assert!(output.stdout.is_empty(), "{output:?}");
+}
+
+#[test]
+fn test_package_mtime_from_source_date_epoch() {
+    let output_file = temp_path("mtime.tgz");
+
+    let output = Command::new(env!("CARGO_BIN_EXE_fhir-schema-migration-tool"))
+        .args(["tests/searchparam", "--fhir-version", "4.0.1", "--output"])
+        .arg(&output_file)
+        .env("SOURCE_DATE_EPOCH", "1700000000")
+        .output()
+        .unwrap();
+    let mtimes = std::panic::catch_unwind(|| {
+        let file = std::fs::File::open(&output_file).unwrap();
+        let mut archive = tar::Archive::new(flate2::read::GzDecoder::new(file));
+        archive
+            .entries()
+            .unwrap()
+            .map(|entry| entry.unwrap().header().mtime().unwrap())
+            .collect::<Vec<_>>()
+    });
+    _ = std::fs::remove_file(&output_file);
+
+    assert!(output.status.success(), "{output:?}");
+    let mtimes = mtimes.unwrap();
+    assert!(!mtimes.is_empty());
+    assert!(
+        mtimes.iter().all(|&mtime| mtime == 1_700_000_000),
+        "{mtimes:?}"
+    );
+}
+
+#[test]
+fn test_bundle_entries() {
+    let output = Command::new(env!("CARGO_BIN_EXE_fhir-schema-migration-tool"))
+        .args(["tests/bundle", "--fhir-version", "4.0.1", "--count"])
+        .output()
+        .unwrap();
+
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "Patient: Extensions: 1; Profiles: 1\nExtensions: 1; Profiles: 1 would be generated\n"
+    );
+}
+
+#[test]
+fn test_stdin_ndjson() {
+    use std::io::Write;
+    use std::process::Stdio;
+
+    let mut child = Command::new(env!("CARGO_BIN_EXE_fhir-schema-migration-tool"))
+        .args(["-", "--fhir-version", "4.0.1", "--count"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+
+    let bundle = std::fs::read_to_string("tests/bundle/bundle.json").unwrap();
+    let bundle: serde_json::Value = serde_json::from_str(&bundle).unwrap();
+    let mut stdin = child.stdin.take().unwrap();
+    for entry in bundle["entry"].as_array().unwrap() {
+        writeln!(stdin, "{}", entry["resource"]).unwrap();
+    }
+    drop(stdin);
+
+    let output = child.wait_with_output().unwrap();
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "Patient: Extensions: 1; Profiles: 1\nExtensions: 1; Profiles: 1 would be generated\n"
+    );
+}
+
+#[test]
+fn test_jobs_do_not_change_output() {
+    let dir = temp_path("jobs");
+    std::fs::create_dir_all(&dir).unwrap();
+    let names: Vec<String> = (0..12).map(|index| format!("field{index:02}")).collect();
+    for name in &names {
+        write_attribute(&dir, name, &format!("http://example.org/{name}"));
+    }
+
+    let run = |jobs: &str| {
+        let output = Command::new(env!("CARGO_BIN_EXE_fhir-schema-migration-tool"))
+            .arg(&dir)
+            .args(["--fhir-version", "4.0.1", "--jobs", jobs])
+            .output()
+            .unwrap();
+        assert!(output.status.success(), "{output:?}");
+        String::from_utf8(output.stdout).unwrap()
+    };
+    let sequential = run("1");
+    let parallel = run("4");
+    _ = std::fs::remove_dir_all(&dir);
+
+    // Every extension reaches stdout, in the same order
+    for name in &names {
+        assert!(sequential.contains(&format!("\"url\": \"http://example.org/{name}\"")));
+    }
+    assert_eq!(sequential, parallel);
+}
+
+#[test]
+fn test_version_from_input_package() {
+    let dir = temp_path("input-package");
+    let output_dir = dir.join("output");
+    std::fs::create_dir_all(&dir).unwrap();
+    let input = dir.join("input.tgz");
+
+    let mut tar = tar::Builder::new(flate2::write::GzEncoder::new(
+        std::fs::File::create(&input).unwrap(),
+        flate2::Compression::default(),
+    ));
+    let mut append = |path: &str, value: serde_json::Value| {
+        let payload = serde_json::to_vec(&value).unwrap();
+        let mut header = tar::Header::new_gnu();
+        header.set_size(payload.len() as u64);
+        header.set_mode(0o644);
+        header.set_cksum();
+        tar.append_data(&mut header, path, payload.as_slice())
+            .unwrap();
+    };
+    append(
+        "package/package.json",
+        serde_json::json!({"name": "attributes", "dependencies": {"hl7.fhir.r4b.core": "4.3.0"}}),
+    );
+    append(
+        "package/Attribute-Patient.nickname.json",
+        serde_json::json!({
+            "resourceType": "Attribute",
+            "id": "Patient.nickname",
+            "path": ["nickname"],
+            "resource": {"id": "Patient", "resourceType": "Entity"},
+            "type": {"id": "string", "resourceType": "Entity"},
+            "extensionUrl": "http://example.org/nickname",
+        }),
+    );
+    tar.into_inner().unwrap().finish().unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_fhir-schema-migration-tool"))
+        .arg(&input)
+        .arg("--assume-version-from-package")
+        .arg("--output-dir")
+        .arg(&output_dir)
+        .output()
+        .unwrap();
+    let package_json = std::fs::read_to_string(output_dir.join("package.json"));
+    _ = std::fs::remove_dir_all(&dir);
+
+    assert!(output.status.success(), "{output:?}");
+    assert!(package_json.unwrap().contains("hl7.fhir.r4b.core"));
+}
+
+#[test]
+fn test_several_fhir_versions() {
+    let dir = temp_path("several-versions");
+    std::fs::create_dir_all(&dir).unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_fhir-schema-migration-tool"))
+        .args([
+            "tests/searchparam",
+            "--fhir-version",
+            "4.0.1",
+            "--fhir-version",
+            "5.0.0",
+        ])
+        .arg("--output")
+        .arg(dir.join("out.tgz"))
+        .output()
+        .unwrap();
+    let read_package_json = |name: &str| {
+        let file = std::fs::File::open(dir.join(name)).unwrap();
+        let mut archive = tar::Archive::new(flate2::read::GzDecoder::new(file));
+        let mut entry = archive
+            .entries()
+            .unwrap()
+            .map(Result::unwrap)
+            .find(|entry| entry.path().unwrap().ends_with("package.json"))
+            .unwrap();
+        let mut contents = String::new();
+        std::io::Read::read_to_string(&mut entry, &mut contents).unwrap();
+        contents
+    };
+    let packages = std::panic::catch_unwind(|| {
+        (
+            read_package_json("out-4.0.1.tgz"),
+            read_package_json("out-5.0.0.tgz"),
+        )
+    });
+    let unsuffixed = dir.join("out.tgz").exists();
+    _ = std::fs::remove_dir_all(&dir);
+
+    assert!(output.status.success(), "{output:?}");
+    assert!(!unsuffixed);
+    let (r4, r5) = packages.unwrap();
+    assert!(r4.contains("hl7.fhir.r4.core"));
+    assert!(r5.contains("hl7.fhir.r5.core"));
+    assert!(output.stdout.is_empty(), "{output:?}");
+}
+
+#[test]
+fn test_version_errors_do_not_block_other_versions() {
+    let dir = temp_path("version-errors");
+    let input = dir.join("input");
+    std::fs::create_dir_all(&input).unwrap();
+    // Observation.triggeredBy is only defined in R5
+    std::fs::write(
+        input.join("trigger.yaml"),
+        "resourceType: Attribute\nid: Observation.trigger\npath: [trigger]\nresource: {id: Observation, resourceType: Entity}\ntype: {id: Observation.triggeredBy, resourceType: Attribute}\n",
+    )
+    .unwrap();
+
+    let run = |versions: [&str; 2]| {
+        let out = dir.join(versions.join("-"));
+        let output = Command::new(env!("CARGO_BIN_EXE_fhir-schema-migration-tool"))
+            .arg(&input)
+            .args(["--allow-recursive", "--fhir-version", versions[0]])
+            .args(["--fhir-version", versions[1], "--output"])
+            .arg(out.join("out.tgz"))
+            .output()
+            .unwrap();
+        let written = (
+            out.join("out-4.0.1.tgz").exists(),
+            out.join("out-5.0.0.tgz").exists(),
+        );
+        (output, written)
+    };
+    std::fs::create_dir_all(dir.join("4.0.1-5.0.0")).unwrap();
+    std::fs::create_dir_all(dir.join("5.0.0-4.0.1")).unwrap();
+    let (r4_first, r4_first_written) = run(["4.0.1", "5.0.0"]);
+    let (r5_first, r5_first_written) = run(["5.0.0", "4.0.1"]);
+    _ = std::fs::remove_dir_all(&dir);
+
+    // Only the failing version is not written, whatever the order of the versions
+    assert_eq!(r4_first.status.code(), Some(1), "{r4_first:?}");
+    assert_eq!(r5_first.status.code(), Some(1), "{r5_first:?}");
+    assert_eq!(r4_first_written, (false, true));
+    assert_eq!(r5_first_written, (false, true));
+}
+
+#[test]
+fn test_fsh_output_dir() {
+    let output_dir = temp_path("fsh-output");
+
+    let output = Command::new(env!("CARGO_BIN_EXE_fhir-schema-migration-tool"))
+        .args([
+            "tests/searchparam",
+            "--fhir-version",
+            "4.0.1",
+            "--output-format",
+            "fsh",
+        ])
+        .arg("--output-dir")
+        .arg(&output_dir)
+        .output()
+        .unwrap();
+    let mut files: Vec<String> = std::fs::read_dir(&output_dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+        .collect();
+    files.sort();
+    let profile = std::fs::read_to_string(
+        output_dir
+            .join("StructureDefinition-legacy.aidbox.app-fhir-StructureDefinition-Patient-fce.fsh"),
+    );
+    _ = std::fs::remove_dir_all(&output_dir);
+
+    assert!(output.status.success(), "{output:?}");
+    assert_eq!(files.len(), 3, "{files:?}");
+    assert!(
+        files
+            .iter()
+            .any(|file| file.starts_with("SearchParameter-") && file.ends_with(".json"))
+    );
+    assert!(profile.unwrap().starts_with("Profile: Patient_fce\n"));
+}
+
+#[test]
+fn test_with_snapshot_alias() {
+    let output = Command::new(env!("CARGO_BIN_EXE_fhir-schema-migration-tool"))
+        .args([
+            "tests/searchparam",
+            "--fhir-version",
+            "4.0.1",
+            "--with-snapshot",
+        ])
+        .output()
+        .unwrap();
+
+    assert!(output.status.success());
+    assert!(
+        String::from_utf8(output.stdout)
+            .unwrap()
+            .contains("\"snapshot\"")
+    );
+}
+
+#[test]
+fn test_error_report() {
+    let dir = temp_path("error-report");
+    std::fs::create_dir_all(&dir).unwrap();
+    let attribute_file = dir.join("unique.yaml");
+    std::fs::write(
+        &attribute_file,
+        "resourceType: Attribute\nid: Patient.nickname\npath: [nickname]\nresource: {id: Patient, resourceType: Entity}\ntype: {id: string, resourceType: Entity}\nisUnique: true\n",
+    )
+    .unwrap();
+    std::fs::write(dir.join("broken.json"), "{").unwrap();
+    let report_file = dir.join("report.json");
+
+    let output = Command::new(env!("CARGO_BIN_EXE_fhir-schema-migration-tool"))
+        .arg(&dir)
+        .args(["--fhir-version", "4.0.1", "--count", "--error-report"])
+        .arg(&report_file)
+        .output()
+        .unwrap();
+    let report = std::fs::read_to_string(&report_file);
+    _ = std::fs::remove_dir_all(&dir);
+
+    assert_eq!(output.status.code(), Some(1), "{output:?}");
+    // The diagnostics are still printed
+    assert!(!output.stderr.is_empty());
+
+    let report: Vec<serde_json::Value> = serde_json::from_str(&report.unwrap()).unwrap();
+    assert_eq!(report.len(), 2, "{report:?}");
+    // Files which could not be read come first
+    assert_eq!(report[0]["code"], "cli::bad_json");
+    assert_eq!(report[0]["attribute_id"], serde_json::Value::Null);
+    assert_eq!(report[0]["file"], dir.join("broken.json").to_str().unwrap());
+    assert_eq!(
+        report[1],
+        serde_json::json!({
+            "code": "attribute::typed::unique_present",
+            "severity": "error",
+            "message": "Attribute Patient.nickname is invalid: Unsupported property: isUnique",
+            "attribute_id": "Patient.nickname",
+            "file": attribute_file.to_str().unwrap(),
+        })
+    );
+}
+
+#[test]
+fn test_summary() {
+    let run = |args: &[&str]| {
+        let output = Command::new(env!("CARGO_BIN_EXE_fhir-schema-migration-tool"))
+            .args(["tests/searchparam", "--fhir-version", "4.0.1", "--count"])
+            .args(args)
+            .output()
+            .unwrap();
+        assert!(output.status.success(), "{output:?}");
+        String::from_utf8(output.stderr).unwrap()
+    };
+
+    assert_eq!(
+        run(&[]),
+        "Read 1 attributes, 1 search params, 0 compartments; emitted 1 profiles, 1 extensions, 1 search params; 0 errors, 0 warnings\n"
+    );
+    assert_eq!(run(&["--quiet"]), "");
+
+    let summary: serde_json::Value = serde_json::from_str(&run(&["--summary-json"])).unwrap();
+    assert_eq!(
+        summary,
+        serde_json::json!({
+            "read": {"attributes": 1, "search_params": 1, "compartments": 0},
+            "emitted": {"profiles": 1, "extensions": 1, "search_params": 1},
+            "errors": 0,
+            "warnings": 0,
+        })
+    );
+}
+
+#[test]
+fn test_quiet_keeps_exit_status() {
+    let output = Command::new(env!("CARGO_BIN_EXE_fhir-schema-migration-tool"))
+        .args(["tests/malformed", "--fhir-version", "4.0.1", "--quiet"])
+        .output()
+        .unwrap();
+
+    assert_eq!(output.status.code(), Some(1), "{output:?}");
+    assert!(output.stderr.is_empty(), "{output:?}");
+}
+
+#[test]
+fn test_gzip_input() {
+    use std::io::Write;
+
+    let dir = temp_path("gzip-input");
+    std::fs::create_dir_all(&dir).unwrap();
+    for name in ["nickname.yaml", "nickname-sp.yaml"] {
+        let contents = std::fs::read(format!("tests/searchparam/{name}")).unwrap();
+        let file = std::fs::File::create(dir.join(format!("{name}.gz"))).unwrap();
+        let mut encoder = flate2::write::GzEncoder::new(file, flate2::Compression::default());
+        encoder.write_all(&contents).unwrap();
+        encoder.finish().unwrap();
+    }
+
+    let run = |path: &std::path::Path| {
+        Command::new(env!("CARGO_BIN_EXE_fhir-schema-migration-tool"))
+            .arg(path)
+            .args(["--fhir-version", "4.0.1"])
+            .output()
+            .unwrap()
+    };
+    let compressed = run(&dir);
+    _ = std::fs::remove_dir_all(&dir);
+    let uncompressed = run(std::path::Path::new("tests/searchparam"));
+
+    assert!(compressed.status.success(), "{compressed:?}");
+    assert!(!compressed.stdout.is_empty());
+    assert_eq!(compressed.stdout, uncompressed.stdout);
+}
+
+#[test]
+fn test_several_input_paths() {
+    let dir = temp_path("several-paths");
+    for name in ["nickname.yaml", "nickname-sp.yaml"] {
+        let subdir = dir.join(name.trim_end_matches(".yaml"));
+        std::fs::create_dir_all(&subdir).unwrap();
+        std::fs::copy(format!("tests/searchparam/{name}"), subdir.join(name)).unwrap();
+    }
+
+    let separate = Command::new(env!("CARGO_BIN_EXE_fhir-schema-migration-tool"))
+        .arg(dir.join("nickname"))
+        .arg(dir.join("nickname-sp"))
+        .args(["--fhir-version", "4.0.1"])
+        .output()
+        .unwrap();
+    let missing = Command::new(env!("CARGO_BIN_EXE_fhir-schema-migration-tool"))
+        .arg(dir.join("nickname"))
+        .arg(dir.join("missing"))
+        .args(["--fhir-version", "4.0.1"])
+        .output()
+        .unwrap();
+    _ = std::fs::remove_dir_all(&dir);
+    let together = Command::new(env!("CARGO_BIN_EXE_fhir-schema-migration-tool"))
+        .args(["tests/searchparam", "--fhir-version", "4.0.1"])
+        .output()
+        .unwrap();
+
+    assert!(separate.status.success(), "{separate:?}");
+    assert_eq!(separate.stdout, together.stdout);
+
+    assert_eq!(missing.status.code(), Some(1), "{missing:?}");
+    let stderr = String::from_utf8(missing.stderr).unwrap();
+    assert!(
+        stderr.contains(&format!(
+            "Error while searching for JSON and YAML files in {}",
+            dir.join("missing").display()
+        )),
+        "{stderr}"
+    );
+}
+
+#[test]
+fn test_dry_run() {
+    let output_file = temp_path("dry-run.tgz");
+
+    let output = Command::new(env!("CARGO_BIN_EXE_fhir-schema-migration-tool"))
+        .args(["tests/searchparam", "--fhir-version", "4.0.1", "--dry-run"])
+        .arg("--output")
+        .arg(&output_file)
+        .output()
+        .unwrap();
+
+    assert!(output.status.success(), "{output:?}");
+    assert!(!output_file.exists());
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "Profiles:
+  http://legacy.aidbox.app/fhir/StructureDefinition/Patient-fce
+Extensions:
+  http://example.org/nickname
+SearchParameters:
+  http://fhir.example.org/fhir/SearchParameter/id-Patient.nickname
+"
+    );
+
+    let output = Command::new(env!("CARGO_BIN_EXE_fhir-schema-migration-tool"))
+        .args(["tests/malformed", "--fhir-version", "4.0.1", "--dry-run"])
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(1), "{output:?}");
+}
+
+#[test]
+fn test_package_name_and_version() {
+    let output_dir = temp_path("package-info");
+
+    let output = Command::new(env!("CARGO_BIN_EXE_fhir-schema-migration-tool"))
+        .args(["tests/searchparam", "--fhir-version", "4.0.1"])
+        .args([
+            "--package-name",
+            "example.fce",
+            "--package-version",
+            "1.2.0",
+        ])
+        .arg("--output-dir")
+        .arg(&output_dir)
+        .output()
+        .unwrap();
+    let package_json = std::fs::read_to_string(output_dir.join("package.json"));
+    _ = std::fs::remove_dir_all(&output_dir);
+
+    assert!(output.status.success(), "{output:?}");
+    let package_json: serde_json::Value = serde_json::from_str(&package_json.unwrap()).unwrap();
+    assert_eq!(package_json["name"], "example.fce");
+    assert_eq!(package_json["version"], "1.2.0");
+
+    let output = Command::new(env!("CARGO_BIN_EXE_fhir-schema-migration-tool"))
+        .args(["tests/searchparam", "--fhir-version", "4.0.1"])
+        .args(["--package-version", "1.2"])
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(1), "{output:?}");
+    assert!(output.stdout.is_empty());
+    assert!(
+        String::from_utf8(output.stderr)
+            .unwrap()
+            .contains("Package version 1.2 is not a semantic version")
+    );
+}
+
+#[test]
+fn test_publication_date() {
+    let output = Command::new(env!("CARGO_BIN_EXE_fhir-schema-migration-tool"))
+        .args(["tests/searchparam", "--fhir-version", "4.0.1", "--quiet"])
+        .env("SOURCE_DATE_EPOCH", "1700000000")
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{output:?}");
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert_eq!(stdout.matches(r#""date": "2023-11-14""#).count(), 3);
+
+    let output = Command::new(env!("CARGO_BIN_EXE_fhir-schema-migration-tool"))
+        .args(["tests/searchparam", "--fhir-version", "4.0.1"])
+        .args(["--date", "2023-13-01"])
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(2), "{output:?}");
+    assert!(
+        String::from_utf8(output.stderr)
+            .unwrap()
+            .contains("invalid date '2023-13-01'")
+    );
+}
+
+#[test]
+fn test_similar_urls_are_written() {
+    let dir = temp_path("similar-urls");
+    let input = dir.join("input");
+    std::fs::create_dir_all(&input).unwrap();
+    write_similar_urls(&input);
+    let out = dir.join("out");
+
+    let output = Command::new(env!("CARGO_BIN_EXE_fhir-schema-migration-tool"))
+        .arg(&input)
+        .args(["--fhir-version", "4.0.1", "--output-dir"])
+        .arg(&out)
+        .output()
+        .unwrap();
+    let extensions: Vec<String> = std::fs::read_dir(&out)
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+        .filter(|filename| filename.starts_with("StructureDefinition-Extension-"))
+        .collect();
+    _ = std::fs::remove_dir_all(&dir);
+
+    // The urls make the same slug, both extensions are written under names with a hash
+    assert!(output.status.success(), "{output:?}");
+    assert_eq!(extensions.len(), 2, "{extensions:?}");
+}
+
+#[test]
+fn test_ndjson_output() {
+    let dir = temp_path("ndjson-output");
+    let input = dir.join("input");
+    std::fs::create_dir_all(&input).unwrap();
+    write_similar_urls(&input);
+    let ndjson_file = dir.join("resources.ndjson");
+    let report_file = dir.join("report.json");
+
+    let output = Command::new(env!("CARGO_BIN_EXE_fhir-schema-migration-tool"))
+        .arg(&input)
+        .args(["--fhir-version", "4.0.1", "--output"])
+        .arg(dir.join("package.tgz"))
+        .arg("--ndjson-out")
+        .arg(&ndjson_file)
+        .arg("--error-report")
+        .arg(&report_file)
+        .output()
+        .unwrap();
+    let ndjson = std::fs::read_to_string(&ndjson_file).unwrap();
+    let report: Vec<serde_json::Value> =
+        serde_json::from_str(&std::fs::read_to_string(&report_file).unwrap()).unwrap();
+    _ = std::fs::remove_dir_all(&dir);
+
+    assert!(output.status.success(), "{output:?}");
+
+    // Both extensions and the profile
+    let lines: Vec<serde_json::Value> = ndjson
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    assert_eq!(lines.len(), 3, "{ndjson}");
+    assert!(
+        lines
+            .iter()
+            .all(|resource| resource["resourceType"].is_string())
+    );
+    assert!(
+        report
+            .iter()
+            .all(|entry| entry["code"] != "package::filename_collision"),
+        "{report:?}"
+    );
+}
@@ -0,0 +1,541 @@
+//! Structural checks of every StructureDefinition the emitter produces for a varied sample input.
+//!
+//! The resources are validated against `fhir.schema.json`, a subset of the official FHIR R4 JSON
+//! Schema: only known properties, valid codes and values matching the patterns of their types.
+//! No JSON Schema validator is available as a dependency, so the test interprets the keywords
+//! used by the FHIR schema itself. This catches emission bugs without a full FHIR validator.
+
+use serde_json::{Value, json};
+
+use fhir_schema_migration_tool::{
+    ConversionInput, ConvertOptions, FhirVersion,
+    attribute::aidbox::Attribute,
+    convert,
+    trie::fhir::{EmitOptions, PublicationStatus, UsageContext},
+};
+
+const SCHEMA: &str = include_str!("fhir.schema.json");
+
+fn attribute(value: Value) -> Attribute {
+    serde_json::from_value(value).unwrap()
+}
+
+fn sample_attributes() -> Vec<Attribute> {
+    let entity = |id: &str| json!({"id": id, "resourceType": "Entity"});
+    vec![
+        attribute(json!({
+            "id": "Patient.nickname",
+            "path": ["nickname"],
+            "resource": entity("Patient"),
+            "type": entity("string"),
+            "maxLength": 32,
+            "isCollection": true,
+            "isSummary": true,
+            "extensionUrl": "http://example.org/nickname",
+        })),
+        attribute(json!({
+            "id": "Patient.deceasedNote",
+            "path": ["deceasedNote"],
+            "resource": entity("Patient"),
+            "type": entity("string"),
+            "isModifier": true,
+            "extensionUrl": "http://example.org/deceasedNote",
+        })),
+        attribute(json!({
+            "id": "Patient.tree",
+            "path": ["tree"],
+            "resource": entity("Patient"),
+        })),
+        attribute(json!({
+            "id": "Patient.tree.child",
+            "path": ["tree", "child"],
+            "resource": entity("Patient"),
+            "type": {"id": "Patient.tree", "resourceType": "Attribute"},
+            "isCollection": true,
+        })),
+        attribute(json!({
+            "id": "Patient.race",
+            "path": ["race"],
+            "resource": entity("Patient"),
+            "type": entity("Coding"),
+            "valueSet": {"id": "race", "resourceType": "ValueSet"},
+            "extensionUrl": "http://example.org/race",
+        })),
+        attribute(json!({
+            "id": "Patient.score",
+            "path": ["score"],
+            "resource": entity("Patient"),
+            "union": [entity("integer"), entity("string")],
+            "isRequired": true,
+            "extensionUrl": "http://example.org/score",
+        })),
+        attribute(json!({
+            "id": "Patient.score.integer",
+            "path": ["score", "integer"],
+            "resource": entity("Patient"),
+            "type": entity("integer"),
+        })),
+        attribute(json!({
+            "id": "Patient.score.string",
+            "path": ["score", "string"],
+            "resource": entity("Patient"),
+            "type": entity("string"),
+        })),
+        attribute(json!({
+            "id": "Patient.origin",
+            "path": ["origin"],
+            "resource": entity("Patient"),
+            "extensionUrl": "http://example.org/origin",
+        })),
+        attribute(json!({
+            "id": "Patient.origin.country",
+            "path": ["origin", "country"],
+            "resource": entity("Patient"),
+            "type": entity("code"),
+            "extensionUrl": "country",
+        })),
+        attribute(json!({
+            "id": "Patient.birthTime",
+            "path": ["_birthDate", "birthTime"],
+            "resource": entity("Patient"),
+            "type": entity("dateTime"),
+            "extensionUrl": "http://example.org/birthTime",
+        })),
+        attribute(json!({
+            "id": "Observation.note.text",
+            "path": ["note", "text"],
+            "resource": entity("Observation"),
+            "type": entity("markdown"),
+            "maxLength": 1024,
+        })),
+    ]
+}
+
+/// Node of the regular expressions used as patterns by the FHIR schema
+#[derive(Debug)]
+enum Regex {
+    Start,
+    End,
+    /// A single character from the class, or outside of it if negated
+    Class(Vec<ClassItem>, bool),
+    Group(Vec<Vec<Regex>>),
+    Repeat(Box<Regex>, usize, Option<usize>),
+}
+
+#[derive(Debug)]
+enum ClassItem {
+    Char(char),
+    Range(char, char),
+    Space,
+    NotSpace,
+    Digit,
+}
+
+impl ClassItem {
+    fn matches(&self, c: char) -> bool {
+        match *self {
+            ClassItem::Char(item) => c == item,
+            ClassItem::Range(from, to) => (from..=to).contains(&c),
+            ClassItem::Space => c.is_whitespace(),
+            ClassItem::NotSpace => !c.is_whitespace(),
+            ClassItem::Digit => c.is_ascii_digit(),
+        }
+    }
+}
+
+fn parse_escape(c: char) -> ClassItem {
+    match c {
+        's' => ClassItem::Space,
+        'S' => ClassItem::NotSpace,
+        'd' => ClassItem::Digit,
+        'r' => ClassItem::Char('\r'),
+        'n' => ClassItem::Char('\n'),
+        't' => ClassItem::Char('\t'),
+        c => ClassItem::Char(c),
+    }
+}
+
+fn parse_number(chars: &[char], pos: &mut usize) -> usize {
+    let start = *pos;
+    while chars[*pos].is_ascii_digit() {
+        *pos += 1;
+    }
+    chars[start..*pos]
+        .iter()
+        .collect::<String>()
+        .parse()
+        .unwrap()
+}
+
+fn parse_alternatives(chars: &[char], pos: &mut usize) -> Vec<Vec<Regex>> {
+    let mut alternatives = vec![parse_sequence(chars, pos)];
+    while chars.get(*pos) == Some(&'|') {
+        *pos += 1;
+        alternatives.push(parse_sequence(chars, pos));
+    }
+    alternatives
+}
+
+fn parse_sequence(chars: &[char], pos: &mut usize) -> Vec<Regex> {
+    let mut sequence = Vec::new();
+    while let Some(&c) = chars.get(*pos)
+        && c != '|'
+        && c != ')'
+    {
+        *pos += 1;
+        let atom = match c {
+            '^' => Regex::Start,
+            '$' => Regex::End,
+            '.' => Regex::Class(Vec::new(), true),
+            '\\' => {
+                *pos += 1;
+                Regex::Class(vec![parse_escape(chars[*pos - 1])], false)
+            }
+            '(' => {
+                let group = parse_alternatives(chars, pos);
+                assert_eq!(chars[*pos], ')');
+                *pos += 1;
+                Regex::Group(group)
+            }
+            '[' => {
+                let negated = chars[*pos] == '^';
+                if negated {
+                    *pos += 1;
+                }
+                let mut items = Vec::new();
+                while chars[*pos] != ']' {
+                    let c = chars[*pos];
+                    *pos += 1;
+                    if c == '\\' {
+                        items.push(parse_escape(chars[*pos]));
+                        *pos += 1;
+                    } else if chars[*pos] == '-' && chars[*pos + 1] != ']' {
+                        items.push(ClassItem::Range(c, chars[*pos + 1]));
+                        *pos += 2;
+                    } else {
+                        items.push(ClassItem::Char(c));
+                    }
+                }
+                *pos += 1;
+                Regex::Class(items, negated)
+            }
+            c => Regex::Class(vec![ClassItem::Char(c)], false),
+        };
+        let (min, max) = match chars.get(*pos) {
+            Some('*') => (0, None),
+            Some('+') => (1, None),
+            Some('?') => (0, Some(1)),
+            Some('{') => {
+                *pos += 1;
+                let min = parse_number(chars, pos);
+                let max = if chars[*pos] == ',' {
+                    *pos += 1;
+                    Some(parse_number(chars, pos))
+                } else {
+                    Some(min)
+                };
+                assert_eq!(chars[*pos], '}');
+                (min, max)
+            }
+            _ => {
+                sequence.push(atom);
+                continue;
+            }
+        };
+        *pos += 1;
+        sequence.push(Regex::Repeat(Box::new(atom), min, max));
+    }
+    sequence
+}
+
+/// Match the sequence at `pos`, calling `next` with every end position until it returns true
+fn match_sequence(
+    sequence: &[Regex],
+    input: &[char],
+    pos: usize,
+    next: &mut dyn FnMut(usize) -> bool,
+) -> bool {
+    let Some((first, rest)) = sequence.split_first() else {
+        return next(pos);
+    };
+    match_node(first, input, pos, &mut |end| {
+        match_sequence(rest, input, end, next)
+    })
+}
+
+fn match_node(
+    node: &Regex,
+    input: &[char],
+    pos: usize,
+    next: &mut dyn FnMut(usize) -> bool,
+) -> bool {
+    match node {
+        Regex::Start => pos == 0 && next(pos),
+        Regex::End => pos == input.len() && next(pos),
+        Regex::Class(items, negated) => {
+            pos < input.len()
+                && items.iter().any(|item| item.matches(input[pos])) != *negated
+                && next(pos + 1)
+        }
+        Regex::Group(alternatives) => alternatives
+            .iter()
+            .any(|sequence| match_sequence(sequence, input, pos, next)),
+        Regex::Repeat(node, min, max) => match_repeat(node, *min, *max, input, pos, next),
+    }
+}
+
+/// Match `node` between `min` and `max` times at `pos`, greedily
+fn match_repeat(
+    node: &Regex,
+    min: usize,
+    max: Option<usize>,
+    input: &[char],
+    pos: usize,
+    next: &mut dyn FnMut(usize) -> bool,
+) -> bool {
+    let more = max != Some(0)
+        && match_node(node, input, pos, &mut |end| {
+            end > pos
+                && match_repeat(
+                    node,
+                    min.saturating_sub(1),
+                    max.map(|max| max - 1),
+                    input,
+                    end,
+                    next,
+                )
+        });
+    more || (min == 0 && next(pos))
+}
+
+/// Whether the pattern matches anywhere in the text, as JSON Schema patterns are not anchored
+fn is_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let mut pos = 0;
+    let regex = Regex::Group(parse_alternatives(&pattern, &mut pos));
+    assert_eq!(pos, pattern.len(), "unsupported pattern");
+    let text: Vec<char> = text.chars().collect();
+    (0..=text.len()).any(|start| match_node(&regex, &text, start, &mut |_| true))
+}
+
+/// Validate the value against the schema, with the keywords used by the FHIR JSON Schema
+fn validate(schema: &Value, root: &Value, value: &Value, at: &str, errors: &mut Vec<String>) {
+    for (keyword, argument) in schema.as_object().unwrap() {
+        match keyword.as_str() {
+            "description" | "$schema" | "id" | "definitions" => {}
+            "$ref" => {
+                let name = argument.as_str().unwrap();
+                let name = name.strip_prefix("#/definitions/").unwrap();
+                validate(&root["definitions"][name], root, value, at, errors);
+            }
+            "type" => {
+                let valid = match argument.as_str().unwrap() {
+                    "string" => value.is_string(),
+                    "number" => value.is_number(),
+                    "boolean" => value.is_boolean(),
+                    "array" => value.is_array(),
+                    "object" => value.is_object(),
+                    other => panic!("unsupported type {other}"),
+                };
+                if !valid {
+                    errors.push(format!("{at}: {value} is not a {argument}"));
+                }
+            }
+            // FHIR gives patterns for numbers and booleans too, they match the JSON text
+            "pattern" => {
+                let text = match value {
+                    Value::String(text) => text.clone(),
+                    Value::Number(_) | Value::Bool(_) => value.to_string(),
+                    _ => continue,
+                };
+                if !is_match(argument.as_str().unwrap(), &text) {
+                    errors.push(format!("{at}: {value} does not match {argument}"));
+                }
+            }
+            "const" => {
+                if value != argument {
+                    errors.push(format!("{at}: {value} is not {argument}"));
+                }
+            }
+            "enum" => {
+                if !argument.as_array().unwrap().contains(value) {
+                    errors.push(format!("{at}: {value} is not one of {argument}"));
+                }
+            }
+            "items" => {
+                for (idx, item) in value.as_array().into_iter().flatten().enumerate() {
+                    validate(argument, root, item, &format!("{at}[{idx}]"), errors);
+                }
+            }
+            "properties" => {
+                for (key, item) in value.as_object().into_iter().flatten() {
+                    if let Some(property) = argument.get(key) {
+                        validate(property, root, item, &format!("{at}.{key}"), errors);
+                    }
+                }
+            }
+            "additionalProperties" => {
+                assert_eq!(argument, false);
+                for key in value.as_object().into_iter().flatten().map(|(key, _)| key) {
+                    if schema["properties"].get(key).is_none() {
+                        errors.push(format!("{at}: unknown property {key}"));
+                    }
+                }
+            }
+            "required" => {
+                for key in argument.as_array().unwrap() {
+                    if value.get(key.as_str().unwrap()).is_none() {
+                        errors.push(format!("{at}: missing {key}"));
+                    }
+                }
+            }
+            other => panic!("unsupported keyword {other}"),
+        }
+    }
+}
+
+fn schema_errors(schema: &Value, sd: &Value) -> Vec<String> {
+    let mut errors = Vec::new();
+    validate(schema, schema, sd, sd["url"].as_str().unwrap(), &mut errors);
+    errors
+}
+
+fn is_max(value: &str) -> bool {
+    value == "*" || (!value.is_empty() && value.chars().all(|c| c.is_ascii_digit()))
+}
+
+/// Invariants of StructureDefinition which the JSON Schema cannot express
+fn check_invariants(sd: &Value) {
+    let url = sd["url"].as_str().unwrap();
+    let sd_type = sd["type"].as_str().unwrap();
+    let elements = sd["differential"]["element"].as_array().unwrap();
+    assert!(!elements.is_empty(), "{url}: empty differential");
+    for element in elements {
+        let id = element["id"].as_str().unwrap();
+        let path = element["path"].as_str().unwrap();
+        assert!(
+            path == sd_type || path.starts_with(&format!("{sd_type}.")),
+            "{id}: path {path} is not within {sd_type}"
+        );
+        if let Some(max) = element.get("max") {
+            assert!(is_max(max.as_str().unwrap()), "{id}: invalid max {max}");
+        }
+    }
+}
+
+#[test]
+fn test_schema_patterns() {
+    let schema: Value = serde_json::from_str(SCHEMA).unwrap();
+    let pattern = |name: &str| {
+        schema["definitions"][name]["pattern"]
+            .as_str()
+            .unwrap()
+            .to_owned()
+    };
+    assert!(is_match(&pattern("id"), "Patient-nick-name.1"));
+    assert!(!is_match(&pattern("id"), "nick_name"));
+    assert!(!is_match(&pattern("id"), &"a".repeat(65)));
+    assert!(is_match(&pattern("code"), "open at end"));
+    assert!(!is_match(&pattern("code"), "open  at end"));
+    assert!(is_match(&pattern("dateTime"), "2024-05"));
+    assert!(is_match(&pattern("dateTime"), "2024-05-01T10:30:00+02:00"));
+    assert!(!is_match(&pattern("dateTime"), "2024-13-01"));
+    assert!(!is_match(&pattern("uri"), "http://example.org/nick name"));
+    assert!(is_match(&pattern("positiveInt"), "32"));
+    assert!(!is_match(&pattern("positiveInt"), "0"));
+}
+
+#[test]
+fn test_schema_rejects_invalid_definitions() {
+    let schema: Value = serde_json::from_str(SCHEMA).unwrap();
+    let sd = json!({
+        "resourceType": "StructureDefinition",
+        "id": "nick_name",
+        "url": "http://example.org/nickname",
+        "status": "final",
+        "differential": {"element": [{"id": "Extension", "path": "Extension", "min": "1"}]},
+        "nickname": true,
+    });
+
+    assert_eq!(
+        schema_errors(&schema, &sd),
+        [
+            r#"http://example.org/nickname.id: "nick_name" does not match "^[A-Za-z0-9\\-\\.]{1,64}$""#,
+            r#"http://example.org/nickname.status: "final" is not one of ["draft","active","retired","unknown"]"#,
+            r#"http://example.org/nickname.differential.element[0].min: "1" is not a "number""#,
+            "http://example.org/nickname: unknown property nickname",
+        ]
+    );
+}
+
+/// Options enabling every optional property of the emitted definitions
+fn sample_options() -> ConvertOptions {
+    ConvertOptions {
+        emit_modifier: true,
+        emit_summary: true,
+        allow_recursive: true,
+        language: Some("en".to_owned()),
+        resource_version: Some("1.0.0".to_owned()),
+        status: PublicationStatus::Draft,
+        date: Some("2024-05-01".to_owned()),
+        publisher: Some("Example Clinic".to_owned()),
+        use_context: vec![UsageContext::new("program", "oncology")],
+        emit: EmitOptions {
+            must_support: ["Patient.extension:nickname".to_owned()].into(),
+            ..Default::default()
+        },
+        ..Default::default()
+    }
+}
+
+#[test]
+fn test_emitted_structure_definitions_are_well_formed() {
+    let input = ConversionInput {
+        attributes: sample_attributes(),
+        ..Default::default()
+    };
+    let result = convert(input, FhirVersion::V4_0_1, &sample_options());
+    assert!(!result.had_errors, "{:?}", result.errors);
+    assert!(!result.extensions.is_empty());
+    assert!(!result.profiles.is_empty());
+
+    let sds: Vec<Value> = result
+        .extensions
+        .iter()
+        .chain(result.profiles.iter())
+        .map(|sd| serde_json::to_value(sd).unwrap())
+        .collect();
+    let schema: Value = serde_json::from_str(SCHEMA).unwrap();
+    for sd in &sds {
+        let errors = schema_errors(&schema, sd);
+        assert!(errors.is_empty(), "{errors:#?}");
+        check_invariants(sd);
+        assert_eq!(sd["fhirVersion"], "4.0.1", "{}", sd["url"]);
+    }
+
+    // The optional properties are emitted by the sample, so that they are validated
+    let elements: Vec<&Value> = sds
+        .iter()
+        .flat_map(|sd| sd["differential"]["element"].as_array().unwrap())
+        .collect();
+    for property in ["version", "date", "publisher", "language", "useContext"] {
+        assert!(
+            sds.iter().all(|sd| sd.get(property).is_some()),
+            "{property} is not emitted"
+        );
+    }
+    for property in [
+        "mustSupport",
+        "isModifier",
+        "isModifierReason",
+        "isSummary",
+        "contentReference",
+    ] {
+        assert!(
+            elements
+                .iter()
+                .any(|element| element.get(property).is_some()),
+            "{property} is not emitted"
+        );
+    }
+}